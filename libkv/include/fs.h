@@ -46,18 +46,43 @@ enum
     SEEK_C
 };
 
-void create_disk(const std::string &path);
-void init(const std::string &path, bool format);
-MemoryEntry *create(const char *);
-int write(MemoryEntry *, const char *, int);
-int read(MemoryEntry *, char *, int);
-MemoryEntry *open(const char *);
-int close(MemoryEntry *);
-void destroy();
-int seek(MemoryEntry *, u64, int);
-bool remove_file(const char *);
-bool rename_file(const char *oldname, const char *newname);
-bool eof(MemoryEntry *);
-u64 fsize(MemoryEntry *ent);
+// A disk file holding the two log files of one KVStore. Every instance keeps its own descriptor,
+// entry table and transfer buffer, so stores on different disks can be open side by side.
+class Disk
+{
+public:
+    Disk(const std::string &path, bool format);
+    ~Disk();
+    MemoryEntry *create(const char *);
+    int write(MemoryEntry *, const char *, int);
+    int read(MemoryEntry *, char *, int);
+    MemoryEntry *open(const char *);
+    int close(MemoryEntry *);
+    bool sync_disk();
+    // whether any disk access failed since the disk was opened, the in-memory state may then be
+    // ahead of the disk
+    bool io_error() const;
+    int seek(MemoryEntry *, u64, int);
+    bool remove_file(const char *);
+    bool rename_file(const char *oldname, const char *newname);
+    bool eof(MemoryEntry *);
+    u64 fsize(MemoryEntry *ent);
+
+private:
+    Disk(const Disk &) = delete;
+    Disk &operator=(const Disk &) = delete;
+    void create_disk(const std::string &path);
+    void write_entry();
+    int find_entry();
+    MemoryEntry *look_up(const char *name);
+    bool read_disk(int block_no, int block_size);
+    bool write_disk(int block_no, int block_size);
+
+    // both are aligned for O_DIRECT
+    superblock *sb;
+    Data *databuf;
+    int fd;
+    bool io_failed;
+};
 
 #endif
\ No newline at end of file
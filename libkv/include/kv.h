@@ -3,26 +3,39 @@
 
 #include <stdint.h>
 #include <string>
-#include <unordered_map>
+#include <map>
 #include <vector>
 
 struct MemoryEntry;
+class Disk;
 
 class KVStore {
 private:
   int offset;
+  Disk *disk;
   MemoryEntry *file;
   std::string dir;
-  std::unordered_map<std::string, std::string> mp;
+  // ordered so scans can resume after the last key they returned
+  std::map<std::string, std::string> mp;
   void savekv(MemoryEntry * ment);
 public:
   KVStore(const std::string &dir, bool format);
+  KVStore(const KVStore &) = delete;
+  KVStore &operator=(const KVStore &) = delete;
   ~KVStore();
   int size() const;
   std::string get(const std::string &key) const;
   bool put(const std::string &key, const std::string &val);
   bool remove(const std::string &key);
   std::vector<std::string> list() const;
+  // up to count keys from the first one not less than from on, in ascending order
+  std::vector<std::string> scan(const std::string &from, uint32_t count) const;
+  // rewrite the log with only the live entries, dropping overwritten values and tombstones
+  void compact();
+  // force every logged entry to stable storage, false when it or an earlier write failed
+  bool sync();
+  // whether loading or writing the log failed, later changes may then not be durable
+  bool failed() const;
 };
 
 #endif
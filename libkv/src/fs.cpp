@@ -1,5 +1,5 @@
-#include <assert.h>
 #include <fcntl.h>
+#include <stdlib.h>
 #include <stdio.h>
 #include <string.h>
 #include <unistd.h>
@@ -13,61 +13,74 @@
 #include "fs.h"
 #include "lru.h"
 
-static struct superblock sb __attribute__((aligned(4096)));
-static Data databuf[1024] __attribute__((aligned(4096)));
-
-static int fd = 0;
-
-void create_disk(const std::string &path){
-    fd = open(path.c_str(), O_CREAT | O_EXCL | O_WRONLY, 0777);
-    assert(fd >= 0);
-    sb.magic_number = MAGICNUM;
-    sb.block_size = DISK_SIZE / BSIZE;
-    memset(sb.entries, 0, sizeof(sb.entries));
-    write(fd, &sb, sizeof(sb));
-    char *tmp = new char[DISK_SIZE - sizeof(sb)];
-    memset(tmp, 0, DISK_SIZE - sizeof(sb));
-    write(fd, tmp, DISK_SIZE - sizeof(sb));
+// number of blocks transferred by a single disk access
+const int DATABUF_BLOCKS = 1024;
+
+void Disk::create_disk(const std::string &path){
+    fd = ::open(path.c_str(), O_CREAT | O_EXCL | O_WRONLY, 0777);
+    if(fd < 0){
+        io_failed = true;
+        return;
+    }
+    sb->magic_number = MAGICNUM;
+    sb->block_size = DISK_SIZE / BSIZE;
+    memset(sb->entries, 0, sizeof(sb->entries));
+    ::write(fd, sb, sizeof(superblock));
+    char *tmp = new char[DISK_SIZE - sizeof(superblock)];
+    memset(tmp, 0, DISK_SIZE - sizeof(superblock));
+    ::write(fd, tmp, DISK_SIZE - sizeof(superblock));
     delete[] tmp;
-    close(fd);
+    ::close(fd);
 }
 
-void init(const std::string &path, bool format) {
-    fd = open(path.c_str(), O_RDWR | O_NOATIME | O_DIRECT, 0777);
+Disk::Disk(const std::string &path, bool format) : fd(-1), io_failed(false) {
+    void *mem = nullptr;
+    posix_memalign(&mem, 4096, sizeof(superblock));
+    sb = static_cast<superblock *>(mem);
+    posix_memalign(&mem, 4096, sizeof(Data) * DATABUF_BLOCKS);
+    databuf = static_cast<Data *>(mem);
+    memset(sb, 0, sizeof(superblock));
+    memset(databuf, 0, sizeof(Data) * DATABUF_BLOCKS);
+    fd = ::open(path.c_str(), O_RDWR | O_NOATIME | O_DIRECT, 0777);
     if(fd == -1){
         create_disk(path);
-        fd = open(path.c_str(), O_RDWR | O_NOATIME | O_DIRECT, 0777);
-    }
-    assert(fd >= 0);
-    read(fd, &sb, sizeof(superblock));
-    if(sb.magic_number != MAGICNUM || format){
-        sb.magic_number = MAGICNUM;
-        sb.block_size = DISK_SIZE / BSIZE;
-        memset(sb.entries, 0, sizeof(sb.entries));
+        fd = ::open(path.c_str(), O_RDWR | O_NOATIME | O_DIRECT, 0777);
+    }
+    if(fd < 0 || ::read(fd, sb, sizeof(superblock)) != sizeof(superblock)){
+        io_failed = true;
+        memset(sb, 0, sizeof(superblock));
+        return;
+    }
+    if(sb->magic_number != MAGICNUM || format){
+        sb->magic_number = MAGICNUM;
+        sb->block_size = DISK_SIZE / BSIZE;
+        memset(sb->entries, 0, sizeof(sb->entries));
         lseek(fd, 0, SEEK_SET);
-        write(fd, &sb, sizeof(sb));
+        ::write(fd, sb, sizeof(superblock));
     }
 }
 
 
-void write_entry() {
+void Disk::write_entry() {
     lseek(fd, 0, SEEK_SET);
-    int size = write(fd, &sb, BSIZE);
-    assert(size == BSIZE);
+    int size = ::write(fd, sb, BSIZE);
+    if(size != BSIZE){
+        io_failed = true;
+    }
 }
 
-int find_entry(){
+int Disk::find_entry(){
     for(int i = 0; i < 2; i++){
-        if(sb.entries[i].used == 0){
+        if(sb->entries[i].used == 0){
             return i;
         }
     }
     return -1;
 }
 
-MemoryEntry *look_up(const char *name){
+MemoryEntry *Disk::look_up(const char *name){
     for (int i = 0; i < 2; i++) {
-        entry *cur = &sb.entries[i];
+        entry *cur = &sb->entries[i];
         if (cur->used && strcmp(cur->name, name) == 0){
             MemoryEntry *ret = new MemoryEntry();
             ret->pos = i;
@@ -78,7 +91,7 @@ MemoryEntry *look_up(const char *name){
     return NULL;
 }
 
-MemoryEntry *create(const char *name) {
+MemoryEntry *Disk::create(const char *name) {
     MemoryEntry *res = look_up(name);
     if (res != NULL)
     {
@@ -89,12 +102,12 @@ MemoryEntry *create(const char *name) {
     {
         return NULL;
     }
-    entry *cur = &(sb.entries[pos]);
+    entry *cur = &(sb->entries[pos]);
     strcpy(cur->name, name);
     if(pos == 0){
-        cur->block_start = sizeof(sb) / BSIZE;
+        cur->block_start = sizeof(superblock) / BSIZE;
     } else {
-        cur->block_start = sb.block_size / 2;
+        cur->block_start = sb->block_size / 2;
     }
     cur->used = 1;
     cur->fsize = 0;
@@ -105,59 +118,71 @@ MemoryEntry *create(const char *name) {
     return res;
 }
 
-void read_disk(int block_no, int block_size){
+bool Disk::read_disk(int block_no, int block_size){
     int size = block_size * BSIZE;
     lseek(fd, block_no * BSIZE, SEEK_SET);
-    int read_size = read(fd, databuf, size);
-    assert(size == read_size);
+    int read_size = ::read(fd, databuf, size);
+    if(size != read_size){
+        io_failed = true;
+        return false;
+    }
+    return true;
 }
 
-void write_disk(int block_no, int block_size) {
+bool Disk::write_disk(int block_no, int block_size) {
     int size = block_size * BSIZE;
     lseek(fd, block_no * BSIZE, SEEK_SET);
-    int write_size = write(fd, &databuf, size);
-    assert(write_size == size);
+    int write_size = ::write(fd, databuf, size);
+    if(write_size != size){
+        io_failed = true;
+        return false;
+    }
+    return true;
 }
 
-int write(MemoryEntry *ment, const char *buffer, int len){
-    entry *ent = &(sb.entries[ment->pos]);
+int Disk::write(MemoryEntry *ment, const char *buffer, int len){
+    entry *ent = &(sb->entries[ment->pos]);
     int p = 0;
     while (p < len) {
         int current_from = ent->fsize % BSIZE;
         int last_block = ent->block_start + (ent->fsize / BSIZE);
-        if (current_from != 0){
-            read_disk(last_block, 1);
+        if (current_from != 0 && !read_disk(last_block, 1)){
+            return -1;
         }
-        int write_size = min(sizeof(databuf) - current_from, len - p);
+        int write_size = min(sizeof(Data) * DATABUF_BLOCKS - current_from, len - p);
         memcpy(databuf[0].buf + current_from, buffer + p, write_size);
         p += write_size;
         ent->fsize += write_size;
         int block_size = (current_from + write_size + BSIZE - 1) / BSIZE;
-        write_disk(last_block, block_size);
+        if(!write_disk(last_block, block_size)){
+            return -1;
+        }
     }
     write_entry();
     return len;
 }
 
-bool eof(MemoryEntry *ment){
-    if (ment->offset == sb.entries[ment->pos].fsize)
+bool Disk::eof(MemoryEntry *ment){
+    if (ment->offset == sb->entries[ment->pos].fsize)
     {
         return true;
     }
     return false;
 }
 
-int read(MemoryEntry *ment, char *buffer, int len){
-    entry *ent = &(sb.entries[ment->pos]);
+int Disk::read(MemoryEntry *ment, char *buffer, int len){
+    entry *ent = &(sb->entries[ment->pos]);
     int fsize = ent->fsize;
     int p = 0;
     int current = ment->offset;
     while (p < len && current < fsize){
         int current_from = current % BSIZE;
-        int cur_block = (ment->offset / BSIZE) + sb.entries[ment->pos].block_start;
-        int read_size = min(min(sizeof(databuf) - current_from, len - p), fsize - current);
+        int cur_block = (ment->offset / BSIZE) + sb->entries[ment->pos].block_start;
+        int read_size = min(min(sizeof(Data) * DATABUF_BLOCKS - current_from, len - p), fsize - current);
         int read_block = (current_from + read_size + BSIZE - 1) / BSIZE;
-        read_disk(cur_block, read_block);
+        if(!read_disk(cur_block, read_block)){
+            break;
+        }
         memcpy(buffer + p, databuf[0].buf + current_from, read_size);
         p += read_size;
         current += read_size;
@@ -166,8 +191,8 @@ int read(MemoryEntry *ment, char *buffer, int len){
     return p;
 }
 
-int seek(MemoryEntry *ment, u64 offset, int from){
-    entry *ent = &(sb.entries[ment->pos]);
+int Disk::seek(MemoryEntry *ment, u64 offset, int from){
+    entry *ent = &(sb->entries[ment->pos]);
     if (from == SEEK_C) {
         offset += ment->offset;
     }
@@ -181,12 +206,12 @@ int seek(MemoryEntry *ment, u64 offset, int from){
     return ment->offset;
 }
 
-MemoryEntry *open(const char *name) {
+MemoryEntry *Disk::open(const char *name) {
     MemoryEntry *ret = look_up(name);
     return ret;
 }
 
-int close(MemoryEntry *p) {
+int Disk::close(MemoryEntry *p) {
     if (p != NULL) {
         delete p;
         return 0;
@@ -194,37 +219,53 @@ int close(MemoryEntry *p) {
     return -1;
 }
 
-void destroy() {
-    close(fd);
+Disk::~Disk() {
+    if(fd >= 0){
+        ::close(fd);
+    }
+    free(sb);
+    free(databuf);
+}
+
+bool Disk::sync_disk() {
+    if(fdatasync(fd) != 0){
+        io_failed = true;
+    }
+    return !io_failed;
 }
 
-u64 fsize(MemoryEntry *ent){
-    return sb.entries[ent->pos].fsize;
+bool Disk::io_error() const {
+    return io_failed;
 }
 
-bool remove_file(const char *filename) {
+u64 Disk::fsize(MemoryEntry *ent){
+    return sb->entries[ent->pos].fsize;
+}
+
+bool Disk::remove_file(const char *filename) {
     MemoryEntry *mement = look_up(filename);
     if (mement == nullptr) {
         return false;
     }
-    memset(&sb.entries[mement->pos], 0, sizeof(entry));
+    memset(&sb->entries[mement->pos], 0, sizeof(entry));
     write_entry();
     delete mement;
     return true;
 }
 
-bool rename_file(const char *oldname, const char *newname) {
+// An existing file named newname is replaced in the same write of the entry table, which fits
+// one sector, so a crash leaves either the old or the new file under that name.
+bool Disk::rename_file(const char *oldname, const char *newname) {
     MemoryEntry *mement = look_up(oldname);
-    MemoryEntry *mementnew = look_up(newname);
     if (mement == nullptr) {
         return false;
     }
+    MemoryEntry *mementnew = look_up(newname);
     if (mementnew) {
-        delete mement;
+        memset(&sb->entries[mementnew->pos], 0, sizeof(entry));
         delete mementnew;
-        return false;
     }
-    strcpy(sb.entries[mement->pos].name, newname);
+    strcpy(sb->entries[mement->pos].name, newname);
     write_entry();
     delete mement;
     return true;
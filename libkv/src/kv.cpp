@@ -1,7 +1,7 @@
 #include <stdint.h>
 
 #include <string>
-#include <unordered_map>
+#include <map>
 #include <vector>
 
 #include "fs.h"
@@ -10,47 +10,46 @@
 void KVStore::savekv(MemoryEntry * ment){
   for(const auto & each : mp){
     int log_size[2] = {int(each.first.size()), int(each.second.size())};
-    write(ment, (char *)log_size, 8);
-    write(ment, each.first.c_str(), log_size[0]);
-    write(ment, each.second.c_str(), log_size[1]);
+    disk->write(ment, (char *)log_size, 8);
+    disk->write(ment, each.first.c_str(), log_size[0]);
+    disk->write(ment, each.second.c_str(), log_size[1]);
   }
 }
 
-KVStore::KVStore(const std::string &dir, bool format) : dir(dir) {
-  init(dir, format);
-  file = open("current");
+KVStore::KVStore(const std::string &dir, bool format) : disk(new Disk(dir, format)), dir(dir) {
+  file = disk->open("current");
   if (file == nullptr) {
-    file = open("new");
+    file = disk->open("new");
     if (file == nullptr) {
-      file = create("current");
+      file = disk->create("current");
     } else {
-      rename_file("new", "current");
+      disk->rename_file("new", "current");
     }
   } else {
-    remove_file("new");
+    disk->remove_file("new");
   }
   offset = 0;
   bool broken = false;
   while (1) {
     int len[2];
     std::string key, val;
-    if (eof(file)) {
+    if (disk->eof(file)) {
       break;
     }
-    int read_size = read(file, (char *)len, 8);
+    int read_size = disk->read(file, (char *)len, 8);
     if(read_size != 8){
       broken = true;
       break;
     }
     key.resize(len[0]);
-    read_size = read(file, &key[0], len[0]);
+    read_size = disk->read(file, &key[0], len[0]);
     if(read_size != len[0]){
       broken = true;
       break;
     }
     if (len[1]) {
       val.resize(len[1]);
-      read_size = read(file, &val[0], len[1]);
+      read_size = disk->read(file, &val[0], len[1]);
       if(read_size != len[1]){
         broken = true;
         break;
@@ -61,25 +60,38 @@ KVStore::KVStore(const std::string &dir, bool format) : dir(dir) {
     }
     offset += 8 + len[0] + len[1];
   }
-  if(broken){
-    close(file);
-    MemoryEntry * newfile = create("new");
+  // a failed read leaves the log intact, only rewrite one that is truly truncated
+  if(broken && !disk->io_error()){
+    disk->close(file);
+    MemoryEntry * newfile = disk->create("new");
     savekv(newfile);
-    remove_file("current");
-    rename_file("new", "current");
+    disk->sync_disk();
+    disk->rename_file("new", "current");
+    disk->sync_disk();
     file = newfile;
     newfile = nullptr;
   }
 }
 
-KVStore::~KVStore() {
-  close(file);
-  MemoryEntry * newfile = create("new");
+// The rewritten log reaches the disk before the entry table points at it, and the entry table
+// before compacting is done, so a crash at any point leaves one complete log as "current".
+void KVStore::compact() {
+  disk->close(file);
+  MemoryEntry * newfile = disk->create("new");
   savekv(newfile);
-  remove_file("current");
-  rename_file("new", "current");
-  close(newfile);
-  destroy();
+  disk->sync_disk();
+  disk->rename_file("new", "current");
+  disk->sync_disk();
+  file = newfile;
+}
+
+bool KVStore::sync() { return disk->sync_disk(); }
+
+bool KVStore::failed() const { return disk->io_error(); }
+
+KVStore::~KVStore() {
+  disk->close(file);
+  delete disk;
 }
 
 int KVStore::size() const { return mp.size(); }
@@ -95,19 +107,19 @@ std::string KVStore::get(const std::string &key) const {
 bool KVStore::put(const std::string &key, const std::string &val) {
   int log_size[2] = {int(key.size()), int(val.size())};
   offset += 8 + log_size[0];
-  write(file, (char *)log_size, 8);
-  write(file, key.c_str(), log_size[0]);
-  write(file, val.c_str(), log_size[1]);
+  disk->write(file, (char *)log_size, 8);
+  disk->write(file, key.c_str(), log_size[0]);
+  disk->write(file, val.c_str(), log_size[1]);
   mp[key] = val;
-  return true;
+  return !disk->io_error();
 }
 
 bool KVStore::remove(const std::string &key) {
   auto iter = mp.find(key);
   if (iter != mp.end()) {
     int log_size[2] = {int(key.size()), 0};
-    write(file, (char *)log_size, 8);
-    write(file, key.c_str(), log_size[0]);
+    disk->write(file, (char *)log_size, 8);
+    disk->write(file, key.c_str(), log_size[0]);
     offset += 8 + log_size[0];
     mp.erase(iter);
     return true;
@@ -121,4 +133,12 @@ std::vector<std::string> KVStore::list() const {
     ret.push_back(each.first);
   }
   return ret;
-}
\ No newline at end of file
+}
+
+std::vector<std::string> KVStore::scan(const std::string &from, uint32_t count) const {
+  std::vector<std::string> ret;
+  for (auto iter = mp.lower_bound(from); iter != mp.end() && ret.size() < count; ++iter) {
+    ret.push_back(iter->first);
+  }
+  return ret;
+}
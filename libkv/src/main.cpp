@@ -6,15 +6,14 @@
 
 int main()
 {
-    init("raw1", false);
-    auto file = create("1234");
-    close(file);
+    Disk disk("raw1", false);
+    auto file = disk.create("1234");
+    disk.close(file);
     char buf1[10000] = {0};
-    file = open("1234");
-    write(file, "1234", 4);
-    read(file, buf1, 4);
+    file = disk.open("1234");
+    disk.write(file, "1234", 4);
+    disk.read(file, buf1, 4);
     printf("%s\n", buf1);
-    close(file);
-    destroy();
+    disk.close(file);
     return 0;
-}
\ No newline at end of file
+}
@@ -0,0 +1,138 @@
+use bitmap_allocator::{BitAlloc, BitAlloc256M};
+use std::alloc::{alloc_zeroed, Layout};
+use std::ops::Range;
+use std::os::raw::c_int;
+
+/// How scattered the free ids of an [`Allocator`] are.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Fragmentation {
+    /// number of maximal runs of consecutive free ids
+    pub free_runs: usize,
+    /// length of the longest of them, the largest contiguous allocation that can succeed
+    pub largest_free_run: usize,
+}
+
+/// A bitmap allocator that keeps count of its free ids.
+pub struct Allocator {
+    bitmap: Box<BitAlloc256M>,
+    start: usize,
+    // ids from here on have never left the allocator
+    high: usize,
+    total: usize,
+    free: usize,
+}
+
+impl Allocator {
+    pub const CAP: usize = BitAlloc256M::CAP;
+
+    pub fn new(avail: Range<usize>) -> Self {
+        let mut bitmap = unsafe {
+            let layout = Layout::new::<BitAlloc256M>();
+            let ptr = alloc_zeroed(layout) as *mut BitAlloc256M;
+            Box::from_raw(ptr)
+        };
+        bitmap.insert(avail.clone());
+        Self {
+            bitmap,
+            start: avail.start,
+            high: avail.start,
+            total: avail.len(),
+            free: avail.len(),
+        }
+    }
+    pub fn total(&self) -> usize {
+        self.total
+    }
+    pub fn free(&self) -> usize {
+        self.free
+    }
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+    pub fn test(&self, key: usize) -> bool {
+        self.bitmap.test(key)
+    }
+    pub fn alloc(&mut self) -> Option<usize> {
+        let key = self.bitmap.alloc()?;
+        self.high = std::cmp::max(self.high, key + 1);
+        self.free -= 1;
+        Some(key)
+    }
+    pub fn alloc_contiguous(&mut self, size: usize) -> Option<usize> {
+        let begin = self.bitmap.alloc_contiguous(size, 0)?;
+        self.high = std::cmp::max(self.high, begin + size);
+        self.free -= size;
+        Some(begin)
+    }
+    /// Allocates `cnt` ids, preferring a single contiguous run and falling back to
+    /// progressively smaller runs once free space is fragmented.
+    pub fn alloc_extents(&mut self, cnt: usize) -> Result<Vec<Range<usize>>, c_int> {
+        let mut extents: Vec<Range<usize>> = vec![];
+        let mut remaining = cnt;
+        let mut run = cnt;
+        while remaining > 0 {
+            match self.alloc_contiguous(run) {
+                Some(begin) => {
+                    extents.push(begin..begin + run);
+                    remaining -= run;
+                    run = std::cmp::min(run, remaining);
+                }
+                None if run > 1 => run /= 2,
+                None => {
+                    extents.into_iter().for_each(|e| self.insert(e));
+                    return Err(libc::ENOSPC);
+                }
+            }
+        }
+        Ok(extents)
+    }
+    pub fn dealloc(&mut self, key: usize) {
+        self.insert(key..key + 1);
+    }
+    /// Marks `range` as free.
+    pub fn insert(&mut self, range: Range<usize>) {
+        self.free += range.clone().filter(|&key| !self.bitmap.test(key)).count();
+        self.bitmap.insert(range);
+    }
+    /// Marks `range` as in use.
+    pub fn remove(&mut self, range: Range<usize>) {
+        self.free -= range.clone().filter(|&key| self.bitmap.test(key)).count();
+        self.high = std::cmp::max(self.high, range.end);
+        self.bitmap.remove(range);
+    }
+    /// Free runs of ids, adjacent free ids counting as one run however they were freed. Ids from
+    /// the highest one ever handed out on are free and not walked.
+    pub fn fragmentation(&self) -> Fragmentation {
+        let mut frag = Fragmentation::default();
+        let mut run = 0;
+        for key in self.start..self.high {
+            if self.bitmap.test(key) {
+                run += 1;
+                continue;
+            }
+            if run > 0 {
+                frag.free_runs += 1;
+                frag.largest_free_run = std::cmp::max(frag.largest_free_run, run);
+            }
+            run = 0;
+        }
+        // the untouched tail continues a run reaching up to it
+        run += self.start + self.total - self.high;
+        if run > 0 {
+            frag.free_runs += 1;
+            frag.largest_free_run = std::cmp::max(frag.largest_free_run, run);
+        }
+        frag
+    }
+    /// Ranges of ids in use, walking only up to the highest id ever handed out.
+    pub fn used_ranges(&self) -> Vec<Range<usize>> {
+        let mut used: Vec<Range<usize>> = vec![];
+        for key in (self.start..self.high).filter(|&key| !self.bitmap.test(key)) {
+            match used.last_mut() {
+                Some(last) if last.end == key => last.end += 1,
+                _ => used.push(key..key + 1),
+            }
+        }
+        used
+    }
+}
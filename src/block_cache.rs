@@ -1,18 +1,59 @@
-use crate::block_dev::BlockDevice;
+use crate::block_dev::{BackendKind, BlockStore, DeviceSet};
+use crate::metrics::Metrics;
 use log::error;
 use lru::LruCache;
-use std::io::{Read, Result, Write};
+use std::collections::BTreeSet;
+use std::io::{Error, Read, Result, Write};
+use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-pub struct Block<const BLOCK_SIZE: usize> {
-    buffer: [u8; BLOCK_SIZE],
+/// When modified blocks reach the device.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WritebackPolicy {
+    /// on eviction or an explicit flush
+    WriteBack,
+    /// as soon as they are modified
+    WriteThrough,
+    /// additionally every `interval`, which must not be zero, driven by whoever owns the cache
+    /// calling `flush`
+    Periodic { interval: Duration },
+}
+
+impl FromStr for WritebackPolicy {
+    type Err = String;
+    /// Parses `writeback`, `writethrough` or `periodic:<seconds>`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "writeback" => Ok(WritebackPolicy::WriteBack),
+            None if s == "writethrough" => Ok(WritebackPolicy::WriteThrough),
+            Some(("periodic", secs)) => match secs.parse() {
+                Ok(0) => Err("the write-back interval must be at least a second".to_string()),
+                Ok(secs) => Ok(WritebackPolicy::Periodic {
+                    interval: Duration::from_secs(secs),
+                }),
+                Err(err) => Err(format!("invalid interval {}: {}", secs, err)),
+            },
+            _ => Err(format!(
+                "unknown write-back policy {}, expected writeback, writethrough or periodic:<seconds>",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Block {
+    buffer: Box<[u8]>,
     block_id: usize,
     dirty: bool,
-    dev: Arc<BlockDevice<BLOCK_SIZE>>,
+    dev: Arc<dyn BlockStore>,
+    failed: Arc<AtomicBool>,
 }
 
-impl<const BLOCK_SIZE: usize> Drop for Block<BLOCK_SIZE> {
+impl Drop for Block {
     fn drop(&mut self) {
         if self.dirty {
             if let Err(err) = self.dev.write_block(self.block_id, &self.buffer) {
@@ -20,64 +61,298 @@ impl<const BLOCK_SIZE: usize> Drop for Block<BLOCK_SIZE> {
                     "failed to write back block cache for block id {}, error {}",
                     self.block_id, err
                 );
+                self.failed.store(true, Ordering::SeqCst);
             }
         }
     }
 }
 
-pub struct BlockCache<const BLOCK_SIZE: usize> {
-    dev: Arc<BlockDevice<BLOCK_SIZE>>,
-    cache: LruCache<usize, Block<BLOCK_SIZE>>,
+pub struct BlockCache {
+    dev: Arc<dyn BlockStore>,
+    cache: LruCache<usize, Block>,
+    policy: WritebackPolicy,
+    // cached blocks not yet written back, so syncing does not scan the whole cache
+    dirty: BTreeSet<usize>,
+    dev_blocks: usize,
+    last_read: Option<usize>,
+    /// number of blocks fetched ahead once reads turn sequential, zero disables read-ahead
+    pub read_ahead: usize,
+    /// number of consecutive blocks from which ranges bypass the cache, zero disables bypassing
+    pub bypass: usize,
+    /// counters the cache lookups of `read_block` are recorded in
+    pub metrics: Arc<Metrics>,
+    // set once a block evicted while dirty failed to be written back, its data is lost
+    failed: Arc<AtomicBool>,
 }
 
-impl<const BLOCK_SIZE: usize> BlockCache<BLOCK_SIZE> {
-    pub fn new<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
+impl BlockCache {
+    /// Caches the blocks of the data devices at `paths`, striped as described at [`DeviceSet`].
+    pub fn new<P: AsRef<Path>>(
+        paths: &[P],
+        block_size: usize,
+        capacity: usize,
+        policy: WritebackPolicy,
+        backend: BackendKind,
+    ) -> Result<Self> {
+        let dev = DeviceSet::new(paths, block_size, backend)?;
+        Self::with_store(Arc::new(dev), capacity, policy)
+    }
+    /// Caches the blocks of `dev`, which may also live in memory, see
+    /// [`MemBlockStore`](crate::block_dev::MemBlockStore).
+    pub fn with_store(
+        dev: Arc<dyn BlockStore>,
+        capacity: usize,
+        policy: WritebackPolicy,
+    ) -> Result<Self> {
         Ok(Self {
-            dev: Arc::from(BlockDevice::new(path)?),
+            dev_blocks: dev.size()?,
+            dev,
             cache: LruCache::new(capacity),
+            policy,
+            dirty: BTreeSet::new(),
+            last_read: None,
+            read_ahead: 0,
+            bypass: 0,
+            metrics: Arc::default(),
+            failed: Arc::default(),
         })
     }
-    pub fn read_block(&mut self, block_id: usize, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
-        if let Some(block) = self.cache.get(&block_id) {
-            block.buffer.as_slice().read_exact(buf)
+    /// Fails with EIO once a write-back on eviction failed. Its caller went on believing the
+    /// block was safe, so nothing may pretend to be durable from then on.
+    fn check(&self) -> Result<()> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(Error::from_raw_os_error(libc::EIO));
+        }
+        Ok(())
+    }
+    pub fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+    /// Blocks the cache is backed by, for accessing those it never holds.
+    pub fn store(&self) -> &Arc<dyn BlockStore> {
+        &self.dev
+    }
+    pub fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        self.check()?;
+        let sequential = self.last_read.map_or(false, |last| last + 1 == block_id);
+        self.last_read = Some(block_id);
+        if sequential && self.read_ahead > 0 && !self.cache.contains(&block_id) {
+            self.read_ahead(block_id, self.read_ahead)?;
+        }
+        let cached = self.cache.get(&block_id);
+        self.metrics.block_cache_access(cached.is_some());
+        if let Some(block) = cached {
+            (&block.buffer[..]).read_exact(buf)
         } else {
             self.dev.read_block(block_id, buf)?;
-            self.cache.put(
+            self.insert(Block {
                 block_id,
-                Block {
-                    block_id,
-                    buffer: *buf,
-                    dev: self.dev.clone(),
-                    dirty: false,
-                },
-            );
+                buffer: buf.into(),
+                dev: self.dev.clone(),
+                failed: self.failed.clone(),
+                dirty: false,
+            });
             Ok(())
         }
     }
-    pub fn write_block(&mut self, block_id: usize, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
+    /// Reads the consecutive blocks from `block_id` on that fill `buf`. At least [`Self::bypass`]
+    /// blocks are read from the device in a single request without caching them, their cached
+    /// copies taking precedence over what the device holds.
+    pub fn read_range(&mut self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        let block_size = self.block_size();
+        let count = buf.len() / block_size;
+        if self.bypass == 0 || count < self.bypass {
+            for (i, buf) in buf.chunks_exact_mut(block_size).enumerate() {
+                self.read_block(block_id + i, buf)?;
+            }
+            return Ok(());
+        }
+        self.check()?;
+        self.dev.read_blocks(block_id, buf)?;
+        self.last_read = Some(block_id + count - 1);
+        for (i, buf) in buf.chunks_exact_mut(block_size).enumerate() {
+            if let Some(block) = self.cache.peek(&(block_id + i)) {
+                buf.copy_from_slice(&block.buffer);
+            }
+        }
+        Ok(())
+    }
+    /// Writes the consecutive blocks from `block_id` on covered by `buf`. At least
+    /// [`Self::bypass`] blocks are written to the device in a single request, dropping their
+    /// cached copies instead of updating them.
+    pub fn write_range(&mut self, block_id: usize, buf: &[u8]) -> Result<()> {
+        let block_size = self.block_size();
+        let count = buf.len() / block_size;
+        if self.bypass == 0 || count < self.bypass {
+            for (i, buf) in buf.chunks_exact(block_size).enumerate() {
+                self.write_block(block_id + i, buf)?;
+            }
+            return Ok(());
+        }
+        self.check()?;
+        self.dev.write_batch(&[(block_id, buf)])?;
+        for block_id in block_id..block_id + count {
+            if let Some(mut block) = self.cache.pop(&block_id) {
+                block.dirty = false;
+            }
+            self.dirty.remove(&block_id);
+        }
+        Ok(())
+    }
+    /// Fetches up to `count` blocks from `block_id` on into the cache with a single device read,
+    /// stopping short of the first block already cached so newer contents are never replaced.
+    pub fn read_ahead(&mut self, block_id: usize, count: usize) -> Result<()> {
+        let count = (block_id..block_id + count.min(self.cache.cap()))
+            .take_while(|&b| b < self.dev_blocks && !self.cache.contains(&b))
+            .count();
+        if count == 0 {
+            return Ok(());
+        }
+        let block_size = self.dev.block_size();
+        let mut data = vec![0u8; count * block_size];
+        self.dev.read_blocks(block_id, &mut data)?;
+        for (i, buffer) in data.chunks_exact(block_size).enumerate() {
+            self.insert(Block {
+                block_id: block_id + i,
+                buffer: buffer.into(),
+                dev: self.dev.clone(),
+                failed: self.failed.clone(),
+                dirty: false,
+            });
+        }
+        Ok(())
+    }
+    /// Caches `block`, forgetting the dirty state of the block it evicts, which writes itself
+    /// back on drop.
+    fn insert(&mut self, block: Block) {
+        if block.dirty {
+            self.dirty.insert(block.block_id);
+        }
+        if let Some((block_id, _)) = self.cache.push(block.block_id, block) {
+            self.dirty.remove(&block_id);
+        }
+    }
+    pub fn write_block(&mut self, block_id: usize, buf: &[u8]) -> Result<()> {
+        self.check()?;
+        let write_through = self.policy == WritebackPolicy::WriteThrough;
+        if write_through {
+            self.dev.write_block(block_id, buf)?;
+        }
         if let Some(block) = self.cache.get_mut(&block_id) {
-            block.dirty = true;
-            block.buffer.as_mut_slice().write_all(buf)
+            (&mut block.buffer[..]).write_all(buf)?;
+            if !write_through {
+                block.dirty = true;
+                self.dirty.insert(block_id);
+            }
         } else {
-            self.cache.put(
+            self.insert(Block {
                 block_id,
-                Block {
-                    block_id,
-                    buffer: *buf,
-                    dev: self.dev.clone(),
-                    dirty: true,
-                },
-            );
-            Ok(())
+                buffer: buf.into(),
+                dev: self.dev.clone(),
+                failed: self.failed.clone(),
+                dirty: !write_through,
+            });
         }
+        Ok(())
     }
-    pub fn flush_block(&mut self, block_id: usize) {
-        self.cache.pop(&block_id);
+    /// Writes `block_id` back to the device if it is dirty, keeping it cached.
+    pub fn flush_block(&mut self, block_id: usize) -> Result<()> {
+        self.check()?;
+        if let Some(block) = self.cache.peek_mut(&block_id) {
+            if block.dirty {
+                self.dev.write_block(block_id, &block.buffer)?;
+                block.dirty = false;
+            }
+        }
+        self.dirty.remove(&block_id);
+        Ok(())
+    }
+    /// Writes every dirty block back to the device in one batch, keeping them cached. When the
+    /// batch fails all of them stay dirty.
+    pub fn flush(&mut self) -> Result<()> {
+        self.check()?;
+        let batch: Vec<(usize, &[u8])> = self
+            .dirty
+            .iter()
+            .filter_map(|block_id| self.cache.peek(block_id))
+            .map(|block| (block.block_id, &block.buffer[..]))
+            .collect();
+        self.dev.write_batch(&batch)?;
+        for block_id in std::mem::take(&mut self.dirty) {
+            if let Some(block) = self.cache.peek_mut(&block_id) {
+                block.dirty = false;
+            }
+        }
+        Ok(())
+    }
+    /// Writes the dirty blocks within `extents` back in one batch, leaving every other dirty
+    /// block cached. Followed by [`sync_written`](Self::sync_written), metadata persisted
+    /// afterwards never references blocks whose contents a crash could still lose.
+    pub fn barrier<'a>(
+        &mut self,
+        extents: impl IntoIterator<Item = &'a Range<usize>>,
+    ) -> Result<()> {
+        self.check()?;
+        let dirty: Vec<usize> = extents
+            .into_iter()
+            .flat_map(|e| self.dirty.range(e.clone()).copied())
+            .collect();
+        if !dirty.is_empty() {
+            let batch: Vec<(usize, &[u8])> = dirty
+                .iter()
+                .filter_map(|block_id| self.cache.peek(block_id))
+                .map(|block| (block.block_id, &block.buffer[..]))
+                .collect();
+            self.dev.write_batch(&batch)?;
+            for block_id in dirty {
+                if let Some(block) = self.cache.peek_mut(&block_id) {
+                    block.dirty = false;
+                }
+                self.dirty.remove(&block_id);
+            }
+        }
+        Ok(())
+    }
+    /// Forces the device to stable storage without writing back any cached block.
+    pub fn sync_device(&mut self) -> Result<()> {
+        self.check()?;
+        self.dev.sync_data()
+    }
+    /// Like [`sync_device`](Self::sync_device), unless nothing was written since the device was
+    /// last synced.
+    pub fn sync_written(&mut self) -> Result<()> {
+        if self.dev.unsynced() {
+            self.sync_device()?;
+        }
+        Ok(())
+    }
+    /// Forgets the cached copies of `blocks` without writing them back, then discards them on
+    /// the device. Their contents must no longer matter.
+    pub fn discard(&mut self, blocks: Range<usize>) -> Result<()> {
+        // only the ids in the range are looked up, however large the cache
+        for block_id in blocks.clone() {
+            if let Some(mut block) = self.cache.pop(&block_id) {
+                block.dirty = false;
+                self.dirty.remove(&block_id);
+            }
+        }
+        self.dev.discard(blocks)
+    }
+    /// Flushes every dirty block and forces the device to stable storage.
+    pub fn sync_data(&mut self) -> Result<()> {
+        self.flush()?;
+        self.dev.sync_data()
+    }
+    pub fn policy(&self) -> WritebackPolicy {
+        self.policy
+    }
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
     }
     pub fn size(&self) -> Result<usize> {
         self.dev.size()
     }
-    pub fn flush(&mut self) {
-        self.cache.clear()
-    }
 }
@@ -1,34 +1,680 @@
+use crate::nbd::{NbdStore, NBD_SCHEME};
+use io_uring::{opcode, types, IoUring};
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::Result;
-use std::os::unix::fs::OpenOptionsExt;
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom};
+use std::ops::Range;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::FileExt;
 use std::path::Path;
+use std::ptr::NonNull;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
-pub struct BlockDevice<const BLOCK_SIZE: usize> {
+/// Memory alignment required for O_DIRECT transfers, large enough to cover
+/// both 512 byte and 4K logical sectors.
+const DIRECT_IO_ALIGN: usize = 4096;
+/// Submission queue size of the io_uring backend, larger batches are submitted in rounds.
+const RING_ENTRIES: usize = 64;
+/// Consecutive global blocks a [`DeviceSet`] keeps on one device before moving to the next.
+pub const STRIPE_BLOCKS: usize = 64;
+/// `_IO(0x12, 119)` from linux/fs.h, which the libc crate does not export.
+const BLKDISCARD: libc::c_ulong = 0x1277;
+
+/// How a [`BlockDevice`] submits its transfers.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BackendKind {
+    /// one blocking pread or pwrite per transfer
+    Pread,
+    /// batches submitted together through an io_uring, so their transfers overlap
+    IoUring,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+    /// Parses `pread` or `io_uring`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pread" => Ok(BackendKind::Pread),
+            "io_uring" => Ok(BackendKind::IoUring),
+            _ => Err(format!(
+                "unknown I/O backend {}, expected pread or io_uring",
+                s
+            )),
+        }
+    }
+}
+
+/// A zeroed heap buffer satisfying the alignment constraints of O_DIRECT.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, DIRECT_IO_ALIGN).unwrap()
+    }
+    /// Allocates `len` bytes, which must be non-zero.
+    pub fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc_zeroed(layout) };
+        Self {
+            ptr: NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout)),
+            len,
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+pub struct BlockDevice {
     backing_file: File,
+    block_size: usize,
+    /// set for the io_uring backend, one batch is in flight at a time
+    ring: Option<Mutex<IoUring>>,
+    /// whether the backing is a block device rather than a regular file
+    is_block_device: bool,
 }
 
-impl<const BLOCK_SIZE: usize> BlockDevice<BLOCK_SIZE> {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Ok(Self {
-            backing_file: OpenOptions::new()
+impl BlockDevice {
+    /// Opens the device at `path` in blocks of `block_size` bytes, which must be a power of two of
+    /// at least one sector as the block arithmetic and O_DIRECT transfers rely on.
+    pub fn new<P: AsRef<Path>>(path: P, block_size: usize, backend: BackendKind) -> Result<Self> {
+        if !block_size.is_power_of_two() || block_size < 512 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "block size {} is not a power of two of at least 512",
+                    block_size
+                ),
+            ));
+        }
+        let open = |flags| {
+            OpenOptions::new()
                 .read(true)
                 .write(true)
-                .custom_flags(libc::O_DIRECT)
-                .custom_flags(libc::O_NOATIME)
-                .open(path)?,
+                .custom_flags(flags)
+                .open(path.as_ref())
+        };
+        // O_NOATIME is refused with EPERM unless the caller owns the file
+        let backing_file = match open(libc::O_DIRECT | libc::O_NOATIME) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => open(libc::O_DIRECT)?,
+            res => res?,
+        };
+        let ring = match backend {
+            BackendKind::Pread => None,
+            BackendKind::IoUring => Some(Mutex::new(IoUring::new(RING_ENTRIES as u32)?)),
+        };
+        let is_block_device = backing_file.metadata()?.file_type().is_block_device();
+        Ok(Self {
+            backing_file,
+            block_size,
+            ring,
+            is_block_device,
+        })
+    }
+}
+
+impl BlockStore for BlockDevice {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    /// Reads the consecutive blocks starting at `block_id` that fill `buf` in a single request.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        self.read_batch(&mut [(block_id, buf)])
+    }
+    /// Reads every `(block_id, buf)` pair, each filling `buf` with the consecutive blocks from
+    /// `block_id` on. The io_uring backend keeps all of them in flight at once.
+    fn read_batch(&self, reqs: &mut [(usize, &mut [u8])]) -> Result<()> {
+        let mut aligned: Vec<AlignedBuffer> = reqs
+            .iter()
+            .map(|(_, buf)| AlignedBuffer::new(buf.len()))
+            .collect();
+        match &self.ring {
+            None => {
+                for ((block_id, _), buf) in reqs.iter().zip(&mut aligned) {
+                    self.backing_file
+                        .read_exact_at(buf, (block_id * self.block_size) as u64)?;
+                }
+            }
+            Some(ring) => {
+                let fd = types::Fd(self.backing_file.as_raw_fd());
+                let entries = reqs.iter().zip(&mut aligned).map(|((block_id, _), buf)| {
+                    opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+                        .offset((block_id * self.block_size) as i64)
+                        .build()
+                });
+                submit(&mut ring.lock().unwrap(), entries.collect(), &aligned)?;
+            }
+        }
+        for ((_, buf), aligned) in reqs.iter_mut().zip(&aligned) {
+            buf.copy_from_slice(aligned);
+        }
+        Ok(())
+    }
+    /// Writes every `(block_id, buf)` pair, each `buf` covering the consecutive blocks from
+    /// `block_id` on. The io_uring backend keeps all of them in flight at once.
+    fn write_batch(&self, reqs: &[(usize, &[u8])]) -> Result<()> {
+        let aligned: Vec<AlignedBuffer> = reqs
+            .iter()
+            .map(|(_, buf)| {
+                let mut aligned = AlignedBuffer::new(buf.len());
+                aligned.copy_from_slice(buf);
+                aligned
+            })
+            .collect();
+        match &self.ring {
+            None => {
+                for ((block_id, _), buf) in reqs.iter().zip(&aligned) {
+                    self.backing_file
+                        .write_all_at(buf, (block_id * self.block_size) as u64)?;
+                }
+                Ok(())
+            }
+            Some(ring) => {
+                let fd = types::Fd(self.backing_file.as_raw_fd());
+                let entries = reqs.iter().zip(&aligned).map(|((block_id, _), buf)| {
+                    opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32)
+                        .offset((block_id * self.block_size) as i64)
+                        .build()
+                });
+                submit(&mut ring.lock().unwrap(), entries.collect(), &aligned)
+            }
+        }
+    }
+    /// Forces written blocks to stable storage.
+    fn sync_data(&self) -> Result<()> {
+        self.backing_file.sync_data()
+    }
+    /// Tells the device `blocks` are no longer in use so it may reclaim their space, with
+    /// BLKDISCARD on block devices and by punching a hole into regular files. Discarded blocks
+    /// read back as zeros from regular files and as undefined contents from devices.
+    fn discard(&self, blocks: Range<usize>) -> Result<()> {
+        let range = [
+            (blocks.start * self.block_size) as u64,
+            (blocks.len() * self.block_size) as u64,
+        ];
+        let fd = self.backing_file.as_raw_fd();
+        let res = if self.is_block_device {
+            unsafe { libc::ioctl(fd, BLKDISCARD, range.as_ptr()) }
+        } else {
+            unsafe {
+                libc::fallocate(
+                    fd,
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    range[0] as libc::off_t,
+                    range[1] as libc::off_t,
+                )
+            }
+        };
+        if res < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+    fn size(&self) -> Result<usize> {
+        // metadata reports a zero length for block devices, seeking to the end works for both
+        Ok((&self.backing_file).seek(SeekFrom::End(0))? as usize / self.block_size)
+    }
+}
+
+/// Blocks the [`BlockCache`](crate::block_cache::BlockCache) is backed by, addressed by global
+/// block number.
+pub trait BlockStore: Send + Sync {
+    fn block_size(&self) -> usize;
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        debug_assert_eq!(buf.len(), self.block_size());
+        self.read_blocks(block_id, buf)
+    }
+    /// Reads the consecutive blocks starting at `block_id` that fill `buf`.
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<()>;
+    /// Reads every `(block_id, buf)` pair, each filling `buf` with the consecutive blocks from
+    /// `block_id` on.
+    fn read_batch(&self, reqs: &mut [(usize, &mut [u8])]) -> Result<()> {
+        for (block_id, buf) in reqs.iter_mut() {
+            self.read_blocks(*block_id, buf)?;
+        }
+        Ok(())
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> Result<()> {
+        debug_assert_eq!(buf.len(), self.block_size());
+        self.write_batch(&[(block_id, buf)])
+    }
+    /// Writes every `(block_id, buf)` pair, each `buf` covering the consecutive blocks from
+    /// `block_id` on.
+    fn write_batch(&self, reqs: &[(usize, &[u8])]) -> Result<()>;
+    /// Forces written blocks to stable storage.
+    fn sync_data(&self) -> Result<()>;
+    /// Whether blocks were written since the store was last synced. Stores that do not keep
+    /// track always report true.
+    fn unsynced(&self) -> bool {
+        true
+    }
+    /// Tells the store `blocks` are no longer in use, their contents become undefined.
+    fn discard(&self, blocks: Range<usize>) -> Result<()>;
+    /// Number of blocks.
+    fn size(&self) -> Result<usize>;
+    /// Number of devices the blocks are spread over.
+    fn devices(&self) -> usize {
+        1
+    }
+}
+
+/// Data devices striped into one global block space. Global blocks are dealt out to the devices
+/// in stripes of [`STRIPE_BLOCKS`], so runs of allocated blocks spread over all of them. A single
+/// device maps every global block to itself.
+pub struct DeviceSet {
+    devs: Vec<Box<dyn BlockStore>>,
+    // set by every write until the devices are synced
+    unsynced: AtomicBool,
+}
+
+impl DeviceSet {
+    /// Opens the devices at `paths`, in the order their stripes are dealt out. A path may also be
+    /// the URL of a remote device, see [`open`].
+    pub fn new<P: AsRef<Path>>(
+        paths: &[P],
+        block_size: usize,
+        backend: BackendKind,
+    ) -> Result<Self> {
+        if paths.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "at least one data device is needed",
+            ));
+        }
+        let devs = paths
+            .iter()
+            .map(|path| open(path, block_size, backend))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            devs,
+            unsynced: AtomicBool::new(false),
         })
     }
-    pub fn read_block(&self, block_id: usize, buf: &mut [u8; BLOCK_SIZE]) -> Result<()> {
-        self.backing_file
-            .read_exact_at(buf, (block_id * BLOCK_SIZE) as u64)
+    /// Number of devices in the set.
+    pub fn len(&self) -> usize {
+        self.devs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.devs.is_empty()
+    }
+    /// Device and block on it holding the global block `block_id`.
+    fn locate(&self, block_id: usize) -> (usize, usize) {
+        let stripe = block_id / STRIPE_BLOCKS;
+        (
+            stripe % self.devs.len(),
+            stripe / self.devs.len() * STRIPE_BLOCKS + block_id % STRIPE_BLOCKS,
+        )
     }
-    pub fn write_block(&self, block_id: usize, buf: &[u8; BLOCK_SIZE]) -> Result<()> {
-        self.backing_file
-            .write_all_at(buf, (block_id * BLOCK_SIZE) as u64)
+    /// Number of consecutive blocks from `block_id` on that are also consecutive on its device.
+    fn run(&self, block_id: usize) -> usize {
+        if self.devs.len() == 1 {
+            usize::MAX
+        } else {
+            STRIPE_BLOCKS - block_id % STRIPE_BLOCKS
+        }
     }
-    pub fn size(&self) -> Result<usize> {
-        Ok(self.backing_file.metadata()?.len() as usize / BLOCK_SIZE)
+}
+
+impl BlockStore for DeviceSet {
+    fn block_size(&self) -> usize {
+        self.devs[0].block_size()
+    }
+    /// Reads the consecutive global blocks starting at `block_id` that fill `buf`, in a single
+    /// batch per device.
+    fn read_blocks(&self, mut block_id: usize, mut buf: &mut [u8]) -> Result<()> {
+        let mut batches: Vec<Vec<(usize, &mut [u8])>> = self.devs.iter().map(|_| vec![]).collect();
+        while !buf.is_empty() {
+            let len = std::cmp::min(
+                buf.len(),
+                self.run(block_id).saturating_mul(self.block_size()),
+            );
+            let (piece, rest) = std::mem::take(&mut buf).split_at_mut(len);
+            let (dev, local) = self.locate(block_id);
+            batches[dev].push((local, piece));
+            block_id += len / self.block_size();
+            buf = rest;
+        }
+        for (dev, mut batch) in self.devs.iter().zip(batches) {
+            dev.read_batch(&mut batch)?;
+        }
+        Ok(())
+    }
+    /// Writes every `(block_id, buf)` pair, each `buf` covering the consecutive global blocks
+    /// from `block_id` on, in a single batch per device.
+    fn write_batch(&self, reqs: &[(usize, &[u8])]) -> Result<()> {
+        self.unsynced.store(true, Ordering::SeqCst);
+        let mut batches: Vec<Vec<(usize, &[u8])>> = self.devs.iter().map(|_| vec![]).collect();
+        for &(mut block_id, mut buf) in reqs {
+            while !buf.is_empty() {
+                let len = std::cmp::min(
+                    buf.len(),
+                    self.run(block_id).saturating_mul(self.block_size()),
+                );
+                let (piece, rest) = buf.split_at(len);
+                let (dev, local) = self.locate(block_id);
+                batches[dev].push((local, piece));
+                block_id += len / self.block_size();
+                buf = rest;
+            }
+        }
+        for (dev, batch) in self.devs.iter().zip(batches) {
+            dev.write_batch(&batch)?;
+        }
+        Ok(())
+    }
+    /// Forces written blocks to stable storage on every device.
+    fn sync_data(&self) -> Result<()> {
+        // cleared up front so a write racing the sync is synced again next time
+        self.unsynced.store(false, Ordering::SeqCst);
+        let res = self.devs.iter().try_for_each(|dev| dev.sync_data());
+        if res.is_err() {
+            self.unsynced.store(true, Ordering::SeqCst);
+        }
+        res
+    }
+    /// Whether blocks were written since the devices were last synced.
+    fn unsynced(&self) -> bool {
+        self.unsynced.load(Ordering::SeqCst)
+    }
+    /// Discards the global `blocks` on the devices holding them, see [`BlockDevice::discard`].
+    fn discard(&self, mut blocks: Range<usize>) -> Result<()> {
+        while !blocks.is_empty() {
+            let len = std::cmp::min(blocks.len(), self.run(blocks.start));
+            let (dev, local) = self.locate(blocks.start);
+            self.devs[dev].discard(local..local + len)?;
+            blocks.start += len;
+        }
+        Ok(())
+    }
+    /// Number of global blocks, striping only covers whole stripes of the smallest device.
+    fn size(&self) -> Result<usize> {
+        if self.devs.len() == 1 {
+            return self.devs[0].size();
+        }
+        let mut smallest = usize::MAX;
+        for dev in &self.devs {
+            smallest = std::cmp::min(smallest, dev.size()?);
+        }
+        Ok(smallest / STRIPE_BLOCKS * STRIPE_BLOCKS * self.devs.len())
+    }
+    fn devices(&self) -> usize {
+        self.devs.len()
+    }
+}
+
+/// Blocks held in memory, starting out zeroed. Nothing survives the store being dropped, so
+/// syncing is free.
+pub struct MemBlockStore {
+    block_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemBlockStore {
+    pub fn new(block_size: usize, blocks: usize) -> Self {
+        Self {
+            block_size,
+            data: Mutex::new(vec![0u8; blocks * block_size]),
+        }
+    }
+    /// Byte range of the blocks from `block_id` on covering `len` bytes, failing when it runs
+    /// past the end of the store.
+    fn span(&self, block_id: usize, len: usize, data: &[u8]) -> Result<Range<usize>> {
+        let start = block_id * self.block_size;
+        if len % self.block_size != 0 || start + len > data.len() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("block {} is out of range", block_id),
+            ));
+        }
+        Ok(start..start + len)
+    }
+}
+
+impl BlockStore for MemBlockStore {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        let data = self.data.lock().unwrap();
+        buf.copy_from_slice(&data[self.span(block_id, buf.len(), &data)?]);
+        Ok(())
+    }
+    fn write_batch(&self, reqs: &[(usize, &[u8])]) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        for &(block_id, buf) in reqs {
+            let span = self.span(block_id, buf.len(), &data)?;
+            data[span].copy_from_slice(buf);
+        }
+        Ok(())
+    }
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+    fn unsynced(&self) -> bool {
+        false
+    }
+    /// Zeroes `blocks`.
+    fn discard(&self, blocks: Range<usize>) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        let span = self.span(blocks.start, blocks.len() * self.block_size, &data)?;
+        data[span].fill(0);
+        Ok(())
+    }
+    fn size(&self) -> Result<usize> {
+        Ok(self.data.lock().unwrap().len() / self.block_size)
+    }
+}
+
+/// Opens the device at `path`, which is connected to over NBD when it is an `nbd://` URL and
+/// otherwise a block device or file transferred through `backend`.
+pub fn open<P: AsRef<Path>>(
+    path: P,
+    block_size: usize,
+    backend: BackendKind,
+) -> Result<Box<dyn BlockStore>> {
+    match path.as_ref().to_str() {
+        Some(url) if url.starts_with(NBD_SCHEME) => {
+            Ok(Box::new(NbdStore::connect(url, block_size)?))
+        }
+        _ => Ok(Box::new(BlockDevice::new(path, block_size, backend)?)),
+    }
+}
+
+/// Pushes `entries` to `ring` in rounds of at most [`RING_ENTRIES`], waiting for each round to
+/// complete. Entry `i` transfers the whole of `bufs[i]`, anything less fails the batch.
+fn submit(
+    ring: &mut IoUring,
+    entries: Vec<io_uring::squeue::Entry>,
+    bufs: &[AlignedBuffer],
+) -> Result<()> {
+    for (round, chunk) in entries.chunks(RING_ENTRIES).enumerate() {
+        let first = round * RING_ENTRIES;
+        for (i, entry) in chunk.iter().enumerate() {
+            let entry = entry.clone().user_data((first + i) as u64);
+            // the buffers outlive the round, which is waited for before returning
+            unsafe { ring.submission().push(&entry) }
+                .map_err(|_| Error::new(ErrorKind::Other, "io_uring submission queue is full"))?;
+        }
+        ring.submit_and_wait(chunk.len())?;
+        let mut res = Ok(());
+        for cqe in ring.completion() {
+            let expected = bufs[cqe.user_data() as usize].len();
+            if cqe.result() < 0 {
+                res = Err(Error::from_raw_os_error(-cqe.result()));
+            } else if cqe.result() as usize != expected && res.is_ok() {
+                res = Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("transferred {} of {} bytes", cqe.result(), expected),
+                ));
+            }
+        }
+        res?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::data_file;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn direct_io_from_unaligned_buffers() {
+        let path = data_file("direct", 1 << 16);
+        let dev = BlockDevice::new(&path, 512, BackendKind::Pread).unwrap();
+        // slicing at an odd offset defeats whatever alignment the allocator happened to give
+        let mut data = vec![0u8; 3 * 512 + 1];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        dev.write_block(5, &data[1..513]).unwrap();
+        dev.write_batch(&[(8, &data[1..])]).unwrap();
+        dev.sync_data().unwrap();
+
+        let mut read = vec![0u8; 3 * 512 + 1];
+        dev.read_block(5, &mut read[1..513]).unwrap();
+        assert_eq!(read[1..513], data[1..513]);
+        dev.read_blocks(8, &mut read[1..]).unwrap();
+        assert_eq!(read[1..], data[1..]);
+    }
+
+    #[test]
+    fn io_uring_batches_match_pread() {
+        let path = data_file("uring", 256 * 512);
+        let pread = BlockDevice::new(&path, 512, BackendKind::Pread).unwrap();
+        let uring = match BlockDevice::new(&path, 512, BackendKind::IoUring) {
+            Ok(dev) => dev,
+            Err(err) => {
+                eprintln!("skipped, io_uring is unavailable: {}", err);
+                return;
+            }
+        };
+        let block = |n: usize| vec![n as u8; 512];
+        let written: Vec<Vec<u8>> = (0..256).map(block).collect();
+        let reqs: Vec<(usize, &[u8])> = written.iter().map(|b| &b[..]).enumerate().collect();
+        pread.write_batch(&reqs).unwrap();
+
+        // more requests than the ring holds at once, of one and of several blocks
+        let mut single: Vec<Vec<u8>> = vec![vec![0; 512]; 100];
+        let mut runs: Vec<Vec<u8>> = vec![vec![0; 3 * 512]; 20];
+        let mut reqs: Vec<(usize, &mut [u8])> = single
+            .iter_mut()
+            .enumerate()
+            .map(|(n, buf)| (255 - 2 * n, &mut buf[..]))
+            .chain(
+                runs.iter_mut()
+                    .enumerate()
+                    .map(|(n, buf)| (n * 7, &mut buf[..])),
+            )
+            .collect();
+        uring.read_batch(&mut reqs).unwrap();
+        for (n, buf) in single.iter().enumerate() {
+            assert_eq!(*buf, block(255 - 2 * n));
+        }
+        for (n, buf) in runs.iter().enumerate() {
+            assert_eq!(*buf, written[n * 7..n * 7 + 3].concat());
+        }
+
+        // and the other way round
+        uring
+            .write_batch(&[(10, &[0xaa; 2 * 512]), (200, &[0xbb; 512])])
+            .unwrap();
+        let mut buf = vec![0; 512];
+        pread.read_blocks(11, &mut buf).unwrap();
+        assert_eq!(buf, [0xaa; 512]);
+        pread.read_blocks(200, &mut buf).unwrap();
+        assert_eq!(buf, [0xbb; 512]);
+        // reads past the end fail the batch instead of coming back short
+        assert!(uring.read_blocks(256, &mut buf).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_block_sizes() {
+        let path = data_file("block_size", 1 << 16);
+        for block_size in [0, 256, 1000, 1536] {
+            let err = BlockDevice::new(&path, block_size, BackendKind::Pread).err();
+            assert_eq!(err.map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+        }
+        for block_size in [512, 4096] {
+            let dev = BlockDevice::new(&path, block_size, BackendKind::Pread).unwrap();
+            assert_eq!(dev.size().unwrap(), (1 << 16) / block_size);
+        }
+    }
+
+    #[test]
+    fn opens_files_of_other_owners_without_noatime() {
+        let path = data_file("noatime", 1 << 16);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        // only a file system user other than the owner is refused O_NOATIME, and changing it is
+        // limited to the thread making the change
+        // opened through a descriptor so directories the user may not search don't get in the way
+        let file = File::open(&path).unwrap();
+        let path = format!("/proc/self/fd/{}", file.as_raw_fd());
+        std::thread::spawn(move || {
+            if unsafe { libc::geteuid() } != 0 {
+                eprintln!("skipped, changing the file system user takes root");
+                return;
+            }
+            unsafe { libc::setfsuid(65534) };
+            let dev = BlockDevice::new(&path, 512, BackendKind::Pread).unwrap();
+            let flags = unsafe { libc::fcntl(dev.backing_file.as_raw_fd(), libc::F_GETFL) };
+            assert_eq!(flags & libc::O_NOATIME, 0);
+            assert_ne!(flags & libc::O_DIRECT, 0);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn memory_stores_serve_the_block_cache() {
+        use crate::block_cache::{BlockCache, WritebackPolicy};
+        let store = std::sync::Arc::new(MemBlockStore::new(512, 16));
+        let mut cache =
+            BlockCache::with_store(store.clone(), 4, WritebackPolicy::WriteBack).unwrap();
+        let data: Vec<u8> = (0..8 * 512).map(|n| (n / 512) as u8).collect();
+        cache.write_range(4, &data).unwrap();
+        // the cache holds fewer blocks than were written, so some already reached the store
+        cache.flush().unwrap();
+        let mut buf = vec![0u8; 8 * 512];
+        store.read_blocks(4, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        let mut cache =
+            BlockCache::with_store(store.clone(), 4, WritebackPolicy::WriteBack).unwrap();
+        cache.read_range(4, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        store.discard(5..7).unwrap();
+        store.read_blocks(4, &mut buf[..4 * 512]).unwrap();
+        assert_eq!(buf[512..3 * 512], [0; 2 * 512]);
+        // nothing lies past the end of the store
+        let err = store.write_block(16, &[1; 512]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(store.read_blocks(15, &mut buf[..2 * 512]).is_err());
+        assert_eq!(store.size().unwrap(), 16);
     }
 }
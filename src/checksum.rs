@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Checksum algorithm protecting the data blocks of an inode, recorded in its `flags`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChecksumKind {
+    None,
+    Crc32c,
+}
+
+/// Bits of the inode `flags` holding the checksum kind.
+const CHECKSUM_MASK: u32 = 0b11;
+
+impl ChecksumKind {
+    /// Kind with the numeric `code` exchanged through ioctl.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(ChecksumKind::None),
+            1 => Some(ChecksumKind::Crc32c),
+            _ => None,
+        }
+    }
+    pub fn code(self) -> u32 {
+        match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32c => 1,
+        }
+    }
+    pub fn from_flags(flags: u32) -> Self {
+        Self::from_code(flags & CHECKSUM_MASK).unwrap_or(ChecksumKind::None)
+    }
+    /// `flags` with the checksum bits replaced by this kind.
+    pub fn with_flags(self, flags: u32) -> u32 {
+        (flags & !CHECKSUM_MASK) | self.code()
+    }
+    /// Checksum of `data`, or `None` when checksums are disabled.
+    pub fn compute(self, data: &[u8]) -> Option<u32> {
+        match self {
+            ChecksumKind::None => None,
+            ChecksumKind::Crc32c => Some(crc32c(data)),
+        }
+    }
+}
+
+/// A data block whose contents no longer match its recorded checksum.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ChecksumError {
+    pub ino: u64,
+    pub block: usize,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checksum mismatch in block {} of inode {}: expected {:#010x}, got {:#010x}",
+            self.block, self.ino, self.expected, self.actual
+        )
+    }
+}
+
+// reflected Castagnoli polynomial
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+/// Compression applied to the data blocks of an inode, recorded in its `flags`. A compressed
+/// block still takes up a whole physical block, the compressed data padded with zeros, so
+/// compression saves no space within the filesystem: statfs and quotas count the same blocks
+/// either way. It only shrinks what storage beneath that compresses, or thinly provisions runs
+/// of zeros, has to keep.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+/// Bits of the inode `flags` holding the compression mode, above the checksum kind.
+const COMPRESSION_SHIFT: u32 = 2;
+const COMPRESSION_MASK: u32 = 0b11 << COMPRESSION_SHIFT;
+
+impl Compression {
+    /// Mode with the numeric `code` exchanged through ioctl.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+    pub fn code(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+    pub fn from_flags(flags: u32) -> Self {
+        Self::from_code((flags & COMPRESSION_MASK) >> COMPRESSION_SHIFT)
+            .unwrap_or(Compression::None)
+    }
+    /// `flags` with the compression bits replaced by this mode.
+    pub fn with_flags(self, flags: u32) -> u32 {
+        (flags & !COMPRESSION_MASK) | (self.code() << COMPRESSION_SHIFT)
+    }
+    /// Compressed form of `data`, or `None` when compression is disabled.
+    pub fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Compression::None => None,
+            Compression::Lz4 => Some(lz4_flex::block::compress(data)),
+            Compression::Zstd => zstd::bulk::compress(data, 0).ok(),
+        }
+    }
+    /// Restores the `size` bytes compressed into `data`.
+    pub fn decompress(self, data: &[u8], size: usize) -> Result<Vec<u8>> {
+        let out = match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_flex::block::decompress(data, size)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?,
+            Compression::Zstd => zstd::bulk::decompress(data, size)?,
+        };
+        if out.len() != size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("decompressed {} bytes, expected {}", out.len(), size),
+            ));
+        }
+        Ok(out)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!(
+                "unknown compression {}, expected none, lz4 or zstd",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Key prefix of the reserved metadata store records counting the references to a data block.
+pub const REFCOUNT_PREFIX: &[u8] = b"refcount/";
+
+/// SHA-256 of the contents of a data block.
+pub type Hash = [u8; 32];
+
+pub fn hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct BlockRef {
+    /// number of logical blocks mapping the block
+    refs: u64,
+    /// hash of the contents, set while other writes may be deduplicated against the block
+    hash: Option<Hash>,
+}
+
+/// Reference counts of the physical data blocks mapped by more than one logical block or
+/// indexed by their contents for deduplication. Blocks without a record are mapped by a single
+/// logical block. Every change is written through to the metadata store.
+pub struct BlockRefs {
+    db: Arc<Mutex<cxx::UniquePtr<crate::ffi::KVStore>>>,
+    blocks: BTreeMap<usize, BlockRef>,
+    hashes: BTreeMap<Hash, usize>,
+}
+
+impl BlockRefs {
+    /// Loads the records kept in `db`.
+    pub fn load(db: Arc<Mutex<cxx::UniquePtr<crate::ffi::KVStore>>>) -> Self {
+        let mut blocks = BTreeMap::new();
+        for key in crate::scan_keys(&db, REFCOUNT_PREFIX) {
+            let block = match key[REFCOUNT_PREFIX.len()..].try_into() {
+                Ok(block) => usize::from_le_bytes(block),
+                Err(_) => continue,
+            };
+            cxx::let_cxx_string!(id = &key);
+            let data = db.lock().unwrap().get(&id);
+            if let Ok(r) = bincode::deserialize::<BlockRef>(data.as_bytes()) {
+                blocks.insert(block, r);
+            }
+        }
+        let hashes = blocks
+            .iter()
+            .filter_map(|(&block, r)| Some((r.hash?, block)))
+            .collect();
+        Self { db, blocks, hashes }
+    }
+
+    fn key(block: usize) -> Vec<u8> {
+        [REFCOUNT_PREFIX, &block.to_le_bytes()].concat()
+    }
+
+    /// Writes the record of `block` through, removing it once the block has none.
+    fn store(&self, block: usize) {
+        cxx::let_cxx_string!(key = Self::key(block));
+        let mut db = self.db.lock().unwrap();
+        match self.blocks.get(&block) {
+            Some(r) => {
+                cxx::let_cxx_string!(value = bincode::serialize(r).unwrap());
+                db.as_mut().unwrap().put(&key, &value);
+            }
+            None => {
+                db.as_mut().unwrap().remove(&key);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn contains(&self, block: usize) -> bool {
+        self.blocks.contains_key(&block)
+    }
+
+    /// Whether `block` is mapped more than once and has to be copied before being modified.
+    pub fn shared(&self, block: usize) -> bool {
+        self.blocks.get(&block).map_or(false, |r| r.refs > 1)
+    }
+
+    /// Sub-ranges of `blocks` without a record.
+    pub fn exclusive(&self, blocks: Range<usize>) -> Vec<Range<usize>> {
+        let mut runs: Vec<Range<usize>> = vec![];
+        let mut start = blocks.start;
+        for &block in self.blocks.range(blocks.clone()).map(|(block, _)| block) {
+            if start < block {
+                runs.push(start..block);
+            }
+            start = block + 1;
+        }
+        if start < blocks.end {
+            runs.push(start..blocks.end);
+        }
+        runs
+    }
+
+    /// Block holding contents that hash to `hash`.
+    pub fn lookup(&self, hash: &Hash) -> Option<usize> {
+        self.hashes.get(hash).copied()
+    }
+
+    /// Indexes the singly mapped `block` as holding contents that hash to `hash`.
+    pub fn index(&mut self, block: usize, hash: Hash) {
+        let r = self.blocks.entry(block).or_insert(BlockRef {
+            refs: 1,
+            hash: None,
+        });
+        r.hash = Some(hash);
+        self.hashes.insert(hash, block);
+        self.store(block);
+    }
+
+    /// Drops the hash of the singly mapped `block` ahead of modifying it in place.
+    pub fn unindex(&mut self, block: usize) {
+        if let Some(r) = self.blocks.remove(&block) {
+            if let Some(hash) = r.hash {
+                self.hashes.remove(&hash);
+            }
+            self.store(block);
+        }
+    }
+
+    /// Adds a reference to `block` from another logical block.
+    pub fn share(&mut self, block: usize) {
+        self.blocks
+            .entry(block)
+            .or_insert(BlockRef {
+                refs: 1,
+                hash: None,
+            })
+            .refs += 1;
+        self.store(block);
+    }
+
+    /// Drops a reference to every block of `blocks`, returning the ranges no longer mapped by
+    /// any logical block.
+    pub fn release(&mut self, blocks: Vec<Range<usize>>) -> Vec<Range<usize>> {
+        if self.blocks.is_empty() {
+            return blocks;
+        }
+        let mut freed: Vec<Range<usize>> = vec![];
+        for block in blocks.into_iter().flatten() {
+            if let Some(r) = self.blocks.get_mut(&block) {
+                if r.refs > 1 {
+                    r.refs -= 1;
+                    self.store(block);
+                    continue;
+                }
+                self.unindex(block);
+            }
+            match freed.last_mut() {
+                Some(last) if last.end == block => last.end += 1,
+                _ => freed.push(block..block + 1),
+            }
+        }
+        freed
+    }
+
+    /// Replaces the reference counts with `counts` found by a scan of every inode, dropping the
+    /// records of blocks no longer mapped.
+    pub fn recount(&mut self, counts: &BTreeMap<usize, u64>) {
+        let blocks: Vec<usize> = self.blocks.keys().copied().collect();
+        for block in blocks {
+            let r = self.blocks.get_mut(&block).unwrap();
+            match counts.get(&block) {
+                // a block mapped once only needs its record while it is indexed
+                Some(&refs) if refs > 1 || r.hash.is_some() => {
+                    r.refs = refs;
+                    self.store(block);
+                }
+                _ => self.unindex(block),
+            }
+        }
+    }
+}
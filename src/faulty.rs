@@ -0,0 +1,187 @@
+use crate::block_dev::BlockStore;
+use std::io::{Error, Result};
+use std::ops::Range;
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct Faults {
+    /// requests of each kind seen so far
+    reads: u64,
+    writes: u64,
+    syncs: u64,
+    /// blocks covered by each write, in order
+    write_sizes: Vec<usize>,
+    /// requests that fail with EIO, counted from the first one
+    failing_reads: Vec<u64>,
+    failing_writes: Vec<u64>,
+    /// `(block_id, bit)` flipped in everything read back
+    flips: Vec<(usize, usize)>,
+    /// set by every write until the next sync
+    unsynced: bool,
+    /// set once writes are dropped
+    crashed: bool,
+}
+
+/// Wraps a [`BlockStore`] to fail chosen requests, corrupt what is read back or stop persisting
+/// writes, so error paths can be exercised without a broken device. Reads and writes are counted
+/// per request, a batch counting once.
+pub struct FaultyBlockDevice<S> {
+    inner: S,
+    faults: Mutex<Faults>,
+}
+
+impl<S: BlockStore> FaultyBlockDevice<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            faults: Mutex::default(),
+        }
+    }
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+    /// Fails the `n`th read from now on, one being the next.
+    pub fn fail_read(&self, n: u64) {
+        let mut faults = self.faults.lock().unwrap();
+        let nth = faults.reads + n;
+        faults.failing_reads.push(nth);
+    }
+    /// Fails the `n`th write from now on, one being the next.
+    pub fn fail_write(&self, n: u64) {
+        let mut faults = self.faults.lock().unwrap();
+        let nth = faults.writes + n;
+        faults.failing_writes.push(nth);
+    }
+    /// Flips `bit` of `block_id` in every later read of it, leaving the stored block intact.
+    pub fn flip_bit(&self, block_id: usize, bit: usize) {
+        debug_assert!(bit < self.inner.block_size() * 8);
+        self.faults.lock().unwrap().flips.push((block_id, bit));
+    }
+    /// Simulates a crash: later writes, discards and syncs report success without reaching the
+    /// wrapped store, which keeps what it held at this point.
+    pub fn crash(&self) {
+        self.faults.lock().unwrap().crashed = true;
+    }
+    pub fn crashed(&self) -> bool {
+        self.faults.lock().unwrap().crashed
+    }
+    /// Read requests seen so far.
+    pub fn reads(&self) -> u64 {
+        self.faults.lock().unwrap().reads
+    }
+    /// Write requests seen so far.
+    pub fn writes(&self) -> u64 {
+        self.faults.lock().unwrap().writes
+    }
+    /// Syncs seen so far.
+    pub fn syncs(&self) -> u64 {
+        self.faults.lock().unwrap().syncs
+    }
+    /// Blocks covered by each write request seen so far, a batch counting as one.
+    pub fn write_sizes(&self) -> Vec<usize> {
+        self.faults.lock().unwrap().write_sizes.clone()
+    }
+}
+
+fn injected() -> Error {
+    Error::from_raw_os_error(libc::EIO)
+}
+
+impl<S: BlockStore> BlockStore for FaultyBlockDevice<S> {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        faults.reads += 1;
+        let nth = faults.reads;
+        if faults.failing_reads.contains(&nth) {
+            return Err(injected());
+        }
+        self.inner.read_blocks(block_id, buf)?;
+        let blocks = block_id..block_id + buf.len() / self.block_size();
+        for &(block, bit) in faults.flips.iter().filter(|(b, _)| blocks.contains(b)) {
+            buf[(block - block_id) * self.block_size() + bit / 8] ^= 1 << (bit % 8);
+        }
+        Ok(())
+    }
+    fn write_batch(&self, reqs: &[(usize, &[u8])]) -> Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        faults.writes += 1;
+        let blocks = reqs.iter().map(|(_, buf)| buf.len()).sum::<usize>() / self.block_size();
+        faults.write_sizes.push(blocks);
+        faults.unsynced = true;
+        let nth = faults.writes;
+        if faults.failing_writes.contains(&nth) {
+            return Err(injected());
+        }
+        if faults.crashed {
+            return Ok(());
+        }
+        self.inner.write_batch(reqs)
+    }
+    fn sync_data(&self) -> Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        faults.syncs += 1;
+        faults.unsynced = false;
+        if faults.crashed {
+            return Ok(());
+        }
+        self.inner.sync_data()
+    }
+    /// Whether blocks were written since the last sync, whatever the wrapped store keeps track
+    /// of.
+    fn unsynced(&self) -> bool {
+        self.faults.lock().unwrap().unsynced
+    }
+    fn discard(&self, blocks: Range<usize>) -> Result<()> {
+        if self.crashed() {
+            return Ok(());
+        }
+        self.inner.discard(blocks)
+    }
+    fn size(&self) -> Result<usize> {
+        self.inner.size()
+    }
+    fn devices(&self) -> usize {
+        self.inner.devices()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_dev::MemBlockStore;
+
+    #[test]
+    fn injects_the_faults_it_was_asked_for() {
+        let dev = FaultyBlockDevice::new(MemBlockStore::new(512, 16));
+        let mut buf = [0u8; 512];
+        dev.fail_write(2);
+        dev.write_block(1, &[1; 512]).unwrap();
+        let err = dev.write_block(2, &[2; 512]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        dev.write_block(2, &[2; 512]).unwrap();
+        assert_eq!(dev.write_sizes(), [1, 1, 1]);
+
+        dev.fail_read(1);
+        assert!(dev.read_block(1, &mut buf).is_err());
+        dev.read_block(1, &mut buf).unwrap();
+        assert_eq!(buf, [1; 512]);
+        assert_eq!((dev.reads(), dev.writes()), (2, 3));
+
+        // flips only show in what is read back
+        dev.flip_bit(2, 9);
+        dev.read_block(2, &mut buf).unwrap();
+        assert_eq!((buf[0], buf[1], buf[2]), (2, 0, 2));
+        dev.inner().read_block(2, &mut buf).unwrap();
+        assert_eq!(buf, [2; 512]);
+
+        // after a crash writes are acknowledged and lost
+        dev.crash();
+        dev.write_block(1, &[3; 512]).unwrap();
+        dev.sync_data().unwrap();
+        dev.read_block(1, &mut buf).unwrap();
+        assert_eq!(buf, [1; 512]);
+    }
+}
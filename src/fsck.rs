@@ -0,0 +1,265 @@
+use crate::inode::{Attrs, DirEntry, FileType};
+use crate::{AllocatorState, CyanFS, ALLOCATOR_STATE_KEY};
+use fuser::FUSE_ROOT_ID;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Range;
+use std::os::raw::c_int;
+
+/// Name of the directory under the root that orphaned inodes are reconnected to.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// A metadata inconsistency found by [`CyanFS::fsck`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Inconsistency {
+    /// a directory entry naming an inode that does not exist
+    DanglingEntry { parent: u64, name: String, ino: u64 },
+    /// an inode no directory entry refers to
+    Orphan { ino: u64 },
+    /// an inode whose link count disagrees with the entries referring to it
+    LinkCount {
+        ino: u64,
+        recorded: u32,
+        actual: u32,
+    },
+    /// physical blocks of an inode already claimed by another one
+    SharedBlocks {
+        ino: u64,
+        owner: u64,
+        blocks: Range<usize>,
+    },
+    /// allocator state saved at the last unmount that disagrees with the blocks or inodes in use
+    AllocatorState,
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::DanglingEntry { parent, name, ino } => write!(
+                f,
+                "entry {} of directory {} refers to missing inode {}",
+                name, parent, ino
+            ),
+            Inconsistency::Orphan { ino } => write!(f, "inode {} is not linked anywhere", ino),
+            Inconsistency::LinkCount {
+                ino,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "inode {} records {} links but has {}",
+                ino, recorded, actual
+            ),
+            Inconsistency::SharedBlocks { ino, owner, blocks } => write!(
+                f,
+                "blocks {:?} of inode {} are already claimed by inode {}",
+                blocks, ino, owner
+            ),
+            Inconsistency::AllocatorState => {
+                write!(f, "saved allocator state disagrees with the inodes")
+            }
+        }
+    }
+}
+
+impl CyanFS {
+    /// Checks the metadata of an unmounted filesystem, returning every inconsistency found. With
+    /// `repair`, dangling entries are removed, blocks claimed twice are unmapped from the later
+    /// inode, orphans are linked into [`LOST_AND_FOUND`], link counts are corrected and a stale
+    /// allocator state is dropped so the next mount rebuilds it. Unlinked files a crash left
+    /// behind are not inconsistencies, repairing deletes them. Fails with EIO when the metadata
+    /// store cannot be read.
+    pub fn fsck(&mut self, repair: bool) -> Result<Vec<Inconsistency>, c_int> {
+        if repair {
+            self.migrate_records()?;
+        }
+        self.replay_journal();
+        let mut inodes: BTreeMap<u64, Attrs> = BTreeMap::new();
+        self.meta.write().unwrap().scan(|i| {
+            inodes.insert(i.ino, i.clone());
+        })?;
+        let mut found = vec![];
+        let mut modified = BTreeSet::new();
+
+        // files unlinked while still open when the filesystem went down are deleted, as a mount
+        // rebuilding the allocators would, rather than counted as orphans
+        let linked: BTreeSet<u64> = inodes
+            .values()
+            .flat_map(|dir| dir.entries.values().map(|entry| entry.ino))
+            .collect();
+        let unlinked: Vec<u64> = inodes
+            .values()
+            .filter(|i| i.nlink == 0 && i.ino != FUSE_ROOT_ID && !linked.contains(&i.ino))
+            .map(|i| i.ino)
+            .collect();
+        for ino in &unlinked {
+            inodes.remove(ino);
+        }
+
+        // claimed physical extents by start, kept disjoint
+        let mut claimed: BTreeMap<usize, (Range<usize>, u64)> = BTreeMap::new();
+        for i in inodes.values_mut() {
+            let mut shared = vec![];
+            for (&start, extent) in &i.extents {
+                // blocks with a reference count may be mapped more than once
+                let exclusive = self.space.lock().unwrap().refs.exclusive(extent.clone());
+                for e in exclusive {
+                    let overlap = claimed
+                        .range(..e.end)
+                        .next_back()
+                        .filter(|(_, (c, _))| c.end > e.start)
+                        .map(|(_, (_, owner))| *owner);
+                    let logical = start + (e.start - extent.start);
+                    match overlap {
+                        Some(owner) => {
+                            found.push(Inconsistency::SharedBlocks {
+                                ino: i.ino,
+                                owner,
+                                blocks: e.clone(),
+                            });
+                            shared.push(logical..logical + e.len());
+                        }
+                        None => {
+                            claimed.insert(e.start, (e.clone(), i.ino));
+                        }
+                    }
+                }
+            }
+            if repair && !shared.is_empty() {
+                for blocks in shared {
+                    i.unmap_blocks(blocks);
+                }
+                modified.insert(i.ino);
+            }
+        }
+
+        let mut refs: BTreeMap<u64, u32> = BTreeMap::new();
+        let mut subdirs: BTreeMap<u64, u32> = BTreeMap::new();
+        let mut dangling = vec![];
+        for dir in inodes.values() {
+            for (name, entry) in &dir.entries {
+                if !inodes.contains_key(&entry.ino) {
+                    found.push(Inconsistency::DanglingEntry {
+                        parent: dir.ino,
+                        name: name.clone(),
+                        ino: entry.ino,
+                    });
+                    dangling.push((dir.ino, name.clone()));
+                    continue;
+                }
+                *refs.entry(entry.ino).or_default() += 1;
+                if entry.kind == FileType::Directory {
+                    *subdirs.entry(dir.ino).or_default() += 1;
+                }
+            }
+        }
+        if repair {
+            for (parent, name) in dangling {
+                inodes.get_mut(&parent).unwrap().entries.remove(&name);
+                modified.insert(parent);
+            }
+        }
+
+        let orphans: Vec<u64> = inodes
+            .keys()
+            .copied()
+            .filter(|&ino| ino != FUSE_ROOT_ID && !refs.contains_key(&ino))
+            .collect();
+        found.extend(orphans.iter().map(|&ino| Inconsistency::Orphan { ino }));
+        if repair && !orphans.is_empty() && inodes.contains_key(&FUSE_ROOT_ID) {
+            let lost = self.lost_and_found(&mut inodes);
+            *refs.entry(lost).or_default() += 1;
+            *subdirs.entry(FUSE_ROOT_ID).or_default() += 1;
+            for ino in orphans {
+                let kind = inodes[&ino].kind;
+                inodes
+                    .get_mut(&lost)
+                    .unwrap()
+                    .entries
+                    .insert(format!("#{}", ino), DirEntry { ino, kind });
+                *refs.entry(ino).or_default() += 1;
+                if kind == FileType::Directory {
+                    *subdirs.entry(lost).or_default() += 1;
+                }
+                inodes.get_mut(&ino).unwrap().parent = lost;
+            }
+            modified.insert(FUSE_ROOT_ID);
+            modified.insert(lost);
+        }
+
+        for i in inodes.values_mut() {
+            let actual = match i.kind {
+                FileType::Directory => 2 + subdirs.get(&i.ino).copied().unwrap_or_default(),
+                _ => refs.get(&i.ino).copied().unwrap_or_default(),
+            };
+            if i.nlink != actual {
+                found.push(Inconsistency::LinkCount {
+                    ino: i.ino,
+                    recorded: i.nlink,
+                    actual,
+                });
+                if repair {
+                    i.nlink = actual;
+                    modified.insert(i.ino);
+                }
+            }
+        }
+
+        let core = self.core.clone();
+        let mut meta = core.meta.write().unwrap();
+        let state = meta.get_reserved(ALLOCATOR_STATE_KEY);
+        if let Some(state) = state.and_then(|s| bincode::deserialize::<AllocatorState>(&s).ok()) {
+            let mut space = core.space.lock().unwrap();
+            for i in inodes.values() {
+                let ino = i.ino as usize;
+                self.inode_allocator.remove(ino..ino + 1);
+                i.extents
+                    .values()
+                    .for_each(|e| space.block_allocator.remove(e.clone()));
+            }
+            if state.blocks != space.block_allocator.used_ranges()
+                || state.inodes != self.inode_allocator.used_ranges()
+            {
+                found.push(Inconsistency::AllocatorState);
+                if repair {
+                    meta.put_reserved(ALLOCATOR_STATE_KEY, None);
+                }
+            }
+        }
+        if repair && !(modified.is_empty() && unlinked.is_empty()) {
+            // block usage changed, the next mount has to rebuild the allocators
+            meta.put_reserved(ALLOCATOR_STATE_KEY, None);
+            for ino in modified.into_iter().chain(unlinked) {
+                meta.restore(ino, inodes.get(&ino));
+            }
+        }
+        meta.flush();
+        meta.check_store()?;
+        Ok(found)
+    }
+    /// Finds or creates the [`LOST_AND_FOUND`] directory under the root in `inodes`.
+    fn lost_and_found(&mut self, inodes: &mut BTreeMap<u64, Attrs>) -> u64 {
+        if let Some(entry) = inodes[&FUSE_ROOT_ID].entries.get(LOST_AND_FOUND) {
+            if entry.kind == FileType::Directory && inodes.contains_key(&entry.ino) {
+                return entry.ino;
+            }
+        }
+        // allocate past every inode in use, the allocator was not rebuilt
+        let ino = inodes.keys().next_back().copied().unwrap_or(FUSE_ROOT_ID) + 1;
+        let root = &inodes[&FUSE_ROOT_ID];
+        let mut lost = self.new_inode(root.uid, root.gid, Some(ino));
+        lost.kind = FileType::Directory;
+        lost.perm = 0o700;
+        lost.nlink = 2;
+        lost.parent = FUSE_ROOT_ID;
+        inodes.get_mut(&FUSE_ROOT_ID).unwrap().entries.insert(
+            LOST_AND_FOUND.to_string(),
+            DirEntry {
+                ino,
+                kind: FileType::Directory,
+            },
+        );
+        inodes.insert(ino, lost);
+        ino
+    }
+}
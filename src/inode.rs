@@ -1,42 +1,117 @@
 use crate::block_cache::BlockCache;
+use crate::checksum::{ChecksumError, ChecksumKind};
+use crate::compress::Compression;
+use crate::metrics::Metrics;
+use bincode::Options;
+use log::error;
 use lru::LruCache;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Range;
 use std::os::raw::c_int;
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::time::SystemTime;
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec;
 
+/// Leads every versioned inode record. Unversioned records start with the inode number, which
+/// never reaches it.
+const RECORD_MARKER: u64 = u64::MAX;
+/// Version of the inode records written by this build. Version 1 records had no header.
+pub const RECORD_VERSION: u8 = 3;
+
+/// Period after which relatime refreshes an access time even without intervening modification.
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum FileType {
     RegularFile,
     Directory,
     Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
 }
 
 #[derive(Clone)]
-pub struct Inode<const BLOCK_SIZE: usize> {
-    pub attrs: Attrs<BLOCK_SIZE>,
+pub struct Inode {
+    pub attrs: Attrs,
     pub dirty: bool,
+    /// when the inode last turned dirty
+    pub dirtied: Instant,
     pub db: Arc<Mutex<cxx::UniquePtr<crate::ffi::KVStore>>>,
-    pub dev: Arc<Mutex<BlockCache<BLOCK_SIZE>>>,
+    pub dev: Arc<Mutex<BlockCache>>,
 }
 
-impl<const BLOCK_SIZE: usize> Inode<BLOCK_SIZE> {
-    fn flush(&self) {
+impl Inode {
+    /// Writes the record to the metadata store after the data it references, returning whether
+    /// it got there.
+    fn flush(&self) -> bool {
+        flush_all(&[self])[0]
+    }
+    /// Writes the dirty cached blocks of the data back, returning whether they got there.
+    fn write_data(&self) -> bool {
+        if self.attrs.extents.is_empty() {
+            return true;
+        }
+        let res = self
+            .dev
+            .lock()
+            .unwrap()
+            .barrier(self.attrs.extents.values());
+        if let Err(err) = res {
+            error!(
+                "failed to write back the data of inode {}, error {}",
+                self.attrs.ino, err
+            );
+            return false;
+        }
+        true
+    }
+    /// Writes the record alone to the metadata store, returning whether it got there.
+    fn put(&self) -> bool {
         cxx::let_cxx_string!(key = self.attrs.ino.to_le_bytes());
-        cxx::let_cxx_string!(value = bincode::serialize(&self.attrs).unwrap());
-        if self.attrs.nlink > 0 {
-            self.db.lock().unwrap().as_mut().unwrap().put(&key, &value);
-        } else {
-            self.db.lock().unwrap().as_mut().unwrap().remove(&key);
+        cxx::let_cxx_string!(value = self.attrs.encode());
+        // unlinked inodes are kept until the kernel forgets them, then removed with `restore`
+        self.db.lock().unwrap().as_mut().unwrap().put(&key, &value);
+        if self.db.lock().unwrap().failed() {
+            error!("failed to write back inode {}", self.attrs.ino);
+            return false;
+        }
+        true
+    }
+}
+
+/// Writes the records of `inodes` to the metadata store after the data they reference, forcing
+/// the device to stable storage once for all of them. Returns whether each record got there.
+fn flush_all(inodes: &[&Inode]) -> Vec<bool> {
+    // the data goes first, a record reaching the store ahead of it would expose stale block
+    // contents after a crash, so the old record is kept when the data cannot be written
+    let mut written: Vec<bool> = inodes.iter().map(|inode| inode.write_data()).collect();
+    // records without extents reference no data and do not wait for the device
+    let data = inodes
+        .iter()
+        .zip(&written)
+        .find(|(inode, &ok)| ok && !inode.attrs.extents.is_empty());
+    if let Some((inode, _)) = data {
+        if let Err(err) = inode.dev.lock().unwrap().sync_written() {
+            error!(
+                "failed to sync the data of written back inodes, error {}",
+                err
+            );
+            for (inode, ok) in inodes.iter().zip(&mut written) {
+                *ok &= inode.attrs.extents.is_empty();
+            }
         }
     }
+    for (inode, ok) in inodes.iter().zip(&mut written) {
+        *ok = *ok && inode.put();
+    }
+    written
 }
 
-impl<const BLOCK_SIZE: usize> Drop for Inode<BLOCK_SIZE> {
+impl Drop for Inode {
     fn drop(&mut self) {
         if self.dirty {
             self.flush();
@@ -44,94 +119,481 @@ impl<const BLOCK_SIZE: usize> Drop for Inode<BLOCK_SIZE> {
     }
 }
 
-impl<const BLOCK_SIZE: usize> Attrs<BLOCK_SIZE> {
+impl Attrs {
+    /// Encodes the attributes as an inode record of the current version.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut record = RECORD_MARKER.to_le_bytes().to_vec();
+        record.push(RECORD_VERSION);
+        bincode::serialize_into(&mut record, self).unwrap();
+        record
+    }
+    /// Decodes an inode record of any known version, along with whether it predates the current
+    /// version and should be rewritten.
+    pub fn decode(record: &[u8]) -> Option<(Self, bool)> {
+        let marker = RECORD_MARKER.to_le_bytes();
+        if record.len() > marker.len() && record[..marker.len()] == marker {
+            let body = &record[marker.len() + 1..];
+            match record[marker.len()] {
+                RECORD_VERSION => bincode::deserialize(body).ok().map(|attrs| (attrs, false)),
+                2 => decode_v1::<Extents, (Xattrs, Checksums, Compressed)>(body)
+                    .map(|attrs| (attrs, true)),
+                _ => None,
+            }
+        } else {
+            // the unversioned layouts only grew, so trying the longest first and refusing
+            // trailing bytes tells them apart
+            decode_v1::<Extents, (Xattrs, Checksums, Compressed)>(record)
+                .or_else(|| decode_v1::<Extents, (Xattrs, Checksums)>(record))
+                .or_else(|| decode_v1::<Extents, (Xattrs,)>(record))
+                .or_else(|| decode_v1::<Vec<Range<usize>>, (Xattrs,)>(record))
+                .or_else(|| decode_v1::<Vec<Range<usize>>, ()>(record))
+                .map(|attrs| (attrs, true))
+        }
+    }
+    /// Whether `self` and `other` read back the same data, differing at most in what reading it
+    /// does not depend on, like timestamps, ownership or the link count.
+    pub fn same_data(&self, other: &Attrs) -> bool {
+        self.size == other.size
+            && self.extents == other.extents
+            && self.checksums == other.checksums
+            && self.compressed == other.compressed
+    }
     pub fn blocks(&self) -> usize {
-        self.extents.iter().map(Range::len).sum()
+        self.extents.values().map(Range::len).sum()
+    }
+    /// Physical block backing logical block `block`, or `None` for a hole.
+    pub fn physical(&self, block: usize) -> Option<usize> {
+        let (start, e) = self.extents.range(..=block).next_back()?;
+        if block - start < e.len() {
+            Some(e.start + (block - start))
+        } else {
+            None
+        }
+    }
+    /// Whether some logical block is backed by the physical `block`.
+    pub fn maps(&self, block: usize) -> bool {
+        self.extents.values().any(|e| e.contains(&block))
+    }
+    /// Backs logical block `block` with the physical `to` holding the same stored contents,
+    /// carrying its checksum and compression over, and returns the physical block it replaced.
+    pub fn remap_block(&mut self, block: usize, to: usize) -> Option<usize> {
+        let from = self.physical(block)?;
+        let checksum = self.checksums.get(&from).copied();
+        let compressed = self.compressed.get(&from).copied();
+        self.unmap_blocks(block..block + 1);
+        self.map_blocks(block, to..to + 1);
+        if let Some(checksum) = checksum {
+            self.checksums.insert(to, checksum);
+        }
+        if let Some(compressed) = compressed {
+            self.compressed.insert(to, compressed);
+        }
+        Some(from)
+    }
+    /// Holes within the logical block range `blocks`.
+    pub fn holes(&self, blocks: Range<usize>) -> Vec<Range<usize>> {
+        let mut holes = vec![];
+        let mut cursor = blocks.start;
+        for (&start, e) in self.extents.range(..blocks.end) {
+            let end = start + e.len();
+            if end <= cursor {
+                continue;
+            }
+            if start > cursor {
+                holes.push(cursor..start);
+            }
+            cursor = end;
+        }
+        if cursor < blocks.end {
+            holes.push(cursor..blocks.end);
+        }
+        holes
+    }
+    /// Copy of what reading or writing the logical `blocks` takes: the parts of the extents
+    /// mapping them along with the checksums and compression of their physical blocks. Directory
+    /// entries, the link target and xattrs are left out.
+    pub fn data_slice(&self, blocks: Range<usize>) -> Attrs {
+        let mut extents = Extents::new();
+        let mut checksums = Checksums::new();
+        let mut compressed = Compressed::new();
+        // extents are disjoint, the first one ending before `blocks` ends the search
+        for (&start, e) in self.extents.range(..blocks.end).rev() {
+            if start + e.len() <= blocks.start {
+                break;
+            }
+            let skip = blocks.start.saturating_sub(start);
+            let len = std::cmp::min(start + e.len(), blocks.end) - (start + skip);
+            let physical = e.start + skip..e.start + skip + len;
+            checksums.extend(self.checksums.range(physical.clone()));
+            compressed.extend(self.compressed.range(physical.clone()));
+            extents.insert(start + skip, physical);
+        }
+        Attrs {
+            ino: self.ino,
+            size: self.size,
+            extents,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            crtime: self.crtime,
+            kind: self.kind,
+            perm: self.perm,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev,
+            flags: self.flags,
+            entries: BTreeMap::new(),
+            link: std::path::PathBuf::new(),
+            xattrs: Xattrs::new(),
+            checksums,
+            compressed,
+            parent: self.parent,
+        }
+    }
+    /// Offset of the first byte at or after `offset` backed by a block, `None` past the last data.
+    pub fn next_data(&self, block_size: usize, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let block = offset as usize / block_size;
+        if self.physical(block).is_some() {
+            return Some(offset);
+        }
+        self.extents
+            .range(block..)
+            .next()
+            .map(|(&start, _)| (start * block_size) as u64)
+            .filter(|&data| data < self.size)
+    }
+    /// Offset of the first hole at or after `offset`, the end of the file counting as one.
+    pub fn next_hole(&self, block_size: usize, offset: u64) -> Option<u64> {
+        if offset >= self.size {
+            return None;
+        }
+        let blocks = (self.size as usize + (block_size - 1)) / block_size;
+        let hole = match self.holes(offset as usize / block_size..blocks).first() {
+            Some(hole) => std::cmp::max((hole.start * block_size) as u64, offset),
+            None => self.size,
+        };
+        Some(std::cmp::min(hole, self.size))
+    }
+    /// Verifies that the extents are disjoint both in the file and on a device of `dev_blocks`
+    /// blocks.
+    pub fn check_invariants(&self, dev_blocks: usize) -> Result<(), String> {
+        if let Some((a, b)) = self
+            .extents
+            .iter()
+            .zip(self.extents.iter().skip(1))
+            .find(|((start, e), (next, _))| *start + e.len() > **next)
+        {
+            return Err(format!("logical extents {:?} and {:?} overlap", a, b));
+        }
+        let mut extents: Vec<Range<usize>> = self.extents.values().cloned().collect();
+        extents.sort_by_key(|e| e.start);
+        if let Some(e) = extents.iter().find(|e| e.is_empty() || e.end > dev_blocks) {
+            return Err(format!("extent {:?} outside of device", e));
+        }
+        if let Some(w) = extents.windows(2).find(|w| w[0].end > w[1].start) {
+            return Err(format!("extents {:?} and {:?} overlap", w[0], w[1]));
+        }
+        Ok(())
+    }
+    /// Maps the physical `blocks` at logical block `start`, which must be a hole, extending the
+    /// preceding extent when both sides are contiguous with it.
+    pub fn map_blocks(&mut self, start: usize, blocks: Range<usize>) {
+        if let Some((prev, e)) = self.extents.range_mut(..start).next_back() {
+            if prev + e.len() == start && e.end == blocks.start {
+                e.end = blocks.end;
+                return;
+            }
+        }
+        self.extents.insert(start, blocks);
+    }
+    /// Records the checksum of `data` just written to the physical `block`.
+    pub fn seal_block(&mut self, block: usize, data: &[u8]) {
+        match ChecksumKind::from_flags(self.flags).compute(data) {
+            Some(sum) => self.checksums.insert(block, sum),
+            None => self.checksums.remove(&block),
+        };
+    }
+    /// Checks `data` read from the physical `block` against its recorded checksum.
+    pub fn verify_block(&self, block: usize, data: &[u8]) -> Result<(), ChecksumError> {
+        let kind = ChecksumKind::from_flags(self.flags);
+        match (self.checksums.get(&block), kind.compute(data)) {
+            (Some(&expected), Some(actual)) if expected != actual => Err(ChecksumError {
+                ino: self.ino,
+                block,
+                expected,
+                actual,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Whether `uid` and `gid` may access the inode as asked by the R_OK, W_OK and X_OK bits of
+    /// `mask`. Supplementary groups of the caller are not known and not considered.
+    pub fn permits(&self, uid: u32, gid: u32, mask: i32) -> bool {
+        let mask = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+        if uid == 0 {
+            // root bypasses everything but executing a file nobody may execute
+            return mask & libc::X_OK as u16 == 0
+                || self.kind == FileType::Directory
+                || self.perm & 0o111 != 0;
+        }
+        let bits = if uid == self.uid {
+            self.perm >> 6
+        } else if gid == self.gid {
+            self.perm >> 3
+        } else {
+            self.perm
+        };
+        bits & mask == mask
+    }
+    /// Whether an access at `now` should update atime under relatime semantics.
+    pub fn atime_stale(&self, now: SystemTime) -> bool {
+        self.atime <= self.mtime
+            || self.atime <= self.ctime
+            || now
+                .duration_since(self.atime)
+                .is_ok_and(|d| d >= RELATIME_INTERVAL)
+    }
+    /// Unmaps every logical block from `blocks` onwards, returning the released physical ranges.
+    pub fn truncate_blocks(&mut self, blocks: usize) -> Vec<Range<usize>> {
+        let mut freed: Vec<Range<usize>> = self.extents.split_off(&blocks).into_values().collect();
+        if let Some((start, e)) = self.extents.iter_mut().next_back() {
+            if start + e.len() > blocks {
+                let split = e.start + (blocks - start);
+                freed.push(split..e.end);
+                e.end = split;
+            }
+        }
+        for block in freed.iter().flat_map(|r| r.clone()) {
+            self.checksums.remove(&block);
+            self.compressed.remove(&block);
+        }
+        freed
+    }
+    /// Unmaps the logical `blocks`, leaving a hole, and returns the released physical ranges.
+    pub fn unmap_blocks(&mut self, blocks: Range<usize>) -> Vec<Range<usize>> {
+        let mut rest = self.extents.split_off(&blocks.end);
+        if let Some((&start, e)) = self.extents.range_mut(..blocks.end).next_back() {
+            if start + e.len() > blocks.end {
+                let split = e.start + (blocks.end - start);
+                rest.insert(blocks.end, split..e.end);
+                e.end = split;
+            }
+        }
+        let freed = self.truncate_blocks(blocks.start);
+        self.extents.append(&mut rest);
+        freed
+    }
+    /// Reads the physical `block` into `buf`, collecting a failed verification in `corrupt`
+    /// and restoring the contents of a compressed block.
+    fn load_block(
+        &self,
+        dev: &Mutex<BlockCache>,
+        block: usize,
+        buf: &mut [u8],
+        corrupt: &mut Vec<ChecksumError>,
+    ) -> std::io::Result<()> {
+        dev.lock().unwrap().read_block(block, buf)?;
+        if let Err(err) = self.verify_block(block, buf) {
+            corrupt.push(err);
+        }
+        if let Some(&(compression, len)) = self.compressed.get(&block) {
+            let data = compression.decompress(&buf[..len], buf.len())?;
+            buf.copy_from_slice(&data);
+        }
+        Ok(())
+    }
+    /// Writes `data` to the physical `block`, compressed and padded with zeros when it comes out
+    /// smaller and as is otherwise.
+    fn store_block(
+        &mut self,
+        dev: &Mutex<BlockCache>,
+        block: usize,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let compression = Compression::from_flags(self.flags);
+        let mut buf = vec![0u8; data.len()];
+        match compression.compress(data).filter(|c| c.len() < data.len()) {
+            Some(c) => {
+                buf[..c.len()].copy_from_slice(&c);
+                self.compressed.insert(block, (compression, c.len()));
+            }
+            None => {
+                buf.copy_from_slice(data);
+                self.compressed.remove(&block);
+            }
+        }
+        dev.lock().unwrap().write_block(block, &buf)?;
+        self.seal_block(block, &buf);
+        Ok(())
     }
+    /// Reads into `buf` from `offset`, collecting blocks that fail verification in `corrupt`
+    /// while still returning their contents.
     pub fn read_at(
         &self,
-        dev: Arc<Mutex<BlockCache<BLOCK_SIZE>>>,
+        dev: Arc<Mutex<BlockCache>>,
         buf: &mut [u8],
         offset: u64,
+        corrupt: &mut Vec<ChecksumError>,
     ) -> std::io::Result<usize> {
-        let mut data = vec![];
-        let begin = offset as usize / BLOCK_SIZE;
-        let end = (offset as usize + buf.len() + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
-        for block in self
-            .extents
-            .iter()
-            .flat_map(|r| r.clone())
-            .skip(begin)
-            .take(end - begin)
-        {
-            let mut buf = [0u8; BLOCK_SIZE];
-            dev.lock().unwrap().read_block(block, &mut buf).unwrap();
-            data.extend_from_slice(&buf);
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let size = std::cmp::min((self.size - offset) as usize, buf.len());
+        let block_size = dev.lock().unwrap().block_size();
+        let begin = offset as usize / block_size;
+        let end = (offset as usize + size + (block_size - 1)) / block_size;
+        // holes read back as zeros
+        let mut data = vec![0u8; (end - begin) * block_size];
+        let mut i = 0;
+        while i < end - begin {
+            let block = match self.physical(begin + i) {
+                Some(block) => block,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            // physically contiguous blocks stored as is are read as one range
+            let run = (i..end - begin)
+                .take_while(|&j| {
+                    let b = block + (j - i);
+                    self.physical(begin + j) == Some(b) && !self.compressed.contains_key(&b)
+                })
+                .count();
+            if run < 2 {
+                let data = &mut data[i * block_size..(i + 1) * block_size];
+                self.load_block(&dev, block, data, corrupt)?;
+                i += 1;
+                continue;
+            }
+            let data = &mut data[i * block_size..(i + run) * block_size];
+            dev.lock().unwrap().read_range(block, data)?;
+            for (j, data) in data.chunks_exact(block_size).enumerate() {
+                if let Err(err) = self.verify_block(block + j, data) {
+                    corrupt.push(err);
+                }
+            }
+            i += run;
         }
-        let size = std::cmp::min((self.size - offset) as usize, buf.len()) as usize;
-        let off = offset as usize % BLOCK_SIZE;
+        let off = offset as usize % block_size;
         buf[..size].copy_from_slice(&data[off..off + size]);
         Ok(size)
     }
+    /// Writes `buf` at `offset`, every block in the range must already be mapped. A partially
+    /// written block that fails verification is collected in `corrupt` and fails the write, as
+    /// merging into it would seal the corruption under a fresh checksum.
     pub fn write_at(
-        &self,
-        dev: Arc<Mutex<BlockCache<BLOCK_SIZE>>>,
+        &mut self,
+        dev: Arc<Mutex<BlockCache>>,
         buf: &[u8],
         offset: u64,
+        corrupt: &mut Vec<ChecksumError>,
     ) -> std::io::Result<usize> {
+        // an empty write at an unaligned offset would otherwise load and rewrite its block
+        if buf.is_empty() {
+            return Ok(0);
+        }
         let mut data = vec![];
-        let begin = offset as usize / BLOCK_SIZE;
-        let end = (offset as usize + buf.len() + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
-        let off = offset as usize % BLOCK_SIZE;
-        let eoff = (offset as usize + buf.len()) % BLOCK_SIZE;
-        for (i, block) in self
-            .extents
-            .iter()
-            .flat_map(|r| r.clone())
-            .enumerate()
-            .skip(begin)
-            .take(end - begin)
-        {
-            let mut buf = [0u8; BLOCK_SIZE];
-            if (i == begin && off != 0) || (i == end && eoff != 0) {
-                dev.lock().unwrap().read_block(block, &mut buf).unwrap();
+        let block_size = dev.lock().unwrap().block_size();
+        let begin = offset as usize / block_size;
+        let end = (offset as usize + buf.len() + (block_size - 1)) / block_size;
+        let off = offset as usize % block_size;
+        let eoff = (offset as usize + buf.len()) % block_size;
+        let blocks = (begin..end)
+            .map(|block| {
+                self.physical(block).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("block {} of inode {} is not mapped", block, self.ino),
+                    )
+                })
+            })
+            .collect::<std::io::Result<Vec<usize>>>()?;
+        for (i, &block) in blocks.iter().enumerate() {
+            let mut buf = vec![0u8; block_size];
+            // only the partial head and tail blocks keep bytes outside of the written range,
+            // a single block may be both; a write starting and ending on block boundaries has
+            // neither and reads nothing
+            let head = i == 0 && off != 0;
+            let tail = i + 1 == blocks.len() && eoff != 0;
+            if head || tail {
+                let verified = corrupt.len();
+                self.load_block(&dev, block, &mut buf, corrupt)?;
+                if corrupt.len() > verified {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        corrupt[verified].to_string(),
+                    ));
+                }
             }
             data.extend_from_slice(&buf);
         }
         data[off..off + buf.len()].copy_from_slice(buf);
-        for (i, block) in self
-            .extents
-            .iter()
-            .flat_map(|r| r.clone())
-            .skip(begin)
-            .take(end - begin)
-            .enumerate()
-        {
-            dev.lock()
-                .unwrap()
-                .write_block(
-                    block,
-                    data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE]
-                        .try_into()
-                        .unwrap(),
-                )
-                .unwrap();
+        if Compression::from_flags(self.flags) != Compression::None {
+            for (i, &block) in blocks.iter().enumerate() {
+                self.store_block(&dev, block, &data[i * block_size..(i + 1) * block_size])?;
+            }
+            return Ok(buf.len());
+        }
+        // uncompressed blocks are written a physically contiguous run at a time
+        let mut i = 0;
+        while i < blocks.len() {
+            let run = (i..blocks.len())
+                .take_while(|&j| blocks[j] == blocks[i] + (j - i))
+                .count();
+            let data = &data[i * block_size..(i + run) * block_size];
+            dev.lock().unwrap().write_range(blocks[i], data)?;
+            for (j, data) in data.chunks_exact(block_size).enumerate() {
+                self.compressed.remove(&(blocks[i] + j));
+                self.seal_block(blocks[i] + j, data);
+            }
+            i += run;
         }
         Ok(buf.len())
     }
-    pub fn fsync(&self, dev: Arc<Mutex<BlockCache<BLOCK_SIZE>>>) {
+    /// Attributes as reported to the kernel for a filesystem of `block_size` byte blocks.
+    pub fn file_attr(&self, block_size: usize) -> fuser::FileAttr {
+        fuser::FileAttr {
+            ino: self.ino,
+            size: self.size,
+            // st_blocks counts 512 byte units
+            blocks: (self.blocks() * block_size / 512) as u64,
+            crtime: self.crtime,
+            atime: self.atime,
+            mtime: self.mtime,
+            ctime: self.ctime,
+            kind: self.kind.into(),
+            perm: self.perm,
+            nlink: self.nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: self.rdev,
+            blksize: block_size as u32,
+            flags: self.flags,
+        }
+    }
+    pub fn fsync(&self, dev: Arc<Mutex<BlockCache>>) -> std::io::Result<()> {
+        let mut dev = dev.lock().unwrap();
         self.extents
-            .iter()
+            .values()
             .flat_map(|r| r.clone())
-            .for_each(|block| dev.lock().unwrap().flush_block(block));
+            .try_for_each(|block| dev.flush_block(block))
     }
 }
 
+/// Maps logical file blocks to physical device blocks, keyed by the first logical block of each
+/// extent. Logical blocks without a mapping are holes.
+pub type Extents = BTreeMap<usize, Range<usize>>;
+
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
-pub struct Attrs<const BLOCK_SIZE: usize> {
+pub struct Attrs {
     pub ino: u64,
     pub size: u64,
-    pub extents: Vec<Range<usize>>,
+    pub extents: Extents,
     pub atime: SystemTime,
     pub mtime: SystemTime,
     pub ctime: SystemTime,
@@ -145,6 +607,132 @@ pub struct Attrs<const BLOCK_SIZE: usize> {
     pub flags: u32,
     pub entries: BTreeMap<String, DirEntry>,
     pub link: std::path::PathBuf,
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// checksums of the data blocks, keyed by physical block
+    pub checksums: BTreeMap<usize, u32>,
+    /// mode and length of the compressed data blocks, keyed by physical block
+    pub compressed: BTreeMap<usize, (Compression, usize)>,
+    /// directory the inode was created in or last moved to, itself for the root and zero when
+    /// unknown
+    pub parent: u64,
+}
+
+type Xattrs = BTreeMap<String, Vec<u8>>;
+type Checksums = BTreeMap<usize, u32>;
+type Compressed = BTreeMap<usize, (Compression, usize)>;
+
+/// Layout of the unversioned inode records, which were written without a header. The first ones
+/// listed the extents in logical order without holes and ended at `link`, later ones appended
+/// the fields in `T` one after the other and keyed the extents by their first logical block.
+/// Version 2 records hold the last of these layouts behind their header.
+#[derive(Deserialize)]
+struct AttrsV1<E, T> {
+    ino: u64,
+    size: u64,
+    extents: E,
+    atime: SystemTime,
+    mtime: SystemTime,
+    ctime: SystemTime,
+    crtime: SystemTime,
+    kind: FileType,
+    perm: u16,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u32,
+    flags: u32,
+    entries: BTreeMap<String, DirEntry>,
+    link: std::path::PathBuf,
+    added: T,
+}
+
+/// Extents as laid out by some [`AttrsV1`].
+trait ExtentsV1: serde::de::DeserializeOwned {
+    fn upgrade(self) -> Extents;
+}
+
+impl ExtentsV1 for Vec<Range<usize>> {
+    /// Keys the listed extents by the logical block they start at.
+    fn upgrade(self) -> Extents {
+        let mut logical = 0;
+        self.into_iter()
+            .map(|e| {
+                logical += e.len();
+                (logical - e.len(), e)
+            })
+            .collect()
+    }
+}
+
+impl ExtentsV1 for Extents {
+    fn upgrade(self) -> Extents {
+        self
+    }
+}
+
+/// Fields appended to some [`AttrsV1`], the missing ones default to empty.
+trait AddedV1: serde::de::DeserializeOwned {
+    fn upgrade(self) -> (Xattrs, Checksums, Compressed);
+}
+
+impl AddedV1 for () {
+    fn upgrade(self) -> (Xattrs, Checksums, Compressed) {
+        Default::default()
+    }
+}
+
+impl AddedV1 for (Xattrs,) {
+    fn upgrade(self) -> (Xattrs, Checksums, Compressed) {
+        (self.0, Default::default(), Default::default())
+    }
+}
+
+impl AddedV1 for (Xattrs, Checksums) {
+    fn upgrade(self) -> (Xattrs, Checksums, Compressed) {
+        (self.0, self.1, Default::default())
+    }
+}
+
+impl AddedV1 for (Xattrs, Checksums, Compressed) {
+    fn upgrade(self) -> (Xattrs, Checksums, Compressed) {
+        self
+    }
+}
+
+/// Decodes `record` when it has exactly the layout of `AttrsV1<E, T>`.
+fn decode_v1<E: ExtentsV1, T: AddedV1>(record: &[u8]) -> Option<Attrs> {
+    let v1: AttrsV1<E, T> = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .deserialize(record)
+        .ok()?;
+    let (xattrs, checksums, compressed) = v1.added.upgrade();
+    Some(Attrs {
+        ino: v1.ino,
+        size: v1.size,
+        extents: v1.extents.upgrade(),
+        atime: v1.atime,
+        mtime: v1.mtime,
+        ctime: v1.ctime,
+        crtime: v1.crtime,
+        kind: v1.kind,
+        perm: v1.perm,
+        nlink: v1.nlink,
+        uid: v1.uid,
+        gid: v1.gid,
+        rdev: v1.rdev,
+        flags: v1.flags,
+        entries: v1.entries,
+        link: v1.link,
+        xattrs,
+        checksums,
+        compressed,
+        // only the root knows its parent without a walk of the tree
+        parent: if v1.ino == fuser::FUSE_ROOT_ID {
+            v1.ino
+        } else {
+            0
+        },
+    })
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -159,109 +747,269 @@ impl From<FileType> for fuser::FileType {
             FileType::RegularFile => fuser::FileType::RegularFile,
             FileType::Directory => fuser::FileType::Directory,
             FileType::Symlink => fuser::FileType::Symlink,
+            FileType::CharDevice => fuser::FileType::CharDevice,
+            FileType::BlockDevice => fuser::FileType::BlockDevice,
+            FileType::Fifo => fuser::FileType::NamedPipe,
+            FileType::Socket => fuser::FileType::Socket,
         }
     }
 }
 
-impl<const BLOCK_SIZE: usize> From<&mut Attrs<BLOCK_SIZE>> for fuser::FileAttr {
-    fn from(attrs: &mut Attrs<BLOCK_SIZE>) -> Self {
-        (&*attrs).into()
-    }
+/// Number of locks inodes are hashed onto by [`InodeLocks`].
+const LOCK_SHARDS: usize = 64;
+
+/// Serializes operations on the same inode while letting unrelated inodes proceed in parallel,
+/// hashing inodes onto a fixed set of locks. Reads of file data share the lock of their inode,
+/// so the blocks they read are neither rewritten nor freed underneath them.
+pub struct InodeLocks {
+    shards: Vec<RwLock<()>>,
 }
 
-impl<const BLOCK_SIZE: usize> From<Attrs<BLOCK_SIZE>> for fuser::FileAttr {
-    fn from(attrs: Attrs<BLOCK_SIZE>) -> Self {
-        attrs.into()
+impl InodeLocks {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..LOCK_SHARDS).map(|_| RwLock::new(())).collect(),
+        }
+    }
+    /// Locks every inode of `inos`, taking the shards in ascending order so that operations on
+    /// overlapping inodes, like a parent and its child, never deadlock.
+    pub fn lock(&self, inos: &[u64]) -> Vec<RwLockWriteGuard<'_, ()>> {
+        let shards: BTreeSet<usize> = inos.iter().map(|ino| *ino as usize % LOCK_SHARDS).collect();
+        shards
+            .into_iter()
+            .map(|shard| self.shards[shard].write().unwrap())
+            .collect()
+    }
+    /// Locks every inode, for operations spanning inodes not known up front.
+    pub fn lock_all(&self) -> Vec<RwLockWriteGuard<'_, ()>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap())
+            .collect()
+    }
+    /// Locks `ino` shared with other readers.
+    pub fn read(&self, ino: u64) -> RwLockReadGuard<'_, ()> {
+        self.shards[ino as usize % LOCK_SHARDS].read().unwrap()
     }
 }
 
-impl<const BLOCK_SIZE: usize> From<&Attrs<BLOCK_SIZE>> for fuser::FileAttr {
-    fn from(attrs: &Attrs<BLOCK_SIZE>) -> Self {
-        fuser::FileAttr {
-            ino: attrs.ino,
-            size: attrs.size,
-            blocks: attrs.blocks() as u64,
-            crtime: attrs.crtime,
-            atime: attrs.atime,
-            mtime: attrs.mtime,
-            ctime: attrs.ctime,
-            kind: attrs.kind.into(),
-            perm: attrs.perm,
-            nlink: attrs.nlink,
-            uid: attrs.uid,
-            gid: attrs.gid,
-            rdev: attrs.rdev,
-            blksize: BLOCK_SIZE as u32,
-            flags: attrs.flags,
-        }
+impl Default for InodeLocks {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-pub struct InodeCache<const BLOCK_SIZE: usize> {
+pub struct InodeCache {
     db: Arc<Mutex<cxx::UniquePtr<crate::ffi::KVStore>>>,
-    dev: Arc<Mutex<BlockCache<BLOCK_SIZE>>>,
-    cache: LruCache<u64, Inode<BLOCK_SIZE>>,
+    dev: Arc<Mutex<BlockCache>>,
+    cache: LruCache<u64, Inode>,
+    // hits served under a shared lock, promoted in the LRU on the next exclusive access
+    touched: Mutex<Vec<u64>>,
+    dirty: usize,
+    max_dirty: usize,
+    metrics: Arc<Metrics>,
 }
 
-impl<const BLOCK_SIZE: usize> InodeCache<BLOCK_SIZE> {
+impl InodeCache {
     pub fn new(
         db: Arc<Mutex<cxx::UniquePtr<crate::ffi::KVStore>>>,
-        dev: Arc<Mutex<BlockCache<BLOCK_SIZE>>>,
+        dev: Arc<Mutex<BlockCache>>,
         capacity: usize,
+        max_dirty: usize,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             db,
             dev,
             cache: LruCache::new(capacity),
+            touched: Mutex::new(vec![]),
+            dirty: 0,
+            max_dirty,
+            metrics,
         }
     }
 
-    pub fn scan(&mut self, mut f: impl FnMut(&Attrs<BLOCK_SIZE>)) -> Result<(), c_int> {
-        let ids = self.db.lock().unwrap().list();
-        for id in ids.into_iter() {
-            let data = self.db.lock().unwrap().get(id);
-            if let Ok(attrs) = bincode::deserialize::<Attrs<BLOCK_SIZE>>(data.as_bytes()) {
-                f(&attrs);
-            } else {
-                return Err(libc::EIO);
+    pub fn dirty(&self) -> usize {
+        self.dirty
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Runs `f` on a cached inode without taking exclusive access, handing `f` back on a miss.
+    pub fn peek<V, F: FnOnce(&Attrs) -> V>(&self, ino: u64, f: F) -> Result<V, F> {
+        match self.cache.peek(&ino) {
+            Some(inode) => {
+                self.metrics.inode_cache_access(true);
+                let mut touched = self.touched.lock().unwrap();
+                if touched.len() < self.cache.cap() {
+                    touched.push(ino);
+                }
+                Ok(f(&inode.attrs))
+            }
+            None => Err(f),
+        }
+    }
+
+    fn promote(&mut self) {
+        for ino in std::mem::take(self.touched.get_mut().unwrap()) {
+            self.cache.get(&ino);
+        }
+    }
+
+    fn put(&mut self, ino: u64, inode: Inode) {
+        if inode.dirty {
+            self.dirty += 1;
+        }
+        // evicted inodes write themselves back on drop
+        if let Some((_, old)) = self.cache.push(ino, inode) {
+            if old.dirty {
+                self.dirty -= 1;
+            }
+        }
+    }
+
+    /// Writes back the least recently used dirty inodes once more than `max_dirty` are held.
+    fn throttle(&mut self) {
+        if self.dirty < self.max_dirty {
+            return;
+        }
+        let batch = self.dirty - self.max_dirty / 2;
+        let inos: Vec<u64> = self
+            .cache
+            .iter()
+            .rev()
+            .filter(|(_, inode)| inode.dirty)
+            .map(|(ino, _)| *ino)
+            .take(batch)
+            .collect();
+        self.write_back_dirty(&inos);
+    }
+
+    /// Writes the dirty inodes among `inos` back to the metadata store, keeping them cached. The
+    /// data of all of them is forced to stable storage at once, ahead of their records. Returns
+    /// how many were written, the others stay dirty and are retried later.
+    fn write_back_dirty(&mut self, inos: &[u64]) -> usize {
+        let inodes: Vec<&Inode> = inos
+            .iter()
+            .filter_map(|ino| self.cache.peek(ino))
+            .filter(|inode| inode.dirty)
+            .collect();
+        let written: Vec<u64> = inodes
+            .iter()
+            .zip(flush_all(&inodes))
+            .filter(|(_, ok)| *ok)
+            .map(|(inode, _)| inode.attrs.ino)
+            .collect();
+        for ino in &written {
+            if let Some(inode) = self.cache.peek_mut(ino) {
+                inode.dirty = false;
+            }
+        }
+        self.dirty -= written.len();
+        written.len()
+    }
+
+    /// Fails with EIO once loading or writing the metadata store failed, the records it holds may
+    /// then be incomplete.
+    pub fn check_store(&self) -> Result<(), c_int> {
+        if self.db.lock().unwrap().failed() {
+            error!("metadata store I/O failed");
+            return Err(libc::EIO);
+        }
+        Ok(())
+    }
+
+    /// Inode records of the metadata store, streamed in batches.
+    fn records(&self) -> impl Iterator<Item = (Vec<u8>, Option<(Attrs, bool)>)> + '_ {
+        // inodes are keyed by their number, reserved keys are of any other length
+        crate::scan_keys(&self.db, &[])
+            .filter(|key| key.len() == std::mem::size_of::<u64>())
+            .map(move |key| {
+                cxx::let_cxx_string!(id = &key);
+                let data = self.db.lock().unwrap().get(&id);
+                let attrs = Attrs::decode(data.as_bytes());
+                (key, attrs)
+            })
+    }
+
+    pub fn scan(&mut self, mut f: impl FnMut(&Attrs)) -> Result<(), c_int> {
+        self.check_store()?;
+        for (_, attrs) in self.records() {
+            match attrs {
+                Some((attrs, _)) => f(&attrs),
+                None => return Err(libc::EIO),
             }
         }
         Ok(())
     }
 
-    pub fn insert(&mut self, attrs: Attrs<BLOCK_SIZE>) {
+    /// Rewrites every inode record of an older version in the current one, returning how many
+    /// were. Nothing is rewritten unless every record decodes, so a store holding records this
+    /// build cannot read stays usable by the build that wrote them.
+    pub fn migrate(&mut self) -> Result<usize, c_int> {
+        self.check_store()?;
+        // a first pass makes sure everything decodes before anything is rewritten
+        if let Some((key, _)) = self.records().find(|(_, attrs)| attrs.is_none()) {
+            error!("inode record {:?} cannot be decoded", key);
+            return Err(libc::EIO);
+        }
+        let mut migrated = 0;
+        for (key, attrs) in self.records() {
+            if let Some((attrs, true)) = attrs {
+                cxx::let_cxx_string!(key = key);
+                cxx::let_cxx_string!(value = attrs.encode());
+                self.db.lock().unwrap().as_mut().unwrap().put(&key, &value);
+                migrated += 1;
+            }
+        }
+        self.sync_store()?;
+        self.check_store()?;
+        Ok(migrated)
+    }
+
+    pub fn insert(&mut self, attrs: Attrs) {
+        self.promote();
+        self.throttle();
         let inode = Inode {
             attrs: attrs.clone(),
             db: self.db.clone(),
             dev: self.dev.clone(),
             dirty: true,
+            dirtied: Instant::now(),
         };
         if attrs.kind == FileType::Directory {
             inode.flush();
         }
-        self.cache.put(attrs.ino, inode);
+        self.put(attrs.ino, inode);
     }
 
-    pub fn read<V>(
-        &mut self,
-        ino: u64,
-        f: impl FnOnce(&Attrs<BLOCK_SIZE>) -> V,
-    ) -> Result<V, c_int> {
-        if let Some(inode) = self.cache.get(&ino) {
+    pub fn read<V>(&mut self, ino: u64, f: impl FnOnce(&Attrs) -> V) -> Result<V, c_int> {
+        self.promote();
+        let cached = self.cache.get(&ino);
+        self.metrics.inode_cache_access(cached.is_some());
+        if let Some(inode) = cached {
             Ok(f(&inode.attrs))
         } else {
             cxx::let_cxx_string!(key = ino.to_le_bytes());
             let data = self.db.lock().unwrap().get(&key);
             if !data.to_string_lossy().is_empty() {
-                if let Ok(attrs) = bincode::deserialize::<Attrs<BLOCK_SIZE>>(data.as_bytes()) {
+                if let Some((attrs, stale)) = Attrs::decode(data.as_bytes()) {
                     let v = f(&attrs);
-                    self.cache.put(
+                    // records of an older version are rewritten on their next write back
+                    self.put(
                         ino,
                         Inode {
                             attrs,
                             db: self.db.clone(),
                             dev: self.dev.clone(),
-                            dirty: false,
+                            dirty: stale,
+                            dirtied: Instant::now(),
                         },
                     );
                     Ok(v)
@@ -274,13 +1022,15 @@ impl<const BLOCK_SIZE: usize> InodeCache<BLOCK_SIZE> {
         }
     }
 
-    pub fn modify<V>(
-        &mut self,
-        ino: u64,
-        f: impl FnOnce(&mut Attrs<BLOCK_SIZE>) -> V,
-    ) -> Result<V, c_int> {
+    pub fn modify<V>(&mut self, ino: u64, f: impl FnOnce(&mut Attrs) -> V) -> Result<V, c_int> {
+        self.promote();
+        self.throttle();
         if let Some(inode) = self.cache.get_mut(&ino) {
-            inode.dirty = true;
+            if !inode.dirty {
+                inode.dirty = true;
+                inode.dirtied = Instant::now();
+                self.dirty += 1;
+            }
             let v = Ok(f(&mut inode.attrs));
             if inode.attrs.kind == FileType::Directory {
                 inode.flush();
@@ -290,18 +1040,19 @@ impl<const BLOCK_SIZE: usize> InodeCache<BLOCK_SIZE> {
             cxx::let_cxx_string!(key = ino.to_le_bytes());
             let data = self.db.lock().unwrap().get(&key);
             if data.to_string_lossy() != "" {
-                if let Ok(mut attrs) = bincode::deserialize::<Attrs<BLOCK_SIZE>>(data.as_bytes()) {
+                if let Some((mut attrs, _)) = Attrs::decode(data.as_bytes()) {
                     let v = f(&mut attrs);
                     let inode = Inode {
                         attrs,
                         db: self.db.clone(),
                         dev: self.dev.clone(),
                         dirty: true,
+                        dirtied: Instant::now(),
                     };
                     if inode.attrs.kind == FileType::Directory {
                         inode.flush();
                     }
-                    self.cache.put(ino, inode);
+                    self.put(ino, inode);
                     Ok(v)
                 } else {
                     Err(libc::EIO)
@@ -312,12 +1063,241 @@ impl<const BLOCK_SIZE: usize> InodeCache<BLOCK_SIZE> {
         }
     }
 
+    /// Whether `ino` is cached with changes its stored record lacks that
+    /// [`Attrs::same_data`] does not ignore.
+    pub fn data_dirty(&self, ino: u64) -> bool {
+        match self.cache.peek(&ino) {
+            Some(inode) if inode.dirty => {
+                cxx::let_cxx_string!(key = ino.to_le_bytes());
+                let data = self.db.lock().unwrap().get(&key);
+                match Attrs::decode(data.as_bytes()) {
+                    Some((stored, _)) => !stored.same_data(&inode.attrs),
+                    None => true,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes `ino` back to the metadata store if it is dirty, keeping it cached. Returns whether
+    /// the store is up to date with it.
+    pub fn write_back(&mut self, ino: u64) -> bool {
+        self.write_back_all(&[ino])
+    }
+
+    /// Writes every dirty inode of `inos` back like [`write_back`](Self::write_back), syncing
+    /// the device once for all of them. Returns whether the store is up to date with every one.
+    pub fn write_back_all(&mut self, inos: &[u64]) -> bool {
+        let dirty = inos
+            .iter()
+            .filter(|ino| matches!(self.cache.peek(*ino), Some(inode) if inode.dirty))
+            .count();
+        self.write_back_dirty(inos) == dirty
+    }
+
+    /// Replaces the stored `ino` with `attrs`, or removes it for `None`, discarding any cached
+    /// copy.
+    pub fn restore(&mut self, ino: u64, attrs: Option<&Attrs>) {
+        if let Some(mut inode) = self.cache.pop(&ino) {
+            if inode.dirty {
+                inode.dirty = false;
+                self.dirty -= 1;
+            }
+        }
+        cxx::let_cxx_string!(key = ino.to_le_bytes());
+        match attrs {
+            Some(attrs) => {
+                cxx::let_cxx_string!(value = attrs.encode());
+                self.db.lock().unwrap().as_mut().unwrap().put(&key, &value);
+            }
+            None => {
+                self.db.lock().unwrap().as_mut().unwrap().remove(&key);
+            }
+        }
+    }
+
+    /// Inode numbers currently cached, from least to most recently used.
+    pub fn cached(&self) -> Vec<u64> {
+        self.cache.iter().rev().map(|(ino, _)| *ino).collect()
+    }
+
+    /// Value of the reserved metadata store `key`, which must not be 8 bytes long.
+    pub fn get_reserved(&self, key: &[u8]) -> Option<Vec<u8>> {
+        cxx::let_cxx_string!(key = key);
+        let data = self.db.lock().unwrap().get(&key);
+        Some(data.as_bytes().to_vec()).filter(|data| !data.is_empty())
+    }
+
+    /// Every reserved metadata store key starting with `prefix` along with its value.
+    pub fn list_reserved(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        crate::scan_keys(&self.db, prefix)
+            .filter(|key| key.len() != std::mem::size_of::<u64>())
+            .filter_map(|key| {
+                let value = self.get_reserved(&key)?;
+                Some((key, value))
+            })
+            .collect()
+    }
+
+    /// Sets the reserved metadata store `key`, removing it for `None`.
+    pub fn put_reserved(&self, key: &[u8], value: Option<&[u8]>) {
+        cxx::let_cxx_string!(key = key);
+        match value {
+            Some(value) => {
+                cxx::let_cxx_string!(value = value);
+                self.db.lock().unwrap().as_mut().unwrap().put(&key, &value);
+            }
+            None => {
+                self.db.lock().unwrap().as_mut().unwrap().remove(&key);
+            }
+        }
+    }
+
+    /// Compacts the log of the metadata store.
+    pub fn compact(&self) {
+        self.db.lock().unwrap().as_mut().unwrap().compact();
+    }
+
+    /// Forces everything written to the metadata store to stable storage.
+    pub fn sync_store(&self) -> Result<(), c_int> {
+        if self.db.lock().unwrap().as_mut().unwrap().sync() {
+            Ok(())
+        } else {
+            error!("failed to sync the metadata store");
+            Err(libc::EIO)
+        }
+    }
+
+    /// Writes back the inodes dirty for longer than `expire` while keeping them cached, returning
+    /// how many were written.
+    pub fn write_back_expired(&mut self, expire: Duration) -> usize {
+        let inos: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|(_, inode)| inode.dirty && inode.dirtied.elapsed() >= expire)
+            .map(|(ino, _)| *ino)
+            .collect();
+        self.write_back_dirty(&inos)
+    }
+
+    /// Writes back every dirty inode while keeping it cached.
+    pub fn sync(&mut self) {
+        let inos: Vec<u64> = self
+            .cache
+            .iter()
+            .filter(|(_, inode)| inode.dirty)
+            .map(|(ino, _)| *ino)
+            .collect();
+        self.write_back_dirty(&inos);
+    }
+
     pub fn flush_inode(&mut self, ino: u64) {
-        self.cache.pop(&ino);
+        if let Some(inode) = self.cache.pop(&ino) {
+            if inode.dirty {
+                self.dirty -= 1;
+            }
+        }
     }
 
     pub fn flush(&mut self) {
         self.cache.clear();
+        self.dirty = 0;
         // self.db.lock().unwrap().sync();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    /// `Attrs` as the first release wrote it.
+    #[derive(Serialize)]
+    struct BaselineAttrs {
+        ino: u64,
+        size: u64,
+        extents: Vec<Range<usize>>,
+        atime: SystemTime,
+        mtime: SystemTime,
+        ctime: SystemTime,
+        crtime: SystemTime,
+        kind: FileType,
+        perm: u16,
+        nlink: u32,
+        uid: u32,
+        gid: u32,
+        rdev: u32,
+        flags: u32,
+        entries: BTreeMap<String, DirEntry>,
+        link: std::path::PathBuf,
+    }
+
+    fn baseline(ino: u64) -> BaselineAttrs {
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        BaselineAttrs {
+            ino,
+            size: 5 * 512,
+            extents: vec![10..12, 40..43],
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            flags: 0,
+            entries: BTreeMap::new(),
+            link: "".into(),
+        }
+    }
+
+    #[test]
+    fn decodes_baseline_records() {
+        let old = baseline(7);
+        let (attrs, stale) = Attrs::decode(&bincode::serialize(&old).unwrap()).unwrap();
+        assert!(stale);
+        assert_eq!(attrs.ino, 7);
+        assert_eq!(attrs.size, old.size);
+        assert_eq!(attrs.mtime, old.mtime);
+        assert_eq!((attrs.perm, attrs.uid, attrs.gid), (0o644, 1000, 100));
+        // the listed extents follow each other in the file
+        assert_eq!(attrs.extents, BTreeMap::from([(0, 10..12), (2, 40..43)]));
+        assert_eq!(attrs.physical(3), Some(41));
+        assert!(attrs.xattrs.is_empty());
+        assert!(attrs.checksums.is_empty());
+        assert!(attrs.compressed.is_empty());
+        assert_eq!(attrs.parent, 0);
+
+        // re-encoding upgrades the record for good
+        let (current, stale) = Attrs::decode(&attrs.encode()).unwrap();
+        assert!(!stale);
+        assert_eq!(current, attrs);
+    }
+
+    #[test]
+    fn decodes_unversioned_records_with_xattrs() {
+        #[derive(Serialize)]
+        struct WithXattrs {
+            attrs: BaselineAttrs,
+            xattrs: BTreeMap<String, Vec<u8>>,
+        }
+        let record = WithXattrs {
+            attrs: baseline(fuser::FUSE_ROOT_ID),
+            xattrs: BTreeMap::from([("user.a".to_string(), b"b".to_vec())]),
+        };
+        let (attrs, stale) = Attrs::decode(&bincode::serialize(&record).unwrap()).unwrap();
+        assert!(stale);
+        assert_eq!(attrs.extents, BTreeMap::from([(0, 10..12), (2, 40..43)]));
+        assert_eq!(attrs.xattrs, record.xattrs);
+        assert_eq!(attrs.parent, fuser::FUSE_ROOT_ID);
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let record = bincode::serialize(&baseline(7)).unwrap();
+        assert!(Attrs::decode(&record[..record.len() - 1]).is_none());
+    }
+}
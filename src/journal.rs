@@ -0,0 +1,82 @@
+use crate::inode::Attrs;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Key prefix reserving journal records in a store shared with the inodes, which are keyed by
+/// their 8 byte inode number. Record keys are longer, so inode scans skip them.
+pub const JOURNAL_PREFIX: &[u8] = b"journal/";
+
+/// Inode images of one metadata transaction, `None` standing for an inode that does not exist.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Transaction {
+    id: u64,
+    pub before: BTreeMap<u64, Option<Attrs>>,
+    /// set once the transaction completed, replay rolls forward to these images instead of
+    /// rolling back to `before`
+    pub after: Option<BTreeMap<u64, Option<Attrs>>>,
+}
+
+impl Transaction {
+    /// Images a replay restores the metadata store to.
+    pub fn replay_images(&self) -> &BTreeMap<u64, Option<Attrs>> {
+        self.after.as_ref().unwrap_or(&self.before)
+    }
+}
+
+/// Write-ahead log of metadata transactions, kept in a KVStore of its own or next to the inodes.
+pub struct Journal {
+    db: Arc<Mutex<cxx::UniquePtr<crate::ffi::KVStore>>>,
+    next: u64,
+}
+
+impl Journal {
+    pub fn new(db: Arc<Mutex<cxx::UniquePtr<crate::ffi::KVStore>>>) -> Self {
+        Self { db, next: 0 }
+    }
+
+    fn key(id: u64) -> Vec<u8> {
+        [JOURNAL_PREFIX, &id.to_le_bytes()].concat()
+    }
+
+    pub fn begin(&mut self) -> Transaction {
+        let id = self.next;
+        self.next += 1;
+        Transaction {
+            id,
+            before: BTreeMap::new(),
+            after: None,
+        }
+    }
+
+    /// Persists the current state of `tx`, replacing what was logged for it before.
+    pub fn log(&self, tx: &Transaction) {
+        cxx::let_cxx_string!(key = Self::key(tx.id));
+        cxx::let_cxx_string!(value = bincode::serialize(tx).unwrap());
+        self.db.lock().unwrap().as_mut().unwrap().put(&key, &value);
+    }
+
+    /// Drops the record of a transaction whose changes all reached the metadata store.
+    pub fn clear(&self, tx: &Transaction) {
+        cxx::let_cxx_string!(key = Self::key(tx.id));
+        self.db.lock().unwrap().as_mut().unwrap().remove(&key);
+    }
+
+    /// Compacts the log of the journal store.
+    pub fn compact(&self) {
+        self.db.lock().unwrap().as_mut().unwrap().compact();
+    }
+
+    /// Transactions left behind by an unclean shutdown, in the order they were started.
+    pub fn pending(&self) -> Vec<Transaction> {
+        let mut pending: Vec<Transaction> = crate::scan_keys(&self.db, JOURNAL_PREFIX)
+            .filter_map(|key| {
+                cxx::let_cxx_string!(id = key);
+                let data = self.db.lock().unwrap().get(&id);
+                bincode::deserialize(data.as_bytes()).ok()
+            })
+            .collect();
+        pending.sort_by_key(|tx| tx.id);
+        pending
+    }
+}
@@ -1,25 +1,55 @@
-use bitmap_allocator::{BitAlloc, BitAlloc256M};
-
 use fuser::{
-    Filesystem, KernelConfig, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyStatfs,
-    Request, FUSE_ROOT_ID,
+    Filesystem, KernelConfig, ReplyAttr, ReplyBmap, ReplyCreate, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen,
+    ReplyStatfs, ReplyXattr, Request, FUSE_ROOT_ID,
 };
 
-use std::collections::BTreeMap;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
-use std::ops::Range;
+use std::ops::{Deref, Range};
 use std::os::raw::c_int;
 use std::os::unix::prelude::OsStrExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
+use std::thread::JoinHandle;
 use std::time::{Duration, SystemTime};
 use std::vec;
 
-use std::alloc::{alloc_zeroed, Layout};
+pub mod allocator;
 pub mod block_cache;
 pub mod block_dev;
+pub mod checksum;
+pub mod compress;
+pub mod dedup;
+#[cfg(any(test, feature = "testing"))]
+pub mod faulty;
+pub mod fsck;
 pub mod inode;
+pub mod journal;
+pub mod lock;
+pub mod metrics;
+pub mod nbd;
+pub mod quota;
+pub mod superblock;
+#[cfg(test)]
+mod tests;
+use crate::allocator::{Allocator, Fragmentation};
+use crate::block_cache::WritebackPolicy;
+use crate::block_dev::{BackendKind, BlockStore, DeviceSet};
+use crate::checksum::{ChecksumError, ChecksumKind};
+use crate::compress::Compression;
+use crate::dedup::BlockRefs;
 use crate::inode::*;
+use crate::journal::{Journal, Transaction};
+use crate::lock::{LockTable, RecordLock};
+use crate::metrics::{FsStats, Metrics, Op};
+use crate::quota::{Limits, Owner, Quotas, Usage, QUOTA_PREFIX};
+use crate::superblock::{Superblock, SUPERBLOCK_BLOCKS};
 
 use autocxx::prelude::*;
 
@@ -29,212 +59,3136 @@ include_cpp! {
     generate!("KVStore")
 }
 
-pub struct CyanFS<const BLOCK_SIZE: usize> {
-    dev: Arc<Mutex<block_cache::BlockCache<BLOCK_SIZE>>>,
-    meta: Arc<Mutex<InodeCache<BLOCK_SIZE>>>,
-    block_allocator: Box<BitAlloc256M>,
-    inode_allocator: Box<BitAlloc256M>,
+/// Maximum length in bytes of a single path component.
+pub const NAME_MAX: usize = 255;
+
+/// How the mount-time scan treats an inode whose extents are out of range or already claimed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CorruptExtents {
+    /// log the inode and keep it, claiming only its valid extents
+    Ignore,
+    /// log the inode and refuse data access to it with EIO
+    Quarantine,
+    /// refuse to mount
+    Fail,
+}
+
+/// How data accesses treat a block failing checksum verification. Either way the block is logged
+/// with its owning inode and recorded in [`CyanFS::corrupt_blocks`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CorruptBlocks {
+    /// hand the data to readers as is, writes merging into the block still fail with EIO
+    Ignore,
+    /// fail the access with EIO
+    Fail,
+}
+
+/// How reads treat file blocks within the size that no extent backs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnbackedReads {
+    /// read them back as zeros, as sparse files expect
+    Zeros,
+    /// fail the read with EIO, taking the size and extents of the file to disagree; only suited
+    /// to filesystems that never hold sparse files
+    Fail,
+}
+
+/// Durability work done by the flush issued on every close of a file handle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FlushOnClose {
+    /// fsync the file on every close
+    Every,
+    /// fsync the file only when its last open handle is closed
+    Last,
+}
+
+/// Link count reported for directories.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DirNlink {
+    /// two plus the number of subdirectories
+    Accurate,
+    /// always 1, which tools like find take as an unknown subdirectory count
+    Unknown,
+}
+
+/// When reads update the access time of a file. Files with the FS_NOATIME_FL attribute keep
+/// theirs regardless.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AtimePolicy {
+    /// never
+    NoAtime,
+    /// when the access time is not newer than the last modification or change, or a day old
+    RelAtime,
+    /// on every read
+    StrictAtime,
+}
+
+impl FromStr for AtimePolicy {
+    type Err = String;
+    /// Parses `noatime`, `relatime` or `strictatime`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "noatime" => Ok(AtimePolicy::NoAtime),
+            "relatime" => Ok(AtimePolicy::RelAtime),
+            "strictatime" => Ok(AtimePolicy::StrictAtime),
+            _ => Err(format!(
+                "unknown atime policy {}, expected noatime, relatime or strictatime",
+                s
+            )),
+        }
+    }
+}
+
+// setxattr(2) flags, which libc does not export for Linux
+const XATTR_CREATE: i32 = 1;
+const XATTR_REPLACE: i32 = 2;
+
+/// ioctl reading the compression mode of a file as a native endian u32, `_IOR('c', 1, u32)`.
+pub const CYANFS_IOC_GET_COMPRESSION: u32 = 0x8004_6301;
+/// ioctl setting the compression mode of a file from a native endian u32, `_IOW('c', 2, u32)`.
+/// Blocks already written keep the mode they were stored with.
+pub const CYANFS_IOC_SET_COMPRESSION: u32 = 0x4004_6302;
+/// ioctl reading the checksum kind of a file as a native endian u32, `_IOR('c', 3, u32)`.
+pub const CYANFS_IOC_GET_CHECKSUM: u32 = 0x8004_6303;
+/// ioctl setting the checksum kind of a file from a native endian u32, `_IOW('c', 4, u32)`.
+/// Blocks already written are only checksummed once they are rewritten.
+pub const CYANFS_IOC_SET_CHECKSUM: u32 = 0x4004_6304;
+/// ioctl taking a snapshot of the whole filesystem named after the NUL terminated string
+/// passed, `_IOW('c', 5, char[256])`. Only root may issue it.
+pub const CYANFS_IOC_SNAPSHOT: u32 = 0x4100_6305;
+/// ioctl relocating the data of a file into contiguous blocks with [`CyanFS::defrag`], returning
+/// the resulting number of extents as a native endian u32, `_IOR('c', 6, u32)`. The file has to
+/// be open for writing.
+pub const CYANFS_IOC_DEFRAG: u32 = 0x8004_6306;
+
+/// Directory under the root holding the snapshots taken with [`CyanFS::snapshot`].
+pub const SNAPSHOTS_DIR: &str = ".snapshots";
+
+// ioctls exchanging the chattr(1) attributes of a file as a native endian int, along with the
+// attributes supported, which libc does not export
+const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+const FS_COMPR_FL: u32 = 0x4;
+const FS_NODUMP_FL: u32 = 0x40;
+const FS_NOATIME_FL: u32 = 0x80;
+/// chattr(1) attributes kept as is in the inode `flags`, clear of the checksum and compression
+/// bits. FS_COMPR_FL maps to the compression mode instead.
+const STORED_FS_FLAGS: u32 = FS_NODUMP_FL | FS_NOATIME_FL;
+
+// FIEMAP ioctl and the layout of its `struct fiemap` header and `struct fiemap_extent` records,
+// which libc does not export
+const FS_IOC_FIEMAP: u32 = 0xc020_660b;
+const FIEMAP_HEADER: usize = 32;
+const FIEMAP_EXTENT: usize = 56;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+
+/// Read-only xattr reporting the extents of a file, one `logical physical length` line in blocks
+/// per extent. It is left out of listxattr so copying tools do not try to set it.
+pub const EXTENTS_XATTR: &str = "user.cyanfs.extents";
+
+/// Estimated memory held by one cached inode, excluding directory entries and extents.
+const INODE_FOOTPRINT: usize = 512;
+
+/// Tunables of a filesystem instance.
+pub struct Config {
+    /// number of blocks held in the block cache
+    pub block_cache: usize,
+    /// number of inodes held in the inode cache
+    pub inode_cache: usize,
+    /// number of dirty inodes tolerated before they are written back to the metadata store
+    pub max_dirty_inodes: usize,
+    /// handling of corrupt extents found while rebuilding the allocators
+    pub corrupt_extents: CorruptExtents,
+    /// extended attributes given to every newly created inode
+    pub default_xattrs: BTreeMap<String, Vec<u8>>,
+    /// total bytes the caches may occupy, overriding their individual capacities when set
+    pub memory_budget: Option<usize>,
+    /// checksum algorithm protecting the data blocks of newly created inodes
+    pub checksum: ChecksumKind,
+    /// handling of data blocks failing checksum verification
+    pub corrupt_blocks: CorruptBlocks,
+    /// number of inodes loaded into the inode cache at mount time, taken from those cached at the
+    /// last clean unmount or else the most recently modified
+    pub preload_inodes: usize,
+    /// compression of the data blocks of newly created inodes, which still take up a whole
+    /// block each, see [`Compression`]
+    pub compression: Compression,
+    /// device holding the metadata journal, which shares the metadata device when unset
+    pub wal: Option<String>,
+    /// number of blocks read ahead of sequential reads
+    pub read_ahead: usize,
+    /// number of physically contiguous blocks from which reads and writes go straight to the
+    /// data device instead of through the block cache, zero disables bypassing
+    pub bypass_blocks: usize,
+    /// whether a clean unmount compacts the metadata and journal stores
+    pub compact_on_unmount: bool,
+    /// when modified data blocks are written from the block cache to the data device
+    pub writeback: WritebackPolicy,
+    /// link count reported for directories
+    pub dir_nlink: DirNlink,
+    /// when reads update access times
+    pub atime: AtimePolicy,
+    /// handling of reads covering file blocks no extent backs
+    pub unbacked_reads: UnbackedReads,
+    /// which closes of a file make it durable
+    pub flush_on_close: FlushOnClose,
+    /// number of extents beyond which punching a hole reflows the file with
+    /// [`CyanFS::reflow`], never when unset
+    pub reflow_extents: Option<usize>,
+    /// size of the data blocks, taken from the superblock when unset and defaulting to 512
+    /// bytes for a new filesystem
+    pub block_size: Option<usize>,
+    /// age after which a background thread writes a dirty inode back to the metadata store,
+    /// never when unset
+    pub dirty_expire: Option<Duration>,
+    /// how the block cache submits its transfers to the data device
+    pub io_backend: BackendKind,
+    /// whether blocks freed by unlink, truncate and hole punching are discarded on the data
+    /// devices, so SSDs and thin provisioned storage can reclaim them
+    pub discard: bool,
+    /// how long the kernel may cache the attributes handed out by getattr and setattr
+    pub attr_timeout: Duration,
+    /// how long the kernel may cache looked up or created entries along with their attributes
+    pub entry_timeout: Duration,
+    /// whether bytes and inodes are tracked per user and group and held to the limits set with
+    /// [`CyanFS::set_quota`]
+    pub quota: bool,
+    /// whether fully written data blocks are hashed and mapped to an existing block with the
+    /// same contents instead of being stored again, which costs a SHA-256 per block. Blocks
+    /// stored compressed are never deduplicated.
+    pub dedup: bool,
+    /// number of threads serving reads, writes and getattr of a mount alongside the FUSE
+    /// session thread, none serves them on the session thread
+    pub workers: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            block_cache: 2048,
+            inode_cache: 2048,
+            max_dirty_inodes: 512,
+            corrupt_extents: CorruptExtents::Quarantine,
+            default_xattrs: BTreeMap::new(),
+            memory_budget: None,
+            checksum: ChecksumKind::Crc32c,
+            corrupt_blocks: CorruptBlocks::Fail,
+            preload_inodes: 0,
+            compression: Compression::None,
+            wal: None,
+            read_ahead: 32,
+            bypass_blocks: 256,
+            compact_on_unmount: true,
+            writeback: WritebackPolicy::WriteBack,
+            dir_nlink: DirNlink::Accurate,
+            atime: AtimePolicy::RelAtime,
+            unbacked_reads: UnbackedReads::Zeros,
+            flush_on_close: FlushOnClose::Last,
+            reflow_extents: None,
+            block_size: None,
+            io_backend: BackendKind::Pread,
+            discard: false,
+            attr_timeout: Duration::from_secs(1),
+            entry_timeout: Duration::from_secs(1),
+            quota: false,
+            dedup: false,
+            workers: 4,
+            dirty_expire: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Metadata store key of the allocator state saved on a clean unmount.
+const ALLOCATOR_STATE_KEY: &[u8] = b"allocators";
+
+/// Allocator state saved on a clean unmount so the next mount can skip the inode scan.
+#[derive(Serialize, Deserialize)]
+struct AllocatorState {
+    blocks: Vec<Range<usize>>,
+    inodes: Vec<Range<usize>>,
+    quarantined: BTreeSet<u64>,
+    /// inodes cached at unmount, from least to most recently used
+    cached: Vec<u64>,
+}
+
+/// Filesystem statistics reported by `statfs`. The FUSE reply has no fsid field, the kernel
+/// derives f_fsid from the mount itself.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Statvfs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+/// State of a file handle handed out by `open` or `create`.
+pub struct OpenFile {
+    pub ino: u64,
+    pub flags: i32,
+}
+
+/// Directory listing snapshotted by `opendir`, so a paginated scan is unaffected by entries
+/// added or removed while it runs.
+pub struct OpenDir {
+    pub ino: u64,
+    pub entries: Vec<(String, DirEntry)>,
+}
+
+/// Data blocks found failing checksum verification, keyed by owning inode.
+struct Corruption {
+    policy: CorruptBlocks,
+    blocks: BTreeSet<(u64, usize)>,
+}
+
+impl Corruption {
+    /// Logs and records `errors`, failing with EIO unless the policy ignores them.
+    fn check(&mut self, errors: Vec<ChecksumError>) -> Result<(), c_int> {
+        if errors.is_empty() {
+            return Ok(());
+        }
+        for err in errors {
+            error!("{}", err);
+            self.blocks.insert((err.ino, err.block));
+        }
+        match self.policy {
+            CorruptBlocks::Ignore => Ok(()),
+            CorruptBlocks::Fail => Err(libc::EIO),
+        }
+    }
+}
+
+/// Blocks, quotas and the bookkeeping around them, shared by every thread serving requests
+/// under the lock of [`Core::space`].
+struct Space {
+    block_allocator: Allocator,
+    // usage is counted at mount time, disabled unless [`Config::quota`] is set
+    quotas: Quotas,
+    // blocks mapped more than once or indexed for deduplication, kept even while it is disabled
+    refs: BlockRefs,
+    corruption: Corruption,
+    // set when freed blocks are discarded on the data devices
+    discard: Option<Arc<Mutex<block_cache::BlockCache>>>,
+}
+
+impl Space {
+    /// Drops a reference to each of `blocks`, freeing those no other file maps.
+    fn release(&mut self, blocks: Vec<Range<usize>>) {
+        let freed = self.refs.release(blocks);
+        freed.into_iter().for_each(|e| self.free(e));
+    }
+    /// Returns `blocks` to the allocator, discarding them first if enabled. A failed discard only
+    /// leaves the space allocated on the device.
+    fn free(&mut self, blocks: Range<usize>) {
+        if let Some(dev) = &self.discard {
+            if let Err(err) = dev.lock().unwrap().discard(blocks.clone()) {
+                error!("failed to discard blocks {:?}, error {}", blocks, err);
+            }
+        }
+        self.block_allocator.insert(blocks);
+    }
+}
+
+/// State of a filesystem instance shared with the worker threads serving reads, writes and
+/// getattr. Locks are taken in the order inode locks, `meta`, `space`, `dev`.
+pub struct Core {
+    dev: Arc<Mutex<block_cache::BlockCache>>,
+    meta: Arc<RwLock<InodeCache>>,
+    // held across whole operations, the metadata lock only guards the cache itself
+    locks: Arc<InodeLocks>,
+    dev_blocks: usize,
+    block_size: usize,
+    config: Config,
+    space: Mutex<Space>,
+    quarantined: RwLock<BTreeSet<u64>>,
+    metrics: Arc<Metrics>,
+}
+
+/// Threads running jobs handed over by the FUSE session thread, so reads, writes and getattr
+/// of one file do not wait for those of another.
+struct Workers {
+    // dropping the sender stops the workers once the queued jobs ran
+    jobs: Option<mpsc::Sender<Box<dyn FnOnce() + Send>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Starts `count` workers, with none every job runs on the calling thread.
+    fn new(count: usize) -> Self {
+        if count == 0 {
+            return Self {
+                jobs: None,
+                threads: vec![],
+            };
+        }
+        let (jobs, queue) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let queue = Arc::new(Mutex::new(queue));
+        let threads = (0..count)
+            .map(|_| {
+                let queue = queue.clone();
+                std::thread::spawn(move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        Self {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+    /// Runs `job` on a worker, or right away without workers.
+    fn run(&self, job: impl FnOnce() + Send + 'static) {
+        match &self.jobs {
+            Some(jobs) => jobs.send(Box::new(job)).unwrap(),
+            None => job(),
+        }
+    }
+    /// Waits for the queued jobs and stops the workers.
+    fn join(&mut self) {
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+pub struct CyanFS {
+    core: Arc<Core>,
+    journal: Journal,
+    inode_allocator: Allocator,
+    files: BTreeMap<u64, OpenFile>,
+    dirs: BTreeMap<u64, OpenDir>,
+    // number of open handles per inode
+    handles: BTreeMap<u64, usize>,
+    next_fh: u64,
+    // blocked setlkw requests are replied to once their lock is granted
+    record_locks: LockTable<ReplyEmpty>,
+    // lookup count of every inode the kernel holds a reference to
+    lookups: BTreeMap<u64, u64>,
+    // started by `init`, so embedders calling into the filesystem directly run no threads
+    workers: Workers,
+    // dropping the sender stops the inode flusher
+    inode_flusher: Option<(mpsc::Sender<()>, JoinHandle<()>)>,
+    // writes dirty blocks back under the periodic write-back policy, stopped like the inode flusher
+    block_flusher: Option<(mpsc::Sender<()>, JoinHandle<()>)>,
+    superblock: Superblock,
+}
+
+impl Deref for CyanFS {
+    type Target = Core;
+    fn deref(&self) -> &Core {
+        &self.core
+    }
+}
+
+/// Answers an xattr query, reporting only the size when the caller passed a zero sized buffer.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// FUSE open flags for a handle opened with the open(2) `flags`. All writes go through this
+/// mount, so the page cache stays valid across opens unless the caller asked to bypass it.
+fn open_reply_flags(flags: i32) -> u32 {
+    if flags & libc::O_DIRECT != 0 {
+        fuser::consts::FOPEN_DIRECT_IO
+    } else {
+        fuser::consts::FOPEN_KEEP_CACHE
+    }
+}
+
+fn time_or_now(t: fuser::TimeOrNow, now: SystemTime) -> SystemTime {
+    match t {
+        fuser::TimeOrNow::SpecificTime(t) => t,
+        fuser::TimeOrNow::Now => now,
+    }
+}
+
+/// Checks that `name` is usable as a single path component, returning it as the key of its
+/// directory entry.
+fn entry_name(name: &OsStr) -> Result<&str, c_int> {
+    if name.len() > NAME_MAX {
+        return Err(libc::ENAMETOOLONG);
+    }
+    match name.to_str() {
+        Some(name)
+            if !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0']) =>
+        {
+            Ok(name)
+        }
+        _ => Err(libc::EINVAL),
+    }
+}
+
+/// Number of fresh blocks zeroed by a single write, bounding the buffer of zeros.
+const ZERO_BLOCKS: usize = 256;
+
+/// Logical blocks of `block_size` bytes touched by `len` bytes at `offset`.
+fn block_range(block_size: usize, offset: u64, len: u64) -> Range<usize> {
+    offset as usize / block_size..((offset + len) as usize + (block_size - 1)) / block_size
+}
+
+/// Maps every hole within the logical `blocks` of `i` to newly allocated blocks. Blocks already
+/// backing the file, including a partially filled trailing one, are reused so only the holes are
+/// allocated and charged to the owners of `i`. Fresh blocks are zeroed, in batches, so stale
+/// device contents never leak into the file, except for those within `overwritten`, which the
+/// caller writes whole right after. A failure to zero them fails the write.
+fn reserve_blocks(
+    allocator: &mut Allocator,
+    quotas: &mut Quotas,
+    dev: &Mutex<block_cache::BlockCache>,
+    i: &mut Attrs,
+    blocks: Range<usize>,
+    overwritten: Range<usize>,
+) -> Result<(), c_int> {
+    let block_size = dev.lock().unwrap().block_size();
+    let zeros = vec![0u8; ZERO_BLOCKS * block_size];
+    for hole in i.holes(blocks) {
+        let mut logical = hole.start;
+        let extents = allocator.alloc_extents(hole.len())?;
+        if let Err(err) = quotas.charge_blocks(i.uid, i.gid, hole.len()) {
+            extents.into_iter().for_each(|e| allocator.insert(e));
+            return Err(err);
+        }
+        let mut extents = extents.into_iter();
+        while let Some(e) = extents.next() {
+            // the physical blocks of `e` before and after the overwritten ones
+            let end = logical + e.len();
+            let head = logical..overwritten.start.clamp(logical, end);
+            let tail = overwritten.end.clamp(logical, end)..end;
+            let zeroed = [head, tail]
+                .into_iter()
+                .map(|r| e.start + (r.start - logical)..e.start + (r.end - logical))
+                .flat_map(|r| {
+                    r.clone()
+                        .step_by(ZERO_BLOCKS)
+                        .map(move |start| (start, r.end))
+                })
+                .try_for_each(|(start, end)| {
+                    let len = std::cmp::min(ZERO_BLOCKS, end - start);
+                    dev.lock()
+                        .unwrap()
+                        .write_range(start, &zeros[..len * block_size])
+                });
+            if let Err(err) = zeroed {
+                error!("failed to zero blocks {:?} for inode {}: {}", e, i.ino, err);
+                // extents mapped so far stay with the file like any other written block
+                let unmapped: Vec<Range<usize>> = std::iter::once(e).chain(extents).collect();
+                quotas.credit_blocks(i.uid, i.gid, unmapped.iter().map(Range::len).sum());
+                unmapped.into_iter().for_each(|e| allocator.insert(e));
+                return Err(libc::EIO);
+            }
+            // overwritten blocks are sealed again by their write, until then they fail to verify
+            for block in e.clone() {
+                i.seal_block(block, &zeros[..block_size]);
+            }
+            i.map_blocks(logical, e.clone());
+            logical = end;
+        }
+    }
+    Ok(())
+}
+
+/// Gives the logical `blocks` of `i` backed by a shared physical block a copy of their own, so
+/// they can be modified in place, and drops the hashes of the other blocks about to change.
+fn unshare_blocks(
+    allocator: &mut Allocator,
+    refs: &mut BlockRefs,
+    dev: &Mutex<block_cache::BlockCache>,
+    i: &mut Attrs,
+    blocks: Range<usize>,
+) -> Result<(), c_int> {
+    if refs.is_empty() {
+        return Ok(());
+    }
+    for block in blocks {
+        match i.physical(block) {
+            Some(physical) if refs.shared(physical) => {
+                let copy = allocator.alloc().ok_or(libc::ENOSPC)?;
+                // the block moves as stored, compressed and sealed
+                let mut dev = dev.lock().unwrap();
+                let mut buf = vec![0u8; dev.block_size()];
+                let copied = dev
+                    .read_block(physical, &mut buf)
+                    .and_then(|_| dev.write_block(copy, &buf));
+                if let Err(err) = copied {
+                    error!("failed to copy shared block {}: {}", physical, err);
+                    allocator.dealloc(copy);
+                    return Err(libc::EIO);
+                }
+                i.remap_block(block, copy);
+                refs.release(vec![physical..physical + 1]);
+            }
+            Some(physical) => refs.unindex(physical),
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Maps the logical blocks `data` written at `offset` fully covers to existing blocks with the
+/// same contents, indexing the others, and returns the physical blocks no longer mapped.
+fn dedup_blocks(
+    refs: &mut BlockRefs,
+    i: &mut Attrs,
+    block_size: usize,
+    offset: u64,
+    data: &[u8],
+) -> Vec<Range<usize>> {
+    let mut freed = vec![];
+    let first = (offset as usize + (block_size - 1)) / block_size;
+    let last = (offset as usize + data.len()) / block_size;
+    for block in first..last {
+        let physical = match i.physical(block) {
+            Some(physical) if !i.compressed.contains_key(&physical) => physical,
+            _ => continue,
+        };
+        let at = block * block_size - offset as usize;
+        let hash = dedup::hash(&data[at..at + block_size]);
+        match refs.lookup(&hash) {
+            // blocks of a file are never shared with itself, so dropping one mapping never
+            // drops the checksum of another
+            Some(existing) if existing != physical && !i.maps(existing) => {
+                i.remap_block(block, existing);
+                refs.share(existing);
+                freed.push(physical..physical + 1);
+            }
+            Some(_) => {}
+            None => refs.index(physical, hash),
+        }
+    }
+    freed
+}
+
+/// Sets the size of `i`, returning the blocks released past a lowered end. A raised end is left
+/// as a hole.
+fn resize(
+    dev: Arc<Mutex<block_cache::BlockCache>>,
+    corruption: &mut Corruption,
+    i: &mut Attrs,
+    size: u64,
+) -> Result<Vec<Range<usize>>, c_int> {
+    let mut freed = vec![];
+    if size < i.size {
+        // clear the tail of the last block so a later extension reads back zeros
+        clear_tail(dev.clone(), corruption, i, size)?;
+        let block_size = dev.lock().unwrap().block_size();
+        let block_cnt = (size as usize + (block_size - 1)) / block_size;
+        freed = i.truncate_blocks(block_cnt);
+    }
+    i.size = size;
+    Ok(freed)
+}
+
+/// Zeroes the bytes of `i` from `size` to the end of the block holding it, if that block is
+/// mapped.
+fn clear_tail(
+    dev: Arc<Mutex<block_cache::BlockCache>>,
+    corruption: &mut Corruption,
+    i: &mut Attrs,
+    size: u64,
+) -> Result<(), c_int> {
+    let block_size = dev.lock().unwrap().block_size();
+    let tail = (block_size - size as usize % block_size) % block_size;
+    if tail != 0 && i.physical(size as usize / block_size).is_some() {
+        let mut corrupt = vec![];
+        let res = i.write_at(dev, &vec![0u8; tail], size, &mut corrupt);
+        corruption.check(corrupt)?;
+        res.map_err(|_| libc::EIO)?;
+    }
+    Ok(())
 }
 
-fn new_allocator(avail: Range<usize>) -> Box<BitAlloc256M> {
-    let mut allocator = unsafe {
-        let layout = Layout::new::<BitAlloc256M>();
-        let ptr = alloc_zeroed(layout) as *mut BitAlloc256M;
-        Box::from_raw(ptr)
+/// Deallocates the `len` bytes at `offset` of `i` without changing its size, returning the
+/// released blocks. Partially covered blocks stay mapped and have the covered bytes zeroed.
+fn punch_hole(
+    dev: Arc<Mutex<block_cache::BlockCache>>,
+    corruption: &mut Corruption,
+    i: &mut Attrs,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<Range<usize>>, c_int> {
+    let end = offset + len;
+    let block_size = dev.lock().unwrap().block_size();
+    let first = (offset as usize + (block_size - 1)) / block_size;
+    let last = end as usize / block_size;
+    let partial = if first > last {
+        vec![offset..end]
+    } else {
+        vec![
+            offset..(first * block_size) as u64,
+            (last * block_size) as u64..end,
+        ]
     };
-    allocator.insert(avail);
-    allocator
+    for range in partial.into_iter().filter(|r| !r.is_empty()) {
+        if i.physical(range.start as usize / block_size).is_some() {
+            let mut corrupt = vec![];
+            let zeros = vec![0u8; (range.end - range.start) as usize];
+            let res = i.write_at(dev.clone(), &zeros, range.start, &mut corrupt);
+            corruption.check(corrupt)?;
+            res.map_err(|_| libc::EIO)?;
+        }
+    }
+    Ok(if first < last {
+        i.unmap_blocks(first..last)
+    } else {
+        vec![]
+    })
+}
+
+/// Panics in debug builds when `i` no longer satisfies its extent invariants.
+fn check_invariants(i: &Attrs, dev_blocks: usize) {
+    if cfg!(debug_assertions) {
+        if let Err(err) = i.check_invariants(dev_blocks) {
+            panic!("inode {} is inconsistent: {}", i.ino, err);
+        }
+    }
 }
 
-impl<const BLOCK_SIZE: usize> CyanFS<BLOCK_SIZE> {
-    pub fn new(data: &str, meta: &str, new: bool, block_cache: usize, inode_cache: usize) -> Self {
-        cxx::let_cxx_string!(meta = meta);
-        let store = ffi::KVStore::new(&meta, new).within_unique_ptr();
-        let dev = Arc::new(Mutex::new(
-            block_cache::BlockCache::new(data, block_cache).unwrap(),
+/// Opens the metadata store at `path`, formatting it with `format`.
+fn open_store(
+    path: &str,
+    format: bool,
+) -> std::io::Result<Arc<Mutex<cxx::UniquePtr<ffi::KVStore>>>> {
+    cxx::let_cxx_string!(path_str = path);
+    let store = ffi::KVStore::new(&path_str, format).within_unique_ptr();
+    if store.failed() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("failed to load the metadata store {}", path),
         ));
-        Self {
-            dev: dev.clone(),
-            meta: Arc::new(Mutex::new(InodeCache::new(
-                Arc::new(Mutex::new(store)),
-                dev,
-                inode_cache,
-            ))),
-            block_allocator: new_allocator(0..BitAlloc256M::CAP),
-            inode_allocator: new_allocator(FUSE_ROOT_ID as usize..BitAlloc256M::CAP),
+    }
+    Ok(Arc::new(Mutex::new(store)))
+}
+
+/// Number of keys a metadata store scan fetches at a time.
+const SCAN_BATCH: u32 = 1024;
+
+/// Keys of the metadata store `db` starting with `prefix`, in ascending order. They are fetched
+/// in batches, so walking a large store never copies all of its keys at once and the store is
+/// only locked while a batch is fetched.
+pub fn scan_keys<'a>(
+    db: &'a Mutex<cxx::UniquePtr<ffi::KVStore>>,
+    prefix: &[u8],
+) -> impl Iterator<Item = Vec<u8>> + 'a {
+    let prefix = prefix.to_vec();
+    let mut from = Some(prefix.clone());
+    let mut batch = std::collections::VecDeque::new();
+    std::iter::from_fn(move || {
+        if batch.is_empty() {
+            let start = from.take()?;
+            cxx::let_cxx_string!(start = start);
+            let keys = db.lock().unwrap().scan(&start, SCAN_BATCH);
+            batch.extend(keys.iter().map(|key| key.as_bytes().to_vec()));
+            if batch.len() == SCAN_BATCH as usize {
+                // the smallest key following the last one
+                let mut next = batch.back().unwrap().clone();
+                next.push(0);
+                from = Some(next);
+            }
+        }
+        batch.pop_front().filter(|key| key.starts_with(&prefix))
+    })
+    .fuse()
+}
+
+/// Error mounting a data device that holds no superblock. Filesystems created before the
+/// superblock was introduced may keep file data in its place and are converted by
+/// [`CyanFS::upgrade`].
+fn no_superblock(dev: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "{} holds no cyanfs superblock, filesystems created before superblocks were \
+             introduced have to be converted with the upgrade command first",
+            dev
+        ),
+    )
+}
+
+impl Core {
+    /// Runs `f` on an inode, only taking the exclusive lock when it has to be loaded.
+    pub fn read_inode<V>(&self, ino: u64, f: impl FnOnce(&Attrs) -> V) -> Result<V, c_int> {
+        let res = self.meta.read().unwrap().peek(ino, f);
+        match res {
+            Ok(v) => Ok(v),
+            Err(f) => self.meta.write().unwrap().read(ino, f),
         }
     }
-    pub fn new_with_parent<V>(
-        &mut self,
-        req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        f: impl FnOnce(&mut Attrs<BLOCK_SIZE>) -> V,
-    ) -> Result<V, c_int> {
-        let mut n = self.new_inode(req, None);
-        let v = f(&mut n);
-        let entry = DirEntry {
-            ino: n.ino,
-            kind: n.kind,
+    fn check_quarantine(&self, ino: u64) -> Result<(), c_int> {
+        if self.quarantined.read().unwrap().contains(&ino) {
+            Err(libc::EIO)
+        } else {
+            Ok(())
+        }
+    }
+    /// Reads up to `size` bytes of `ino` at `offset`.
+    pub fn read_file(&self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.check_quarantine(ino)?;
+        if self.read_inode(ino, |i| i.kind)? == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+        let buf = self.read_data(ino, offset, size)?;
+        self.touch_atime(ino);
+        Ok(buf)
+    }
+    /// Writes `data` to `ino` at `offset`, or at its end for `None`, returning the number of
+    /// bytes written.
+    pub fn write_file(&self, ino: u64, offset: Option<u64>, data: &[u8]) -> Result<usize, c_int> {
+        self.check_quarantine(ino)?;
+        if self.read_inode(ino, |i| i.kind)? == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+        self.write_data(ino, offset, data)
+    }
+    /// Reads up to `size` bytes of `ino` at `offset`, applying the corruption and unbacked read
+    /// policies.
+    fn read_data(&self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        // blocks stay mapped while the inode lock is shared, so only the part of the inode the
+        // read needs is copied and the device is read without holding the metadata lock
+        let _guard = self.locks.read(ino);
+        let i = self.read_inode(ino, |i| {
+            if self.config.unbacked_reads == UnbackedReads::Fail && offset < i.size {
+                let len = std::cmp::min(size as u64, i.size - offset);
+                if let Some(hole) = i.holes(block_range(self.block_size, offset, len)).first() {
+                    error!(
+                        "blocks {:?} of inode {} are not backed by extents",
+                        hole, ino
+                    );
+                    return Err(libc::EIO);
+                }
+            }
+            Ok(i.data_slice(block_range(self.block_size, offset, size as u64)))
+        })??;
+        let mut corrupt = vec![];
+        let mut buf = vec![0u8; size as usize];
+        let res = match i.read_at(self.dev.clone(), &mut buf, offset, &mut corrupt) {
+            Ok(size) => {
+                buf.truncate(size);
+                Ok(buf)
+            }
+            Err(err) => {
+                error!("failed to read inode {}: {}", ino, err);
+                Err(libc::EIO)
+            }
         };
-        self.meta.lock().unwrap().insert(n);
-        self.insert_dirent(parent, name, entry).map(|_| v)
+        let checked = self.space.lock().unwrap().corruption.check(corrupt);
+        let buf = checked.and(res)?;
+        self.metrics.read(buf.len());
+        Ok(buf)
     }
-    pub fn new_inode(&mut self, req: &Request<'_>, ino: Option<u64>) -> Attrs<BLOCK_SIZE> {
-        let now = SystemTime::now();
-        Attrs {
-            ino: match ino {
-                Some(ino) => ino,
-                None => self.inode_allocator.alloc().unwrap() as u64,
-            },
-            size: 0,
-            extents: vec![],
-            atime: now,
-            mtime: now,
-            ctime: now,
-            crtime: now,
-            kind: FileType::RegularFile,
-            perm: 0o777,
-            nlink: 1,
-            uid: req.uid(),
-            gid: req.gid(),
-            rdev: 0,
-            flags: 0,
-            link: std::path::PathBuf::new(),
-            entries: BTreeMap::new(),
+    /// Writes `data` to `ino` at `offset`, or at its end for `None`, allocating blocks as needed.
+    fn write_data(&self, ino: u64, offset: Option<u64>, data: &[u8]) -> Result<usize, c_int> {
+        // an empty write changes nothing, not even the times
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let locks = self.locks.clone();
+        let _guard = locks.lock(&[ino]);
+        // map the blocks under the metadata lock, then write the data without holding it
+        let res = self.meta.write().unwrap().modify(ino, |i| {
+            // resolved under the inode lock so concurrent appenders never share an offset
+            let offset = offset.unwrap_or(i.size);
+            let new_size = offset as usize + data.len();
+            let blocks = block_range(self.block_size, offset, data.len() as u64);
+            let mut space = self.space.lock().unwrap();
+            let space = &mut *space;
+            let last = i.size as usize / self.block_size;
+            if offset > i.size && last * self.block_size < i.size as usize {
+                // the old last block may still hold bytes past the end, left by a write whose
+                // size was lost in a crash, the gap after it stays a hole
+                unshare_blocks(
+                    &mut space.block_allocator,
+                    &mut space.refs,
+                    &self.dev,
+                    i,
+                    last..last + 1,
+                )?;
+                clear_tail(self.dev.clone(), &mut space.corruption, i, i.size)?;
+            }
+            unshare_blocks(
+                &mut space.block_allocator,
+                &mut space.refs,
+                &self.dev,
+                i,
+                blocks.clone(),
+            )?;
+            // only the partially written blocks at either end need zeros underneath
+            let first = block_range(self.block_size, 0, offset).end;
+            let whole = first..std::cmp::max(first, new_size / self.block_size);
+            reserve_blocks(
+                &mut space.block_allocator,
+                &mut space.quotas,
+                &self.dev,
+                i,
+                blocks,
+                whole,
+            )?;
+            if new_size > i.size as usize {
+                i.size = new_size as u64;
+            }
+            let now = SystemTime::now();
+            i.mtime = now;
+            i.ctime = now;
+            check_invariants(i, self.dev_blocks);
+            Ok((
+                offset,
+                i.data_slice(block_range(self.block_size, offset, data.len() as u64)),
+            ))
+        });
+        let (offset, mut attrs) = match res {
+            Ok(Ok(v)) => v,
+            Ok(Err(err)) | Err(err) => return Err(err),
+        };
+        let mut corrupt = vec![];
+        let res = attrs.write_at(self.dev.clone(), data, offset, &mut corrupt);
+        // publish how the written blocks were sealed, nothing else touched them meanwhile
+        let dedup = self.config.dedup && res.is_ok() && corrupt.is_empty();
+        let published = self.meta.write().unwrap().modify(ino, |i| {
+            for block in attrs.extents.values().flat_map(Range::clone) {
+                match attrs.checksums.get(&block) {
+                    Some(&sum) => i.checksums.insert(block, sum),
+                    None => i.checksums.remove(&block),
+                };
+                match attrs.compressed.get(&block) {
+                    Some(&entry) => i.compressed.insert(block, entry),
+                    None => i.compressed.remove(&block),
+                };
+            }
+            if dedup {
+                let mut space = self.space.lock().unwrap();
+                let space = &mut *space;
+                let freed = dedup_blocks(&mut space.refs, i, self.block_size, offset, data);
+                freed.into_iter().for_each(|e| space.free(e));
+            }
+        });
+        let checked = self.space.lock().unwrap().corruption.check(corrupt);
+        let res = checked.and(published).and_then(|_| {
+            res.map_err(|err| {
+                error!("failed to write inode {}: {}", ino, err);
+                libc::EIO
+            })
+        });
+        if let Ok(written) = res {
+            self.metrics.written(written);
         }
+        res
     }
-    pub fn remove_dirent(&mut self, parent: u64, name: &OsStr) -> Result<DirEntry, c_int> {
-        let res = self.meta.lock().unwrap().modify(parent, |p| {
-            if let Some(entry) = p.entries.remove(name.to_str().unwrap()) {
-                Ok(entry)
-            } else {
-                Err(libc::ENOENT)
+    /// Applies the configured directory link count to attributes handed to the kernel.
+    fn report(&self, mut attrs: fuser::FileAttr) -> fuser::FileAttr {
+        if attrs.kind == fuser::FileType::Directory && self.config.dir_nlink == DirNlink::Unknown {
+            attrs.nlink = 1;
+        }
+        attrs
+    }
+    /// Sets the size of `ino`, freeing the blocks past a lowered end.
+    pub fn truncate(&self, ino: u64, size: u64) -> Result<(), c_int> {
+        self.check_quarantine(ino)?;
+        let locks = self.locks.clone();
+        let _guard = locks.lock(&[ino]);
+        let res = self.meta.write().unwrap().modify(ino, |i| {
+            if i.kind == FileType::Directory {
+                return Err(libc::EISDIR);
             }
+            let mut space = self.space.lock().unwrap();
+            let space = &mut *space;
+            if size < i.size && size as usize % self.block_size != 0 {
+                // the tail of the new last block is cleared in place
+                let last = size as usize / self.block_size;
+                unshare_blocks(
+                    &mut space.block_allocator,
+                    &mut space.refs,
+                    &self.dev,
+                    i,
+                    last..last + 1,
+                )?;
+            }
+            let freed = resize(self.dev.clone(), &mut space.corruption, i, size)?;
+            space
+                .quotas
+                .credit_blocks(i.uid, i.gid, freed.iter().map(Range::len).sum());
+            space.release(freed);
+            let now = SystemTime::now();
+            i.mtime = now;
+            i.ctime = now;
+            Ok(())
         });
-        res.clone().and(res.unwrap())
+        res.and_then(|r| r)
     }
-    pub fn lookup_dirent(&mut self, parent: u64, name: &OsStr) -> Result<DirEntry, c_int> {
-        let res = self.meta.lock().unwrap().read(parent, |p| {
-            if let Some(entry) = p.entries.get(name.to_str().unwrap()) {
-                Ok(entry.to_owned())
-            } else {
-                Err(libc::ENOENT)
+    /// Records a read of `ino` in its access time as far as [`Config::atime`] asks for it.
+    pub fn touch_atime(&self, ino: u64) {
+        let policy = self.config.atime;
+        if policy == AtimePolicy::NoAtime {
+            return;
+        }
+        let now = SystemTime::now();
+        let mut meta = self.meta.write().unwrap();
+        let update = meta.read(ino, |i| {
+            i.flags & FS_NOATIME_FL == 0
+                && (policy == AtimePolicy::StrictAtime || i.atime_stale(now))
+        });
+        if update == Ok(true) {
+            // the read itself succeeded, a lost access time is not worth failing it for
+            if let Err(err) = meta.modify(ino, |i| i.atime = now) {
+                error!(
+                    "failed to update the access time of inode {}, error {}",
+                    ino, err
+                );
+            }
+        }
+    }
+}
+
+impl CyanFS {
+    /// Opens the filesystem striped over the data devices `data`, which have to be given in the
+    /// order they were formatted in. With `new`, the devices are formatted first.
+    pub fn new(data: &[String], meta: &str, new: bool, config: Config) -> std::io::Result<Self> {
+        let block_size = match data.first() {
+            Some(_) if new => config.block_size.unwrap_or(512),
+            Some(first) => match Superblock::probe(first)? {
+                Some(sb) => config.block_size.unwrap_or(sb.block_size as usize),
+                None => return Err(no_superblock(first)),
+            },
+            None => 512,
+        };
+        let dev = DeviceSet::new(data, block_size, config.io_backend)?;
+        Self::with_store(Arc::new(dev), meta, new, config)
+    }
+    /// Opens the filesystem kept in the blocks of `dev`, which may also live in memory, see
+    /// [`MemBlockStore`](crate::block_dev::MemBlockStore). With `new`, `dev` is formatted first.
+    pub fn with_store(
+        dev: Arc<dyn BlockStore>,
+        meta: &str,
+        new: bool,
+        config: Config,
+    ) -> std::io::Result<Self> {
+        let block_size = dev.block_size();
+        let superblock = if new {
+            let sb = Superblock::new(block_size, dev.size()?, dev.devices());
+            sb.write(&*dev)?;
+            sb
+        } else {
+            let sb = Superblock::read(&*dev)?.ok_or_else(|| no_superblock("the data device"))?;
+            // extents are block numbers, reading them with another size corrupts data
+            sb.check(block_size, dev.devices())?;
+            sb.check_labels(&*dev)?;
+            sb
+        };
+        Self::open(dev, superblock, meta, new, config)
+    }
+    /// Opens the filesystem in `dev` described by `superblock`, formatting the metadata store
+    /// with `new`.
+    fn open(
+        dev: Arc<dyn BlockStore>,
+        superblock: Superblock,
+        meta: &str,
+        new: bool,
+        mut config: Config,
+    ) -> std::io::Result<Self> {
+        let block_size = dev.block_size();
+        if let Some(budget) = config.memory_budget {
+            // data blocks get three quarters of the budget, inodes and their dirty backlog the rest
+            config.block_cache = std::cmp::max(1, budget / 4 * 3 / block_size);
+            config.inode_cache = std::cmp::max(1, budget / 4 / INODE_FOOTPRINT);
+            config.max_dirty_inodes = std::cmp::min(config.max_dirty_inodes, config.inode_cache);
+        }
+        let store = open_store(meta, new)?;
+        let journal = match &config.wal {
+            Some(wal) => open_store(wal, new)?,
+            None => store.clone(),
+        };
+        let mut dev =
+            block_cache::BlockCache::with_store(dev, config.block_cache, config.writeback)?;
+        dev.read_ahead = config.read_ahead;
+        dev.bypass = config.bypass_blocks;
+        let metrics = Arc::new(Metrics::default());
+        dev.metrics = metrics.clone();
+        let dev = Arc::new(Mutex::new(dev));
+        let block_flusher = match config.writeback {
+            WritebackPolicy::Periodic { interval } if interval.is_zero() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "the write-back interval must not be zero",
+                ));
+            }
+            WritebackPolicy::Periodic { interval } => {
+                let (stop, stopped) = mpsc::channel::<()>();
+                let dev = dev.clone();
+                let handle = std::thread::spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                        if let Err(err) = dev.lock().unwrap().flush() {
+                            error!("failed to flush block cache, error {}", err);
+                        }
+                    }
+                });
+                Some((stop, handle))
             }
+            _ => None,
+        };
+        let dev_blocks = dev.lock().unwrap().size()?;
+        let corrupt_blocks = config.corrupt_blocks;
+        let refs = BlockRefs::load(store.clone());
+        let meta = Arc::new(RwLock::new(InodeCache::new(
+            store,
+            dev.clone(),
+            config.inode_cache,
+            config.max_dirty_inodes,
+            metrics.clone(),
+        )));
+        let inode_flusher = config.dirty_expire.map(|expire| {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let meta = meta.clone();
+            let handle = std::thread::spawn(move || {
+                // checking twice per period bounds the age of a dirty inode to 1.5 periods
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(expire / 2) {
+                    meta.write().unwrap().write_back_expired(expire);
+                }
+            });
+            (stop, handle)
         });
-        res.clone().and(res.unwrap())
+        let mut block_allocator =
+            Allocator::new(SUPERBLOCK_BLOCKS..std::cmp::min(dev_blocks, Allocator::CAP));
+        // the first blocks of the other data devices hold their labels
+        for block in superblock.label_blocks().skip(1) {
+            block_allocator.remove(block..block + 1);
+        }
+        let space = Space {
+            block_allocator,
+            quotas: Quotas::default(),
+            refs,
+            corruption: Corruption {
+                policy: corrupt_blocks,
+                blocks: BTreeSet::new(),
+            },
+            discard: config.discard.then(|| dev.clone()),
+        };
+        Ok(Self {
+            core: Arc::new(Core {
+                dev,
+                meta,
+                locks: Arc::new(InodeLocks::new()),
+                dev_blocks,
+                block_size,
+                config,
+                space: Mutex::new(space),
+                quarantined: RwLock::new(BTreeSet::new()),
+                metrics,
+            }),
+            journal: Journal::new(journal),
+            inode_allocator: Allocator::new(FUSE_ROOT_ID as usize..Allocator::CAP),
+            files: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+            handles: BTreeMap::new(),
+            next_fh: 1,
+            record_locks: LockTable::new(),
+            lookups: BTreeMap::new(),
+            workers: Workers::new(0),
+            inode_flusher,
+            block_flusher,
+            superblock,
+        })
     }
-    pub fn insert_dirent(
+    pub fn new_with_parent<V>(
         &mut self,
+        uid: u32,
+        gid: u32,
         parent: u64,
         name: &OsStr,
-        entry: DirEntry,
-    ) -> Result<(), c_int> {
-        let res = self.meta.lock().unwrap().modify(parent, |p| {
-            match p.entries.get(name.to_str().unwrap()) {
-                None => {
-                    p.entries.insert(name.to_str().unwrap().to_string(), entry);
-                    Ok(())
+        f: impl FnOnce(&mut Attrs) -> V,
+    ) -> Result<V, c_int> {
+        self.transaction(&[parent], |fs, tx| {
+            fs.space.lock().unwrap().quotas.charge_inode(uid, gid)?;
+            let mut n = fs.new_inode(uid, gid, None);
+            fs.touch(tx, n.ino);
+            let v = f(&mut n);
+            let entry = DirEntry {
+                ino: n.ino,
+                kind: n.kind,
+            };
+            if let Err(err) = fs.insert_dirent(parent, name, entry) {
+                fs.inode_allocator.dealloc(n.ino as usize);
+                fs.space.lock().unwrap().quotas.credit_inode(n.uid, n.gid);
+                return Err(err);
+            }
+            if n.kind == FileType::Directory {
+                // the ".." entry of the new directory links back to its parent
+                fs.meta.write().unwrap().modify(parent, |p| p.nlink += 1)?;
+            }
+            n.parent = parent;
+            fs.meta.write().unwrap().insert(n);
+            Ok(v)
+        })
+    }
+    /// Creates an unnamed regular file for O_TMPFILE, unlinked from the start so it is deleted
+    /// once the kernel lets go of it unless a later link gives it a name. Its parent only decides
+    /// where it goes once linked.
+    pub fn create_tmpfile(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        perm: u16,
+    ) -> Result<u64, c_int> {
+        let locks = self.locks.clone();
+        let _guard = locks.lock(&[parent]);
+        if self.read_inode(parent, |p| p.kind)? != FileType::Directory {
+            return Err(libc::ENOTDIR);
+        }
+        self.space.lock().unwrap().quotas.charge_inode(uid, gid)?;
+        let mut n = self.new_inode(uid, gid, None);
+        n.perm = perm;
+        n.nlink = 0;
+        n.parent = parent;
+        let ino = n.ino;
+        self.meta.write().unwrap().insert(n);
+        Ok(ino)
+    }
+    /// Runs the multi-step metadata operation `f` as a journaled transaction covering `inos`,
+    /// so a crash leaves the metadata store with either all or none of its changes.
+    pub fn transaction<V>(
+        &mut self,
+        inos: &[u64],
+        f: impl FnOnce(&mut Self, &mut Transaction) -> V,
+    ) -> V {
+        let locks = self.locks.clone();
+        let _guard = locks.lock(inos);
+        let mut tx = self.journal.begin();
+        for &ino in inos {
+            tx.before.insert(ino, self.image(ino));
+        }
+        self.journal.log(&tx);
+        let v = f(self, &mut tx);
+        self.commit(tx);
+        v
+    }
+    /// Adds `ino` to `tx` ahead of its first modification.
+    pub fn touch(&mut self, tx: &mut Transaction, ino: u64) {
+        if !tx.before.contains_key(&ino) {
+            tx.before.insert(ino, self.image(ino));
+            self.journal.log(tx);
+        }
+    }
+    /// Current state of `ino` as recorded in the journal, `None` when it does not exist.
+    fn image(&self, ino: u64) -> Option<Attrs> {
+        self.read_inode(ino, |i| i.clone())
+            .ok()
+            .filter(|i| i.nlink > 0)
+    }
+    /// Logs the outcome of `tx`, then writes its inodes back and retires the record.
+    fn commit(&mut self, mut tx: Transaction) {
+        let after = tx
+            .before
+            .keys()
+            .map(|&ino| (ino, self.image(ino)))
+            .collect();
+        tx.after = Some(after);
+        self.journal.log(&tx);
+        let inos: Vec<u64> = tx.before.keys().copied().collect();
+        self.meta.write().unwrap().write_back_all(&inos);
+        self.journal.clear(&tx);
+    }
+    /// Rebuilds the allocators and the quarantine from a scan of every inode, returning the inode
+    /// numbers from least to most recently modified. Inodes left unlinked by a crash while still
+    /// open are deleted and the reference counts of shared blocks are corrected.
+    fn rebuild_allocators(&mut self) -> Result<Vec<u64>, c_int> {
+        let mut recent = vec![];
+        let mut unlinked = vec![];
+        let mut shared: BTreeMap<usize, u64> = BTreeMap::new();
+        let mut quarantined = BTreeSet::new();
+        let core = self.core.clone();
+        let mut meta = core.meta.write().unwrap();
+        let mut space = core.space.lock().unwrap();
+        let space = &mut *space;
+        let inode_allocator = &mut self.inode_allocator;
+        meta.scan(|i| {
+            if i.nlink == 0 {
+                unlinked.push(i.ino);
+                return;
+            }
+            recent.push((i.mtime, i.ino));
+            let ino = i.ino as usize;
+            inode_allocator.remove(ino as usize..ino + 1);
+            let mut corrupt = false;
+            // the allocator only knows the blocks of the data devices, testing others panics
+            let limit = std::cmp::min(core.dev_blocks, Allocator::CAP);
+            for e in i.extents.values().cloned() {
+                // blocks still free at this point are not claimed by any inode seen so far,
+                // unless they are shared
+                let claimable = |b| space.block_allocator.test(b) || space.refs.contains(b);
+                if e.is_empty()
+                    || e.start < SUPERBLOCK_BLOCKS
+                    || e.end > limit
+                    || !e.clone().all(claimable)
+                {
+                    error!(
+                        "inode {} references invalid or shared extent {:?}",
+                        i.ino, e
+                    );
+                    corrupt = true;
+                } else {
+                    if !space.refs.is_empty() {
+                        for b in e.clone().filter(|&b| space.refs.contains(b)) {
+                            *shared.entry(b).or_default() += 1;
+                        }
+                    }
+                    space.block_allocator.remove(e);
                 }
-                Some(_) => Err(libc::EEXIST),
             }
-        });
-        res.and(res.unwrap())
+            if corrupt {
+                quarantined.insert(i.ino);
+            }
+        })?;
+        *core.quarantined.write().unwrap() = quarantined;
+        for ino in unlinked {
+            // none of its blocks were claimed, so removing the record frees them
+            meta.restore(ino, None);
+        }
+        // reference counts are written through ahead of the inodes, a crash leaves them off
+        space.refs.recount(&shared);
+        recent.sort_unstable();
+        Ok(recent.into_iter().map(|(_, ino)| ino).collect())
+    }
+    /// Device block backing the logical block `idx` of `ino`, counted in `block_size` bytes, which
+    /// has to be the block size of the filesystem. Holes map to block 0, which always holds the
+    /// superblock.
+    pub fn bmap_block(&self, ino: u64, block_size: u32, idx: u64) -> Result<u64, c_int> {
+        if block_size as usize != self.block_size {
+            return Err(libc::EINVAL);
+        }
+        self.read_inode(ino, |i| i.physical(idx as usize).map_or(0, |b| b as u64))
+    }
+    /// Answers FS_IOC_FIEMAP for `ino` given the `struct fiemap` header in `query`. Holes are left
+    /// out, extents past the end of the file were preallocated and are reported unwritten. The
+    /// reply is cut to `out_size`, which for the restricted ioctls FUSE forwards only covers the
+    /// header, and only the extents that fit in it are counted as mapped.
+    pub fn fiemap(&self, ino: u64, query: &[u8], out_size: u32) -> Result<Vec<u8>, c_int> {
+        if query.len() < FIEMAP_HEADER {
+            return Err(libc::EINVAL);
+        }
+        let u64_at = |at: usize| u64::from_ne_bytes(query[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_ne_bytes(query[at..at + 4].try_into().unwrap());
+        let (start, length, flags, count) = (u64_at(0), u64_at(8), u32_at(16), u32_at(24));
+        if flags & !FIEMAP_FLAG_SYNC != 0 {
+            return Err(libc::EBADR);
+        }
+        let end = start.saturating_add(length);
+        let res = self.read_inode(ino, |i| {
+            if flags & FIEMAP_FLAG_SYNC != 0 {
+                i.fsync(self.dev.clone())?;
+            }
+            let extents: Vec<(usize, Range<usize>)> = i
+                .extents
+                .iter()
+                .filter(|(&l, e)| {
+                    ((l * self.block_size) as u64) < end
+                        && (((l + e.len()) * self.block_size) as u64) > start
+                })
+                .map(|(&l, e)| (l, e.clone()))
+                .collect();
+            Ok::<_, std::io::Error>((extents, i.size, i.extents.keys().next_back().copied()))
+        });
+        let (extents, size, last) = match res {
+            Ok(Ok(res)) => res,
+            Ok(Err(err)) => {
+                error!("failed to write back inode {}: {}", ino, err);
+                return Err(libc::EIO);
+            }
+            Err(err) => return Err(err),
+        };
+        let mut out = query[..FIEMAP_HEADER].to_vec();
+        out[20..24].copy_from_slice(&(extents.len() as u32).to_ne_bytes());
+        if count > 0 {
+            let fits = (out_size as usize).saturating_sub(FIEMAP_HEADER) / FIEMAP_EXTENT;
+            let mapped = std::cmp::min(std::cmp::min(extents.len(), count as usize), fits);
+            for (logical, e) in extents.iter().take(mapped) {
+                let mut flags = 0;
+                if Some(*logical) == last {
+                    flags |= FIEMAP_EXTENT_LAST;
+                }
+                if (logical * self.block_size) as u64 >= size {
+                    flags |= FIEMAP_EXTENT_UNWRITTEN;
+                }
+                let mut record = [0u8; FIEMAP_EXTENT];
+                let bytes = |blocks: usize| ((blocks * self.block_size) as u64).to_ne_bytes();
+                record[0..8].copy_from_slice(&bytes(*logical));
+                record[8..16].copy_from_slice(&bytes(e.start));
+                record[16..24].copy_from_slice(&bytes(e.len()));
+                record[40..44].copy_from_slice(&flags.to_ne_bytes());
+                out.extend_from_slice(&record);
+            }
+            out[20..24].copy_from_slice(&(mapped as u32).to_ne_bytes());
+        }
+        out.truncate(out_size as usize);
+        Ok(out)
+    }
+    /// `parent` along with the inode `name` refers to in it, if any.
+    fn dirent_inos(&mut self, parent: u64, name: &OsStr) -> Vec<u64> {
+        let mut inos = vec![parent];
+        inos.extend(self.lookup_dirent(parent, name).ok().map(|e| e.ino));
+        inos
+    }
+    /// Formats the data devices, striped in the given order, and the metadata device with
+    /// `block_size` byte blocks and creates the root directory, owned by the calling user. A
+    /// first data device already holding a superblock is only reformatted when `force` is set.
+    pub fn mkfs(
+        data: &[String],
+        meta: &str,
+        block_size: usize,
+        force: bool,
+    ) -> std::io::Result<()> {
+        let blocks = DeviceSet::new(data, block_size, BackendKind::Pread)?.size()?;
+        if blocks <= SUPERBLOCK_BLOCKS {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} holds {} blocks, at least {} are needed",
+                    data.join(", "),
+                    blocks,
+                    SUPERBLOCK_BLOCKS + 1
+                ),
+            ));
+        }
+        if !force {
+            Self::check_unformatted(data)?;
+        }
+        let config = Config {
+            block_size: Some(block_size),
+            ..Config::default()
+        };
+        let mut fs = Self::new(data, meta, true, config)?;
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        fs.create_root(uid, gid);
+        // leaves the allocator state behind, so the first mount starts clean
+        fs.destroy();
+        Ok(())
+    }
+    /// Converts the filesystem on the data device `data` and the metadata device `meta` created
+    /// before data devices carried a superblock, which may keep file data in the block the
+    /// superblock goes to. That data is moved to a free block and the inode records are
+    /// rewritten in the current version before the superblock is written. Such filesystems span
+    /// a single device of 512 byte blocks.
+    pub fn upgrade(data: &str, meta: &str) -> std::io::Result<()> {
+        if Superblock::probe(data)?.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already holds a cyanfs superblock", data),
+            ));
+        }
+        let dev: Arc<dyn BlockStore> = Arc::new(DeviceSet::new(&[data], 512, BackendKind::Pread)?);
+        let superblock = Superblock {
+            inode_version: 0,
+            ..Superblock::new(512, dev.size()?, 1)
+        };
+        let mut fs = Self::open(dev.clone(), superblock, meta, false, Config::default())?;
+        let errno = std::io::Error::from_raw_os_error;
+        fs.meta.read().unwrap().check_store().map_err(errno)?;
+        fs.replay_journal();
+        fs.meta.write().unwrap().migrate().map_err(errno)?;
+        // claim the blocks of every inode, remembering the one mapping block 0
+        let mut owner = None;
+        let dev_blocks = fs.dev_blocks;
+        let mut meta = fs.meta.write().unwrap();
+        let mut space = fs.space.lock().unwrap();
+        let allocator = &mut space.block_allocator;
+        meta.scan(|i| {
+            for e in i.extents.values().filter(|e| e.end <= dev_blocks) {
+                allocator.remove(std::cmp::max(e.start, SUPERBLOCK_BLOCKS)..e.end);
+            }
+            if i.maps(0) {
+                owner = Some(i.clone());
+            }
+        })
+        .map_err(errno)?;
+        if let Some(mut i) = owner {
+            let to = allocator.alloc().ok_or_else(|| errno(libc::ENOSPC))?;
+            let mut buf = vec![0u8; 512];
+            dev.read_block(0, &mut buf)?;
+            dev.write_block(to, &buf)?;
+            dev.sync_data()?;
+            let logical = i
+                .extents
+                .iter()
+                .find(|(_, e)| e.start == 0)
+                .map(|(&logical, _)| logical)
+                .unwrap();
+            i.remap_block(logical, to);
+            meta.restore(i.ino, Some(&i));
+        }
+        drop(space);
+        meta.sync_store().map_err(errno)?;
+        meta.check_store().map_err(errno)?;
+        drop(meta);
+        fs.superblock.inode_version = RECORD_VERSION as u32;
+        fs.superblock.write(&*dev)
+    }
+    /// Fails with AlreadyExists when the first of the data devices `data` holds a filesystem,
+    /// which formatting them would destroy.
+    pub fn check_unformatted(data: &[String]) -> std::io::Result<()> {
+        match data.first() {
+            Some(first) if Superblock::probe(first)?.is_some() => Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already holds a cyanfs filesystem", first),
+            )),
+            _ => Ok(()),
+        }
+    }
+    /// Rewrites the inode records of versions older than the one the superblock allows for,
+    /// then raises that version so builds unable to read the rewritten records refuse to mount.
+    pub(crate) fn migrate_records(&mut self) -> Result<(), c_int> {
+        if self.superblock.inode_version >= RECORD_VERSION as u32 {
+            return Ok(());
+        }
+        self.meta.write().unwrap().migrate()?;
+        let sb = Superblock {
+            inode_version: RECORD_VERSION as u32,
+            ..self.superblock
+        };
+        if let Err(err) = sb.write(&**self.dev.lock().unwrap().store()) {
+            error!("failed to write the superblock, error {}", err);
+            return Err(libc::EIO);
+        }
+        self.superblock = sb;
+        Ok(())
+    }
+    /// Settles the transactions a crash left in the journal.
+    fn replay_journal(&mut self) {
+        for tx in self.journal.pending() {
+            let mut meta = self.meta.write().unwrap();
+            for (ino, attrs) in tx.replay_images() {
+                meta.restore(*ino, attrs.as_ref());
+            }
+            drop(meta);
+            self.journal.clear(&tx);
+        }
+    }
+    fn create_root(&mut self, uid: u32, gid: u32) {
+        let mut root = self.new_inode(uid, gid, Some(FUSE_ROOT_ID));
+        root.kind = FileType::Directory;
+        root.nlink = 2;
+        root.parent = FUSE_ROOT_ID;
+        self.meta.write().unwrap().insert(root);
+        let ino = FUSE_ROOT_ID as usize;
+        self.inode_allocator.remove(ino..ino + 1);
+    }
+    pub fn new_inode(&mut self, uid: u32, gid: u32, ino: Option<u64>) -> Attrs {
+        let now = SystemTime::now();
+        Attrs {
+            ino: match ino {
+                Some(ino) => ino,
+                None => self.inode_allocator.alloc().unwrap() as u64,
+            },
+            size: 0,
+            extents: BTreeMap::new(),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind: FileType::RegularFile,
+            perm: 0o777,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: self
+                .config
+                .compression
+                .with_flags(self.config.checksum.with_flags(0)),
+            link: std::path::PathBuf::new(),
+            entries: BTreeMap::new(),
+            xattrs: self.config.default_xattrs.clone(),
+            checksums: BTreeMap::new(),
+            compressed: BTreeMap::new(),
+            parent: 0,
+        }
+    }
+    pub fn remove_dirent(&mut self, parent: u64, name: &OsStr) -> Result<DirEntry, c_int> {
+        let name = entry_name(name)?;
+        let res = self.meta.write().unwrap().modify(parent, |p| {
+            if p.kind != FileType::Directory {
+                Err(libc::ENOTDIR)
+            } else if let Some(entry) = p.entries.remove(name) {
+                Ok(entry)
+            } else {
+                Err(libc::ENOENT)
+            }
+        });
+        res.clone().and(res.unwrap())
+    }
+    pub fn lookup_dirent(&mut self, parent: u64, name: &OsStr) -> Result<DirEntry, c_int> {
+        let name = entry_name(name)?;
+        let res = self.read_inode(parent, |p| {
+            if p.kind != FileType::Directory {
+                Err(libc::ENOTDIR)
+            } else if let Some(entry) = p.entries.get(name) {
+                Ok(entry.to_owned())
+            } else {
+                Err(libc::ENOENT)
+            }
+        });
+        res.clone().and(res.unwrap())
+    }
+    pub fn insert_dirent(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        entry: DirEntry,
+    ) -> Result<(), c_int> {
+        let name = entry_name(name)?;
+        let res = self
+            .meta
+            .write()
+            .unwrap()
+            .modify(parent, |p| match p.entries.get(name) {
+                _ if p.kind != FileType::Directory => Err(libc::ENOTDIR),
+                None => {
+                    p.entries.insert(name.to_string(), entry);
+                    Ok(())
+                }
+                Some(_) => Err(libc::EEXIST),
+            });
+        res.and(res.unwrap())
+    }
+    pub fn remove_dir(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let ent = self.lookup_dirent(parent, name)?;
+        if ent.kind != FileType::Directory {
+            return Err(libc::ENOTDIR);
+        }
+        if !self
+            .meta
+            .write()
+            .unwrap()
+            .read(ent.ino, |d| d.entries.is_empty())?
+        {
+            return Err(libc::ENOTEMPTY);
+        }
+        self.remove_dirent(parent, name)?;
+        self.release_dir(parent, ent.ino)
+    }
+    /// Unlinks the directory `ino` after its entry has been removed from `parent`.
+    pub fn release_dir(&mut self, parent: u64, ino: u64) -> Result<(), c_int> {
+        let mut meta = self.meta.write().unwrap();
+        meta.modify(parent, |p| p.nlink -= 1)?;
+        meta.modify(ino, |d| d.nlink = 0)?;
+        drop(meta);
+        if !self.in_use(ino) {
+            self.delete_inode(ino)?;
+        }
+        Ok(())
+    }
+    /// Drops one link to `ino`, deleting it once none remain and the kernel holds no reference.
+    pub fn drop_link(&mut self, ino: u64) -> Result<(), c_int> {
+        let nlink = self.meta.write().unwrap().modify(ino, |i| {
+            i.nlink -= 1;
+            i.nlink
+        })?;
+        if nlink == 0 && !self.in_use(ino) {
+            self.delete_inode(ino)?;
+        }
+        Ok(())
+    }
+    /// Whether the kernel still refers to `ino` or holds it open, which keeps it alive once
+    /// unlinked.
+    fn in_use(&self, ino: u64) -> bool {
+        self.lookups.contains_key(&ino) || self.handles.contains_key(&ino)
+    }
+    /// Deletes `ino` if it is unlinked and no longer in use.
+    fn delete_unused(&mut self, ino: u64) {
+        if self.in_use(ino) {
+            return;
+        }
+        let locks = self.locks.clone();
+        let _guard = locks.lock(&[ino]);
+        if let Ok(0) = self.read_inode(ino, |i| i.nlink) {
+            if let Err(err) = self.delete_inode(ino) {
+                error!("failed to delete unlinked inode {}, error {}", ino, err);
+            }
+        }
+    }
+    /// Releases the blocks and number of the unlinked `ino` and removes its record.
+    fn delete_inode(&mut self, ino: u64) -> Result<(), c_int> {
+        let mut meta = self.meta.write().unwrap();
+        let (uid, gid, extents) = meta.read(ino, |i| {
+            (
+                i.uid,
+                i.gid,
+                i.extents.values().cloned().collect::<Vec<_>>(),
+            )
+        })?;
+        let mut space = self.space.lock().unwrap();
+        space.quotas.credit_inode(uid, gid);
+        space
+            .quotas
+            .credit_blocks(uid, gid, extents.iter().map(Range::len).sum());
+        // extents of a quarantined inode were never claimed and must not be freed
+        if !self.quarantined.read().unwrap().contains(&ino) {
+            space.release(extents);
+        }
+        drop(space);
+        meta.restore(ino, None);
+        drop(meta);
+        self.inode_allocator.dealloc(ino as usize);
+        Ok(())
+    }
+    /// Counts a reference to `ino` handed to the kernel, dropped again by `forget`.
+    fn looked_up(&mut self, ino: u64) {
+        *self.lookups.entry(ino).or_default() += 1;
+    }
+    /// Drops `nlookup` references the kernel held to `ino`, deleting it once the last one is gone
+    /// if it is unlinked and not open.
+    pub fn forget_inode(&mut self, ino: u64, nlookup: u64) {
+        let remaining = match self.lookups.get_mut(&ino) {
+            Some(lookups) => {
+                *lookups = lookups.saturating_sub(nlookup);
+                *lookups
+            }
+            None => return,
+        };
+        if remaining == 0 {
+            self.lookups.remove(&ino);
+            self.delete_unused(ino);
+        }
+    }
+    pub fn rename_dirent(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), c_int> {
+        let ent = self.lookup_dirent(parent, name)?;
+        // validate the replaced entry up front so an error leaves both directories untouched
+        match self.lookup_dirent(newparent, newname) {
+            Ok(old) if old.ino == ent.ino => return Ok(()),
+            Ok(old) => match (ent.kind, old.kind) {
+                (FileType::Directory, FileType::Directory) => {
+                    if !self
+                        .meta
+                        .write()
+                        .unwrap()
+                        .read(old.ino, |d| d.entries.is_empty())?
+                    {
+                        return Err(libc::ENOTEMPTY);
+                    }
+                }
+                (FileType::Directory, _) => return Err(libc::ENOTDIR),
+                (_, FileType::Directory) => return Err(libc::EISDIR),
+                _ => {}
+            },
+            Err(libc::ENOENT) => {}
+            Err(err) => return Err(err),
+        }
+        self.remove_dirent(parent, name)?;
+        let replaced = self.meta.write().unwrap().modify(newparent, |p| {
+            p.entries
+                .insert(entry_name(newname).unwrap().to_string(), ent.clone())
+        })?;
+        match replaced {
+            Some(old) if old.kind == FileType::Directory => self.release_dir(newparent, old.ino)?,
+            Some(old) => self.drop_link(old.ino)?,
+            None => {}
+        }
+        if parent != newparent {
+            let mut meta = self.meta.write().unwrap();
+            if ent.kind == FileType::Directory {
+                meta.modify(parent, |p| p.nlink -= 1)?;
+                meta.modify(newparent, |p| p.nlink += 1)?;
+            }
+            meta.modify(ent.ino, |i| i.parent = newparent)?;
+        }
+        Ok(())
+    }
+    /// Path of `ino` from the root, following the directory each inode was created in or last
+    /// moved to. A hard linked file resolves through that directory only, and fails with ENOENT
+    /// once it has been unlinked from there.
+    pub fn path_of(&self, ino: u64) -> Result<PathBuf, c_int> {
+        let mut names = vec![];
+        let mut seen = BTreeSet::new();
+        let mut ino = ino;
+        while ino != FUSE_ROOT_ID {
+            if !seen.insert(ino) {
+                return Err(libc::ELOOP);
+            }
+            let parent = match self.read_inode(ino, |i| i.parent)? {
+                0 => return Err(libc::ENOENT),
+                parent => parent,
+            };
+            let name = self.read_inode(parent, |p| {
+                p.entries
+                    .iter()
+                    .find(|(_, e)| e.ino == ino)
+                    .map(|(name, _)| name.clone())
+            })?;
+            names.push(name.ok_or(libc::ENOENT)?);
+            ino = parent;
+        }
+        Ok(std::iter::once("/".to_string())
+            .chain(names.into_iter().rev())
+            .collect())
+    }
+    /// Calls `f` on every inode of the filesystem, including changes not yet written back.
+    pub fn scan_inodes(&mut self, f: impl FnMut(&Attrs)) -> Result<(), c_int> {
+        let mut meta = self.meta.write().unwrap();
+        meta.sync();
+        meta.scan(f)
+    }
+    /// Loads the quota limits and counts the usage of every owner from a scan of every inode.
+    fn count_quotas(&mut self) -> Result<(), c_int> {
+        let mut quotas = Quotas::new(self.block_size);
+        for (key, value) in self.meta.read().unwrap().list_reserved(QUOTA_PREFIX) {
+            if let (Some(owner), Ok(limits)) = (Owner::from_key(&key), bincode::deserialize(&value))
+            {
+                quotas.set_limits(owner, limits);
+            }
+        }
+        self.scan_inodes(|i| quotas.account(i.uid, i.gid, i.blocks()))?;
+        self.space.lock().unwrap().quotas = quotas;
+        Ok(())
+    }
+    /// Sets the limits of `owner` in the metadata store, all zero limits removing them. Mounts
+    /// with [`Config::quota`] enforce them.
+    pub fn set_quota(&mut self, owner: Owner, limits: Limits) {
+        let value = bincode::serialize(&limits).unwrap();
+        self.meta.read().unwrap().put_reserved(
+            &owner.key(),
+            Some(&value[..]).filter(|_| limits != Limits::default()),
+        );
+        self.space.lock().unwrap().quotas.set_limits(owner, limits);
+    }
+    /// Limits of `owner` along with its usage, which is only counted with [`Config::quota`].
+    pub fn quota(&self, owner: Owner) -> (Limits, Usage) {
+        let space = self.space.lock().unwrap();
+        (space.quotas.limits(owner), space.quotas.usage(owner))
+    }
+    /// Hands `ino` to the user `uid` and the group `gid` where given, moving its blocks and the
+    /// inode itself over to their quotas.
+    pub fn change_owner(
+        &mut self,
+        ino: u64,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<(), c_int> {
+        let locks = self.locks.clone();
+        let _guard = locks.lock(&[ino]);
+        let res = self.meta.write().unwrap().modify(ino, |i| {
+            let owner = (uid.unwrap_or(i.uid), gid.unwrap_or(i.gid));
+            self.space
+                .lock()
+                .unwrap()
+                .quotas
+                .transfer((i.uid, i.gid), owner, i.blocks())?;
+            i.uid = owner.0;
+            i.gid = owner.1;
+            i.ctime = SystemTime::now();
+            Ok(())
+        });
+        res.and_then(|r| r)
+    }
+    /// Allocates the bytes of `ino` from `offset` on for `length` bytes as fallocate does with
+    /// `mode`, growing the file unless FALLOC_FL_KEEP_SIZE is set, or punches a hole there with
+    /// FALLOC_FL_PUNCH_HOLE. Other modes fail with EOPNOTSUPP.
+    pub fn fallocate_file(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+    ) -> Result<(), c_int> {
+        self.check_quarantine(ino)?;
+        let punch = match mode {
+            0 | libc::FALLOC_FL_KEEP_SIZE => false,
+            // the kernel only passes a punch together with keeping the size
+            m if m == libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE => true,
+            _ => return Err(libc::EOPNOTSUPP),
+        };
+        let locks = self.locks.clone();
+        let guard = locks.lock(&[ino]);
+        let res = self.meta.write().unwrap().modify(ino, |i| {
+            let mut space = self.space.lock().unwrap();
+            let space = &mut *space;
+            if punch {
+                // partially covered blocks at either end are zeroed in place
+                let end = (offset + length) as usize / self.block_size;
+                for edge in [offset as usize / self.block_size, end] {
+                    unshare_blocks(
+                        &mut space.block_allocator,
+                        &mut space.refs,
+                        &self.dev,
+                        i,
+                        edge..edge + 1,
+                    )?;
+                }
+                let freed = punch_hole(
+                    self.dev.clone(),
+                    &mut space.corruption,
+                    i,
+                    offset as u64,
+                    length as u64,
+                )?;
+                space
+                    .quotas
+                    .credit_blocks(i.uid, i.gid, freed.iter().map(Range::len).sum());
+                space.release(freed);
+                let now = SystemTime::now();
+                i.mtime = now;
+                i.ctime = now;
+                check_invariants(i, self.dev_blocks);
+                return Ok(i.extents.len());
+            }
+            let new_size = offset as usize + length as usize;
+            reserve_blocks(
+                &mut space.block_allocator,
+                &mut space.quotas,
+                &self.dev,
+                i,
+                block_range(self.block_size, offset as u64, length as u64),
+                0..0,
+            )?;
+            if new_size > i.size as usize && mode & libc::FALLOC_FL_KEEP_SIZE == 0 {
+                i.size = new_size as u64;
+            }
+            check_invariants(i, self.dev_blocks);
+            Ok(i.extents.len())
+        });
+        drop(guard);
+        match res {
+            Ok(Ok(extents)) => {
+                // reflowing only tidies the layout, the punch already succeeded either way
+                if punch
+                    && self
+                        .config
+                        .reflow_extents
+                        .map_or(false, |max| extents > max)
+                {
+                    if let Err(err) = self.reflow(ino) {
+                        error!(
+                            "failed to reflow inode {} after punching a hole: {}",
+                            ino, err
+                        );
+                    }
+                }
+                Ok(())
+            }
+            Ok(Err(err)) | Err(err) => Err(err),
+        }
+    }
+    /// Copies up to `len` bytes of `ino_in` from `offset_in` to `ino_out` at `offset_out`,
+    /// stopping at the end of `ino_in`, and returns the number of bytes copied. Whole blocks at
+    /// the same offset within a block in both files are shared with the source, holes staying
+    /// holes, when both files seal blocks alike; the rest is copied through the block cache.
+    pub fn copy_range(
+        &mut self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<usize, c_int> {
+        let (size, kind) =
+            self.read_inode(ino_in, |i| (i.size, ChecksumKind::from_flags(i.flags)))?;
+        let len = std::cmp::min(len, size.saturating_sub(offset_in));
+        let block_size = self.block_size as u64;
+        let shareable = ino_in != ino_out
+            && offset_in % block_size == offset_out % block_size
+            && self.read_inode(ino_out, |o| ChecksumKind::from_flags(o.flags))? == kind;
+        let first = block_range(self.block_size, 0, offset_in).end as u64;
+        let last = block_range(self.block_size, offset_in + len, 0).start as u64;
+        // the bytes before and after the shared blocks, or all of them, are copied
+        let shared = if shareable && first < last {
+            first * block_size..last * block_size
+        } else {
+            offset_in + len..offset_in + len
+        };
+        let mut copied = 0;
+        let segments = [
+            (
+                offset_in,
+                std::cmp::min(shared.start, offset_in + len) - offset_in,
+                false,
+            ),
+            (shared.start, shared.end - shared.start, true),
+            (shared.end, offset_in + len - shared.end, false),
+        ];
+        for (from, len, share) in segments.into_iter().filter(|s| s.1 > 0) {
+            let to = offset_out + (from - offset_in);
+            let res = if share {
+                self.share_range(ino_in, from, ino_out, to, len)
+            } else {
+                self.copy_bytes(ino_in, from, ino_out, to, len)
+            };
+            match res {
+                Ok(n) => copied += n,
+                Err(err) if copied == 0 => return Err(err),
+                // report the partial copy, the caller retries the rest and sees the error then
+                Err(_) => break,
+            }
+            if copied < from - offset_in + len {
+                break;
+            }
+        }
+        if copied > 0 {
+            self.touch_atime(ino_in);
+        }
+        Ok(copied as usize)
+    }
+    /// Copies `len` bytes of `ino_in` at `offset_in` to `ino_out` at `offset_out` a chunk at a
+    /// time through the block cache, returning the number of bytes copied.
+    fn copy_bytes(
+        &self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<u64, c_int> {
+        const CHUNK: u64 = 1 << 20;
+        let mut copied = 0;
+        while copied < len {
+            let size = std::cmp::min(CHUNK, len - copied) as u32;
+            let res = self
+                .read_data(ino_in, offset_in + copied, size)
+                .and_then(|buf| self.write_data(ino_out, Some(offset_out + copied), &buf));
+            match res {
+                Ok(0) => break,
+                Ok(size) => copied += size as u64,
+                Err(err) if copied == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+        Ok(copied)
+    }
+    /// Maps the whole blocks `len` bytes from `offset_in` on in `ino_in` at `offset_out` in
+    /// `ino_out` as well, sharing them copy-on-write and replacing what `ino_out` mapped there.
+    /// Holes of `ino_in` become holes of `ino_out`.
+    fn share_range(
+        &mut self,
+        ino_in: u64,
+        offset_in: u64,
+        ino_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<u64, c_int> {
+        let locks = self.locks.clone();
+        let _guards = locks.lock(&[ino_in, ino_out]);
+        let (first_in, first_out) = (
+            offset_in as usize / self.block_size,
+            offset_out as usize / self.block_size,
+        );
+        let count = len as usize / self.block_size;
+        let src = self.read_inode(ino_in, |i| i.data_slice(first_in..first_in + count))?;
+        let res = self.meta.write().unwrap().modify(ino_out, |o| {
+            let blocks = first_out..first_out + count;
+            let mapped = count
+                - o.holes(blocks.clone())
+                    .iter()
+                    .map(Range::len)
+                    .sum::<usize>();
+            let mut space = self.space.lock().unwrap();
+            let space = &mut *space;
+            if src.blocks() > mapped {
+                space
+                    .quotas
+                    .charge_blocks(o.uid, o.gid, src.blocks() - mapped)?;
+            } else {
+                space
+                    .quotas
+                    .credit_blocks(o.uid, o.gid, mapped - src.blocks());
+            }
+            let freed = o.unmap_blocks(blocks);
+            space.release(freed);
+            for (&start, e) in &src.extents {
+                for block in e.clone() {
+                    space.refs.share(block);
+                    if let Some(&checksum) = src.checksums.get(&block) {
+                        o.checksums.insert(block, checksum);
+                    }
+                    if let Some(&compressed) = src.compressed.get(&block) {
+                        o.compressed.insert(block, compressed);
+                    }
+                }
+                o.map_blocks(first_out + (start - first_in), e.clone());
+            }
+            o.size = std::cmp::max(o.size, offset_out + len);
+            let now = SystemTime::now();
+            o.mtime = now;
+            o.ctime = now;
+            check_invariants(o, self.dev_blocks);
+            Ok(len)
+        });
+        res.and_then(|r| r)
+    }
+    /// Makes the data and metadata of `ino` durable. With `datasync` metadata changes reading the
+    /// data back does not depend on, like timestamps, are left out. The data is durable before
+    /// its metadata is committed, so a crash never leaves metadata referencing data that was lost.
+    pub fn sync_file(&mut self, ino: u64, datasync: bool) -> Result<(), c_int> {
+        let _guards = self.locks.lock(&[ino]);
+        let mut meta = self.meta.write().unwrap();
+        let metadata = !datasync || meta.data_dirty(ino);
+        // the record written back next finds the device synced and does not sync it again
+        match meta.read(ino, |i| {
+            let mut dev = self.dev.lock().unwrap();
+            dev.barrier(i.extents.values())?;
+            dev.sync_device()
+        }) {
+            Ok(Ok(_)) if metadata => {
+                if !meta.write_back(ino) {
+                    return Err(libc::EIO);
+                }
+                meta.sync_store()
+            }
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => {
+                error!("failed to sync inode {}: {}", ino, err);
+                Err(libc::EIO)
+            }
+            Err(err) => Err(err),
+        }
+    }
+    /// Relocates the data of `ino` into contiguous blocks in logical order, leaving one extent per
+    /// stretch of data between holes. Holes stay unallocated. Returns the resulting number of
+    /// extents.
+    pub fn reflow(&mut self, ino: u64) -> Result<usize, c_int> {
+        self.check_quarantine(ino)?;
+        let locks = self.locks.clone();
+        let _guard = locks.lock(&[ino]);
+        let res = self.meta.write().unwrap().modify(ino, |i| {
+            let old: Vec<(usize, Range<usize>)> =
+                i.extents.iter().map(|(&l, e)| (l, e.clone())).collect();
+            if old.windows(2).all(|w| w[0].1.end == w[1].1.start) {
+                return Ok(old.len());
+            }
+            let blocks = old.iter().map(|(_, e)| e.len()).sum();
+            let mut space = self.space.lock().unwrap();
+            let fresh = space.block_allocator.alloc_extents(blocks)?;
+            // free space too fragmented to improve on the current layout
+            if fresh.len() >= old.len() {
+                fresh
+                    .into_iter()
+                    .for_each(|e| space.block_allocator.insert(e));
+                return Ok(old.len());
+            }
+            let moves: Vec<(usize, usize)> = old
+                .iter()
+                .flat_map(|(_, e)| e.clone())
+                .zip(fresh.iter().flat_map(|e| e.clone()))
+                .collect();
+            // blocks move as stored, compressed and sealed, so only their keys change
+            let copied = {
+                let mut dev = self.dev.lock().unwrap();
+                let mut buf = vec![0u8; self.block_size];
+                moves.iter().try_for_each(|&(from, to)| {
+                    dev.read_block(from, &mut buf)?;
+                    dev.write_block(to, &buf)
+                })
+            };
+            if let Err(err) = copied {
+                error!("failed to reflow inode {}: {}", ino, err);
+                fresh
+                    .into_iter()
+                    .for_each(|e| space.block_allocator.insert(e));
+                return Err(libc::EIO);
+            }
+            let checksums = std::mem::take(&mut i.checksums);
+            let compressed = std::mem::take(&mut i.compressed);
+            i.extents.clear();
+            let mut to = moves.iter().map(|&(_, to)| to);
+            for (logical, e) in &old {
+                for l in *logical..logical + e.len() {
+                    let block = to.next().unwrap();
+                    i.map_blocks(l, block..block + 1);
+                }
+            }
+            for &(from, to) in &moves {
+                if let Some(&sum) = checksums.get(&from) {
+                    i.checksums.insert(to, sum);
+                }
+                if let Some(&entry) = compressed.get(&from) {
+                    i.compressed.insert(to, entry);
+                }
+            }
+            space.release(old.into_iter().map(|(_, e)| e).collect());
+            check_invariants(i, self.dev_blocks);
+            Ok(i.extents.len())
+        });
+        res.and_then(|r| r)
+    }
+    /// Reflows `ino` through its open handle `fh`, see [`Self::reflow`]. Relocating data takes as
+    /// much I/O as rewriting it, so the handle has to be open for writing.
+    pub fn defrag(&mut self, ino: u64, fh: u64) -> Result<usize, c_int> {
+        if self.check_fh(ino, fh)?.flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(libc::EBADF);
+        }
+        self.reflow(ino)
+    }
+    /// Clones the tree under the root into [`SNAPSHOTS_DIR`]`/name`, leaving the snapshots
+    /// themselves out, and returns the inode number of the snapshot. The clones share every data
+    /// block with the originals until either side writes it. The snapshot is linked in once
+    /// every clone reached the metadata store, so a crash midway only leaves orphans behind.
+    /// [`SNAPSHOTS_DIR`] is created on behalf of `uid` and `gid` if missing.
+    pub fn snapshot(&mut self, uid: u32, gid: u32, name: &OsStr) -> Result<u64, c_int> {
+        let snapshots_dir = OsStr::new(SNAPSHOTS_DIR);
+        let snapshots = match self.lookup_dirent(FUSE_ROOT_ID, snapshots_dir) {
+            Ok(entry) if entry.kind == FileType::Directory => entry.ino,
+            Ok(_) => return Err(libc::ENOTDIR),
+            Err(libc::ENOENT) => {
+                self.new_with_parent(uid, gid, FUSE_ROOT_ID, snapshots_dir, |n| {
+                    n.kind = FileType::Directory;
+                    n.nlink = 2;
+                    n.perm = 0o755;
+                    n.ino
+                })?
+            }
+            Err(err) => return Err(err),
+        };
+        match self.lookup_dirent(snapshots, name) {
+            Ok(_) => return Err(libc::EEXIST),
+            Err(libc::ENOENT) => {}
+            Err(err) => return Err(err),
+        }
+        // nothing may change the tree while it is cloned, a write or truncate between reading an
+        // inode and sharing its blocks would rewrite them in place or free them
+        let locks = self.locks.clone();
+        let guard = locks.lock_all();
+        let meta = self.meta.clone();
+        let mut meta = meta.write().unwrap();
+        let mut tree: BTreeMap<u64, Attrs> = BTreeMap::new();
+        let mut pending = vec![FUSE_ROOT_ID];
+        while let Some(ino) = pending.pop() {
+            if tree.contains_key(&ino) {
+                continue;
+            }
+            let mut i = meta.read(ino, |i| i.clone())?;
+            if ino == FUSE_ROOT_ID && i.entries.remove(SNAPSHOTS_DIR).is_some() {
+                i.nlink -= 1;
+            }
+            pending.extend(i.entries.values().map(|entry| entry.ino));
+            tree.insert(ino, i);
+        }
+        // hard links within the tree keep sharing one clone
+        let mut clones = BTreeMap::new();
+        for &ino in tree.keys() {
+            match self.inode_allocator.alloc() {
+                Some(clone) => clones.insert(ino, clone as u64),
+                None => {
+                    for clone in clones.into_values() {
+                        self.inode_allocator.dealloc(clone as usize);
+                    }
+                    return Err(libc::ENOSPC);
+                }
+            };
+        }
+        let mut space = self.space.lock().unwrap();
+        let mut quarantined = self.quarantined.write().unwrap();
+        for (ino, mut i) in tree {
+            i.ino = clones[&ino];
+            i.parent = match ino {
+                FUSE_ROOT_ID => snapshots,
+                _ => clones.get(&i.parent).copied().unwrap_or(0),
+            };
+            for entry in i.entries.values_mut() {
+                entry.ino = clones[&entry.ino];
+            }
+            // extents of a quarantined inode were never claimed and stay out of the counts
+            if quarantined.contains(&ino) {
+                quarantined.insert(i.ino);
+            } else {
+                i.extents
+                    .values()
+                    .flat_map(|e| e.clone())
+                    .for_each(|block| space.refs.share(block));
+            }
+            space.quotas.account(i.uid, i.gid, i.blocks());
+            meta.insert(i);
+        }
+        drop(quarantined);
+        drop(space);
+        meta.flush();
+        drop(meta);
+        drop(guard);
+        let root = clones[&FUSE_ROOT_ID];
+        self.transaction(&[snapshots], |fs, _| {
+            let entry = DirEntry {
+                ino: root,
+                kind: FileType::Directory,
+            };
+            fs.insert_dirent(snapshots, name, entry)?;
+            fs.meta.write().unwrap().modify(snapshots, |s| s.nlink += 1)
+        })?;
+        Ok(root)
+    }
+    /// Opens `ino` with the given open(2) flags and returns the new file handle.
+    pub fn open_file(&mut self, ino: u64, flags: i32) -> Result<u64, c_int> {
+        // directories are only ever opened for reading
+        let writable = flags & libc::O_ACCMODE != libc::O_RDONLY;
+        if writable && self.read_inode(ino, |i| i.kind)? == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+        if flags & libc::O_TRUNC != 0 && writable {
+            self.truncate(ino, 0)?;
+        } else {
+            self.read_inode(ino, |_| {})?;
+        }
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.files.insert(fh, OpenFile { ino, flags });
+        *self.handles.entry(ino).or_default() += 1;
+        Ok(fh)
+    }
+    /// Flushes handle `fh` of `ino` on its close, dropping the POSIX locks `lock_owner` holds on
+    /// it. Unless configured to sync on every close, only the close of the last open handle makes
+    /// the file durable.
+    pub fn flush_file(&mut self, ino: u64, fh: u64, lock_owner: u64) -> Result<(), c_int> {
+        self.check_fh(ino, fh)?;
+        // closing any descriptor of a file drops the POSIX locks its process holds on it
+        self.release_locks(ino, lock_owner);
+        // flush precedes the release of the handle, which is still counted here
+        let last = self.handles.get(&ino).copied().unwrap_or_default() <= 1;
+        if self.config.flush_on_close == FlushOnClose::Last && !last {
+            return Ok(());
+        }
+        self.sync_file(ino, true)
+    }
+    /// Releases the file handle `fh`, dropping the flock locks of `lock_owner`, set on the last
+    /// close of a file holding some.
+    pub fn release_file(&mut self, fh: u64, lock_owner: Option<u64>) -> Result<(), c_int> {
+        let file = self.files.remove(&fh).ok_or(libc::EBADF)?;
+        if let Some(owner) = lock_owner {
+            self.release_locks(file.ino, owner);
+        }
+        if let Some(handles) = self.handles.get_mut(&file.ino) {
+            *handles -= 1;
+            if *handles == 0 {
+                self.handles.remove(&file.ino);
+                // an unlinked file lives on until its last close
+                self.delete_unused(file.ino);
+            }
+        }
+        Ok(())
+    }
+    fn check_fh(&self, ino: u64, fh: u64) -> Result<&OpenFile, c_int> {
+        match self.files.get(&fh) {
+            Some(file) if file.ino == ino => Ok(file),
+            _ => Err(libc::EBADF),
+        }
+    }
+    /// Replaces the `flags` of `ino` with what `f` makes of them, on behalf of `uid`, which has to
+    /// own the inode.
+    fn update_flags(
+        &mut self,
+        ino: u64,
+        uid: u32,
+        f: impl FnOnce(u32) -> Result<u32, c_int>,
+    ) -> Result<(), c_int> {
+        self.meta
+            .write()
+            .unwrap()
+            .modify(ino, |i| {
+                if uid != 0 && uid != i.uid {
+                    return Err(libc::EPERM);
+                }
+                i.flags = f(i.flags)?;
+                i.ctime = SystemTime::now();
+                Ok(())
+            })
+            .and_then(|r| r)
+    }
+    /// The chattr(1) attributes of `ino` as FS_IOC_GETFLAGS reports them.
+    pub fn fs_flags(&self, ino: u64) -> Result<u32, c_int> {
+        self.read_inode(ino, |i| {
+            let mut fs_flags = i.flags & STORED_FS_FLAGS;
+            if Compression::from_flags(i.flags) != Compression::None {
+                fs_flags |= FS_COMPR_FL;
+            }
+            fs_flags
+        })
+    }
+    /// Replaces the chattr(1) attributes of `ino` as FS_IOC_SETFLAGS does on behalf of `uid`,
+    /// which has to own the inode. Attributes other than the supported ones fail with
+    /// EOPNOTSUPP.
+    pub fn set_fs_flags(&mut self, ino: u64, uid: u32, fs_flags: u32) -> Result<(), c_int> {
+        // a compressed file keeps its mode, an uncompressed one takes the mount default
+        let default = match self.config.compression {
+            Compression::None => Compression::Lz4,
+            compression => compression,
+        };
+        self.update_flags(ino, uid, |flags| {
+            if fs_flags & !(STORED_FS_FLAGS | FS_COMPR_FL) != 0 {
+                return Err(libc::EOPNOTSUPP);
+            }
+            let compression = match Compression::from_flags(flags) {
+                _ if fs_flags & FS_COMPR_FL == 0 => Compression::None,
+                Compression::None => default,
+                compression => compression,
+            };
+            let flags = (flags & !STORED_FS_FLAGS) | (fs_flags & STORED_FS_FLAGS);
+            Ok(compression.with_flags(flags))
+        })
+    }
+    /// Drops the record locks `owner` holds on `ino`, failing its pending waits with EINTR.
+    fn release_locks(&mut self, ino: u64, owner: u64) {
+        let (dropped, granted) = self.record_locks.release(ino, owner);
+        dropped
+            .into_iter()
+            .for_each(|reply| reply.error(libc::EINTR));
+        granted.into_iter().for_each(|reply| reply.ok());
+    }
+    /// Estimated bytes currently held by the block and inode caches.
+    pub fn memory_usage(&self) -> usize {
+        self.dev.lock().unwrap().len() * self.block_size
+            + self.meta.read().unwrap().len() * INODE_FOOTPRINT
+    }
+    /// Counters accumulated since mount, along with the current fragmentation of free space.
+    pub fn stats(&self) -> FsStats {
+        FsStats {
+            free_space: Some(self.fragmentation()),
+            ..self.metrics.snapshot()
+        }
+    }
+    /// Block and inode counts along with the limits `statfs` reports.
+    pub fn statvfs(&self) -> Statvfs {
+        let space = self.space.lock().unwrap();
+        Statvfs {
+            blocks: space.block_allocator.total() as u64,
+            bfree: space.block_allocator.free() as u64,
+            bavail: space.block_allocator.free() as u64,
+            files: self.inode_allocator.total() as u64,
+            ffree: self.inode_allocator.free() as u64,
+            bsize: self.block_size as u32,
+            namelen: NAME_MAX as u32,
+            frsize: self.block_size as u32,
+        }
+    }
+    /// Free runs of data blocks, telling whether relocating files into contiguous extents would
+    /// pay off.
+    pub fn fragmentation(&self) -> Fragmentation {
+        self.space.lock().unwrap().block_allocator.fragmentation()
+    }
+    /// Live counters, for reporting them once the filesystem has been handed to the mount.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+    /// Data blocks that failed checksum verification, with their owning inode.
+    pub fn corrupt_blocks(&self) -> BTreeSet<(u64, usize)> {
+        self.space.lock().unwrap().corruption.blocks.clone()
+    }
+    /// Inodes found with corrupt extents at mount time.
+    pub fn quarantined(&self) -> BTreeSet<u64> {
+        self.quarantined.read().unwrap().clone()
+    }
+    /// Loads the filesystem for use, replaying the journal, creating a missing root directory
+    /// owned by `uid` and `gid` and restoring the allocators. Mounting does this on its own,
+    /// embedders call it before anything else.
+    pub fn start(&mut self, uid: u32, gid: u32) -> Result<(), c_int> {
+        // a metadata store that could not be loaded would look like an empty filesystem
+        self.meta.read().unwrap().check_store()?;
+        self.migrate_records()?;
+        // settle transactions interrupted by a crash before anything reads the inodes
+        self.replay_journal();
+        let root = self.meta.write().unwrap().read(FUSE_ROOT_ID, |_| {});
+        match root {
+            Ok(_) => {}
+            Err(libc::ENOENT) => self.create_root(uid, gid),
+            Err(err) => return Err(err),
+        }
+        self.meta.write().unwrap().flush();
+        // a clean unmount leaves the allocator state behind, taking it marks the mount unclean
+        let state = self.meta.read().unwrap().get_reserved(ALLOCATOR_STATE_KEY);
+        self.meta
+            .read()
+            .unwrap()
+            .put_reserved(ALLOCATOR_STATE_KEY, None);
+        let recent = match state.and_then(|s| bincode::deserialize::<AllocatorState>(&s).ok()) {
+            Some(state) => {
+                let mut space = self.space.lock().unwrap();
+                state
+                    .blocks
+                    .into_iter()
+                    .for_each(|e| space.block_allocator.remove(e));
+                drop(space);
+                state
+                    .inodes
+                    .into_iter()
+                    .for_each(|e| self.inode_allocator.remove(e));
+                *self.quarantined.write().unwrap() = state.quarantined;
+                state.cached
+            }
+            None => self.rebuild_allocators()?,
+        };
+        let mut quarantined = self.quarantined.write().unwrap();
+        match self.config.corrupt_extents {
+            CorruptExtents::Fail if !quarantined.is_empty() => return Err(libc::EIO),
+            CorruptExtents::Ignore => quarantined.clear(),
+            _ => {}
+        }
+        drop(quarantined);
+        if self.config.quota {
+            self.count_quotas()?;
+        }
+        // warm the cache with the inodes cached at the last clean unmount, or else the newest
+        // ones, loading the most recent last so it ends up most recently used
+        let preload = std::cmp::min(self.config.preload_inodes, self.config.inode_cache);
+        let mut meta = self.meta.write().unwrap();
+        for ino in &recent[recent.len().saturating_sub(preload)..] {
+            meta.read(*ino, |_| {}).ok();
+        }
+        Ok(())
+    }
+    /// Writes everything back and records the allocator state for the next [`Self::start`], the
+    /// counterpart of unmounting.
+    pub fn shutdown(&mut self) {
+        self.workers.join();
+        for (stop, handle) in self
+            .inode_flusher
+            .take()
+            .into_iter()
+            .chain(self.block_flusher.take())
+        {
+            drop(stop);
+            handle.join().unwrap();
+        }
+        // references and handles of a kernel that is going away no longer keep unlinked inodes
+        // alive
+        let lookups = std::mem::take(&mut self.lookups);
+        let handles = std::mem::take(&mut self.handles);
+        for ino in lookups.into_keys().chain(handles.into_keys()) {
+            self.delete_unused(ino);
+        }
+        let cached = self.meta.read().unwrap().cached();
+        self.meta.write().unwrap().flush();
+        if let Err(err) = self.dev.lock().unwrap().flush() {
+            error!("failed to flush block cache, error {}", err);
+        }
+        let state = AllocatorState {
+            blocks: self.space.lock().unwrap().block_allocator.used_ranges(),
+            inodes: self.inode_allocator.used_ranges(),
+            quarantined: self.quarantined(),
+            cached,
+        };
+        self.meta.read().unwrap().put_reserved(
+            ALLOCATOR_STATE_KEY,
+            Some(&bincode::serialize(&state).unwrap()),
+        );
+        if self.config.compact_on_unmount {
+            self.meta.read().unwrap().compact();
+            // a journal sharing the metadata store was compacted along with it
+            if self.config.wal.is_some() {
+                self.journal.compact();
+            }
+        }
+    }
+    /// Creates the regular file `name` in `parent` with permissions `perm`, returning its inode
+    /// number.
+    pub fn create_file(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+        perm: u16,
+    ) -> Result<u64, c_int> {
+        self.new_with_parent(uid, gid, parent, name, |n| {
+            n.perm = perm;
+            n.ino
+        })
+    }
+    /// Creates the directory `name` in `parent`.
+    pub fn make_dir(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<fuser::FileAttr, c_int> {
+        let block_size = self.block_size;
+        self.new_with_parent(uid, gid, parent, name, |n| {
+            n.kind = FileType::Directory;
+            n.nlink = 2;
+            n.file_attr(block_size)
+        })
+    }
+    /// Creates the node `name` in `parent`, a regular file, device, FIFO or socket as the file
+    /// type bits of `mode` say. Only device nodes keep `rdev`.
+    pub fn make_node(
+        &mut self,
+        uid: u32,
+        gid: u32,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+    ) -> Result<fuser::FileAttr, c_int> {
+        let kind = match mode & libc::S_IFMT {
+            libc::S_IFREG => FileType::RegularFile,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            libc::S_IFIFO => FileType::Fifo,
+            libc::S_IFSOCK => FileType::Socket,
+            _ => return Err(libc::EINVAL),
+        };
+        let block_size = self.block_size;
+        self.new_with_parent(uid, gid, parent, name, |n| {
+            n.perm = (mode & !libc::S_IFMT) as u16;
+            n.kind = kind;
+            if matches!(kind, FileType::CharDevice | FileType::BlockDevice) {
+                n.rdev = rdev;
+            }
+            n.file_attr(block_size)
+        })
+    }
+    /// Links `ino` into `newparent` as `newname`. Directories cannot be linked.
+    pub fn link_entry(
+        &mut self,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<fuser::FileAttr, c_int> {
+        let block_size = self.block_size;
+        self.transaction(&[ino, newparent], |fs, _| {
+            let kind = fs.read_inode(ino, |i| i.kind)?;
+            // hard links to directories would make the tree a graph
+            if kind == FileType::Directory {
+                return Err(libc::EPERM);
+            }
+            // the entry goes in first, so a name that is taken or invalid leaves the count alone
+            fs.insert_dirent(newparent, newname, DirEntry { ino, kind })?;
+            fs.meta.write().unwrap().modify(ino, |i| {
+                i.nlink += 1;
+                i.file_attr(block_size)
+            })
+        })
+    }
+    /// Moves the entry `name` of `parent` to `newname` of `newparent` as one transaction,
+    /// honoring the `RENAME_NOREPLACE` and `RENAME_EXCHANGE` flags.
+    pub fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), c_int> {
+        let mut inos = self.dirent_inos(parent, name);
+        inos.extend(self.dirent_inos(newparent, newname));
+        self.transaction(&inos, |fs, _| match flags {
+            0 => fs.rename_dirent(parent, name, newparent, newname),
+            libc::RENAME_NOREPLACE => match fs.lookup_dirent(newparent, newname) {
+                Ok(_) => Err(libc::EEXIST),
+                Err(libc::ENOENT) => fs.rename_dirent(parent, name, newparent, newname),
+                Err(err) => Err(err),
+            },
+            libc::RENAME_EXCHANGE => fs.exchange_dirents(parent, name, newparent, newname),
+            _ => Err(libc::EINVAL),
+        })
+    }
+    /// Attributes of the entry `name` of `parent`.
+    pub fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> Result<fuser::FileAttr, c_int> {
+        let ent = self.lookup_dirent(parent, name)?;
+        let attrs = self.read_inode(ent.ino, |e| e.file_attr(self.block_size))?;
+        Ok(self.report(attrs))
+    }
+    /// Entries of the directory `ino`, starting with "." and "..".
+    pub fn read_dir(&mut self, ino: u64) -> Result<Vec<(String, DirEntry)>, c_int> {
+        let entries = self.read_inode(ino, |i| match i.kind {
+            FileType::Directory => {
+                let dir = |ino| DirEntry {
+                    ino,
+                    kind: FileType::Directory,
+                };
+                // a parent lost to an upgrade from an older record is reported as the directory
+                // itself, the kernel resolves ".." on its own anyway
+                let parent = if i.parent == 0 { ino } else { i.parent };
+                let mut entries =
+                    vec![(".".to_string(), dir(ino)), ("..".to_string(), dir(parent))];
+                entries.extend(
+                    i.entries
+                        .iter()
+                        .map(|(name, entry)| (name.clone(), entry.clone())),
+                );
+                Ok(entries)
+            }
+            _ => Err(libc::ENOTDIR),
+        });
+        entries.and_then(|r| r)
+    }
+    /// Opens the directory `ino` for a scan, snapshotting its entries, and returns the handle.
+    pub fn open_dir(&mut self, ino: u64) -> Result<u64, c_int> {
+        let entries = self.read_dir(ino)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.dirs.insert(fh, OpenDir { ino, entries });
+        Ok(fh)
+    }
+    /// Entries of the scan of `ino` through handle `fh` from `offset` on. Offsets index into the
+    /// snapshot taken by [`open_dir`](Self::open_dir), which stays put for the lifetime of the
+    /// handle.
+    pub fn dir_entries(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<&[(String, DirEntry)], c_int> {
+        match self.dirs.get(&fh) {
+            Some(dir) if dir.ino == ino => {
+                let offset = std::cmp::min(offset as usize, dir.entries.len());
+                Ok(&dir.entries[offset..])
+            }
+            _ => Err(libc::EBADF),
+        }
+    }
+    /// Like [`dir_entries`](Self::dir_entries), along with the attributes of each entry as getattr
+    /// reports them and the offset following it. Attributes are read as the entries are consumed,
+    /// and entries removed since the snapshot are left out.
+    pub fn dir_entries_plus(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<impl Iterator<Item = (i64, &str, fuser::FileAttr)> + '_, c_int> {
+        let entries = self.dir_entries(ino, fh, offset)?;
+        Ok(entries
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, (name, entry))| {
+                let attrs = self.read_inode(entry.ino, |i| i.file_attr(self.block_size));
+                let attrs = self.report(attrs.ok()?);
+                Some((offset + index as i64 + 1, name.as_str(), attrs))
+            }))
+    }
+    /// Ends the directory scan through handle `fh`.
+    pub fn close_dir(&mut self, fh: u64) -> Result<(), c_int> {
+        self.dirs.remove(&fh).map(|_| ()).ok_or(libc::EBADF)
+    }
+    /// Sets the extended attribute `name` of `ino` to `value`, honoring the XATTR_CREATE and
+    /// XATTR_REPLACE `flags`. Names have to be valid UTF-8.
+    pub fn set_xattr(
+        &mut self,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<(), c_int> {
+        if name == EXTENTS_XATTR {
+            return Err(libc::EPERM);
+        }
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        let res = self.meta.write().unwrap().modify(ino, |i| {
+            match i.xattrs.contains_key(name) {
+                true if flags & XATTR_CREATE != 0 => return Err(libc::EEXIST),
+                false if flags & XATTR_REPLACE != 0 => return Err(libc::ENODATA),
+                _ => {}
+            }
+            i.xattrs.insert(name.to_string(), value.to_vec());
+            i.ctime = SystemTime::now();
+            Ok(())
+        });
+        res.and_then(|r| r)
+    }
+    /// Value of the extended attribute `name` of `ino`, including the generated
+    /// [`EXTENTS_XATTR`].
+    pub fn get_xattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        if name == EXTENTS_XATTR {
+            return self.read_inode(ino, |i| {
+                i.extents
+                    .iter()
+                    .map(|(start, e)| format!("{} {} {}\n", start, e.start, e.len()))
+                    .collect::<String>()
+                    .into_bytes()
+            });
+        }
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        self.read_inode(ino, |i| i.xattrs.get(name).cloned())?
+            .ok_or(libc::ENODATA)
+    }
+    /// Removes the extended attribute `name` of `ino`.
+    pub fn remove_xattr(&mut self, ino: u64, name: &OsStr) -> Result<(), c_int> {
+        if name == EXTENTS_XATTR {
+            return Err(libc::EPERM);
+        }
+        let name = name.to_str().ok_or(libc::EINVAL)?;
+        let removed = self.meta.write().unwrap().modify(ino, |i| {
+            let removed = i.xattrs.remove(name);
+            i.ctime = SystemTime::now();
+            removed
+        })?;
+        removed.map(|_| ()).ok_or(libc::ENODATA)
+    }
+    /// Removes the entry `name` of `parent`, which must not be a directory, deleting its inode
+    /// with the last link unless it is still open or looked up.
+    pub fn unlink_entry(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let inos = self.dirent_inos(parent, name);
+        self.transaction(&inos, |fs, _| match fs.lookup_dirent(parent, name) {
+            // directories are only ever removed through rmdir
+            Ok(ent) if ent.kind == FileType::Directory => Err(libc::EISDIR),
+            Ok(_) => fs
+                .remove_dirent(parent, name)
+                .and_then(|ent| fs.drop_link(ent.ino)),
+            Err(err) => Err(err),
+        })
+    }
+    pub fn exchange_dirents(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<(), c_int> {
+        // resolve both sides before touching anything so a failed lookup leaves the tree intact
+        let old = self.lookup_dirent(parent, name)?;
+        let new = self.lookup_dirent(newparent, newname)?;
+        let mut meta = self.meta.write().unwrap();
+        meta.modify(parent, |p| {
+            p.entries
+                .insert(entry_name(name).unwrap().to_string(), new.clone());
+        })?;
+        meta.modify(newparent, |p| {
+            p.entries
+                .insert(entry_name(newname).unwrap().to_string(), old.clone());
+        })?;
+        if parent != newparent {
+            meta.modify(old.ino, |i| i.parent = newparent)?;
+            meta.modify(new.ino, |i| i.parent = parent)?;
+            // a directory carries the ".." link of its parent along with it
+            let moved =
+                (old.kind == FileType::Directory) as i64 - (new.kind == FileType::Directory) as i64;
+            if moved != 0 {
+                meta.modify(parent, |p| p.nlink = (p.nlink as i64 - moved) as u32)?;
+                meta.modify(newparent, |p| p.nlink = (p.nlink as i64 + moved) as u32)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for CyanFS {
+    fn init(&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        // kernels without readdirplus keep issuing a lookup per listed entry
+        config
+            .add_capabilities(fuser::consts::FUSE_DO_READDIRPLUS)
+            .ok();
+        // without these the kernel keeps record and flock locks local to itself
+        config
+            .add_capabilities(fuser::consts::FUSE_POSIX_LOCKS | fuser::consts::FUSE_FLOCK_LOCKS)
+            .ok();
+        self.start(req.uid(), req.gid())?;
+        self.workers = Workers::new(self.config.workers);
+        Ok(())
+    }
+    fn destroy(&mut self) {
+        self.shutdown()
+    }
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.metrics.op(Op::Forget);
+        self.forget_inode(ino, nlookup);
+    }
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        self.metrics.op(Op::Read);
+        if let Err(err) = self.check_fh(ino, fh) {
+            reply.error(err);
+            return;
+        }
+        let core = self.core.clone();
+        self.workers
+            .run(move || match core.read_file(ino, offset as u64, size) {
+                Ok(buf) => reply.data(&buf),
+                Err(err) => reply.error(err),
+            });
+    }
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
+    ) {
+        self.metrics.op(Op::Write);
+        let append = match self.check_fh(ino, fh) {
+            Ok(file) => file.flags & libc::O_APPEND != 0,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+        let offset = if append { None } else { Some(offset as u64) };
+        let core = self.core.clone();
+        let data = data.to_vec();
+        self.workers
+            .run(move || match core.write_file(ino, offset, &data) {
+                Ok(size) => reply.written(size as u32),
+                Err(err) => reply.error(err),
+            });
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.metrics.op(Op::Open);
+        match self.open_file(ino, flags) {
+            Ok(fh) => reply.opened(fh, open_reply_flags(flags)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.metrics.op(Op::Create);
+        let tmpfile = flags & libc::O_TMPFILE == libc::O_TMPFILE;
+        let ino = if tmpfile {
+            // O_TMPFILE opens the directory rather than an entry, and requires write access
+            match flags & libc::O_ACCMODE {
+                libc::O_RDONLY => Err(libc::EINVAL),
+                _ => self.create_tmpfile(req.uid(), req.gid(), parent, (mode & !umask) as u16),
+            }
+        } else {
+            match self.lookup_dirent(parent, name) {
+                Ok(_) if flags & libc::O_EXCL != 0 => Err(libc::EEXIST),
+                Ok(ent) if ent.kind == FileType::Directory => Err(libc::EISDIR),
+                Ok(ent) => Ok(ent.ino),
+                Err(libc::ENOENT) => {
+                    self.create_file(req.uid(), req.gid(), parent, name, (mode & !umask) as u16)
+                }
+                Err(err) => Err(err),
+            }
+        };
+        match ino.and_then(|ino| {
+            let fh = self.open_file(ino, flags & !libc::O_TMPFILE)?;
+            let attrs = self.read_inode(ino, |i| i.file_attr(self.block_size))?;
+            Ok((attrs, fh))
+        }) {
+            Ok((attrs, fh)) => {
+                self.looked_up(attrs.ino);
+                reply.created(
+                    &self.config.entry_timeout,
+                    &attrs,
+                    0,
+                    fh,
+                    open_reply_flags(flags),
+                )
+            }
+            Err(err) => reply.error(err),
+        }
     }
-}
 
-impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
-    fn init(&mut self, req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
-        if self
-            .meta
-            .lock()
-            .unwrap()
-            .read(FUSE_ROOT_ID, |_| {})
-            .is_err()
-        {
-            let mut root = self.new_inode(req, Some(FUSE_ROOT_ID));
-            root.kind = FileType::Directory;
-            self.meta.lock().unwrap().insert(root);
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.metrics.op(Op::Release);
+        match self.release_file(fh, lock_owner) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
         }
-        self.meta.lock().unwrap().flush();
-        self.meta
-            .lock()
-            .unwrap()
-            .scan(|i| {
-                let ino = i.ino as usize;
-                self.inode_allocator.remove(ino as usize..ino + 1);
-                i.extents.clone().into_iter().for_each(|e| {
-                    self.block_allocator.remove(e);
-                })
-            })
-            .unwrap();
-        Ok(())
-    }
-    fn destroy(&mut self) {
-        self.meta.lock().unwrap().flush();
-        self.dev.lock().unwrap().flush();
     }
-    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
-    fn read(
+
+    fn setxattr(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: fuser::ReplyData,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
     ) {
-        match self.meta.lock().unwrap().read(ino, |i| {
-            let mut buf = vec![0u8; size as usize];
-            let size = i
-                .read_at(self.dev.clone(), &mut buf, offset as u64)
-                .unwrap();
-            buf.truncate(size);
-            buf
-        }) {
-            Ok(buf) => reply.data(&buf),
+        self.metrics.op(Op::Setxattr);
+        match self.set_xattr(ino, name, value, flags) {
+            Ok(()) => reply.ok(),
             Err(err) => reply.error(err),
-        };
+        }
     }
-    fn write(
+
+    fn getxattr(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: fuser::ReplyWrite,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
     ) {
-        match self.meta.lock().unwrap().modify(ino, |i| {
-            let new_size = offset as usize + data.len();
-            if new_size > i.size as usize {
-                i.size = new_size as u64;
+        self.metrics.op(Op::Getxattr);
+        match self.get_xattr(ino, name) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.metrics.op(Op::Listxattr);
+        match self.read_inode(ino, |i| {
+            let mut names = vec![];
+            for name in i.xattrs.keys() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
             }
-            let block_cnt = (new_size + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
-            let origi_cnt = i.blocks();
-            if block_cnt > origi_cnt {
-                let cnt = block_cnt - origi_cnt;
-                let begin = self
-                    .block_allocator
-                    .alloc_contiguous(block_cnt - origi_cnt, 0)
-                    .unwrap();
-                i.extents.push(begin..begin + cnt);
-            }
-            i.write_at(self.dev.clone(), data, offset as u64).unwrap()
+            names
         }) {
-            Ok(size) => reply.written(size as u32),
+            Ok(names) => reply_xattr(reply, size, &names),
             Err(err) => reply.error(err),
-        };
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.metrics.op(Op::Removexattr);
+        match self.remove_xattr(ino, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.metrics.op(Op::Ioctl);
+        if let Err(err) = self.check_fh(ino, fh) {
+            reply.error(err);
+            return;
+        }
+        // the argument of the setters, the kernel passes at least an int
+        let arg = in_data
+            .get(..4)
+            .map(|arg| u32::from_ne_bytes(arg.try_into().unwrap()));
+        match cmd {
+            CYANFS_IOC_GET_COMPRESSION => {
+                match self.read_inode(ino, |i| Compression::from_flags(i.flags)) {
+                    Ok(compression) => reply.ioctl(0, &compression.code().to_ne_bytes()),
+                    Err(err) => reply.error(err),
+                }
+            }
+            CYANFS_IOC_SET_COMPRESSION => {
+                let compression = match arg.and_then(Compression::from_code) {
+                    Some(compression) => compression,
+                    None => {
+                        reply.error(libc::EINVAL);
+                        return;
+                    }
+                };
+                match self.update_flags(ino, req.uid(), |flags| Ok(compression.with_flags(flags))) {
+                    Ok(()) => reply.ioctl(0, &[]),
+                    Err(err) => reply.error(err),
+                }
+            }
+            CYANFS_IOC_GET_CHECKSUM => {
+                match self.read_inode(ino, |i| ChecksumKind::from_flags(i.flags)) {
+                    Ok(kind) => reply.ioctl(0, &kind.code().to_ne_bytes()),
+                    Err(err) => reply.error(err),
+                }
+            }
+            CYANFS_IOC_SET_CHECKSUM => {
+                let kind = match arg.and_then(ChecksumKind::from_code) {
+                    Some(kind) => kind,
+                    None => {
+                        reply.error(libc::EINVAL);
+                        return;
+                    }
+                };
+                match self.update_flags(ino, req.uid(), |flags| Ok(kind.with_flags(flags))) {
+                    Ok(()) => reply.ioctl(0, &[]),
+                    Err(err) => reply.error(err),
+                }
+            }
+            CYANFS_IOC_SNAPSHOT => {
+                // a snapshot exposes every file of the filesystem
+                if req.uid() != 0 {
+                    reply.error(libc::EPERM);
+                    return;
+                }
+                let name = in_data.split(|&b| b == 0).next().unwrap_or_default();
+                match self.snapshot(req.uid(), req.gid(), OsStr::from_bytes(name)) {
+                    Ok(_) => reply.ioctl(0, &[]),
+                    Err(err) => reply.error(err),
+                }
+            }
+            CYANFS_IOC_DEFRAG => match self.defrag(ino, fh) {
+                Ok(extents) => reply.ioctl(0, &(extents as u32).to_ne_bytes()),
+                Err(err) => reply.error(err),
+            },
+            FS_IOC_GETFLAGS => match self.fs_flags(ino) {
+                Ok(fs_flags) => reply.ioctl(0, &fs_flags.to_ne_bytes()),
+                Err(err) => reply.error(err),
+            },
+            FS_IOC_SETFLAGS => {
+                let fs_flags = match arg {
+                    Some(fs_flags) => fs_flags,
+                    None => {
+                        reply.error(libc::EINVAL);
+                        return;
+                    }
+                };
+                match self.set_fs_flags(ino, req.uid(), fs_flags) {
+                    Ok(()) => reply.ioctl(0, &[]),
+                    Err(err) => reply.error(err),
+                }
+            }
+            FS_IOC_FIEMAP => match self.fiemap(ino, in_data, out_size) {
+                Ok(out) => reply.ioctl(0, &out),
+                Err(err) => reply.error(err),
+            },
+            _ => reply.error(libc::ENOTTY),
+        }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        match self.meta.lock().unwrap().read(ino, |i| i.into()) {
-            Ok(attrs) => reply.attr(&Duration::new(0, 0), &attrs),
+        self.metrics.op(Op::Getattr);
+        let core = self.core.clone();
+        self.workers.run(
+            move || match core.read_inode(ino, |i| i.file_attr(core.block_size)) {
+                Ok(attrs) => reply.attr(&core.config.attr_timeout, &core.report(attrs)),
+                Err(err) => reply.error(err),
+            },
+        );
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.metrics.op(Op::Opendir);
+        match self.open_dir(ino) {
+            Ok(fh) => reply.opened(fh, 0),
             Err(err) => reply.error(err),
         }
     }
@@ -243,47 +3197,98 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        // TODO: handle error
-        self.meta
-            .lock()
-            .unwrap()
-            .read(ino, |i| {
-                for (index, (name, entry)) in i.entries.iter().skip(offset as usize).enumerate() {
-                    let buffer_full: bool = reply.add(
-                        entry.ino,
-                        offset + index as i64 + 1,
-                        entry.kind.into(),
-                        OsStr::new(&name),
-                    );
-                    if buffer_full {
-                        break;
-                    }
-                }
-                reply.ok();
-            })
-            .unwrap();
+        self.metrics.op(Op::Readdir);
+        let entries = match self.dir_entries(ino, fh, offset) {
+            Ok(entries) => entries,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+        for (index, (name, entry)) in entries.iter().enumerate() {
+            let buffer_full: bool = reply.add(
+                entry.ino,
+                offset + index as i64 + 1,
+                entry.kind.into(),
+                OsStr::new(&name),
+            );
+            if buffer_full {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        self.metrics.op(Op::Readdirplus);
+        let entries = match self.dir_entries_plus(ino, fh, offset) {
+            Ok(entries) => entries,
+            Err(err) => {
+                reply.error(err);
+                return;
+            }
+        };
+        let mut listed = vec![];
+        for (next, name, attrs) in entries {
+            let buffer_full: bool = reply.add(
+                attrs.ino,
+                next,
+                OsStr::new(name),
+                &self.config.entry_timeout,
+                &attrs,
+                0,
+            );
+            if buffer_full {
+                break;
+            }
+            // the kernel takes a reference to every entry but "." and ".."
+            if name != "." && name != ".." {
+                listed.push(attrs.ino);
+            }
+        }
+        listed.into_iter().for_each(|ino| self.looked_up(ino));
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.metrics.op(Op::Releasedir);
+        match self.close_dir(fh) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
 
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        self.metrics.op(Op::Statfs);
+        let s = self.statvfs();
         reply.statfs(
-            u64::MAX,
-            u64::MAX,
-            u64::MAX,
-            0,
-            u64::MAX,
-            BLOCK_SIZE as u32,
-            u32::MAX,
-            BLOCK_SIZE as u32,
+            s.blocks, s.bfree, s.bavail, s.files, s.ffree, s.bsize, s.namelen, s.frsize,
         );
     }
 
-    fn access(&mut self, _req: &Request, ino: u64, _mask: i32, reply: ReplyEmpty) {
-        match self.meta.lock().unwrap().read(ino, |_| {}) {
-            Ok(_) => reply.ok(),
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.metrics.op(Op::Access);
+        match self.read_inode(ino, |i| i.permits(req.uid(), req.gid(), mask)) {
+            Ok(true) => reply.ok(),
+            Ok(false) => reply.error(libc::EACCES),
             Err(err) => reply.error(err),
         }
     }
@@ -293,12 +3298,12 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         _req: &Request<'_>,
         ino: u64,
         mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<SystemTime>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        ctime: Option<SystemTime>,
         _fh: Option<u64>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
@@ -306,16 +3311,37 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        match self.meta.lock().unwrap().modify(ino, |i| {
-            if let Some(size) = size {
-                i.size = size;
+        self.metrics.op(Op::Setattr);
+        if uid.is_some() || gid.is_some() {
+            if let Err(err) = self.change_owner(ino, uid, gid) {
+                reply.error(err);
+                return;
+            }
+        }
+        if let Some(size) = size {
+            if let Err(err) = self.truncate(ino, size) {
+                reply.error(err);
+                return;
             }
+        }
+        let locks = self.locks.clone();
+        let _guard = locks.lock(&[ino]);
+        match self.meta.write().unwrap().modify(ino, |i| {
+            let now = SystemTime::now();
             if let Some(mode) = mode {
                 i.perm = mode as u16;
             }
-            i.into()
+            if let Some(atime) = atime {
+                i.atime = time_or_now(atime, now);
+            }
+            if let Some(mtime) = mtime {
+                i.mtime = time_or_now(mtime, now);
+            }
+            i.ctime = ctime.unwrap_or(now);
+            check_invariants(i, self.dev_blocks);
+            i.file_attr(self.block_size)
         }) {
-            Ok(attrs) => reply.attr(&Duration::new(0, 0), &attrs),
+            Ok(attrs) => reply.attr(&self.config.attr_timeout, &self.report(attrs)),
             Err(err) => reply.error(err),
         }
     }
@@ -326,55 +3352,32 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         name: &OsStr,
         mode: u32,
         umask: u32,
-        _rdev: u32,
+        rdev: u32,
         reply: ReplyEntry,
     ) {
-        let kind = match mode & libc::S_IFMT {
-            libc::S_IFREG => FileType::RegularFile,
-            libc::S_IFCHR | libc::S_IFBLK | libc::S_IFIFO | libc::S_IFSOCK => {
-                reply.error(libc::ENOSYS);
-                return;
-            }
-            _ => {
-                reply.error(libc::EINVAL);
-                return;
+        self.metrics.op(Op::Mknod);
+        match self.make_node(req.uid(), req.gid(), parent, name, mode & !umask, rdev) {
+            Ok(attrs) => {
+                self.looked_up(attrs.ino);
+                reply.entry(&self.config.entry_timeout, &attrs, 0)
             }
-        };
-        match self.new_with_parent(req, parent, name, |n| {
-            n.perm = (mode & !umask) as u16;
-            n.kind = kind;
-            n.into()
-        }) {
-            Ok(attrs) => reply.entry(&Duration::new(0, 0), &attrs, 0),
             Err(err) => reply.error(err),
         }
     }
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        match self.remove_dirent(parent, name) {
-            Ok(ent) => {
-                match self.meta.lock().unwrap().modify(ent.ino, |i| {
-                    i.nlink -= 1;
-                    if i.nlink == 0 {
-                        i.extents.clone().into_iter().for_each(|e| {
-                            self.block_allocator.insert(e);
-                        });
-                        self.inode_allocator.dealloc(i.ino as usize);
-                    }
-                }) {
-                    Ok(_) => reply.ok(),
-                    Err(err) => reply.error(err),
-                }
-            }
+        self.metrics.op(Op::Unlink);
+        match self.unlink_entry(parent, name) {
+            Ok(_) => reply.ok(),
             Err(err) => reply.error(err),
         };
     }
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let ent = self.lookup_dirent(parent, name);
-        match ent {
-            Ok(ent) => match self.meta.lock().unwrap().read(ent.ino, |e| e.into()) {
-                Ok(attrs) => reply.entry(&Duration::new(0, 0), &attrs, 0),
-                Err(err) => reply.error(err),
-            },
+        self.metrics.op(Op::Lookup);
+        match self.lookup_entry(parent, name) {
+            Ok(attrs) => {
+                self.looked_up(attrs.ino);
+                reply.entry(&self.config.entry_timeout, &attrs, 0)
+            }
             Err(err) => reply.error(err),
         }
     }
@@ -387,11 +3390,12 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         _umask: u32,
         reply: ReplyEntry,
     ) {
-        match self.new_with_parent(req, parent, name, |n| {
-            n.kind = FileType::Directory;
-            n.into()
-        }) {
-            Ok(attrs) => reply.entry(&Duration::new(0, 0), &attrs, 0),
+        self.metrics.op(Op::Mkdir);
+        match self.make_dir(req.uid(), req.gid(), parent, name) {
+            Ok(attrs) => {
+                self.looked_up(attrs.ino);
+                reply.entry(&self.config.entry_timeout, &self.report(attrs), 0)
+            }
             Err(err) => reply.error(err),
         }
     }
@@ -403,45 +3407,105 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        let attrs = self.meta.lock().unwrap().modify(ino, |i| {
-            i.nlink += 1;
-            i.to_owned()
-        });
-        match attrs {
+        self.metrics.op(Op::Link);
+        match self.link_entry(ino, newparent, newname) {
             Ok(attrs) => {
-                match self.insert_dirent(
-                    newparent,
-                    newname,
-                    DirEntry {
-                        ino: attrs.ino,
-                        kind: attrs.kind,
-                    },
-                ) {
-                    Ok(_) => reply.entry(&Duration::new(0, 0), &attrs.into(), 0),
-                    Err(err) => reply.error(err),
-                };
+                self.looked_up(attrs.ino);
+                reply.entry(&self.config.entry_timeout, &attrs, 0)
             }
             Err(err) => reply.error(err),
         }
     }
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        match self.remove_dirent(parent, name) {
+        self.metrics.op(Op::Rmdir);
+        let inos = self.dirent_inos(parent, name);
+        match self.transaction(&inos, |fs, _| fs.remove_dir(parent, name)) {
             Ok(_) => reply.ok(),
             Err(err) => reply.error(err),
         }
     }
-    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        self.fsync(req, ino, fh, true, reply)
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        self.metrics.op(Op::Flush);
+        match self.flush_file(ino, fh, lock_owner) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
-    fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        self.meta.lock().unwrap().flush_inode(ino);
-        match self.meta.lock().unwrap().read(ino, |i| {
-            i.fsync(self.dev.clone());
-        }) {
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, datasync: bool, reply: ReplyEmpty) {
+        self.metrics.op(Op::Fsync);
+        match self.sync_file(ino, datasync) {
             Ok(_) => reply.ok(),
             Err(err) => reply.error(err),
         };
     }
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        self.metrics.op(Op::Getlk);
+        if let Err(err) = self.check_fh(ino, fh) {
+            reply.error(err);
+            return;
+        }
+        let lock = RecordLock {
+            start,
+            end,
+            typ,
+            owner: lock_owner,
+            pid,
+        };
+        match self.record_locks.conflict(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, pid),
+        }
+    }
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.metrics.op(Op::Setlk);
+        if let Err(err) = self.check_fh(ino, fh) {
+            reply.error(err);
+            return;
+        }
+        if !matches!(typ, libc::F_RDLCK | libc::F_WRLCK | libc::F_UNLCK) || start > end {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let lock = RecordLock {
+            start,
+            end,
+            typ,
+            owner: lock_owner,
+            pid,
+        };
+        match self.record_locks.set(ino, lock) {
+            Ok(granted) => {
+                reply.ok();
+                granted.into_iter().for_each(|reply| reply.ok());
+            }
+            // answered once the conflicting locks are gone, other requests are served meanwhile
+            Err(libc::EAGAIN) if sleep => self.record_locks.wait(ino, lock, reply),
+            Err(err) => reply.error(err),
+        }
+    }
     fn rename(
         &mut self,
         _req: &Request<'_>,
@@ -449,31 +3513,13 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
-        // TODO: check error
-        if parent == newparent {
-            self.meta
-                .lock()
-                .unwrap()
-                .modify(parent, |p| {
-                    let ent = p.entries.remove(name.to_str().unwrap()).unwrap();
-                    p.entries.insert(newname.to_str().unwrap().to_string(), ent);
-                })
-                .unwrap();
-            reply.ok();
-        } else {
-            let entry = self.remove_dirent(parent, name);
-            if let Err(err) = entry {
-                reply.error(err);
-                return;
-            }
-            if let Err(err) = self.insert_dirent(newparent, newname, entry.unwrap()) {
-                reply.error(err);
-            } else {
-                reply.ok();
-            }
+        self.metrics.op(Op::Rename);
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(_) => reply.ok(),
+            Err(err) => reply.error(err),
         }
     }
     fn symlink(
@@ -484,26 +3530,99 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         link: &std::path::Path,
         reply: ReplyEntry,
     ) {
-        match self.new_with_parent(req, parent, name, |n| {
+        self.metrics.op(Op::Symlink);
+        let block_size = self.block_size;
+        match self.new_with_parent(req.uid(), req.gid(), parent, name, |n| {
             n.kind = FileType::Symlink;
             n.link = link.to_path_buf();
-            n.into()
+            n.file_attr(block_size)
         }) {
-            Ok(attrs) => reply.entry(&Duration::new(0, 0), &attrs, 0),
+            Ok(attrs) => {
+                self.looked_up(attrs.ino);
+                reply.entry(&self.config.entry_timeout, &attrs, 0)
+            }
             Err(err) => reply.error(err),
         }
     }
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyData) {
-        match self
-            .meta
-            .lock()
-            .unwrap()
-            .read(ino, |i| i.link.as_os_str().as_bytes().to_vec())
-        {
+        self.metrics.op(Op::Readlink);
+        match self.read_inode(ino, |i| i.link.as_os_str().as_bytes().to_vec()) {
             Ok(link) => reply.data(&link),
             Err(err) => reply.error(err),
         }
     }
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        self.metrics.op(Op::CopyFileRange);
+        let res = self
+            .check_fh(ino_in, fh_in)
+            .and_then(|_| self.check_fh(ino_out, fh_out))
+            .and_then(|_| self.check_quarantine(ino_in))
+            .and_then(|_| self.check_quarantine(ino_out));
+        if let Err(err) = res {
+            reply.error(err);
+            return;
+        }
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        let len = std::cmp::min(len, u32::MAX as u64);
+        match self.copy_range(ino_in, offset_in as u64, ino_out, offset_out as u64, len) {
+            Ok(copied) => reply.written(copied as u32),
+            Err(err) => reply.error(err),
+        }
+    }
+    fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.metrics.op(Op::Bmap);
+        match self.bmap_block(ino, blocksize, idx) {
+            Ok(block) => reply.bmap(block),
+            Err(err) => reply.error(err),
+        }
+    }
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        self.metrics.op(Op::Lseek);
+        if let Err(err) = self.check_fh(ino, fh) {
+            reply.error(err);
+            return;
+        }
+        if offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        // the kernel resolves the other whence values itself
+        match self.read_inode(ino, |i| match whence {
+            libc::SEEK_DATA => i
+                .next_data(self.block_size, offset as u64)
+                .ok_or(libc::ENXIO),
+            libc::SEEK_HOLE => i
+                .next_hole(self.block_size, offset as u64)
+                .ok_or(libc::ENXIO),
+            _ => Err(libc::EINVAL),
+        }) {
+            Ok(Ok(offset)) => reply.offset(offset as i64),
+            Ok(Err(err)) | Err(err) => reply.error(err),
+        }
+    }
     fn fallocate(
         &mut self,
         _req: &Request<'_>,
@@ -511,27 +3630,13 @@ impl<const BLOCK_SIZE: usize> Filesystem for CyanFS<BLOCK_SIZE> {
         _fh: u64,
         offset: i64,
         length: i64,
-        _mode: i32,
+        mode: i32,
         reply: ReplyEmpty,
     ) {
-        match self.meta.lock().unwrap().modify(ino, |i| {
-            let new_size = offset as usize + length as usize;
-            if new_size > i.size as usize {
-                i.size = new_size as u64;
-            }
-            let block_cnt = (new_size + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
-            let origi_cnt = i.blocks();
-            if block_cnt > origi_cnt {
-                let cnt = block_cnt - origi_cnt;
-                let begin = self
-                    .block_allocator
-                    .alloc_contiguous(block_cnt - origi_cnt, 0)
-                    .unwrap();
-                i.extents.push(begin..begin + cnt);
-            }
-        }) {
-            Ok(_) => reply.ok(),
+        self.metrics.op(Op::Fallocate);
+        match self.fallocate_file(ino, offset, length, mode) {
+            Ok(()) => reply.ok(),
             Err(err) => reply.error(err),
-        };
+        }
     }
 }
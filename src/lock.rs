@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::os::raw::c_int;
+
+/// An advisory POSIX record lock over the inclusive byte range `start..=end`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RecordLock {
+    pub start: u64,
+    pub end: u64,
+    /// F_RDLCK, F_WRLCK or F_UNLCK
+    pub typ: i32,
+    pub owner: u64,
+    pub pid: u32,
+}
+
+impl RecordLock {
+    fn overlaps(&self, other: &RecordLock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+    /// Whether `self` cannot be granted while `other` is held.
+    fn conflicts(&self, other: &RecordLock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other)
+            && (self.typ == libc::F_WRLCK || other.typ == libc::F_WRLCK)
+    }
+}
+
+/// Record locks held on every inode, along with the requests waiting for a conflicting lock to
+/// go away. Waiters are retried in arrival order whenever locks of their inode change.
+pub struct LockTable<W> {
+    held: BTreeMap<u64, Vec<RecordLock>>,
+    waiting: BTreeMap<u64, Vec<(RecordLock, W)>>,
+}
+
+impl<W> LockTable<W> {
+    pub fn new() -> Self {
+        Self {
+            held: BTreeMap::new(),
+            waiting: BTreeMap::new(),
+        }
+    }
+    /// A lock held on `ino` by another owner that keeps `lock` from being granted.
+    pub fn conflict(&self, ino: u64, lock: &RecordLock) -> Option<RecordLock> {
+        self.held
+            .get(&ino)?
+            .iter()
+            .find(|held| lock.conflicts(held))
+            .copied()
+    }
+    /// Sets `lock` on `ino`, replacing the locks its owner holds within its range, or unlocks the
+    /// range for F_UNLCK. Fails with EAGAIN on a conflict, otherwise returns the waiters granted
+    /// their lock as a result.
+    pub fn set(&mut self, ino: u64, lock: RecordLock) -> Result<Vec<W>, c_int> {
+        if lock.typ != libc::F_UNLCK && self.conflict(ino, &lock).is_some() {
+            return Err(libc::EAGAIN);
+        }
+        self.apply(ino, lock);
+        Ok(self.wake(ino))
+    }
+    /// Queues `waiter` until `lock` can be set on `ino`.
+    pub fn wait(&mut self, ino: u64, lock: RecordLock, waiter: W) {
+        self.waiting.entry(ino).or_default().push((lock, waiter));
+    }
+    /// Drops every lock `owner` holds on `ino` along with its waiters. Returns the dropped waiters
+    /// and those granted their lock as a result.
+    pub fn release(&mut self, ino: u64, owner: u64) -> (Vec<W>, Vec<W>) {
+        let mut dropped = vec![];
+        if let Some(waiting) = self.waiting.get_mut(&ino) {
+            let (own, others): (Vec<_>, Vec<_>) = std::mem::take(waiting)
+                .into_iter()
+                .partition(|(lock, _)| lock.owner == owner);
+            *waiting = others;
+            dropped = own.into_iter().map(|(_, waiter)| waiter).collect();
+        }
+        self.apply(
+            ino,
+            RecordLock {
+                start: 0,
+                end: u64::MAX,
+                typ: libc::F_UNLCK,
+                owner,
+                pid: 0,
+            },
+        );
+        (dropped, self.wake(ino))
+    }
+    fn apply(&mut self, ino: u64, lock: RecordLock) {
+        let held = self.held.entry(ino).or_default();
+        let mut kept = vec![];
+        for l in held.drain(..) {
+            if l.owner != lock.owner || !l.overlaps(&lock) {
+                kept.push(l);
+                continue;
+            }
+            // the parts of the owner's lock outside the new range stay as they were
+            if l.start < lock.start {
+                kept.push(RecordLock {
+                    end: lock.start - 1,
+                    ..l
+                });
+            }
+            if l.end > lock.end {
+                kept.push(RecordLock {
+                    start: lock.end + 1,
+                    ..l
+                });
+            }
+        }
+        if lock.typ != libc::F_UNLCK {
+            kept.push(lock);
+        }
+        *held = kept;
+        if held.is_empty() {
+            self.held.remove(&ino);
+        }
+    }
+    fn wake(&mut self, ino: u64) -> Vec<W> {
+        let mut granted = vec![];
+        let mut blocked = vec![];
+        for (lock, waiter) in self.waiting.remove(&ino).unwrap_or_default() {
+            if self.conflict(ino, &lock).is_none() {
+                self.apply(ino, lock);
+                granted.push(waiter);
+            } else {
+                blocked.push((lock, waiter));
+            }
+        }
+        if !blocked.is_empty() {
+            self.waiting.insert(ino, blocked);
+        }
+        granted
+    }
+}
+
+impl<W> Default for LockTable<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(owner: u64, typ: i32, start: u64, end: u64) -> RecordLock {
+        RecordLock {
+            start,
+            end,
+            typ,
+            owner,
+            pid: owner as u32,
+        }
+    }
+
+    #[test]
+    fn exclusive_locks_keep_other_owners_out_until_released() {
+        let mut table = LockTable::new();
+        let first = lock(1, libc::F_WRLCK, 0, 99);
+        assert_eq!(table.set(7, first), Ok(vec![]));
+
+        // only overlapping ranges of the same inode conflict
+        let second = lock(2, libc::F_WRLCK, 50, 60);
+        assert_eq!(table.set(7, second), Err(libc::EAGAIN));
+        assert_eq!(table.conflict(7, &second), Some(first));
+        assert_eq!(
+            table.set(7, lock(2, libc::F_RDLCK, 50, 60)),
+            Err(libc::EAGAIN)
+        );
+        assert_eq!(table.set(7, lock(2, libc::F_WRLCK, 100, 200)), Ok(vec![]));
+        assert_eq!(table.set(8, second), Ok(vec![]));
+
+        // a blocked request is granted once the lock goes away, then keeps the first owner out
+        table.wait(7, second, "second");
+        assert_eq!(table.set(7, lock(1, libc::F_UNLCK, 0, 49)), Ok(vec![]));
+        assert_eq!(
+            table.set(7, lock(1, libc::F_UNLCK, 50, 99)),
+            Ok(vec!["second"])
+        );
+        assert_eq!(
+            table.set(7, lock(1, libc::F_RDLCK, 55, 55)),
+            Err(libc::EAGAIN)
+        );
+        assert_eq!(table.release(7, 2), (vec![], vec![]));
+        assert_eq!(table.set(7, lock(1, libc::F_RDLCK, 55, 55)), Ok(vec![]));
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let mut table = LockTable::<()>::new();
+        assert!(table.set(7, lock(1, libc::F_RDLCK, 0, 99)).is_ok());
+        assert!(table.set(7, lock(2, libc::F_RDLCK, 0, 99)).is_ok());
+        assert_eq!(
+            table.set(7, lock(3, libc::F_WRLCK, 99, 99)),
+            Err(libc::EAGAIN)
+        );
+        // an owner may upgrade its own lock only once nobody else shares the range
+        assert_eq!(
+            table.set(7, lock(1, libc::F_WRLCK, 0, 9)),
+            Err(libc::EAGAIN)
+        );
+        table.release(7, 2);
+        assert!(table.set(7, lock(1, libc::F_WRLCK, 0, 9)).is_ok());
+    }
+}
@@ -1,34 +1,359 @@
+use cyanfs::block_cache::WritebackPolicy;
+use cyanfs::block_dev::BackendKind;
+use cyanfs::checksum::ChecksumKind;
+use cyanfs::compress::Compression;
+use cyanfs::metrics;
+use cyanfs::quota::{Limits, Owner};
+use cyanfs::{AtimePolicy, Config, CorruptBlocks, CyanFS, DirNlink, FlushOnClose, UnbackedReads};
 use fuser::{mount2, MountOption};
-use cyanfs::CyanFS;
+use std::time::Duration;
 
 use argh::FromArgs;
 
 #[derive(FromArgs)]
 /// cyanfs - a poor imitation of Ceph BlueStore
 struct Args {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Mount(MountArgs),
+    Mkfs(MkfsArgs),
+    Fsck(FsckArgs),
+    Quota(QuotaArgs),
+    Upgrade(UpgradeArgs),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "upgrade")]
+/// convert an unmounted filesystem created before data devices carried a superblock, moving the
+/// file data kept in the first block out of its way
+struct UpgradeArgs {
+    /// metadata device
+    #[argh(option)]
+    meta: String,
+    /// data device
+    #[argh(option)]
+    data: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "fsck")]
+/// check and optionally repair the metadata of an unmounted filesystem
+struct FsckArgs {
+    /// metadata device
+    #[argh(option)]
+    meta: String,
+    /// data device or nbd://host[:port][/export] URL, repeated to stripe the data over several
+    /// devices in the given order
+    #[argh(option)]
+    data: Vec<String>,
+    /// metadata journal device, defaults to the metadata device
+    #[argh(option)]
+    wal: Option<String>,
+    /// fix the inconsistencies found
+    #[argh(switch)]
+    repair: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "quota")]
+/// set the space and inode limits of a user or group of an unmounted filesystem
+struct QuotaArgs {
+    /// metadata device
+    #[argh(option)]
+    meta: String,
+    /// data device or nbd://host[:port][/export] URL, repeated to stripe the data over several
+    /// devices in the given order
+    #[argh(option)]
+    data: Vec<String>,
+    /// metadata journal device, defaults to the metadata device
+    #[argh(option)]
+    wal: Option<String>,
+    /// uid whose limits are set
+    #[argh(option)]
+    user: Option<u32>,
+    /// gid whose limits are set
+    #[argh(option)]
+    group: Option<u32>,
+    /// bytes of allocated blocks allowed, 0 for unlimited
+    #[argh(option, default = "0")]
+    bytes: u64,
+    /// number of inodes allowed, 0 for unlimited
+    #[argh(option, default = "0")]
+    inodes: u64,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "mkfs")]
+/// format a data and metadata device
+struct MkfsArgs {
+    /// metadata device
+    #[argh(option)]
+    meta: String,
+    /// data device or nbd://host[:port][/export] URL, repeated to stripe the data over several
+    /// devices in the given order
+    #[argh(option)]
+    data: Vec<String>,
+    /// block size in bytes, a power of two of at least 512
+    #[argh(option, default = "512")]
+    block_size: usize,
+    /// format the data device even if it already holds a filesystem
+    #[argh(switch)]
+    force: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "mount")]
+/// mount a filesystem
+struct MountArgs {
     /// mountpoint
     #[argh(option)]
     mountpoint: String,
     /// metadata device
     #[argh(option)]
     meta: String,
-    /// data device
+    /// data device or nbd://host[:port][/export] URL, repeated to stripe the data over several
+    /// devices in the given order
     #[argh(option)]
-    data: String,
+    data: Vec<String>,
+    /// metadata journal device, defaults to the metadata device
+    #[argh(option)]
+    wal: Option<String>,
     /// whether to create a new filesystem
     #[argh(switch)]
     new: bool,
+    /// with --new, format the data device even if it already holds a filesystem
+    #[argh(switch)]
+    force: bool,
+    /// block size in bytes, defaults to the one recorded on the data device or 512 for a new
+    /// filesystem, mounting fails when it disagrees with the data device
+    #[argh(option)]
+    block_size: Option<usize>,
+    /// number of dirty inodes held before forcing a metadata flush
+    #[argh(option, default = "512")]
+    max_dirty_inodes: usize,
+    /// bytes of memory shared by the block and inode caches
+    #[argh(option)]
+    memory_budget: Option<usize>,
+    /// do not checksum the data blocks of new files
+    #[argh(switch)]
+    no_checksums: bool,
+    /// serve reads of blocks failing checksum verification instead of failing with EIO
+    #[argh(switch)]
+    ignore_corrupt_blocks: bool,
+    /// number of recently modified inodes loaded into the cache at mount time
+    #[argh(option, default = "0")]
+    preload_inodes: usize,
+    /// compression of new files: none, lz4 or zstd, compressed blocks still take up a whole
+    /// block on the data device
+    #[argh(option, default = "Compression::None")]
+    compression: Compression,
+    /// number of blocks read ahead of sequential reads
+    #[argh(option, default = "32")]
+    read_ahead: usize,
+    /// number of contiguous blocks from which I/O bypasses the block cache, 0 disables it
+    #[argh(option, default = "256")]
+    bypass_blocks: usize,
+    /// skip compacting the metadata store on unmount
+    #[argh(switch)]
+    no_compact: bool,
+    /// when modified data reaches the data device: writeback, writethrough or periodic:<seconds>
+    #[argh(option, default = "WritebackPolicy::WriteBack")]
+    writeback: WritebackPolicy,
+    /// report a link count of 1 for directories instead of counting their subdirectories
+    #[argh(switch)]
+    unknown_dir_nlink: bool,
+    /// when reads update access times: noatime, relatime or strictatime
+    #[argh(option, default = "AtimePolicy::RelAtime")]
+    atime: AtimePolicy,
+    /// fail reads of file blocks no extent backs with EIO instead of reading zeros
+    #[argh(switch)]
+    fail_unbacked_reads: bool,
+    /// fsync a file on every close instead of only the close of its last open handle
+    #[argh(switch)]
+    flush_every_close: bool,
+    /// number of extents beyond which punching a hole relocates the remaining data of the file
+    #[argh(option)]
+    reflow_extents: Option<usize>,
+    /// seconds after which dirty inodes are written back in the background, 0 disables it
+    #[argh(option, default = "30")]
+    dirty_expire: u64,
+    /// address to serve metrics in the Prometheus text format on, e.g. 127.0.0.1:9100
+    #[argh(option)]
+    metrics_addr: Option<String>,
+    /// how data blocks are transferred: pread or io_uring
+    #[argh(option, default = "BackendKind::Pread")]
+    io_backend: BackendKind,
+    /// discard freed blocks on the data devices
+    #[argh(switch)]
+    discard: bool,
+    /// seconds the kernel may cache file attributes, 0 revalidates them on every access
+    #[argh(option, default = "1")]
+    attr_timeout: u64,
+    /// seconds the kernel may cache looked up names, 0 revalidates them on every access
+    #[argh(option, default = "1")]
+    entry_timeout: u64,
+    /// enforce the limits set with the quota command
+    #[argh(switch)]
+    quota: bool,
+    /// store fully written blocks with the same contents only once, at the cost of hashing them
+    #[argh(switch)]
+    dedup: bool,
+    /// number of threads serving reads, writes and getattr, 0 serves them on the FUSE session
+    /// thread
+    #[argh(option, default = "4")]
+    workers: usize,
 }
 
-fn main() {
-    simple_logger::SimpleLogger::new().init().unwrap();
-    let args: Args = argh::from_env();
+/// Opens the filesystem, exiting with `code` when that fails.
+fn open(data: &[String], meta: &str, new: bool, config: Config, code: i32) -> CyanFS {
+    match CyanFS::new(data, meta, new, config) {
+        Ok(fs) => fs,
+        Err(err) => {
+            eprintln!("cannot open the filesystem: {}", err);
+            std::process::exit(code);
+        }
+    }
+}
+
+fn mount(args: MountArgs) {
     let options = vec![
         MountOption::FSName("cyanfs".to_string()),
         MountOption::AllowOther,
         MountOption::AutoUnmount,
         MountOption::DefaultPermissions,
     ];
-    let fs: CyanFS<512> = CyanFS::new(&args.data, &args.meta, args.new, 2048, 2048);
+    let config = Config {
+        max_dirty_inodes: args.max_dirty_inodes,
+        memory_budget: args.memory_budget,
+        checksum: if args.no_checksums {
+            ChecksumKind::None
+        } else {
+            ChecksumKind::Crc32c
+        },
+        corrupt_blocks: if args.ignore_corrupt_blocks {
+            CorruptBlocks::Ignore
+        } else {
+            CorruptBlocks::Fail
+        },
+        preload_inodes: args.preload_inodes,
+        compression: args.compression,
+        wal: args.wal,
+        read_ahead: args.read_ahead,
+        bypass_blocks: args.bypass_blocks,
+        compact_on_unmount: !args.no_compact,
+        writeback: args.writeback,
+        io_backend: args.io_backend,
+        discard: args.discard,
+        attr_timeout: Duration::from_secs(args.attr_timeout),
+        entry_timeout: Duration::from_secs(args.entry_timeout),
+        quota: args.quota,
+        dedup: args.dedup,
+        workers: args.workers,
+        dir_nlink: if args.unknown_dir_nlink {
+            DirNlink::Unknown
+        } else {
+            DirNlink::Accurate
+        },
+        atime: args.atime,
+        unbacked_reads: if args.fail_unbacked_reads {
+            UnbackedReads::Fail
+        } else {
+            UnbackedReads::Zeros
+        },
+        flush_on_close: if args.flush_every_close {
+            FlushOnClose::Every
+        } else {
+            FlushOnClose::Last
+        },
+        reflow_extents: args.reflow_extents,
+        dirty_expire: Some(Duration::from_secs(args.dirty_expire)).filter(|d| !d.is_zero()),
+        block_size: args.block_size,
+        ..Default::default()
+    };
+    if args.new && !args.force {
+        if let Err(err) = CyanFS::check_unformatted(&args.data) {
+            eprintln!("mount failed: {}, pass --force to format it anyway", err);
+            std::process::exit(1);
+        }
+    }
+    let fs = open(&args.data, &args.meta, args.new, config, 1);
+    if let Some(addr) = args.metrics_addr {
+        metrics::serve(addr, fs.metrics()).unwrap();
+    }
     mount2(fs, args.mountpoint, &options).unwrap();
 }
+
+/// Checks the filesystem, exiting with 1 when inconsistencies were repaired, 4 when some remain
+/// and 8 when the check itself failed, like e2fsck.
+fn fsck(args: FsckArgs) {
+    let config = Config {
+        wal: args.wal,
+        ..Default::default()
+    };
+    let mut fs = open(&args.data, &args.meta, false, config, 8);
+    let found = match fs.fsck(args.repair) {
+        Ok(found) => found,
+        Err(err) => {
+            eprintln!("fsck failed: {}", std::io::Error::from_raw_os_error(err));
+            std::process::exit(8);
+        }
+    };
+    for inconsistency in &found {
+        println!("{}", inconsistency);
+    }
+    match (found.is_empty(), args.repair) {
+        (true, _) => {}
+        (false, true) => std::process::exit(1),
+        (false, false) => std::process::exit(4),
+    }
+}
+
+fn quota(args: QuotaArgs) {
+    let owner = match (args.user, args.group) {
+        (Some(uid), None) => Owner::User(uid),
+        (None, Some(gid)) => Owner::Group(gid),
+        _ => {
+            eprintln!("quota: exactly one of --user and --group is required");
+            std::process::exit(1);
+        }
+    };
+    let config = Config {
+        wal: args.wal,
+        ..Default::default()
+    };
+    let mut fs = open(&args.data, &args.meta, false, config, 1);
+    fs.set_quota(
+        owner,
+        Limits {
+            bytes: args.bytes,
+            inodes: args.inodes,
+        },
+    );
+}
+
+fn main() {
+    simple_logger::SimpleLogger::new().init().unwrap();
+    let args: Args = argh::from_env();
+    match args.command {
+        Command::Mkfs(args) => {
+            if let Err(err) = CyanFS::mkfs(&args.data, &args.meta, args.block_size, args.force) {
+                eprintln!("mkfs failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        Command::Mount(args) => mount(args),
+        Command::Fsck(args) => fsck(args),
+        Command::Quota(args) => quota(args),
+        Command::Upgrade(args) => {
+            if let Err(err) = CyanFS::upgrade(&args.data, &args.meta) {
+                eprintln!("upgrade failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
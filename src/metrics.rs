@@ -0,0 +1,318 @@
+use crate::allocator::Fragmentation;
+use log::error;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// FUSE operations counted by [`Metrics`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Op {
+    Lookup,
+    Forget,
+    Getattr,
+    Setattr,
+    Readlink,
+    Mknod,
+    Mkdir,
+    Unlink,
+    Rmdir,
+    Symlink,
+    Rename,
+    Link,
+    Open,
+    Read,
+    Write,
+    Flush,
+    Release,
+    Fsync,
+    Getlk,
+    Setlk,
+    Opendir,
+    Readdir,
+    Readdirplus,
+    Releasedir,
+    Statfs,
+    Setxattr,
+    Getxattr,
+    Listxattr,
+    Removexattr,
+    Access,
+    Create,
+    Ioctl,
+    Fallocate,
+    Lseek,
+    CopyFileRange,
+    Bmap,
+}
+
+impl Op {
+    pub const ALL: [Op; 36] = [
+        Op::Lookup,
+        Op::Forget,
+        Op::Getattr,
+        Op::Setattr,
+        Op::Readlink,
+        Op::Mknod,
+        Op::Mkdir,
+        Op::Unlink,
+        Op::Rmdir,
+        Op::Symlink,
+        Op::Rename,
+        Op::Link,
+        Op::Open,
+        Op::Read,
+        Op::Write,
+        Op::Flush,
+        Op::Release,
+        Op::Fsync,
+        Op::Getlk,
+        Op::Setlk,
+        Op::Opendir,
+        Op::Readdir,
+        Op::Readdirplus,
+        Op::Releasedir,
+        Op::Statfs,
+        Op::Setxattr,
+        Op::Getxattr,
+        Op::Listxattr,
+        Op::Removexattr,
+        Op::Access,
+        Op::Create,
+        Op::Ioctl,
+        Op::Fallocate,
+        Op::Lseek,
+        Op::CopyFileRange,
+        Op::Bmap,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Lookup => "lookup",
+            Op::Forget => "forget",
+            Op::Getattr => "getattr",
+            Op::Setattr => "setattr",
+            Op::Readlink => "readlink",
+            Op::Mknod => "mknod",
+            Op::Mkdir => "mkdir",
+            Op::Unlink => "unlink",
+            Op::Rmdir => "rmdir",
+            Op::Symlink => "symlink",
+            Op::Rename => "rename",
+            Op::Link => "link",
+            Op::Open => "open",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Flush => "flush",
+            Op::Release => "release",
+            Op::Fsync => "fsync",
+            Op::Getlk => "getlk",
+            Op::Setlk => "setlk",
+            Op::Opendir => "opendir",
+            Op::Readdir => "readdir",
+            Op::Readdirplus => "readdirplus",
+            Op::Releasedir => "releasedir",
+            Op::Statfs => "statfs",
+            Op::Setxattr => "setxattr",
+            Op::Getxattr => "getxattr",
+            Op::Listxattr => "listxattr",
+            Op::Removexattr => "removexattr",
+            Op::Access => "access",
+            Op::Create => "create",
+            Op::Ioctl => "ioctl",
+            Op::Fallocate => "fallocate",
+            Op::Lseek => "lseek",
+            Op::CopyFileRange => "copy_file_range",
+            Op::Bmap => "bmap",
+        }
+    }
+}
+
+/// Counters shared by the caches and the FUSE handlers, updated without taking any lock.
+pub struct Metrics {
+    block_cache_hits: AtomicU64,
+    block_cache_misses: AtomicU64,
+    inode_cache_hits: AtomicU64,
+    inode_cache_misses: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    ops: [AtomicU64; Op::ALL.len()],
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        // arrays of more than 32 elements do not implement Default
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            block_cache_hits: ZERO,
+            block_cache_misses: ZERO,
+            inode_cache_hits: ZERO,
+            inode_cache_misses: ZERO,
+            bytes_read: ZERO,
+            bytes_written: ZERO,
+            ops: [ZERO; Op::ALL.len()],
+        }
+    }
+}
+
+impl Metrics {
+    pub fn block_cache_access(&self, hit: bool) {
+        let counter = if hit {
+            &self.block_cache_hits
+        } else {
+            &self.block_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn inode_cache_access(&self, hit: bool) {
+        let counter = if hit {
+            &self.inode_cache_hits
+        } else {
+            &self.inode_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn op(&self, op: Op) {
+        self.ops[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    /// Current value of every counter. Counters are read one by one, so the snapshot is only
+    /// consistent while the filesystem is idle.
+    pub fn snapshot(&self) -> FsStats {
+        FsStats {
+            block_cache_hits: self.block_cache_hits.load(Ordering::Relaxed),
+            block_cache_misses: self.block_cache_misses.load(Ordering::Relaxed),
+            inode_cache_hits: self.inode_cache_hits.load(Ordering::Relaxed),
+            inode_cache_misses: self.inode_cache_misses.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            ops: Op::ALL
+                .iter()
+                .map(|&op| (op.name(), self.ops[op as usize].load(Ordering::Relaxed)))
+                .collect(),
+            free_space: None,
+        }
+    }
+}
+
+/// Snapshot of the [`Metrics`] counters, all monotonic since mount, along with the state of the
+/// block allocator when taken by [`CyanFS::stats`](crate::CyanFS::stats).
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct FsStats {
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+    pub inode_cache_hits: u64,
+    pub inode_cache_misses: u64,
+    /// bytes returned by reads
+    pub bytes_read: u64,
+    /// bytes accepted by writes
+    pub bytes_written: u64,
+    /// FUSE requests handled, keyed by operation name
+    pub ops: BTreeMap<&'static str, u64>,
+    /// free space of the data devices, in blocks
+    pub free_space: Option<Fragmentation>,
+}
+
+impl FsStats {
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, samples: &[(String, u64)]| {
+            writeln!(out, "# HELP cyanfs_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE cyanfs_{} counter", name).unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "cyanfs_{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        let cache = |hits: u64, misses: u64| {
+            [
+                ("{result=\"hit\"}".to_string(), hits),
+                ("{result=\"miss\"}".to_string(), misses),
+            ]
+        };
+        counter(
+            "block_cache_lookups_total",
+            "Block cache lookups by result.",
+            &cache(self.block_cache_hits, self.block_cache_misses),
+        );
+        counter(
+            "inode_cache_lookups_total",
+            "Inode cache lookups by result.",
+            &cache(self.inode_cache_hits, self.inode_cache_misses),
+        );
+        counter(
+            "read_bytes_total",
+            "Bytes returned by reads.",
+            &[(String::new(), self.bytes_read)],
+        );
+        counter(
+            "written_bytes_total",
+            "Bytes accepted by writes.",
+            &[(String::new(), self.bytes_written)],
+        );
+        let ops: Vec<(String, u64)> = self
+            .ops
+            .iter()
+            .map(|(op, count)| (format!("{{op=\"{}\"}}", op), *count))
+            .collect();
+        counter(
+            "operations_total",
+            "FUSE requests handled by operation.",
+            &ops,
+        );
+        if let Some(frag) = self.free_space {
+            let mut gauge = |name: &str, help: &str, value: usize| {
+                writeln!(out, "# HELP cyanfs_{} {}", name, help).unwrap();
+                writeln!(out, "# TYPE cyanfs_{} gauge", name).unwrap();
+                writeln!(out, "cyanfs_{} {}", name, value).unwrap();
+            };
+            gauge(
+                "free_block_runs",
+                "Runs of contiguous free data blocks.",
+                frag.free_runs,
+            );
+            gauge(
+                "largest_free_block_run",
+                "Length of the longest run of contiguous free data blocks.",
+                frag.largest_free_run,
+            );
+        }
+        out
+    }
+}
+
+/// Serves `metrics` in the Prometheus text format to every HTTP request on `addr`, from a
+/// background thread.
+pub fn serve<A: ToSocketAddrs>(addr: A, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.and_then(|mut stream| {
+                // the request itself is irrelevant, skip its line and headers
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line)? > 2 {
+                    line.clear();
+                }
+                let body = metrics.snapshot().prometheus();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            });
+            if let Err(err) = res {
+                error!("failed to serve metrics, error {}", err);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,400 @@
+use crate::block_dev::BlockStore;
+use log::error;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Scheme of the data device URLs served over NBD, `nbd://host[:port][/export]`.
+pub const NBD_SCHEME: &str = "nbd://";
+/// Port NBD servers listen on unless the URL names another.
+const DEFAULT_PORT: u16 = 10809;
+/// How long connecting, sending a request or waiting for its reply may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest transfer sent as a single request, servers commonly refuse anything beyond 32MiB.
+const MAX_REQUEST: usize = 1 << 20;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const OPT_EXPORT_NAME: u32 = 1;
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+const FLAG_SEND_TRIM: u16 = 1 << 5;
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+
+struct Connection {
+    stream: TcpStream,
+    /// handle of the next request, only used to match replies
+    handle: u64,
+}
+
+/// Data device exported by a remote NBD server. Requests are sent one at a time; a request that
+/// fails on the network is retried once over a new connection before it fails with EIO.
+pub struct NbdStore {
+    addr: String,
+    export: String,
+    block_size: usize,
+    /// export size in bytes
+    size: u64,
+    /// transmission flags of the export
+    flags: u16,
+    conn: Mutex<Option<Connection>>,
+}
+
+impl NbdStore {
+    /// Connects to the export named by `url`, `nbd://host[:port][/export]`.
+    pub fn connect(url: &str, block_size: usize) -> Result<Self> {
+        let rest = url.strip_prefix(NBD_SCHEME).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not an nbd:// URL", url),
+            )
+        })?;
+        let (host, export) = rest.split_once('/').unwrap_or((rest, ""));
+        let addr = match host.rsplit_once(':') {
+            Some(_) => host.to_string(),
+            None => format!("{}:{}", host, DEFAULT_PORT),
+        };
+        let (stream, size, flags) = handshake(&addr, export)?;
+        if flags & FLAG_READ_ONLY != 0 {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("export of {} is read-only", url),
+            ));
+        }
+        Ok(Self {
+            addr,
+            export: export.to_string(),
+            block_size,
+            size,
+            flags,
+            conn: Mutex::new(Some(Connection { stream, handle: 0 })),
+        })
+    }
+    /// Sends a request of type `cmd` for `len` bytes at `offset`, carrying `data` for writes and
+    /// filling `buf` for reads.
+    fn request(
+        &self,
+        cmd: u16,
+        offset: u64,
+        len: usize,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut retried = false;
+        loop {
+            if conn.is_none() {
+                match handshake(&self.addr, &self.export) {
+                    Ok((stream, _, _)) => *conn = Some(Connection { stream, handle: 0 }),
+                    Err(err) => {
+                        error!(
+                            "failed to reconnect to nbd server {}, error {}",
+                            self.addr, err
+                        );
+                        return Err(Error::from_raw_os_error(libc::EIO));
+                    }
+                }
+            }
+            let c = conn.as_mut().unwrap();
+            c.handle += 1;
+            match transact(&mut c.stream, c.handle, cmd, offset, len, data, buf) {
+                Ok(0) => return Ok(()),
+                // errors reported by the server are errnos, the connection stays usable
+                Ok(err) => return Err(Error::from_raw_os_error(err as i32)),
+                Err(err) => {
+                    // replies may still be in flight, the connection cannot be trusted anymore
+                    *conn = None;
+                    if retried {
+                        error!("nbd request to {} failed, error {}", self.addr, err);
+                        return Err(Error::from_raw_os_error(libc::EIO));
+                    }
+                    retried = true;
+                }
+            }
+        }
+    }
+}
+
+/// Connects to `addr` and selects `export` with the fixed newstyle handshake, returning the
+/// stream ready for requests along with the export size and its transmission flags.
+fn handshake(addr: &str, export: &str) -> Result<(TcpStream, u64, u16)> {
+    let mut last = Error::new(ErrorKind::NotFound, format!("{} resolves to nothing", addr));
+    let mut stream = None;
+    for sock in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock, TIMEOUT) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(err) => last = err,
+        }
+    }
+    let mut stream = stream.ok_or(last)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let invalid =
+        |what: &str| Error::new(ErrorKind::InvalidData, format!("{} from {}", what, addr));
+    if read_u64(&mut stream)? != NBDMAGIC || read_u64(&mut stream)? != IHAVEOPT {
+        return Err(invalid("not a newstyle nbd server"));
+    }
+    let server_flags = read_u16(&mut stream)?;
+    let client_flags = server_flags & (FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES);
+    let mut msg = (client_flags as u32).to_be_bytes().to_vec();
+    msg.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    msg.extend_from_slice(&OPT_EXPORT_NAME.to_be_bytes());
+    msg.extend_from_slice(&(export.len() as u32).to_be_bytes());
+    msg.extend_from_slice(export.as_bytes());
+    stream.write_all(&msg)?;
+    // a server without the export hangs up instead of answering
+    let size = read_u64(&mut stream)?;
+    let flags = read_u16(&mut stream)?;
+    if client_flags & FLAG_NO_ZEROES == 0 {
+        stream.read_exact(&mut [0u8; 124])?;
+    }
+    Ok((stream, size, flags))
+}
+
+/// Sends one request and waits for its reply, returning the error the server reported.
+fn transact(
+    stream: &mut TcpStream,
+    handle: u64,
+    cmd: u16,
+    offset: u64,
+    len: usize,
+    data: &[u8],
+    buf: &mut [u8],
+) -> Result<u32> {
+    let mut msg = Vec::with_capacity(28 + data.len());
+    msg.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    msg.extend_from_slice(&cmd.to_be_bytes());
+    msg.extend_from_slice(&handle.to_be_bytes());
+    msg.extend_from_slice(&offset.to_be_bytes());
+    msg.extend_from_slice(&(len as u32).to_be_bytes());
+    msg.extend_from_slice(data);
+    stream.write_all(&msg)?;
+    let mut reply = [0u8; 16];
+    stream.read_exact(&mut reply)?;
+    let magic = u32::from_be_bytes(reply[0..4].try_into().unwrap());
+    let err = u32::from_be_bytes(reply[4..8].try_into().unwrap());
+    let replied = u64::from_be_bytes(reply[8..16].try_into().unwrap());
+    if magic != SIMPLE_REPLY_MAGIC || replied != handle {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "unexpected reply from nbd server",
+        ));
+    }
+    if err == 0 && cmd == CMD_READ {
+        stream.read_exact(buf)?;
+    }
+    Ok(err)
+}
+
+fn read_u64(stream: &mut TcpStream) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_u16(stream: &mut TcpStream) -> Result<u16> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+impl BlockStore for NbdStore {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
+        let offset = block_id * self.block_size;
+        for (i, piece) in buf.chunks_mut(MAX_REQUEST).enumerate() {
+            let offset = (offset + i * MAX_REQUEST) as u64;
+            self.request(CMD_READ, offset, piece.len(), &[], piece)?;
+        }
+        Ok(())
+    }
+    fn write_batch(&self, reqs: &[(usize, &[u8])]) -> Result<()> {
+        for &(block_id, buf) in reqs {
+            let offset = block_id * self.block_size;
+            for (i, piece) in buf.chunks(MAX_REQUEST).enumerate() {
+                let offset = (offset + i * MAX_REQUEST) as u64;
+                self.request(CMD_WRITE, offset, piece.len(), piece, &mut [])?;
+            }
+        }
+        Ok(())
+    }
+    /// Asks the server to flush its writes, a no-op on servers that cannot.
+    fn sync_data(&self) -> Result<()> {
+        if self.flags & FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(CMD_FLUSH, 0, 0, &[], &mut [])
+    }
+    /// Trims `blocks` on servers that support it, discarding is advisory elsewhere.
+    fn discard(&self, blocks: Range<usize>) -> Result<()> {
+        if self.flags & FLAG_SEND_TRIM == 0 {
+            return Ok(());
+        }
+        let (offset, len) = (
+            blocks.start * self.block_size,
+            blocks.len() * self.block_size,
+        );
+        for start in (0..len).step_by(MAX_REQUEST) {
+            let piece = std::cmp::min(MAX_REQUEST, len - start);
+            self.request(CMD_TRIM, (offset + start) as u64, piece, &[], &mut [])?;
+        }
+        Ok(())
+    }
+    fn size(&self) -> Result<usize> {
+        Ok(self.size as usize / self.block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    const FLAG_HAS_FLAGS: u16 = 1 << 0;
+
+    /// In-memory export served to every connection, hanging up on the next request while
+    /// `hang_up` is set, like a server restarting.
+    struct Server {
+        disk: Mutex<Vec<u8>>,
+        hang_up: AtomicBool,
+    }
+
+    impl Server {
+        /// Starts serving `size` zeroed bytes, returning the server and the port it listens on.
+        fn start(size: usize) -> (Arc<Self>, u16) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = Arc::new(Server {
+                disk: Mutex::new(vec![0; size]),
+                hang_up: AtomicBool::new(false),
+            });
+            let shared = server.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let server = shared.clone();
+                    std::thread::spawn(move || server.serve(stream.unwrap()));
+                }
+            });
+            (server, port)
+        }
+        fn serve(&self, mut stream: TcpStream) -> Result<()> {
+            let mut hello = NBDMAGIC.to_be_bytes().to_vec();
+            hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+            hello.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+            stream.write_all(&hello)?;
+            let mut option = [0u8; 20];
+            stream.read_exact(&mut option)?;
+            let len = u32::from_be_bytes(option[16..20].try_into().unwrap());
+            stream.read_exact(&mut vec![0; len as usize])?;
+            let size = self.disk.lock().unwrap().len() as u64;
+            let mut export = size.to_be_bytes().to_vec();
+            let flags = FLAG_HAS_FLAGS | FLAG_SEND_FLUSH | FLAG_SEND_TRIM;
+            export.extend_from_slice(&flags.to_be_bytes());
+            stream.write_all(&export)?;
+            loop {
+                let mut request = [0u8; 28];
+                stream.read_exact(&mut request)?;
+                if self.hang_up.swap(false, Ordering::SeqCst) {
+                    return Ok(());
+                }
+                let cmd = u16::from_be_bytes(request[6..8].try_into().unwrap());
+                let offset = u64::from_be_bytes(request[16..24].try_into().unwrap()) as usize;
+                let len = u32::from_be_bytes(request[24..28].try_into().unwrap()) as usize;
+                let mut data = vec![0; if cmd == CMD_WRITE { len } else { 0 }];
+                stream.read_exact(&mut data)?;
+                let mut disk = self.disk.lock().unwrap();
+                let (err, payload) = match cmd {
+                    _ if offset + len > disk.len() => (libc::EINVAL as u32, vec![]),
+                    CMD_READ => (0, disk[offset..offset + len].to_vec()),
+                    CMD_WRITE => {
+                        disk[offset..offset + len].copy_from_slice(&data);
+                        (0, vec![])
+                    }
+                    CMD_TRIM => {
+                        disk[offset..offset + len].fill(0);
+                        (0, vec![])
+                    }
+                    CMD_FLUSH => (0, vec![]),
+                    _ => (libc::EINVAL as u32, vec![]),
+                };
+                let mut reply = SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+                reply.extend_from_slice(&err.to_be_bytes());
+                reply.extend_from_slice(&request[8..16]);
+                reply.extend_from_slice(&payload);
+                stream.write_all(&reply)?;
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_round_trip_through_an_nbd_server() {
+        let (server, port) = Server::start(64 * 512);
+        let url = format!("nbd://127.0.0.1:{}/export", port);
+        let store = NbdStore::connect(&url, 512).unwrap();
+        assert_eq!(store.size().unwrap(), 64);
+        // data devices given as nbd:// URLs are opened the same way
+        let opened = crate::block_dev::open(&url, 512, crate::block_dev::BackendKind::Pread);
+        assert_eq!(opened.unwrap().size().unwrap(), 64);
+
+        let data: Vec<u8> = (0..4 * 512).map(|n| (n / 512 + 1) as u8).collect();
+        store
+            .write_batch(&[(2, &data[..]), (10, &[9; 512])])
+            .unwrap();
+        store.sync_data().unwrap();
+        let mut buf = vec![0u8; 4 * 512];
+        store.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(server.disk.lock().unwrap()[10 * 512..11 * 512], [9; 512]);
+        store.discard(3..5).unwrap();
+        store.read_blocks(2, &mut buf).unwrap();
+        assert_eq!(buf[512..3 * 512], [0; 2 * 512]);
+
+        // errors of the server fail the request alone
+        let err = store.read_blocks(63, &mut buf).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        // a dropped connection is replaced
+        server.hang_up.store(true, Ordering::SeqCst);
+        store.read_blocks(10, &mut buf[..512]).unwrap();
+        assert_eq!(buf[..512], [9; 512]);
+    }
+
+    #[test]
+    fn lost_servers_fail_requests_with_eio() {
+        let (_server, port) = Server::start(64 * 512);
+        let store = NbdStore::connect(&format!("nbd://127.0.0.1:{}", port), 512).unwrap();
+        // nothing listens on a port that was just released
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert!(NbdStore::connect(&format!("nbd://{}", gone), 512).is_err());
+        assert!(NbdStore::connect("tcp://127.0.0.1", 512).is_err());
+
+        // without its connection the store has to reconnect, which fails
+        let store = NbdStore {
+            addr: gone.to_string(),
+            conn: Mutex::new(None),
+            ..store
+        };
+        let err = store.write_block(0, &[1; 512]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+}
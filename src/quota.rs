@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::os::raw::c_int;
+
+/// Prefix of the reserved metadata store keys holding the limits of every [`Owner`].
+pub const QUOTA_PREFIX: &[u8] = b"quota/";
+
+/// A user or group that quotas are kept for.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Owner {
+    User(u32),
+    Group(u32),
+}
+
+impl Owner {
+    /// Reserved metadata store key of the limits, [`QUOTA_PREFIX`] followed by `u` or `g` and the
+    /// id in little endian.
+    pub fn key(self) -> Vec<u8> {
+        let (kind, id) = match self {
+            Owner::User(uid) => (b'u', uid),
+            Owner::Group(gid) => (b'g', gid),
+        };
+        [QUOTA_PREFIX, &[kind], &id.to_le_bytes()].concat()
+    }
+    pub fn from_key(key: &[u8]) -> Option<Self> {
+        let rest = key.strip_prefix(QUOTA_PREFIX)?;
+        let id = u32::from_le_bytes(rest.get(1..)?.try_into().ok()?);
+        match rest[0] {
+            b'u' => Some(Owner::User(id)),
+            b'g' => Some(Owner::Group(id)),
+            _ => None,
+        }
+    }
+}
+
+/// Upper bounds on what an owner may use, zero meaning unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Limits {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+/// Space and inodes used by an owner, space counted in whole allocated blocks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+/// Usage of every user and group, held to their limits. Disabled quotas track nothing and never
+/// refuse anything.
+#[derive(Default)]
+pub struct Quotas {
+    enabled: bool,
+    block_size: u64,
+    limits: BTreeMap<Owner, Limits>,
+    usage: BTreeMap<Owner, Usage>,
+}
+
+impl Quotas {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            enabled: true,
+            block_size: block_size as u64,
+            ..Default::default()
+        }
+    }
+    pub fn set_limits(&mut self, owner: Owner, limits: Limits) {
+        self.limits.insert(owner, limits);
+    }
+    pub fn limits(&self, owner: Owner) -> Limits {
+        self.limits.get(&owner).copied().unwrap_or_default()
+    }
+    pub fn usage(&self, owner: Owner) -> Usage {
+        self.usage.get(&owner).copied().unwrap_or_default()
+    }
+    /// Counts an existing inode of `blocks` blocks towards its owners without checking limits.
+    pub fn account(&mut self, uid: u32, gid: u32, blocks: usize) {
+        if self.enabled {
+            self.add(uid, gid, blocks as u64 * self.block_size, 1);
+        }
+    }
+    /// Charges `blocks` newly allocated blocks to the owners, failing with EDQUOT when that
+    /// exceeds a limit of either.
+    pub fn charge_blocks(&mut self, uid: u32, gid: u32, blocks: usize) -> Result<(), c_int> {
+        self.charge(uid, gid, blocks as u64 * self.block_size, 0)
+    }
+    pub fn credit_blocks(&mut self, uid: u32, gid: u32, blocks: usize) {
+        self.credit(uid, gid, blocks as u64 * self.block_size, 0)
+    }
+    /// Charges a new inode to the owners, failing with EDQUOT when that exceeds a limit of
+    /// either.
+    pub fn charge_inode(&mut self, uid: u32, gid: u32) -> Result<(), c_int> {
+        self.charge(uid, gid, 0, 1)
+    }
+    pub fn credit_inode(&mut self, uid: u32, gid: u32) {
+        self.credit(uid, gid, 0, 1)
+    }
+    /// Moves an inode of `blocks` blocks from the owners `from` to the owners `to`, both given as
+    /// uid and gid, failing with EDQUOT when that exceeds a limit of a new owner. Owners kept
+    /// are left alone.
+    pub fn transfer(
+        &mut self,
+        from: (u32, u32),
+        to: (u32, u32),
+        blocks: usize,
+    ) -> Result<(), c_int> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let bytes = blocks as u64 * self.block_size;
+        let moves: Vec<(Owner, Owner)> = [
+            (Owner::User(from.0), Owner::User(to.0)),
+            (Owner::Group(from.1), Owner::Group(to.1)),
+        ]
+        .into_iter()
+        .filter(|(old, new)| old != new)
+        .collect();
+        if moves.iter().any(|&(_, new)| self.exceeds(new, bytes, 1)) {
+            return Err(libc::EDQUOT);
+        }
+        for (old, new) in moves {
+            let usage = self.usage.entry(old).or_default();
+            usage.bytes = usage.bytes.saturating_sub(bytes);
+            usage.inodes = usage.inodes.saturating_sub(1);
+            let usage = self.usage.entry(new).or_default();
+            usage.bytes += bytes;
+            usage.inodes += 1;
+        }
+        Ok(())
+    }
+    /// Whether `bytes` and `inodes` more would exceed a limit of `owner`.
+    fn exceeds(&self, owner: Owner, bytes: u64, inodes: u64) -> bool {
+        let limits = self.limits(owner);
+        let usage = self.usage(owner);
+        let over = |limit: u64, used: u64, more: u64| more > 0 && limit > 0 && used + more > limit;
+        over(limits.bytes, usage.bytes, bytes) || over(limits.inodes, usage.inodes, inodes)
+    }
+    fn charge(&mut self, uid: u32, gid: u32, bytes: u64, inodes: u64) -> Result<(), c_int> {
+        if !self.enabled {
+            return Ok(());
+        }
+        for owner in [Owner::User(uid), Owner::Group(gid)] {
+            if self.exceeds(owner, bytes, inodes) {
+                return Err(libc::EDQUOT);
+            }
+        }
+        self.add(uid, gid, bytes, inodes);
+        Ok(())
+    }
+    fn credit(&mut self, uid: u32, gid: u32, bytes: u64, inodes: u64) {
+        if !self.enabled {
+            return;
+        }
+        for owner in [Owner::User(uid), Owner::Group(gid)] {
+            let usage = self.usage.entry(owner).or_default();
+            usage.bytes = usage.bytes.saturating_sub(bytes);
+            usage.inodes = usage.inodes.saturating_sub(inodes);
+        }
+    }
+    fn add(&mut self, uid: u32, gid: u32, bytes: u64, inodes: u64) {
+        for owner in [Owner::User(uid), Owner::Group(gid)] {
+            let usage = self.usage.entry(owner).or_default();
+            usage.bytes += bytes;
+            usage.inodes += inodes;
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::block_dev::{self, BackendKind, BlockStore, STRIPE_BLOCKS};
+use crate::inode::RECORD_VERSION;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Identifies a formatted data device, "cyanfs" in ASCII.
+pub const MAGIC: u64 = 0x7366_6e61_7963;
+/// On-disk format version written by this build.
+pub const VERSION: u32 = 1;
+/// Blocks at the start of the data device reserved for the superblock.
+pub const SUPERBLOCK_BLOCKS: usize = 1;
+
+/// Header kept in the first block of the data device. Every other data device of the set keeps
+/// a copy in its first block as well, labeling it with its position in the set.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Superblock {
+    pub magic: u64,
+    pub version: u32,
+    pub block_size: u32,
+    /// size of the data devices in global blocks when they were formatted
+    pub blocks: u64,
+    /// newest inode record version the metadata store may hold, zero for superblocks written
+    /// before it was recorded
+    pub inode_version: u32,
+    /// number of data devices the blocks are striped over, zero for superblocks written before
+    /// it was recorded, which span a single device
+    pub devices: u32,
+    /// identifies the devices formatted together, zero for superblocks written before devices
+    /// were labeled, which only keep the copy on the first device
+    pub uuid: [u8; 16],
+    /// position within the set of the device holding this copy
+    pub index: u32,
+}
+
+impl Superblock {
+    pub fn new(block_size: usize, blocks: usize, devices: usize) -> Self {
+        let mut uuid = [0u8; 16];
+        let filled = unsafe { libc::getrandom(uuid.as_mut_ptr().cast(), uuid.len(), 0) };
+        if filled != uuid.len() as isize {
+            // it only has to tell apart the devices of different sets, the time does for that
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            uuid = std::cmp::max(now.as_nanos(), 1).to_le_bytes();
+        }
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            block_size: block_size as u32,
+            blocks: blocks as u64,
+            inode_version: RECORD_VERSION as u32,
+            devices: devices as u32,
+            uuid,
+            index: 0,
+        }
+    }
+    /// Global blocks holding the copies of the superblock, the first block of each device in
+    /// the order of the set.
+    pub fn label_blocks(&self) -> impl Iterator<Item = usize> {
+        let copies = if self.uuid == [0; 16] {
+            1
+        } else {
+            std::cmp::max(self.devices, 1) as usize
+        };
+        // stripe `index` is the first one dealt to device `index`
+        (0..copies).map(|index| index * STRIPE_BLOCKS)
+    }
+    /// Reads the superblock of the first data device at `path`, `None` when it holds none.
+    pub fn probe<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        // the smallest supported block size covers the encoded superblock
+        let dev = block_dev::open(path, 512, BackendKind::Pread)?;
+        Self::read(&*dev)
+    }
+    /// Reads the superblock from global block 0 of `dev`, `None` when it holds none.
+    pub fn read(dev: &dyn BlockStore) -> Result<Option<Self>> {
+        if dev.size()? == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; dev.block_size()];
+        dev.read_block(0, &mut buf)?;
+        Ok(bincode::deserialize::<Self>(&buf)
+            .ok()
+            .filter(|sb| sb.magic == MAGIC))
+    }
+    /// Writes the superblock to global block 0, which is the first block of the first device,
+    /// and its copies to the first blocks of the other devices.
+    pub fn write(&self, dev: &dyn BlockStore) -> Result<()> {
+        for (index, block) in self.label_blocks().enumerate() {
+            let copy = Self {
+                index: index as u32,
+                ..*self
+            };
+            let mut buf = vec![0u8; dev.block_size()];
+            bincode::serialize_into(&mut buf[..], &copy)
+                .map_err(|err| Error::new(ErrorKind::Other, err))?;
+            dev.write_block(block, &buf)?;
+        }
+        dev.sync_data()
+    }
+    /// Checks that each device of `dev` carries a copy of the superblock for its position, so
+    /// devices of another filesystem or given in another order than formatted are refused.
+    pub fn check_labels(&self, dev: &dyn BlockStore) -> Result<()> {
+        let mut buf = vec![0u8; dev.block_size()];
+        for (index, block) in self.label_blocks().enumerate() {
+            dev.read_block(block, &mut buf)?;
+            let label = bincode::deserialize::<Self>(&buf)
+                .ok()
+                .filter(|sb| sb.magic == MAGIC && sb.uuid == self.uuid);
+            match label {
+                Some(label) if label.index == index as u32 => {}
+                Some(label) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "data device {} was formatted as device {} of the set",
+                            index, label.index
+                        ),
+                    ))
+                }
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("data device {} belongs to another filesystem", index),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Checks that the devices were formatted by a compatible version with `block_size` blocks
+    /// striped over `devices` devices and hold no inode records this build cannot read.
+    pub fn check(&self, block_size: usize, devices: usize) -> Result<()> {
+        if self.version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported format version {}", self.version),
+            ));
+        }
+        if self.inode_version > RECORD_VERSION as u32 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "inode records of version {} are newer than the supported {}",
+                    self.inode_version, RECORD_VERSION
+                ),
+            ));
+        }
+        if self.block_size as usize != block_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "formatted with {} byte blocks, not {}",
+                    self.block_size, block_size
+                ),
+            ));
+        }
+        if std::cmp::max(self.devices, 1) as usize != devices {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "formatted with {} data devices, not {}",
+                    std::cmp::max(self.devices, 1),
+                    devices
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+//! The inode and block caches.
+
+use super::*;
+use crate::block_cache::{BlockCache, WritebackPolicy};
+use crate::block_dev::{BackendKind, BlockDevice};
+use crate::faulty::FaultyBlockDevice;
+use crate::{Core, Workers};
+use std::collections::BTreeSet;
+use std::sync::atomic::AtomicUsize;
+use std::time::Instant;
+
+/// Inodes whose records reached the metadata store.
+fn stored(fs: &CyanFS) -> BTreeSet<u64> {
+    let mut inos = BTreeSet::new();
+    fs.meta
+        .write()
+        .unwrap()
+        .scan(|i| {
+            inos.insert(i.ino);
+        })
+        .unwrap();
+    inos
+}
+
+#[test]
+fn dirty_inodes_stay_under_the_cap() {
+    let dev = mem_store(256);
+    let config = || Config {
+        max_dirty_inodes: 8,
+        dirty_expire: None,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config());
+    let mut inos = vec![];
+    for n in 0..100 {
+        inos.push(fs.create(&format!("f{}", n)));
+        assert!(fs.meta.read().unwrap().dirty() <= 8);
+    }
+    // all but the few still dirty were written back while creating the others
+    let written = stored(&fs);
+    assert!(inos.iter().filter(|ino| !written.contains(ino)).count() <= 8);
+
+    let mut fs = fs.remount(dev, config());
+    for (n, ino) in inos.into_iter().enumerate() {
+        let name = format!("f{}", n);
+        assert_eq!(
+            fs.lookup_entry(FUSE_ROOT_ID, OsStr::new(&name))
+                .unwrap()
+                .ino,
+            ino
+        );
+    }
+}
+
+#[test]
+fn scan_sees_every_inode_once() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev, Config::default());
+    // more than one batch of keys
+    let mut created: Vec<u64> = (0..1100).map(|n| fs.create(&format!("f{}", n))).collect();
+    created.push(FUSE_ROOT_ID);
+    created.sort_unstable();
+    let mut seen = vec![];
+    fs.scan_inodes(|i| seen.push(i.ino)).unwrap();
+    seen.sort_unstable();
+    assert_eq!(seen, created);
+}
+
+/// Has `threads` threads stat every inode of `inos` `rounds` times each.
+fn stat_concurrently(core: &Arc<Core>, inos: &Arc<Vec<u64>>, threads: usize, rounds: usize) {
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let (core, inos) = (core.clone(), inos.clone());
+            std::thread::spawn(move || {
+                for _ in 0..rounds {
+                    for &ino in inos.iter() {
+                        core.read_inode(ino, |i| i.file_attr(core.block_size))
+                            .unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+}
+
+#[test]
+fn concurrent_stats_share_the_inode_cache() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let inos: Vec<u64> = (0..64).map(|n| fs.create(&format!("f{}", n))).collect();
+    let inos = Arc::new(inos);
+    let dirty = fs.meta.read().unwrap().dirty();
+    stat_concurrently(&fs.core, &inos, 4, 2);
+    // hits are served under the shared lock without touching the inodes
+    assert_eq!(fs.meta.read().unwrap().dirty(), dirty);
+    let meta = fs.meta.read().unwrap();
+    assert!(inos.iter().all(|&ino| meta.peek(ino, |_| ()).is_ok()));
+}
+
+/// Measures the stat throughput on one and on four threads, run it with `--ignored
+/// --nocapture`.
+#[test]
+#[ignore]
+fn concurrent_stat_throughput() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let inos: Vec<u64> = (0..64).map(|n| fs.create(&format!("f{}", n))).collect();
+    let inos = Arc::new(inos);
+    for threads in [1, 4] {
+        let start = Instant::now();
+        stat_concurrently(&fs.core, &inos, threads, 200);
+        let rate = (threads * 200 * inos.len()) as f64 / start.elapsed().as_secs_f64();
+        println!("stats per second on {} threads: {:.0}", threads, rate);
+    }
+}
+
+#[test]
+fn workers_run_every_queued_job() {
+    let mut workers = Workers::new(4);
+    let done = Arc::new(AtomicUsize::new(0));
+    for _ in 0..100 {
+        let done = done.clone();
+        workers.run(move || {
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    workers.join();
+    assert_eq!(done.load(Ordering::SeqCst), 100);
+}
+
+#[test]
+fn caches_stay_within_the_memory_budget() {
+    let budget = 64 * 1024;
+    let config = Config {
+        memory_budget: Some(budget),
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(mem_store(1024), config);
+    let capacity = fs.config.block_cache * 512 + fs.config.inode_cache * crate::INODE_FOOTPRINT;
+    assert!(capacity <= budget);
+    assert!(fs.config.max_dirty_inodes <= fs.config.inode_cache);
+
+    for n in 0..100 {
+        let ino = fs.create(&format!("f{}", n));
+        fs.write_file(ino, Some(0), &[n as u8; 4 * 512]).unwrap();
+        assert!(fs.memory_usage() <= budget);
+    }
+}
+
+#[test]
+fn preloaded_inodes_are_cache_hits() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let inos: Vec<u64> = (0..20).map(|n| fs.create(&format!("f{}", n))).collect();
+    let preload = |n| Config {
+        preload_inodes: n,
+        ..Config::default()
+    };
+
+    // without preloading every first access loads the record
+    let fs = fs.remount(dev.clone(), preload(0));
+    let before = fs.stats();
+    inos.iter()
+        .for_each(|&ino| fs.read_inode(ino, |_| ()).unwrap());
+    let after = fs.stats();
+    assert_eq!(after.inode_cache_misses - before.inode_cache_misses, 20);
+
+    let fs = fs.remount(dev.clone(), preload(64));
+    let before = fs.stats();
+    inos.iter()
+        .for_each(|&ino| fs.read_inode(ino, |_| ()).unwrap());
+    let after = fs.stats();
+    assert_eq!(after.inode_cache_misses, before.inode_cache_misses);
+    assert_eq!(after.inode_cache_hits - before.inode_cache_hits, 20);
+
+    // preloading is bounded by the cache
+    let config = Config {
+        inode_cache: 8,
+        ..preload(64)
+    };
+    let fs = fs.remount(dev, config);
+    assert!(fs.meta.read().unwrap().len() <= 8);
+}
+
+/// Device reads taken by reading the first `blocks` blocks one at a time, in order, through a
+/// cache fetching `read_ahead` blocks ahead.
+fn sequential_reads(blocks: usize, read_ahead: usize) -> u64 {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, blocks)));
+    let mut cache =
+        BlockCache::with_store(dev.clone(), blocks, WritebackPolicy::WriteBack).unwrap();
+    cache.read_ahead = read_ahead;
+    let mut buf = [0u8; 512];
+    for block in 0..blocks {
+        cache.read_block(block, &mut buf).unwrap();
+    }
+    dev.reads()
+}
+
+#[test]
+fn read_ahead_batches_sequential_reads() {
+    assert_eq!(sequential_reads(256, 0), 256);
+    // the first read only starts the sequence, the other 255 blocks are fetched a window at a time
+    assert_eq!(sequential_reads(256, 16), 1 + 16);
+    assert_eq!(sequential_reads(256, 64), 1 + 4);
+}
+
+#[test]
+fn write_through_blocks_reach_the_device_at_once() {
+    let path = data_file("writethrough", 64 * 512);
+    let mut cache = BlockCache::new(
+        &[&path],
+        512,
+        16,
+        WritebackPolicy::WriteThrough,
+        BackendKind::Pread,
+    )
+    .unwrap();
+    cache.write_block(3, &[7; 512]).unwrap();
+    let dev = BlockDevice::new(&path, 512, BackendKind::Pread).unwrap();
+    let mut buf = [0u8; 512];
+    dev.read_block(3, &mut buf).unwrap();
+    assert_eq!(buf, [7; 512]);
+}
+
+#[test]
+fn periodic_write_back_flushes_on_its_own() {
+    assert!("periodic:0".parse::<WritebackPolicy>().is_err());
+    let zero = Config {
+        writeback: WritebackPolicy::Periodic {
+            interval: Duration::ZERO,
+        },
+        ..Config::default()
+    };
+    let turn = Turn::take();
+    assert!(CyanFS::with_store(mem_store(256), &meta_path(), true, zero).is_err());
+    drop(turn);
+
+    let dev = mem_store(256);
+    let config = Config {
+        writeback: "periodic:1".parse().unwrap(),
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config);
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[7; 512]).unwrap();
+    let block = fs.read_inode(ino, |i| i.extents[&0].start).unwrap();
+    std::thread::sleep(Duration::from_millis(1500));
+    let mut buf = [0u8; 512];
+    dev.read_block(block, &mut buf).unwrap();
+    assert_eq!(buf, [7; 512]);
+
+    // unmounting stops the flusher, blocks dirtied later stay in the cache
+    fs.shutdown();
+    fs.dev
+        .lock()
+        .unwrap()
+        .write_block(block, &[8; 512])
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(1500));
+    dev.read_block(block, &mut buf).unwrap();
+    assert_eq!(buf, [7; 512]);
+}
+
+#[test]
+fn flushing_writes_dirty_blocks_back_and_keeps_them() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 64)));
+    let mut cache = BlockCache::with_store(dev.clone(), 16, WritebackPolicy::WriteBack).unwrap();
+    cache.write_block(3, &[7; 512]).unwrap();
+    cache.write_block(4, &[8; 512]).unwrap();
+    let mut buf = [0u8; 512];
+    dev.read_block(3, &mut buf).unwrap();
+    assert_eq!(buf, [0; 512]);
+
+    cache.flush_block(3).unwrap();
+    dev.read_block(3, &mut buf).unwrap();
+    assert_eq!(buf, [7; 512]);
+    dev.read_block(4, &mut buf).unwrap();
+    assert_eq!(buf, [0; 512]);
+    cache.flush().unwrap();
+    dev.read_block(4, &mut buf).unwrap();
+    assert_eq!(buf, [8; 512]);
+
+    // both stay cached, reading them takes no device read
+    let reads = dev.reads();
+    cache.read_block(3, &mut buf).unwrap();
+    cache.read_block(4, &mut buf).unwrap();
+    assert_eq!((dev.reads(), buf), (reads, [8; 512]));
+}
+
+#[test]
+fn lost_write_backs_fail_later_accesses() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 64)));
+    let mut cache = BlockCache::with_store(dev.clone(), 2, WritebackPolicy::WriteBack).unwrap();
+    cache.write_block(1, &[1; 512]).unwrap();
+    cache.write_block(2, &[2; 512]).unwrap();
+    // the third block evicts the dirty first, whose write-back fails
+    dev.fail_write(1);
+    cache.write_block(3, &[3; 512]).unwrap();
+    assert_eq!(dev.writes(), 1);
+
+    let eio = |r: std::io::Result<()>| r.unwrap_err().raw_os_error() == Some(libc::EIO);
+    let mut buf = [0u8; 512];
+    assert!(eio(cache.read_block(2, &mut buf)));
+    assert!(eio(cache.write_block(4, &[4; 512])));
+    assert!(eio(cache.flush_block(3)));
+    assert!(eio(cache.flush()));
+}
+
+#[test]
+fn expired_dirty_inodes_are_written_back_on_their_own() {
+    let dev = mem_store(256);
+    let config = |dirty_expire| Config {
+        dirty_expire,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config(Some(Duration::from_millis(100))));
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), b"hello").unwrap();
+    assert!(fs.meta.read().unwrap().dirty() > 0);
+    let start = Instant::now();
+    while fs.meta.read().unwrap().dirty() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    // expiring took at least the interval
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // nothing is written back on the way down, yet the size reached the store
+    let fs = fs.crash(dev.clone(), config(None));
+    assert_eq!(fs.read_inode(ino, |i| i.size).unwrap(), 5);
+
+    // without the flusher the change is lost
+    fs.write_file(ino, Some(0), b"hello world").unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    let fs = fs.crash(dev, config(None));
+    assert_eq!(fs.read_inode(ino, |i| i.size).unwrap(), 5);
+}
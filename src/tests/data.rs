@@ -0,0 +1,1263 @@
+//! Reading, writing and resizing file data.
+
+use super::*;
+use crate::block_dev::MemBlockStore;
+use crate::compress::Compression;
+use crate::faulty::FaultyBlockDevice;
+use crate::inode::Extents;
+use crate::{AtimePolicy, Core};
+use std::time::{Instant, SystemTime};
+
+#[test]
+fn truncate_frees_blocks() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[7; 10 * 512]).unwrap();
+    let free = fs.space.lock().unwrap().block_allocator.free();
+    fs.truncate(ino, 2 * 512).unwrap();
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free + 8);
+    assert_eq!(fs.read_inode(ino, |i| i.blocks()).unwrap(), 2);
+    assert_eq!(fs.read_file(ino, 0, 4096).unwrap(), [7; 2 * 512]);
+}
+
+#[test]
+fn writes_and_reads_update_times() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    let created = fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    fs.write_file(ino, Some(0), b"hello").unwrap();
+    let written = fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    assert!(written.mtime > created.mtime);
+    assert!(written.ctime > created.ctime);
+    assert_eq!(written.atime, created.atime);
+
+    // the access time predates the modification, so relatime updates it
+    std::thread::sleep(Duration::from_millis(10));
+    fs.read_file(ino, 0, 512).unwrap();
+    let read = fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    assert!(read.atime > written.mtime);
+    assert_eq!(read.mtime, written.mtime);
+}
+
+/// Access times of `ino` after each of `reads` reads, a little apart.
+fn atimes_after_reads(fs: &CyanFS, ino: u64, reads: usize) -> Vec<SystemTime> {
+    (0..reads)
+        .map(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            fs.read_file(ino, 0, 512).unwrap();
+            fs.read_inode(ino, |i| i.atime).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn reads_update_atime_as_the_policy_says() {
+    for policy in [
+        AtimePolicy::NoAtime,
+        AtimePolicy::RelAtime,
+        AtimePolicy::StrictAtime,
+    ] {
+        let config = Config {
+            atime: policy,
+            ..Config::default()
+        };
+        let mut fs = TestFs::format(mem_store(256), config);
+        let ino = fs.create("f");
+        fs.write_file(ino, Some(0), b"hello").unwrap();
+        let written = fs.read_inode(ino, |i| i.atime).unwrap();
+        let atimes = atimes_after_reads(&fs, ino, 3);
+        match policy {
+            AtimePolicy::NoAtime => assert_eq!(atimes, [written; 3]),
+            // only the first read follows the modification
+            AtimePolicy::RelAtime => {
+                assert!(atimes[0] > written);
+                assert_eq!(atimes[1..], [atimes[0]; 2]);
+            }
+            AtimePolicy::StrictAtime => {
+                assert!(written < atimes[0] && atimes[0] < atimes[1] && atimes[1] < atimes[2]);
+            }
+        }
+
+        // a day old access time is updated by relatime as well
+        let old = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        fs.meta
+            .write()
+            .unwrap()
+            .modify(ino, |i| i.atime = old)
+            .unwrap();
+        let atime = atimes_after_reads(&fs, ino, 1)[0];
+        assert_eq!(atime > old, policy != AtimePolicy::NoAtime);
+    }
+}
+
+#[test]
+fn sequential_writes_extend_one_extent() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    for n in 0..64 {
+        fs.write_file(ino, None, &[n as u8; 512]).unwrap();
+    }
+    let extents = fs.read_inode(ino, |i| i.extents.clone()).unwrap();
+    assert_eq!(extents.len(), 1);
+    assert_eq!(extents[&0].len(), 64);
+}
+
+#[test]
+fn appending_fills_the_partial_block_first() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, None, &[1; 510]).unwrap();
+    let free = fs.space.lock().unwrap().block_allocator.free();
+    for n in 0..4 {
+        fs.write_file(ino, None, &[2]).unwrap();
+        // bytes 510 and 511 still fit the first block, byte 512 starts the second
+        let allocated = if n < 2 { 0 } else { 1 };
+        assert_eq!(
+            fs.space.lock().unwrap().block_allocator.free(),
+            free - allocated
+        );
+    }
+    assert_eq!(fs.read_inode(ino, |i| i.blocks()).unwrap(), 2);
+    let data = fs.read_file(ino, 0, 1024).unwrap();
+    assert_eq!(data.len(), 514);
+    assert!(data[510..].iter().all(|&b| b == 2));
+}
+
+#[test]
+fn failing_to_zero_fresh_blocks_fails_the_write() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let config = Config {
+        // zeros go straight to the device instead of the block cache
+        bypass_blocks: 1,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config);
+    let ino = fs.create("f");
+    let free = fs.space.lock().unwrap().block_allocator.free();
+    // the first and last block are written partially and need zeros underneath
+    dev.fail_write(1);
+    let data = [1; 3 * 512];
+    assert_eq!(fs.write_file(ino, Some(100), &data), Err(libc::EIO));
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free);
+    assert_eq!(
+        fs.read_inode(ino, |i| (i.size, i.blocks())).unwrap(),
+        (0, 0)
+    );
+    assert_eq!(fs.write_file(ino, Some(100), &data), Ok(3 * 512));
+}
+
+#[test]
+fn only_partially_written_fresh_blocks_are_zeroed() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let config = || Config {
+        bypass_blocks: 1,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config());
+    // leave stale contents behind in the free blocks
+    let stale = fs.create("stale");
+    fs.write_file(stale, Some(0), &[0xff; 8 * 512]).unwrap();
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("stale")).unwrap();
+
+    let ino = fs.create("f");
+    let writes = dev.writes();
+    fs.write_file(ino, Some(100), &[1; 3 * 512]).unwrap();
+    // zeros for the first and the last block, then the data
+    let sizes = &dev.write_sizes()[writes as usize..];
+    assert_eq!(sizes[..2], [1, 1]);
+    assert_eq!(sizes[2..].iter().sum::<usize>(), 4);
+
+    fs.truncate(ino, 4 * 512).unwrap();
+    let fs = fs.remount(dev, config());
+    let data = fs.read_file(ino, 0, 4 * 512).unwrap();
+    assert_eq!(data[..100], [0; 100]);
+    assert_eq!(data[100..100 + 3 * 512], [1; 3 * 512]);
+    assert_eq!(data[100 + 3 * 512..], [0; 412]);
+}
+
+#[test]
+fn failing_reads_fail_with_eio() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 4 * 512]).unwrap();
+    // a fresh mount has nothing cached, so the read goes to the device
+    let fs = fs.remount(dev.clone(), Config::default());
+    dev.fail_read(1);
+    assert_eq!(fs.read_file(ino, 0, 4 * 512), Err(libc::EIO));
+    assert_eq!(fs.read_file(ino, 0, 4 * 512).unwrap(), [1; 4 * 512]);
+}
+
+#[test]
+fn writes_span_fragmented_free_space() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    // leave no free run longer than two blocks
+    let mut space = fs.space.lock().unwrap();
+    let free: Vec<usize> = (1..256)
+        .filter(|&b| space.block_allocator.test(b))
+        .collect();
+    for b in free.iter().filter(|&&b| b % 3 == 0) {
+        space.block_allocator.remove(*b..*b + 1);
+    }
+    drop(space);
+    let data: Vec<u8> = (0..16 * 512).map(|n| (n / 512) as u8).collect();
+    fs.write_file(ino, Some(0), &data).unwrap();
+    assert!(fs.read_inode(ino, |i| i.extents.len()).unwrap() >= 8);
+    assert_eq!(fs.read_file(ino, 0, data.len() as u32).unwrap(), data);
+
+    let left = fs.space.lock().unwrap().block_allocator.free();
+    let other = fs.create("g");
+    let err = fs.write_file(other, Some(0), &vec![0; (left + 1) * 512]);
+    assert_eq!(err, Err(libc::ENOSPC));
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), left);
+}
+
+#[test]
+#[should_panic(expected = "is inconsistent")]
+fn debug_builds_panic_on_broken_invariants() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 512]).unwrap();
+    let mut attrs = fs.read_inode(ino, |i| i.clone()).unwrap();
+    attrs.extents.insert(1, 300..301);
+    crate::check_invariants(&attrs, fs.dev_blocks);
+}
+
+#[test]
+fn catches_broken_extent_invariants() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    let mut attrs = fs.read_inode(ino, |i| i.clone()).unwrap();
+    attrs.extents = Extents::from([(0, 10..12), (2, 40..43)]);
+    assert_eq!(attrs.check_invariants(64), Ok(()));
+    // the second extent ends past the device
+    assert!(attrs.check_invariants(42).is_err());
+    // logical blocks 1 and 2 mapped twice
+    attrs.extents.insert(1, 50..52);
+    assert!(attrs.check_invariants(64).is_err());
+    attrs.extents.remove(&1);
+    // physical block 11 mapped twice
+    attrs.extents.insert(10, 11..12);
+    assert!(attrs.check_invariants(64).is_err());
+}
+
+/// Writes `len` bytes at every offset within the second block of a file, comparing each result
+/// with a model of the file.
+fn write_at_every_offset(block_size: usize, len: usize) {
+    let dev = Arc::new(MemBlockStore::new(block_size, 64));
+    let mut fs = TestFs::format(dev, Config::default());
+    assert_eq!(fs.block_size, block_size);
+    let ino = fs.create("f");
+    let mut model = vec![0xffu8; block_size];
+    fs.write_file(ino, Some(0), &model).unwrap();
+    for offset in block_size..2 * block_size {
+        let data: Vec<u8> = (0..len).map(|n| (offset + n) as u8).collect();
+        fs.write_file(ino, Some(offset as u64), &data).unwrap();
+        if model.len() < offset + len {
+            model.resize(offset + len, 0);
+        }
+        model[offset..offset + len].copy_from_slice(&data);
+        let read = fs.read_file(ino, 0, 4 * block_size as u32).unwrap();
+        assert_eq!(read, model, "{} bytes at offset {}", len, offset);
+    }
+}
+
+#[test]
+fn writes_at_every_offset_of_small_blocks() {
+    write_at_every_offset(512, 3);
+    write_at_every_offset(512, 512 + 5);
+}
+
+#[test]
+fn writes_at_every_offset_of_large_blocks() {
+    write_at_every_offset(4096, 3);
+    write_at_every_offset(4096, 4096 + 5);
+}
+
+#[test]
+fn partial_writes_keep_the_rest_of_their_blocks() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    let mut expected: Vec<u8> = (0..2 * 512).map(|n| n as u8).collect();
+    fs.write_file(ino, Some(0), &expected).unwrap();
+
+    // head and tail partial within a single block
+    fs.write_file(ino, Some(1), b"abc").unwrap();
+    expected[1..4].copy_from_slice(b"abc");
+    assert_eq!(fs.read_file(ino, 0, 2 * 512).unwrap(), expected);
+
+    // a partial tail of one block and a partial head of the next
+    fs.write_file(ino, Some(510), b"xyz").unwrap();
+    expected[510..513].copy_from_slice(b"xyz");
+    assert_eq!(fs.read_file(ino, 0, 2 * 512).unwrap(), expected);
+}
+
+#[test]
+fn reads_past_the_extents_return_zeros() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[7; 512]).unwrap();
+    // raising the size leaves a hole no extent backs
+    fs.truncate(ino, 4 * 512 + 100).unwrap();
+    assert_eq!(fs.read_inode(ino, |i| i.blocks()).unwrap(), 1);
+
+    let data = fs.read_file(ino, 0, 8 * 512).unwrap();
+    assert_eq!(data.len(), 4 * 512 + 100);
+    assert_eq!(data[..512], [7; 512]);
+    assert!(data[512..].iter().all(|&b| b == 0));
+    assert_eq!(fs.read_file(ino, 4 * 512, 512).unwrap(), [0; 100]);
+}
+
+#[test]
+fn gaps_left_by_writes_past_the_end_read_zeros() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    // leave stale contents behind in the free blocks
+    let stale = fs.create("stale");
+    fs.write_file(stale, Some(0), &[0xff; 16 * 512]).unwrap();
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("stale")).unwrap();
+
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 100]).unwrap();
+    fs.write_file(ino, Some(5 * 512 + 200), &[2; 100]).unwrap();
+    let check = |fs: &CyanFS| {
+        let data = fs.read_file(ino, 0, 8 * 512).unwrap();
+        assert_eq!(data.len(), 5 * 512 + 300);
+        assert_eq!(data[..100], [1; 100]);
+        // the rest of the first block as well as the blocks in between
+        assert!(data[100..5 * 512 + 200].iter().all(|&b| b == 0));
+        assert_eq!(data[5 * 512 + 200..], [2; 100]);
+    };
+    check(&fs);
+    let fs = fs.remount(dev, Config::default());
+    check(&fs);
+}
+
+#[test]
+fn writes_past_the_end_clear_the_rest_of_the_old_last_block() {
+    use crate::checksum::ChecksumKind;
+    let config = Config {
+        checksum: ChecksumKind::None,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(mem_store(256), config);
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 100]).unwrap();
+    // bytes past the end, as a write whose size was lost in a crash leaves them behind
+    let block = fs.read_inode(ino, |i| i.extents[&0].start).unwrap();
+    let mut buf = [7u8; 512];
+    buf[..100].fill(1);
+    fs.dev.lock().unwrap().write_block(block, &buf).unwrap();
+
+    fs.write_file(ino, Some(3 * 512), &[2; 100]).unwrap();
+    let data = fs.read_file(ino, 0, 4 * 512).unwrap();
+    assert_eq!(data[..100], [1; 100]);
+    assert!(data[100..3 * 512].iter().all(|&b| b == 0));
+    assert_eq!(data[3 * 512..], [2; 100]);
+    // the blocks in between stay a hole
+    let mapped = fs.read_inode(ino, |i| (1..3).any(|l| i.physical(l).is_some()));
+    assert_eq!(mapped, Ok(false));
+}
+
+#[test]
+fn a_block_far_into_the_file_allocates_one_block() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    let free = fs.statvfs().bfree;
+    let gib = 1 << 30;
+    fs.write_file(ino, Some(gib), &[9; 512]).unwrap();
+    assert_eq!(fs.statvfs().bfree, free - 1);
+    assert_eq!(
+        fs.read_inode(ino, |i| (i.size, i.blocks())).unwrap(),
+        (gib + 512, 1)
+    );
+    assert_eq!(fs.read_file(ino, gib - 512, 1024).unwrap()[..512], [0; 512]);
+    assert_eq!(fs.read_file(ino, gib, 512).unwrap(), [9; 512]);
+}
+
+#[test]
+fn compressed_files_round_trip() {
+    for compression in [Compression::Lz4, Compression::Zstd] {
+        let config = Config {
+            compression,
+            ..Config::default()
+        };
+        let mut fs = TestFs::format(mem_store(256), config);
+        let text: Vec<u8> = b"compressible "
+            .iter()
+            .cycle()
+            .take(8 * 512)
+            .copied()
+            .collect();
+        // a linear congruential generator leaves nothing to compress
+        let mut x: u32 = 1;
+        let noise: Vec<u8> = (0..8 * 512)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (x >> 16) as u8
+            })
+            .collect();
+        let compressible = fs.create("compressible");
+        let incompressible = fs.create("incompressible");
+        fs.write_file(compressible, Some(0), &text).unwrap();
+        fs.write_file(incompressible, Some(0), &noise).unwrap();
+
+        let stored = |fs: &TestFs, ino| {
+            fs.read_inode(ino, |i| (i.compressed.len(), i.blocks()))
+                .unwrap()
+        };
+        // compressed blocks are padded to a whole block, they save no space
+        assert_eq!(stored(&fs, compressible), (8, 8));
+        assert_eq!(stored(&fs, incompressible), (0, 8));
+        assert_eq!(fs.read_file(compressible, 0, 8 * 512).unwrap(), text);
+        assert_eq!(fs.read_file(incompressible, 0, 8 * 512).unwrap(), noise);
+        assert_eq!(
+            fs.read_file(compressible, 700, 100).unwrap(),
+            text[700..800]
+        );
+    }
+}
+
+#[test]
+fn reads_never_see_blocks_freed_under_them() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    fs.write_file(a, Some(0), &[0xaa; 64 * 512]).unwrap();
+    let free = fs.space.lock().unwrap().block_allocator.free();
+    let stop = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let core = fs.core.clone();
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                // a truncate may land before or after the read, but never in the middle of it
+                let data = core.read_file(a, 0, 64 * 512).unwrap();
+                assert!(
+                    data.iter().all(|&b| b == 0xaa),
+                    "read the blocks of another file"
+                );
+            }
+        })
+    };
+    for _ in 0..200 {
+        fs.truncate(a, 0).unwrap();
+        fs.write_file(b, Some(0), &[0x55; 64 * 512]).unwrap();
+        fs.truncate(b, 0).unwrap();
+        fs.write_file(a, Some(0), &[0xaa; 64 * 512]).unwrap();
+    }
+    stop.store(true, Ordering::SeqCst);
+    reader.join().unwrap();
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free);
+}
+
+/// Has one thread per inode of `inos` write `blocks` blocks of its own file, each filled with
+/// the low byte of its inode number.
+fn write_concurrently(core: &Arc<Core>, inos: &[u64], blocks: usize) {
+    let handles: Vec<_> = inos
+        .iter()
+        .map(|&ino| {
+            let core = core.clone();
+            std::thread::spawn(move || {
+                for n in 0..blocks {
+                    core.write_file(ino, Some(n as u64 * 512), &[ino as u8; 512])
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+}
+
+#[test]
+fn concurrent_writes_to_distinct_files() {
+    let mut fs = TestFs::format(mem_store(2048), Config::default());
+    let inos: Vec<u64> = (0..4).map(|n| fs.create(&format!("f{}", n))).collect();
+    write_concurrently(&fs.core, &inos, 50);
+    // writers of different files only share the metadata lock while mapping and publishing
+    for &ino in &inos {
+        let data = fs.read_file(ino, 0, 50 * 512).unwrap();
+        assert!(data.len() == 50 * 512 && data.iter().all(|&b| b == ino as u8));
+    }
+}
+
+/// Measures the write throughput of one writer and of four writers of distinct files, run it
+/// with `--ignored --nocapture`.
+#[test]
+#[ignore]
+fn concurrent_write_throughput() {
+    let mut fs = TestFs::format(mem_store(2048), Config::default());
+    let inos: Vec<u64> = (0..5).map(|n| fs.create(&format!("f{}", n))).collect();
+    for writers in [&inos[..1], &inos[1..]] {
+        let start = Instant::now();
+        write_concurrently(&fs.core, writers, 200);
+        let rate = (writers.len() * 200) as f64 / start.elapsed().as_secs_f64();
+        println!(
+            "blocks written per second by {} writers: {:.0}",
+            writers.len(),
+            rate
+        );
+    }
+}
+
+/// FIEMAP query for the whole file asking for up to `count` extents.
+fn fiemap_query(flags: u32, count: u32) -> Vec<u8> {
+    let mut query = vec![0u8; 32];
+    query[8..16].copy_from_slice(&u64::MAX.to_ne_bytes());
+    query[16..20].copy_from_slice(&flags.to_ne_bytes());
+    query[24..28].copy_from_slice(&count.to_ne_bytes());
+    query
+}
+
+#[test]
+fn fiemap_reports_the_mapped_extents() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    // blocks 0 and 1 of `a` are split by a block of `b`, blocks 2 and 3 are a hole
+    fs.write_file(a, Some(0), &[1; 512]).unwrap();
+    fs.write_file(b, Some(0), &[2; 512]).unwrap();
+    fs.write_file(a, Some(512), &[1; 512]).unwrap();
+    fs.write_file(a, Some(4 * 512), &[1; 2 * 512]).unwrap();
+    let extents: Vec<(usize, std::ops::Range<usize>)> = fs
+        .read_inode(a, |i| i.extents.clone().into_iter().collect())
+        .unwrap();
+    assert_eq!(extents.len(), 3);
+    let mapped = |reply: &[u8]| u32::from_ne_bytes(reply[20..24].try_into().unwrap());
+
+    // asking for no extents only counts them
+    let reply = fs.fiemap(a, &fiemap_query(0, 0), 32).unwrap();
+    assert_eq!((reply.len(), mapped(&reply)), (32, 3));
+
+    let reply = fs.fiemap(a, &fiemap_query(1, 8), 32 + 8 * 56).unwrap();
+    assert_eq!((reply.len(), mapped(&reply)), (32 + 3 * 56, 3));
+    for ((logical, e), record) in extents.iter().zip(reply[32..].chunks(56)) {
+        let u64_at = |at: usize| u64::from_ne_bytes(record[at..at + 8].try_into().unwrap());
+        assert_eq!(u64_at(0), *logical as u64 * 512);
+        assert_eq!(u64_at(8), e.start as u64 * 512);
+        assert_eq!(u64_at(16), e.len() as u64 * 512);
+        let last = u32::from_ne_bytes(record[40..44].try_into().unwrap()) & 1 != 0;
+        assert_eq!(last, *logical == 4);
+    }
+
+    // only the extents that fit in the reply are mapped
+    let reply = fs.fiemap(a, &fiemap_query(0, 8), 32 + 56).unwrap();
+    assert_eq!((reply.len(), mapped(&reply)), (32 + 56, 1));
+}
+
+#[test]
+fn bmap_follows_the_extents() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 3 * 512]).unwrap();
+    // another file takes the blocks after the first extent, a hole follows the second
+    let other = fs.create("g");
+    fs.write_file(other, Some(0), &[2; 512]).unwrap();
+    fs.write_file(ino, None, &[3; 2 * 512]).unwrap();
+    fs.write_file(ino, Some(8 * 512), &[4; 512]).unwrap();
+
+    let extents = fs.read_inode(ino, |i| i.extents.clone()).unwrap();
+    assert_eq!(extents.keys().copied().collect::<Vec<_>>(), [0, 3, 8]);
+    for (&start, e) in &extents {
+        for (n, physical) in e.clone().enumerate() {
+            let idx = (start + n) as u64;
+            assert_eq!(fs.bmap_block(ino, 512, idx), Ok(physical as u64));
+        }
+    }
+    let first = fs.bmap_block(ino, 512, 0).unwrap();
+    assert_eq!(fs.bmap_block(ino, 512, 2), Ok(first + 2));
+    assert_ne!(fs.bmap_block(ino, 512, 3), Ok(first + 3));
+    for hole in [5, 7, 9, 100] {
+        assert_eq!(fs.bmap_block(ino, 512, hole), Ok(0));
+    }
+    assert_eq!(fs.bmap_block(ino, 4096, 0), Err(libc::EINVAL));
+}
+
+#[test]
+fn fiemap_sync_fails_with_the_write_back() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 512]).unwrap();
+    dev.fail_write(1);
+    assert_eq!(fs.fiemap(ino, &fiemap_query(1, 0), 32), Err(libc::EIO));
+    assert!(fs.fiemap(ino, &fiemap_query(1, 0), 32).is_ok());
+}
+
+/// Data device recording the order in which writes and syncs reach it, failing syncs while
+/// `fail_sync` is set.
+struct Recorder {
+    inner: MemBlockStore,
+    log: std::sync::Mutex<Vec<&'static str>>,
+    fail_sync: AtomicBool,
+}
+
+impl BlockStore for Recorder {
+    fn block_size(&self) -> usize {
+        self.inner.block_size()
+    }
+    fn read_blocks(&self, block_id: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        self.inner.read_blocks(block_id, buf)
+    }
+    fn write_batch(&self, reqs: &[(usize, &[u8])]) -> std::io::Result<()> {
+        self.log.lock().unwrap().push("write");
+        self.inner.write_batch(reqs)
+    }
+    fn sync_data(&self) -> std::io::Result<()> {
+        if self.fail_sync.load(Ordering::SeqCst) {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO));
+        }
+        self.log.lock().unwrap().push("sync");
+        self.inner.sync_data()
+    }
+    fn discard(&self, blocks: std::ops::Range<usize>) -> std::io::Result<()> {
+        self.inner.discard(blocks)
+    }
+    fn size(&self) -> std::io::Result<usize> {
+        self.inner.size()
+    }
+}
+
+#[test]
+fn fsync_makes_data_durable_before_the_metadata() {
+    let dev = Arc::new(Recorder {
+        inner: MemBlockStore::new(512, 256),
+        log: Default::default(),
+        fail_sync: AtomicBool::new(false),
+    });
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 4 * 512]).unwrap();
+    dev.log.lock().unwrap().clear();
+
+    // data that never became durable leaves the metadata referencing it uncommitted
+    dev.fail_sync.store(true, Ordering::SeqCst);
+    assert_eq!(fs.sync_file(ino, false), Err(libc::EIO));
+    assert!(fs.meta.read().unwrap().data_dirty(ino));
+
+    dev.fail_sync.store(false, Ordering::SeqCst);
+    fs.sync_file(ino, false).unwrap();
+    assert!(!fs.meta.read().unwrap().data_dirty(ino));
+    let log = dev.log.lock().unwrap().clone();
+    assert_eq!(log.first(), Some(&"write"));
+    assert_eq!(log.last(), Some(&"sync"));
+}
+
+#[test]
+fn written_back_inodes_never_reference_unwritten_data() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 4 * 512]).unwrap();
+    let block = fs.read_inode(ino, |i| i.extents[&0].start).unwrap();
+
+    // data that cannot be written keeps the record referencing it out of the store
+    dev.fail_write(1);
+    assert!(!fs.meta.write().unwrap().write_back(ino));
+    assert!(fs.meta.read().unwrap().data_dirty(ino));
+    dev.fail_write(1);
+    assert_eq!(fs.sync_file(ino, false), Err(libc::EIO));
+
+    assert!(fs.meta.write().unwrap().write_back(ino));
+    assert!(!fs.meta.read().unwrap().data_dirty(ino));
+    let mut buf = [0u8; 512];
+    dev.inner().read_block(block, &mut buf).unwrap();
+    assert_eq!(buf, [1; 512]);
+}
+
+#[test]
+fn write_backs_sync_the_device_once_per_batch() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    // records referencing no data never wait for the device
+    let syncs = dev.syncs();
+    let inos: Vec<u64> = (0..4).map(|n| fs.create(&format!("f{}", n))).collect();
+    fs.make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d")).unwrap();
+    fs.meta.write().unwrap().sync();
+    assert_eq!(dev.syncs(), syncs);
+
+    for &ino in &inos {
+        fs.write_file(ino, Some(0), &[1; 2 * 512]).unwrap();
+    }
+    fs.meta.write().unwrap().sync();
+    assert_eq!(dev.syncs(), syncs + 1);
+    let meta = fs.meta.read().unwrap();
+    assert!(inos.iter().all(|&ino| !meta.data_dirty(ino)));
+}
+
+#[test]
+fn block_aligned_writes_read_nothing() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    // data goes straight to the device, so every request is counted
+    let config = || Config {
+        bypass_blocks: 1,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 8 * 512]).unwrap();
+    let fs = fs.remount(dev.clone(), config());
+    let mtime = fs.read_inode(ino, |i| i.mtime).unwrap();
+    let (reads, writes) = (dev.reads(), dev.writes());
+    assert_eq!(fs.write_file(ino, Some(3 * 512), &[]), Ok(0));
+    assert_eq!((dev.reads(), dev.writes()), (reads, writes));
+    assert_eq!(fs.read_inode(ino, |i| i.mtime).unwrap(), mtime);
+
+    // whole blocks, including the last one of the file, are written in one request
+    for (offset, blocks) in [(0, 2), (2 * 512, 3), (5 * 512, 3)] {
+        let reads = dev.reads();
+        fs.write_file(ino, Some(offset), &vec![2; blocks * 512])
+            .unwrap();
+        assert_eq!(dev.reads(), reads);
+        assert_eq!(dev.write_sizes().last(), Some(&blocks));
+    }
+
+    // only the partial head and tail blocks are read
+    let reads = dev.reads();
+    fs.write_file(ino, Some(100), &[3; 3 * 512]).unwrap();
+    assert_eq!(dev.reads(), reads + 2);
+    let reads = dev.reads();
+    fs.write_file(ino, Some(512 + 100), &[3; 100]).unwrap();
+    assert_eq!(dev.reads(), reads + 1);
+}
+
+#[test]
+fn punching_a_hole_frees_its_blocks() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 8 * 512]).unwrap();
+    let free = fs.space.lock().unwrap().block_allocator.free();
+    let before = fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    std::thread::sleep(Duration::from_millis(10));
+    let punch = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    // blocks 2 to 4 are covered whole, the partial blocks 1 and 5 are zeroed in place
+    fs.fallocate_file(ino, 512 + 100, 4 * 512, punch).unwrap();
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free + 3);
+    let after = fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    assert_eq!(after.size, 8 * 512);
+    assert!(after.mtime > before.mtime);
+    assert!(after.ctime > before.ctime);
+    let data = fs.read_file(ino, 0, 8 * 512).unwrap();
+    assert_eq!(data[..612], [1; 612]);
+    assert_eq!(data[612..612 + 4 * 512], [0; 4 * 512]);
+    assert_eq!(data[612 + 4 * 512..], [1; 3 * 512 - 100]);
+
+    assert_eq!(
+        fs.fallocate_file(ino, 0, 512, libc::FALLOC_FL_PUNCH_HOLE),
+        Err(libc::EOPNOTSUPP)
+    );
+}
+
+#[test]
+fn preallocating_with_keep_size_leaves_the_size_alone() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 512]).unwrap();
+    let free = fs.space.lock().unwrap().block_allocator.free();
+
+    fs.fallocate_file(ino, 0, 8 * 512, libc::FALLOC_FL_KEEP_SIZE)
+        .unwrap();
+    let attr = fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    assert_eq!((attr.size, attr.blocks), (512, 8));
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free - 7);
+    assert_eq!(fs.read_file(ino, 0, 8 * 512).unwrap(), [1; 512]);
+
+    // writes past the end of file land in the reserved blocks
+    fs.write_file(ino, Some(512), &[2; 7 * 512]).unwrap();
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free - 7);
+    let attr = fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    assert_eq!((attr.size, attr.blocks), (8 * 512, 8));
+}
+
+#[test]
+fn seeking_finds_data_and_holes() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    // data in blocks 0 and 3, holes in blocks 1, 2, 4 and 5
+    fs.write_file(ino, Some(0), &[1; 512]).unwrap();
+    fs.write_file(ino, Some(3 * 512), &[1; 512]).unwrap();
+    fs.truncate(ino, 6 * 512).unwrap();
+    let seek = |whence, offset| {
+        fs.read_inode(ino, |i| match whence {
+            libc::SEEK_DATA => i.next_data(512, offset),
+            _ => i.next_hole(512, offset),
+        })
+        .unwrap()
+    };
+    let data = [
+        (0, Some(0)),
+        (100, Some(100)),
+        (512, Some(1536)),
+        (1600, Some(1600)),
+    ];
+    for (offset, expected) in data {
+        assert_eq!(seek(libc::SEEK_DATA, offset), expected);
+    }
+    // past the last data and the end of the file there is nothing to find
+    assert_eq!(seek(libc::SEEK_DATA, 2048), None);
+    assert_eq!(seek(libc::SEEK_DATA, 6 * 512), None);
+    let holes = [
+        (0, Some(512)),
+        (600, Some(600)),
+        (1536, Some(2048)),
+        (2100, Some(2100)),
+    ];
+    for (offset, expected) in holes {
+        assert_eq!(seek(libc::SEEK_HOLE, offset), expected);
+    }
+    assert_eq!(seek(libc::SEEK_HOLE, 6 * 512), None);
+}
+
+#[test]
+fn reads_beyond_the_extents_follow_the_unbacked_policy() {
+    for policy in [crate::UnbackedReads::Zeros, crate::UnbackedReads::Fail] {
+        let config = Config {
+            unbacked_reads: policy,
+            ..Config::default()
+        };
+        let mut fs = TestFs::format(mem_store(256), config);
+        let ino = fs.create("f");
+        fs.write_file(ino, Some(0), &[1; 2 * 512]).unwrap();
+        // a size claiming two blocks more than the extents back
+        fs.meta
+            .write()
+            .unwrap()
+            .modify(ino, |i| i.size = 4 * 512)
+            .unwrap();
+        assert_eq!(fs.read_file(ino, 0, 2 * 512).unwrap(), [1; 2 * 512]);
+        let read = fs.read_file(ino, 512, 3 * 512);
+        match policy {
+            crate::UnbackedReads::Zeros => {
+                let data = read.unwrap();
+                assert_eq!(data.len(), 3 * 512);
+                assert_eq!(data[..512], [1; 512]);
+                assert_eq!(data[512..], [0; 2 * 512]);
+            }
+            crate::UnbackedReads::Fail => assert_eq!(read, Err(libc::EIO)),
+        }
+    }
+}
+
+#[test]
+fn copying_ranges_shares_whole_blocks() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    let c = fs.create("c");
+    // blocks 0, 1 and 3 hold data, block 2 is a hole and block 4 is partially filled
+    let mut data = vec![0u8; 4 * 512 + 100];
+    data[..2 * 512].fill(1);
+    data[3 * 512..].fill(2);
+    fs.write_file(a, Some(0), &data[..2 * 512]).unwrap();
+    fs.write_file(a, Some(3 * 512), &data[3 * 512..]).unwrap();
+    let free = fs.space.lock().unwrap().block_allocator.free();
+
+    assert_eq!(fs.copy_range(a, 0, b, 0, 1 << 20), Ok(data.len()));
+    assert_eq!(fs.read_file(b, 0, 8 * 512).unwrap(), data);
+    let physical = |fs: &TestFs, ino, block| fs.read_inode(ino, |i| i.physical(block)).unwrap();
+    for block in [0, 1, 3] {
+        assert_eq!(physical(&fs, b, block), physical(&fs, a, block));
+    }
+    assert_eq!(physical(&fs, b, 2), None);
+    // only the partial last block took a block of its own
+    assert_ne!(physical(&fs, b, 4), physical(&fs, a, 4));
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free - 1);
+
+    // the shared blocks are copied on write
+    fs.write_file(b, Some(0), &[3; 512]).unwrap();
+    assert_eq!(fs.read_file(a, 0, 512).unwrap(), [1; 512]);
+    assert_ne!(physical(&fs, b, 0), physical(&fs, a, 0));
+
+    // offsets at different places within a block leave nothing to share
+    assert_eq!(fs.copy_range(a, 0, c, 100, 2 * 512), Ok(2 * 512));
+    assert_eq!(fs.read_file(c, 100, 2 * 512).unwrap(), [1; 2 * 512]);
+    assert_eq!(fs.read_inode(c, |i| i.size).unwrap(), 100 + 2 * 512);
+    assert_ne!(physical(&fs, c, 0), physical(&fs, a, 0));
+}
+
+#[test]
+fn reflowing_compacts_the_data_around_holes() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    // interleaved writes leave every block of either file in an extent of its own
+    for block in 0..8u64 {
+        let offset = block * 512;
+        fs.write_file(a, Some(offset), &[block as u8 + 1; 512])
+            .unwrap();
+        fs.write_file(b, Some(offset), &[0xff; 512]).unwrap();
+    }
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    fs.fallocate_file(a, 2 * 512, 2 * 512, mode).unwrap();
+    fs.fallocate_file(a, 6 * 512, 512, mode).unwrap();
+    assert_eq!(fs.read_inode(a, |i| i.extents.len()).unwrap(), 5);
+    let free = fs.statvfs().bfree;
+
+    // one extent per stretch of data, the holes staying unallocated
+    assert_eq!(fs.reflow(a), Ok(3));
+    let extents = fs.read_inode(a, |i| i.extents.clone()).unwrap();
+    assert_eq!(extents.keys().copied().collect::<Vec<_>>(), [0, 4, 7]);
+    assert_eq!(extents[&0].end, extents[&4].start);
+    assert_eq!(extents[&4].end, extents[&7].start);
+    assert_eq!(fs.statvfs().bfree, free);
+    let data = fs.read_file(a, 0, 8 * 512).unwrap();
+    for (block, chunk) in data.chunks(512).enumerate() {
+        let expected = if [2, 3, 6].contains(&block) {
+            0
+        } else {
+            block as u8 + 1
+        };
+        assert!(chunk.iter().all(|&b| b == expected), "block {}", block);
+    }
+    assert_eq!(fs.read_file(b, 0, 8 * 512).unwrap(), [0xff; 8 * 512]);
+    // contiguous data is left in place
+    assert_eq!(fs.reflow(a), Ok(3));
+    assert_eq!(fs.read_inode(a, |i| i.extents.clone()).unwrap(), extents);
+}
+
+#[test]
+fn defragmenting_leaves_one_extent_with_the_same_contents() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    // interleaved appends leave every block of either file in an extent of its own
+    for block in 0..32u8 {
+        fs.write_file(a, None, &[block; 512]).unwrap();
+        fs.write_file(b, None, &[0xff; 512]).unwrap();
+    }
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("b")).unwrap();
+    assert_eq!(fs.read_inode(a, |i| i.extents.len()).unwrap(), 32);
+    let free = fs.statvfs().bfree;
+
+    let fh = fs.open_file(a, libc::O_RDONLY).unwrap();
+    assert_eq!(fs.defrag(a, fh), Err(libc::EBADF));
+    fs.release_file(fh, None).unwrap();
+    let fh = fs.open_file(a, libc::O_RDWR).unwrap();
+    assert_eq!(fs.defrag(a, fh), Ok(1));
+    assert_eq!(fs.defrag(a + 1, fh), Err(libc::EBADF));
+    fs.release_file(fh, None).unwrap();
+    assert_eq!(fs.statvfs().bfree, free);
+
+    let fs = fs.remount(dev, Config::default());
+    let extents = fs.read_inode(a, |i| i.extents.clone()).unwrap();
+    assert_eq!(extents.len(), 1);
+    assert_eq!(extents[&0].len(), 32);
+    let data = fs.read_file(a, 0, 32 * 512).unwrap();
+    for (block, chunk) in data.chunks(512).enumerate() {
+        assert!(chunk.iter().all(|&b| b == block as u8), "block {}", block);
+    }
+}
+
+#[test]
+fn synced_files_survive_a_crash() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let synced = fs.create("synced");
+    let unsynced = fs.create("unsynced");
+    fs.write_file(synced, Some(0), &[1; 3 * 512 + 10]).unwrap();
+    fs.write_file(unsynced, Some(0), &[2; 512]).unwrap();
+    fs.sync_file(synced, false).unwrap();
+
+    let fs = fs.crash(dev, Config::default());
+    assert_eq!(fs.read_inode(synced, |i| i.size).unwrap(), 3 * 512 + 10);
+    assert_eq!(fs.read_file(synced, 0, 4 * 512).unwrap(), [1; 3 * 512 + 10]);
+    // the other file only got as far as its creation
+    assert_eq!(fs.read_inode(unsynced, |i| i.size).unwrap(), 0);
+}
+
+#[test]
+fn discarding_punches_freed_blocks_out_of_file_backings() {
+    use crate::block_dev::{BackendKind, BlockDevice};
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks counts 512 byte units whatever the block size of the host filesystem
+    let allocated = |path: &str| std::fs::metadata(path).unwrap().blocks();
+    for discard in [false, true] {
+        let path = data_file("discard", 1 << 20);
+        let dev = Arc::new(BlockDevice::new(&path, 512, BackendKind::Pread).unwrap());
+        let config = Config {
+            discard,
+            ..Config::default()
+        };
+        let mut fs = TestFs::format(dev, config);
+        let ino = fs.create("f");
+        fs.write_file(ino, Some(0), &[7; 128 * 512]).unwrap();
+        fs.sync_file(ino, false).unwrap();
+        let before = allocated(&path);
+        assert!(before >= 128);
+
+        let punch = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        fs.fallocate_file(ino, 8 * 512, 112 * 512, punch).unwrap();
+        fs.sync_file(ino, false).unwrap();
+        // only whole host blocks can be given back
+        if discard {
+            assert!(allocated(&path) <= before - 96);
+        } else {
+            assert!(allocated(&path) >= before);
+        }
+        assert_eq!(fs.read_file(ino, 0, 8 * 512).unwrap(), [7; 8 * 512]);
+        fs.unmount();
+    }
+}
+
+#[test]
+fn owners_set_file_flags_that_read_back() {
+    use crate::checksum::ChecksumKind;
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs
+        .create_file(1000, 1000, FUSE_ROOT_ID, OsStr::new("f"), 0o644)
+        .unwrap();
+    let (noatime, compr) = (crate::FS_NOATIME_FL, crate::FS_COMPR_FL);
+    assert_eq!(fs.fs_flags(ino), Ok(0));
+
+    fs.set_fs_flags(ino, 1000, noatime | compr).unwrap();
+    assert_eq!(fs.fs_flags(ino), Ok(noatime | compr));
+    // compressing takes the default mode, the other flags keep their bits
+    let flags = fs.read_inode(ino, |i| i.flags).unwrap();
+    assert_eq!(Compression::from_flags(flags), Compression::Lz4);
+    assert_eq!(ChecksumKind::from_flags(flags), ChecksumKind::Crc32c);
+    fs.update_flags(ino, 1000, |flags| Ok(ChecksumKind::None.with_flags(flags)))
+        .unwrap();
+    fs.set_fs_flags(ino, 0, noatime).unwrap();
+    let flags = fs.read_inode(ino, |i| i.flags).unwrap();
+    assert_eq!(Compression::from_flags(flags), Compression::None);
+    assert_eq!(ChecksumKind::from_flags(flags), ChecksumKind::None);
+    assert_eq!(fs.fs_flags(ino), Ok(noatime));
+
+    // only the owner and root may change them, and only to supported flags
+    assert_eq!(fs.set_fs_flags(ino, 1001, 0), Err(libc::EPERM));
+    let immutable = 0x10;
+    assert_eq!(fs.set_fs_flags(ino, 1000, immutable), Err(libc::EOPNOTSUPP));
+    assert_eq!(fs.fs_flags(ino), Ok(noatime));
+}
+
+#[test]
+fn writes_past_the_quota_fail() {
+    use crate::quota::{Limits, Owner, Usage};
+    let config = || Config {
+        quota: true,
+        ..Config::default()
+    };
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), config());
+    let ino = fs
+        .create_file(1000, 1000, FUSE_ROOT_ID, OsStr::new("f"), 0o644)
+        .unwrap();
+    let limits = Limits {
+        bytes: 4 * 512,
+        inodes: 1,
+    };
+    fs.set_quota(Owner::User(1000), limits);
+    fs.write_file(ino, Some(0), &[1; 3 * 512]).unwrap();
+    assert_eq!(
+        fs.write_file(ino, Some(3 * 512), &[2; 2 * 512]),
+        Err(libc::EDQUOT)
+    );
+    let used = Usage {
+        bytes: 3 * 512,
+        inodes: 1,
+    };
+    assert_eq!(fs.quota(Owner::User(1000)), (limits, used));
+    assert_eq!(fs.quota(Owner::Group(1000)).1, used);
+    assert_eq!(
+        fs.create_file(1000, 1000, FUSE_ROOT_ID, OsStr::new("g"), 0o644)
+            .err(),
+        Some(libc::EDQUOT)
+    );
+    // other users are not held to it
+    fs.create_file(1001, 1001, FUSE_ROOT_ID, OsStr::new("g"), 0o644)
+        .unwrap();
+
+    // the limits are kept and the usage counted again on the next mount
+    let fs = fs.remount(dev, config());
+    assert_eq!(fs.quota(Owner::User(1000)), (limits, used));
+    fs.truncate(ino, 512).unwrap();
+    fs.write_file(ino, Some(512), &[2; 3 * 512]).unwrap();
+    assert_eq!(fs.quota(Owner::User(1000)).1.bytes, 4 * 512);
+}
+
+#[test]
+fn changing_owners_moves_the_usage() {
+    use crate::quota::{Limits, Owner, Usage};
+    let config = || Config {
+        quota: true,
+        ..Config::default()
+    };
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), config());
+    let ino = fs
+        .create_file(1000, 1000, FUSE_ROOT_ID, OsStr::new("f"), 0o644)
+        .unwrap();
+    fs.write_file(ino, Some(0), &[1; 3 * 512]).unwrap();
+    let used = Usage {
+        bytes: 3 * 512,
+        inodes: 1,
+    };
+    let limits = Limits {
+        bytes: 2 * 512,
+        inodes: 0,
+    };
+    fs.set_quota(Owner::User(1001), limits);
+
+    // the new owner has no room for the file, nothing changes
+    assert_eq!(fs.change_owner(ino, Some(1001), None), Err(libc::EDQUOT));
+    assert_eq!(
+        fs.read_inode(ino, |i| (i.uid, i.gid)).unwrap(),
+        (1000, 1000)
+    );
+    assert_eq!(fs.quota(Owner::User(1000)).1, used);
+
+    // a new group alone leaves the user charged
+    fs.change_owner(ino, None, Some(1002)).unwrap();
+    assert_eq!(fs.quota(Owner::User(1000)).1, used);
+    assert_eq!(fs.quota(Owner::Group(1000)).1, Usage::default());
+    assert_eq!(fs.quota(Owner::Group(1002)).1, used);
+    fs.set_quota(Owner::User(1001), Limits::default());
+    fs.change_owner(ino, Some(1001), None).unwrap();
+    assert_eq!(fs.quota(Owner::User(1000)).1, Usage::default());
+    assert_eq!(fs.quota(Owner::User(1001)).1, used);
+
+    // the ownership sticks and is counted the same on the next mount
+    let fs = fs.remount(dev, config());
+    assert_eq!(
+        fs.read_inode(ino, |i| (i.uid, i.gid)).unwrap(),
+        (1001, 1002)
+    );
+    assert_eq!(fs.quota(Owner::User(1001)).1, used);
+    assert_eq!(fs.quota(Owner::Group(1002)).1, used);
+}
+
+#[test]
+fn identical_blocks_are_stored_once_with_dedup() {
+    let config = Config {
+        dedup: true,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(mem_store(256), config);
+    let free = fs.statvfs().bfree;
+    let content: Vec<u8> = (0..4 * 512).map(|n| (n / 512 + 1) as u8).collect();
+    let a = fs.create("a");
+    let b = fs.create("b");
+    fs.write_file(a, Some(0), &content).unwrap();
+    fs.write_file(b, Some(0), &content).unwrap();
+    assert_eq!(fs.statvfs().bfree, free - 4);
+    let physical = |fs: &TestFs, ino| fs.read_inode(ino, |i| i.physical(0)).unwrap();
+    assert_eq!(physical(&fs, a), physical(&fs, b));
+
+    // writing a shared block leaves the other file alone
+    fs.write_file(b, Some(0), &[9; 512]).unwrap();
+    assert_ne!(physical(&fs, a), physical(&fs, b));
+    assert_eq!(fs.statvfs().bfree, free - 5);
+    assert_eq!(fs.read_file(a, 0, 4 * 512).unwrap(), content);
+    assert_eq!(fs.read_file(b, 0, 512).unwrap(), [9; 512]);
+
+    // shared blocks are only freed along with their last user
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+    assert_eq!(fs.statvfs().bfree, free - 4);
+    assert_eq!(fs.read_file(b, 512, 3 * 512).unwrap(), content[512..]);
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("b")).unwrap();
+    assert_eq!(fs.statvfs().bfree, free);
+}
+
+#[test]
+fn snapshots_keep_the_contents_of_their_time() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let dir = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    let ino = fs.create_file(0, 0, dir, OsStr::new("f"), 0o644).unwrap();
+    fs.write_file(ino, Some(0), &[1; 4 * 512]).unwrap();
+    let free = fs.statvfs().bfree;
+
+    let snap = fs.snapshot(0, 0, OsStr::new("s")).unwrap();
+    assert_eq!(fs.snapshot(0, 0, OsStr::new("s")), Err(libc::EEXIST));
+    let snapshots = fs
+        .lookup_dirent(FUSE_ROOT_ID, OsStr::new(crate::SNAPSHOTS_DIR))
+        .unwrap();
+    assert_eq!(
+        fs.lookup_dirent(snapshots.ino, OsStr::new("s"))
+            .map(|e| e.ino),
+        Ok(snap)
+    );
+    let snap_dir = fs.lookup_dirent(snap, OsStr::new("d")).unwrap();
+    let clone = fs.lookup_dirent(snap_dir.ino, OsStr::new("f")).unwrap().ino;
+    assert_ne!(clone, ino);
+    // the clone shares the blocks of the original
+    assert_eq!(fs.statvfs().bfree, free);
+
+    fs.write_file(ino, Some(512), &[2; 512]).unwrap();
+    fs.truncate(ino, 3 * 512).unwrap();
+    assert_eq!(fs.read_file(clone, 0, 4 * 512).unwrap(), [1; 4 * 512]);
+    let mut data = vec![1; 3 * 512];
+    data[512..2 * 512].fill(2);
+    assert_eq!(fs.read_file(ino, 0, 4 * 512).unwrap(), data);
+    // only the rewritten block took new space
+    assert_eq!(fs.statvfs().bfree, free - 1);
+    // the snapshot does not include the snapshots taken before it
+    let second = fs.snapshot(0, 0, OsStr::new("t")).unwrap();
+    assert_eq!(
+        fs.lookup_dirent(second, OsStr::new(crate::SNAPSHOTS_DIR))
+            .err(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[test]
+fn snapshots_taken_during_writes_see_whole_operations() {
+    let dev = mem_store(2048);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (core, stop) = (fs.core.clone(), stop.clone());
+        std::thread::spawn(move || {
+            for n in 1.. {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                core.truncate(ino, 0).unwrap();
+                core.write_file(ino, Some(0), &[n as u8; 16 * 512]).unwrap();
+                core.write_file(ino, Some(512), &[n as u8; 512]).unwrap();
+            }
+        })
+    };
+    let snaps: Vec<u64> = (0..40)
+        .map(|n| fs.snapshot(0, 0, OsStr::new(&n.to_string())).unwrap())
+        .collect();
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+
+    // every clone holds the file either truncated or as one writer left it
+    for snap in snaps {
+        let clone = fs.lookup_dirent(snap, OsStr::new("f")).unwrap().ino;
+        let data = fs.read_file(clone, 0, 32 * 512).unwrap();
+        assert!(data.is_empty() || data.len() == 16 * 512 && data.iter().all(|&b| b == data[0]));
+    }
+    // no block was shared after being freed or rewritten
+    let turn = fs.unmount();
+    let mut fs = CyanFS::with_store(dev, &meta_path(), false, Config::default()).unwrap();
+    assert_eq!(fs.fsck(false).unwrap(), []);
+    drop(fs);
+    drop(turn);
+}
+
+#[test]
+fn large_aligned_writes_take_one_device_write() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 4096)));
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    // fresh blocks the write covers whole are not zeroed first
+    let writes = dev.writes();
+    fs.write_file(ino, Some(0), &vec![1; 1 << 20]).unwrap();
+    assert_eq!(dev.write_sizes()[writes as usize..], [2048]);
+
+    let fs = fs.remount(dev.clone(), Config::default());
+    let (reads, writes) = (dev.reads(), dev.writes());
+    fs.write_file(ino, Some(0), &vec![2; 1 << 20]).unwrap();
+    assert_eq!(dev.reads(), reads);
+    assert_eq!(dev.write_sizes()[writes as usize..], [2048]);
+    assert_eq!(fs.read_file(ino, 0, 1 << 20).unwrap(), vec![2; 1 << 20]);
+}
@@ -0,0 +1,232 @@
+//! Formatting, opening and upgrading data devices.
+
+use super::*;
+use crate::block_dev::STRIPE_BLOCKS;
+use crate::inode::{Attrs, DirEntry, FileType};
+use crate::superblock::Superblock;
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Write};
+use std::time::UNIX_EPOCH;
+
+#[test]
+fn mkfs_then_mount() {
+    let _turn = Turn::take();
+    let data = vec![data_file("mkfs", 1 << 20)];
+    CyanFS::mkfs(&data, &meta_path(), 4096, false).unwrap();
+    let err = CyanFS::mkfs(&data, &meta_path(), 4096, false).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(
+        CyanFS::check_unformatted(&data).unwrap_err().kind(),
+        ErrorKind::AlreadyExists
+    );
+
+    // the block size is taken from the superblock
+    let mut fs = CyanFS::new(&data, &meta_path(), false, Config::default()).unwrap();
+    fs.start(0, 0).unwrap();
+    assert_eq!(fs.block_size, 4096);
+    let ino = fs
+        .create_file(0, 0, FUSE_ROOT_ID, OsStr::new("f"), 0o644)
+        .unwrap();
+    fs.write_file(ino, Some(0), b"hello").unwrap();
+    fs.shutdown();
+    drop(fs);
+
+    let mut fs = CyanFS::new(&data, &meta_path(), false, Config::default()).unwrap();
+    fs.start(0, 0).unwrap();
+    let ino = fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("f")).unwrap().ino;
+    assert_eq!(fs.read_file(ino, 0, 4096).unwrap(), b"hello");
+}
+
+#[test]
+fn format_memory_store_then_remount() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    assert!(Superblock::read(&*dev).unwrap().is_some());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), b"hello").unwrap();
+    let fs = fs.remount(dev, Config::default());
+    assert_eq!(fs.read_file(ino, 0, 512).unwrap(), b"hello");
+}
+
+#[test]
+fn mount_rejects_mismatched_superblocks() {
+    let _turn = Turn::take();
+    let data = vec![
+        data_file("mismatch-0", 1 << 20),
+        data_file("mismatch-1", 1 << 20),
+    ];
+    CyanFS::mkfs(&data, &meta_path(), 512, false).unwrap();
+
+    let config = Config {
+        block_size: Some(4096),
+        ..Config::default()
+    };
+    let err = CyanFS::new(&data, &meta_path(), false, config)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let err = CyanFS::new(&data[..1], &meta_path(), false, Config::default())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(CyanFS::new(&data, &meta_path(), false, Config::default()).is_ok());
+}
+
+#[test]
+fn devices_opened_with_another_block_size_are_refused() {
+    use crate::block_dev::{BackendKind, BlockDevice};
+    let _turn = Turn::take();
+    let data = vec![data_file("blocksize", 1 << 20)];
+    CyanFS::mkfs(&data, &meta_path(), 512, false).unwrap();
+
+    let open = |block_size| {
+        let dev = BlockDevice::new(&data[0], block_size, BackendKind::Pread).unwrap();
+        CyanFS::with_store(Arc::new(dev), &meta_path(), false, Config::default())
+    };
+    let err = open(4096).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(err.to_string(), "formatted with 512 byte blocks, not 4096");
+    assert!(open(512).is_ok());
+}
+
+#[test]
+fn data_is_striped_over_labeled_devices() {
+    let _turn = Turn::take();
+    let data = vec![
+        data_file("stripe-0", 1 << 20),
+        data_file("stripe-1", 1 << 20),
+    ];
+    CyanFS::mkfs(&data, &meta_path(), 512, false).unwrap();
+    let mut fs = CyanFS::new(&data, &meta_path(), false, Config::default()).unwrap();
+    fs.start(0, 0).unwrap();
+    let ino = fs
+        .create_file(0, 0, FUSE_ROOT_ID, OsStr::new("f"), 0o644)
+        .unwrap();
+    let blocks = 4 * STRIPE_BLOCKS;
+    let content: Vec<u8> = (0..blocks * 512).map(|n| (n / 512 + 1) as u8).collect();
+    fs.write_file(ino, Some(0), &content).unwrap();
+    // the first block of the second device holds its label
+    let mapped = fs.read_inode(ino, |i| {
+        (0..blocks).map(|l| i.physical(l)).collect::<Vec<_>>()
+    });
+    assert!(!mapped.unwrap().contains(&Some(STRIPE_BLOCKS)));
+    fs.shutdown();
+    drop(fs);
+
+    for path in &data {
+        let raw = std::fs::read(path).unwrap();
+        let label: Superblock = bincode::deserialize(&raw[..512]).unwrap();
+        assert_eq!(label.devices, 2);
+        assert!(raw[512..].iter().any(|&b| b != 0), "{}", path);
+    }
+    let mut fs = CyanFS::new(&data, &meta_path(), false, Config::default()).unwrap();
+    fs.start(0, 0).unwrap();
+    assert_eq!(fs.read_file(ino, 0, content.len() as u32).unwrap(), content);
+    fs.shutdown();
+    drop(fs);
+
+    // devices given out of order or taken from another filesystem are refused
+    let swapped = [data[1].clone(), data[0].clone()];
+    let err = CyanFS::new(&swapped, &meta_path(), false, Config::default()).err();
+    assert_eq!(err.map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+    let other = vec![
+        data_file("stripe-other-0", 1 << 20),
+        data_file("stripe-other-1", 1 << 20),
+    ];
+    CyanFS::mkfs(&other, &meta_path(), 512, false).unwrap();
+    let mixed = [data[0].clone(), other[1].clone()];
+    let err = CyanFS::new(&mixed, &meta_path(), false, Config::default()).err();
+    assert_eq!(err.map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+}
+
+/// A regular file of five blocks in two extents.
+fn record(ino: u64) -> Attrs {
+    let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    Attrs {
+        ino,
+        size: 5 * 512,
+        extents: BTreeMap::from([(0, 10..12), (2, 40..43)]),
+        atime: time,
+        mtime: time,
+        ctime: time,
+        crtime: time,
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        nlink: 1,
+        uid: 1000,
+        gid: 100,
+        rdev: 0,
+        flags: 0,
+        entries: BTreeMap::new(),
+        link: "".into(),
+        xattrs: BTreeMap::new(),
+        checksums: BTreeMap::new(),
+        compressed: BTreeMap::new(),
+        parent: 0,
+    }
+}
+
+/// Writes the records of a filesystem created before superblocks were introduced, whose only
+/// file `f` keeps its first block in block 0 of `data`.
+fn pre_superblock_fs(data: &str) {
+    let mut dev = OpenOptions::new().write(true).open(data).unwrap();
+    dev.write_all(&[0xab; 512]).unwrap();
+    dev.write_all(&[0xcd; 512]).unwrap();
+    dev.sync_all().unwrap();
+
+    let mut root = record(FUSE_ROOT_ID);
+    root.kind = FileType::Directory;
+    root.nlink = 2;
+    root.size = 0;
+    root.extents = BTreeMap::new();
+    root.entries = BTreeMap::from([(
+        "f".to_string(),
+        DirEntry {
+            ino: 2,
+            kind: FileType::RegularFile,
+        },
+    )]);
+    let mut file = record(2);
+    file.size = 1024;
+    file.extents = BTreeMap::from([(0, 0..2)]);
+    let store = crate::open_store(&meta_path(), true).unwrap();
+    for attrs in [root, file] {
+        cxx::let_cxx_string!(key = attrs.ino.to_le_bytes());
+        cxx::let_cxx_string!(value = attrs.encode());
+        store.lock().unwrap().as_mut().unwrap().put(&key, &value);
+    }
+}
+
+#[test]
+fn upgrade_moves_data_out_of_the_superblock() {
+    let _turn = Turn::take();
+    let data = data_file("upgrade", 1 << 20);
+    pre_superblock_fs(&data);
+
+    let err = CyanFS::new(
+        std::slice::from_ref(&data),
+        &meta_path(),
+        false,
+        Config::default(),
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("upgrade"));
+
+    CyanFS::upgrade(&data, &meta_path()).unwrap();
+    let sb = Superblock::probe(&data).unwrap().unwrap();
+    assert_eq!(sb.block_size, 512);
+    let err = CyanFS::upgrade(&data, &meta_path()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+    let mut fs = CyanFS::new(&[data], &meta_path(), false, Config::default()).unwrap();
+    fs.start(0, 0).unwrap();
+    let ino = fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("f")).unwrap().ino;
+    let physical = fs.read_inode(ino, |i| i.physical(0)).unwrap();
+    assert!(!matches!(physical, None | Some(0)));
+    let mut expected = vec![0xab; 512];
+    expected.extend_from_slice(&[0xcd; 512]);
+    assert_eq!(fs.read_file(ino, 0, 1024).unwrap(), expected);
+    assert!(fs.quarantined().is_empty());
+}
@@ -0,0 +1,117 @@
+//! Checking and repairing the metadata of unmounted filesystems.
+
+use super::*;
+use crate::fsck::{Inconsistency, LOST_AND_FOUND};
+use crate::inode::Attrs;
+
+/// Replaces the stored record of `ino` with what `f` makes of it.
+fn edit(ino: u64, f: impl FnOnce(&mut Attrs)) {
+    let (mut attrs, _) = Attrs::decode(&get_record(ino)).unwrap();
+    f(&mut attrs);
+    put_record(ino, &attrs.encode());
+}
+
+/// Runs fsck on the filesystem formatted on `dev` without mounting it.
+fn fsck(dev: Arc<dyn BlockStore>, repair: bool) -> Vec<Inconsistency> {
+    let mut fs = CyanFS::with_store(dev, &meta_path(), false, Config::default()).unwrap();
+    fs.fsck(repair).unwrap()
+}
+
+#[test]
+fn fsck_finds_and_repairs_inconsistencies() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    fs.write_file(a, Some(0), &[1; 512]).unwrap();
+    fs.write_file(b, Some(0), &[2; 512]).unwrap();
+    let blocks = fs.read_inode(a, |i| i.extents[&0].clone()).unwrap();
+    let turn = fs.unmount();
+    assert_eq!(fsck(dev.clone(), false), []);
+
+    // b loses its entry and claims the block of a, which records too many links, and the root
+    // gains an entry for an inode that was never created
+    edit(FUSE_ROOT_ID, |root| {
+        root.entries.remove("b");
+        let ghost = crate::inode::DirEntry {
+            ino: 999,
+            kind: crate::inode::FileType::RegularFile,
+        };
+        root.entries.insert("ghost".to_string(), ghost);
+    });
+    edit(a, |i| i.nlink = 5);
+    edit(b, |i| {
+        i.extents.insert(0, blocks.clone());
+    });
+    let found = fsck(dev.clone(), false);
+    let expected = [
+        Inconsistency::SharedBlocks {
+            ino: b,
+            owner: a,
+            blocks,
+        },
+        Inconsistency::DanglingEntry {
+            parent: FUSE_ROOT_ID,
+            name: "ghost".to_string(),
+            ino: 999,
+        },
+        Inconsistency::Orphan { ino: b },
+        Inconsistency::LinkCount {
+            ino: a,
+            recorded: 5,
+            actual: 1,
+        },
+    ];
+    for inconsistency in &expected {
+        assert!(found.contains(inconsistency), "{}", inconsistency);
+    }
+    // checking alone changes nothing
+    assert_eq!(fsck(dev.clone(), false), found);
+
+    let repaired = fsck(dev.clone(), true);
+    for inconsistency in &expected {
+        assert!(repaired.contains(inconsistency), "{}", inconsistency);
+    }
+    assert_eq!(fsck(dev.clone(), false), []);
+    let mut fs = TestFs::mount(turn, dev, Config::default()).unwrap();
+    assert_eq!(fs.read_inode(a, |i| i.nlink).unwrap(), 1);
+    assert_eq!(fs.read_file(a, 0, 512).unwrap(), [1; 512]);
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new("ghost")).err(),
+        Some(libc::ENOENT)
+    );
+    // the orphan is linked into lost+found, without the block it shared
+    let lost = fs
+        .lookup_dirent(FUSE_ROOT_ID, OsStr::new(LOST_AND_FOUND))
+        .unwrap();
+    let entry = fs.lookup_dirent(lost.ino, OsStr::new(&format!("#{}", b)));
+    assert_eq!(entry.map(|e| e.ino), Ok(b));
+    assert_eq!(fs.read_inode(b, |i| i.blocks()).unwrap(), 0);
+}
+
+#[test]
+fn fsck_deletes_files_unlinked_while_open() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let a = fs.create("a");
+    fs.write_file(a, Some(0), &[1; 4 * 512]).unwrap();
+    fs.open_file(a, libc::O_RDWR).unwrap();
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("a")).unwrap();
+    fs.sync_file(a, false).unwrap();
+    let free = fs.statvfs().bfree;
+    // the filesystem goes down before the handle is closed
+    let turn = fs.abandon();
+    assert_eq!(Attrs::decode(&get_record(a)).map(|(i, _)| i.nlink), Some(0));
+
+    // neither an orphan nor linked into lost+found
+    assert_eq!(fsck(dev.clone(), false), []);
+    assert_eq!(fsck(dev.clone(), true), []);
+    assert_eq!(get_record(a), []);
+    let mut fs = TestFs::mount(turn, dev, Config::default()).unwrap();
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new(LOST_AND_FOUND))
+            .err(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(fs.statvfs().bfree, free + 4);
+}
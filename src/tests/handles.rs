@@ -0,0 +1,240 @@
+//! File handles handed out by open and create.
+
+use super::*;
+use crate::block_dev::MemBlockStore;
+use crate::faulty::FaultyBlockDevice;
+use crate::inode::FileType;
+use crate::FlushOnClose;
+
+#[test]
+fn open_with_o_trunc_empties_the_file() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[7; 4 * 512]).unwrap();
+    let free = fs.statvfs().bfree;
+
+    // opening for reading leaves the data alone, O_TRUNC only applies to writable opens
+    let fh = fs.open_file(ino, libc::O_RDONLY | libc::O_TRUNC).unwrap();
+    assert_eq!(fs.read_inode(ino, |i| i.size).unwrap(), 4 * 512);
+    assert!(fs.check_fh(ino, fh).is_ok());
+
+    let fh = fs.open_file(ino, libc::O_WRONLY | libc::O_TRUNC).unwrap();
+    assert_eq!(
+        fs.read_inode(ino, |i| (i.size, i.blocks())).unwrap(),
+        (0, 0)
+    );
+    assert_eq!(fs.statvfs().bfree, free + 4);
+    assert_eq!(fs.read_file(ino, 0, 512).unwrap(), []);
+    // handles are only valid for the inode they were opened on
+    assert_eq!(
+        fs.check_fh(ino, fh).map(|f| f.flags),
+        Ok(libc::O_WRONLY | libc::O_TRUNC)
+    );
+    assert_eq!(fs.check_fh(FUSE_ROOT_ID, fh).err(), Some(libc::EBADF));
+}
+
+#[test]
+fn appends_land_at_the_end() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), b"head").unwrap();
+    let fh = fs.open_file(ino, libc::O_WRONLY | libc::O_APPEND).unwrap();
+    assert!(fs.check_fh(ino, fh).unwrap().flags & libc::O_APPEND != 0);
+    fs.write_file(ino, None, b"one").unwrap();
+    fs.write_file(ino, None, b"two").unwrap();
+    assert_eq!(fs.read_file(ino, 0, 512).unwrap(), b"headonetwo");
+
+    // appenders on other threads never share an offset
+    let handles: Vec<_> = (0..4u8)
+        .map(|n| {
+            let core = fs.core.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    core.write_file(ino, None, &[n; 7]).unwrap();
+                }
+            })
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    let data = fs.read_file(ino, 10, 4 * 50 * 7).unwrap();
+    assert_eq!(data.len(), 4 * 50 * 7);
+    for record in data.chunks(7) {
+        assert!(record.iter().all(|&b| b == record[0]));
+    }
+}
+
+#[test]
+fn open_reply_flags_follow_o_direct() {
+    use fuser::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+    assert_eq!(crate::open_reply_flags(libc::O_RDWR), FOPEN_KEEP_CACHE);
+    assert_eq!(
+        crate::open_reply_flags(libc::O_RDWR | libc::O_CREAT),
+        FOPEN_KEEP_CACHE
+    );
+    assert_eq!(
+        crate::open_reply_flags(libc::O_RDONLY | libc::O_DIRECT),
+        FOPEN_DIRECT_IO
+    );
+}
+
+#[test]
+fn only_the_last_close_syncs_the_file() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    let first = fs.open_file(ino, libc::O_WRONLY).unwrap();
+    let second = fs.open_file(ino, libc::O_RDONLY).unwrap();
+    fs.write_file(ino, Some(0), &[1; 512]).unwrap();
+    let syncs = dev.syncs();
+
+    fs.flush_file(ino, first, 0).unwrap();
+    fs.release_file(first, None).unwrap();
+    assert_eq!(dev.syncs(), syncs);
+    assert!(fs.meta.read().unwrap().data_dirty(ino));
+
+    fs.flush_file(ino, second, 0).unwrap();
+    fs.release_file(second, None).unwrap();
+    assert_eq!(dev.syncs(), syncs + 1);
+    assert!(!fs.meta.read().unwrap().data_dirty(ino));
+    assert_eq!(fs.release_file(second, None), Err(libc::EBADF));
+}
+
+#[test]
+fn every_close_syncs_the_file_when_configured() {
+    let dev = Arc::new(FaultyBlockDevice::new(MemBlockStore::new(512, 256)));
+    let config = Config {
+        flush_on_close: FlushOnClose::Every,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config);
+    let ino = fs.create("f");
+    let first = fs.open_file(ino, libc::O_WRONLY).unwrap();
+    let _second = fs.open_file(ino, libc::O_RDONLY).unwrap();
+    fs.write_file(ino, Some(0), &[1; 512]).unwrap();
+    let syncs = dev.syncs();
+    fs.flush_file(ino, first, 0).unwrap();
+    assert_eq!(dev.syncs(), syncs + 1);
+    assert!(!fs.meta.read().unwrap().data_dirty(ino));
+}
+
+#[test]
+fn permits_follows_the_mode_bits_of_the_caller() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    let mut attrs = fs.read_inode(ino, |i| i.clone()).unwrap();
+    attrs.uid = 1000;
+    attrs.gid = 100;
+    attrs.perm = 0o640;
+    let rw = libc::R_OK | libc::W_OK;
+    // owner, group and others each get their own bits
+    assert!(attrs.permits(1000, 1, rw));
+    assert!(!attrs.permits(1000, 1, libc::X_OK));
+    assert!(attrs.permits(2000, 100, libc::R_OK));
+    assert!(!attrs.permits(2000, 100, rw));
+    assert!(!attrs.permits(2000, 1, libc::R_OK));
+    assert!(attrs.permits(2000, 1, libc::F_OK));
+    // root reads and writes anything, but only executes what somebody may execute
+    assert!(attrs.permits(0, 0, rw));
+    assert!(!attrs.permits(0, 0, libc::X_OK));
+    attrs.perm = 0o601;
+    assert!(attrs.permits(0, 0, libc::X_OK));
+    attrs.kind = FileType::Directory;
+    attrs.perm = 0o600;
+    assert!(attrs.permits(0, 0, libc::X_OK));
+}
+
+#[test]
+fn unlinked_files_live_until_their_last_close() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 2 * 512]).unwrap();
+    let free = fs.statvfs().bfree;
+    let first = fs.open_file(ino, libc::O_RDWR).unwrap();
+    let second = fs.open_file(ino, libc::O_RDONLY).unwrap();
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("f")).unwrap();
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new("f")).err(),
+        Some(libc::ENOENT)
+    );
+
+    // the handles keep working on the nameless file
+    fs.write_file(ino, Some(2 * 512), &[2; 512]).unwrap();
+    let mut data = vec![1; 2 * 512];
+    data.extend([2; 512]);
+    assert_eq!(fs.read_file(ino, 0, 3 * 512).unwrap(), data);
+    fs.release_file(first, None).unwrap();
+    assert_eq!(fs.read_inode(ino, |i| i.nlink), Ok(0));
+    assert_eq!(fs.statvfs().bfree, free - 1);
+
+    fs.release_file(second, None).unwrap();
+    assert_eq!(fs.read_inode(ino, |i| i.nlink).err(), Some(libc::ENOENT));
+    assert_eq!(fs.statvfs().bfree, free + 2);
+}
+
+#[test]
+fn unlinked_files_live_until_the_kernel_forgets_them() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 2 * 512]).unwrap();
+    let free = fs.statvfs().bfree;
+    // the kernel looked the file up twice
+    fs.looked_up(ino);
+    fs.looked_up(ino);
+    let fh = fs.open_file(ino, libc::O_RDWR).unwrap();
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("f")).unwrap();
+    fs.write_file(ino, Some(0), &[2; 512]).unwrap();
+    assert_eq!(fs.read_file(ino, 0, 512).unwrap(), [2; 512]);
+
+    // closing the last handle is not enough while the kernel still refers to the inode
+    fs.release_file(fh, None).unwrap();
+    fs.forget_inode(ino, 1);
+    assert_eq!(fs.read_inode(ino, |i| i.nlink), Ok(0));
+    assert_eq!(fs.statvfs().bfree, free);
+    fs.forget_inode(ino, 1);
+    assert_eq!(fs.read_inode(ino, |i| i.nlink).err(), Some(libc::ENOENT));
+    assert_eq!(fs.statvfs().bfree, free + 2);
+    // forgetting inodes the kernel never saw does nothing
+    fs.forget_inode(ino, 1);
+}
+
+#[test]
+fn tmpfiles_are_nameless_until_linked() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let free = fs.statvfs().bfree;
+    let dir = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    let ino = fs.create_tmpfile(0, 0, dir, 0o600).unwrap();
+    let fh = fs.open_file(ino, libc::O_RDWR).unwrap();
+    fs.write_file(ino, Some(0), &[1; 2 * 512]).unwrap();
+    assert_eq!(fs.read_inode(ino, |i| i.nlink), Ok(0));
+    let fh_dir = fs.open_dir(dir).unwrap();
+    let names: Vec<String> = fs
+        .dir_entries(dir, fh_dir, 0)
+        .unwrap()
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect();
+    assert_eq!(names, [".", ".."]);
+    fs.close_dir(fh_dir).unwrap();
+
+    // linking gives it a name that outlives the handle
+    let attrs = fs.link_entry(ino, dir, OsStr::new("t")).unwrap();
+    assert_eq!((attrs.ino, attrs.nlink), (ino, 1));
+    fs.release_file(fh, None).unwrap();
+    let entry = fs.lookup_dirent(dir, OsStr::new("t")).unwrap();
+    assert_eq!(fs.read_file(entry.ino, 0, 2 * 512).unwrap(), [1; 2 * 512]);
+
+    // one that never got a name is gone with its last handle
+    let other = fs.create_tmpfile(0, 0, dir, 0o600).unwrap();
+    let fh = fs.open_file(other, libc::O_RDWR).unwrap();
+    fs.write_file(other, Some(0), &[2; 512]).unwrap();
+    fs.release_file(fh, None).unwrap();
+    assert_eq!(fs.read_inode(other, |i| i.nlink).err(), Some(libc::ENOENT));
+    assert_eq!(fs.statvfs().bfree, free - 2);
+    assert_eq!(
+        fs.create_tmpfile(0, 0, entry.ino, 0o600).err(),
+        Some(libc::ENOTDIR)
+    );
+}
@@ -0,0 +1,74 @@
+//! Checksums of data blocks and the handling of blocks failing them.
+
+use super::*;
+use crate::CorruptBlocks;
+use std::collections::BTreeSet;
+
+/// Writes two blocks to a new file on `dev` and corrupts the first of them on the device behind
+/// the back of the unmounted filesystem, returning the turn, the inode and the corrupt block.
+fn corrupt_file(dev: &Arc<MemBlockStore>) -> (Turn, u64, usize) {
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[5; 2 * 512]).unwrap();
+    let block = fs.read_inode(ino, |i| i.physical(0)).unwrap().unwrap();
+    let turn = fs.unmount();
+    let mut buf = vec![0u8; 512];
+    dev.read_block(block, &mut buf).unwrap();
+    buf[17] ^= 0xff;
+    dev.write_block(block, &buf).unwrap();
+    (turn, ino, block)
+}
+
+#[test]
+fn corrupt_blocks_fail_reads() {
+    let dev = mem_store(256);
+    let (turn, ino, block) = corrupt_file(&dev);
+    let fs = TestFs::mount(turn, dev, Config::default()).unwrap();
+    assert_eq!(fs.read_file(ino, 0, 512), Err(libc::EIO));
+    assert_eq!(fs.read_file(ino, 512, 512).unwrap(), [5; 512]);
+    assert_eq!(fs.corrupt_blocks(), BTreeSet::from([(ino, block)]));
+}
+
+#[test]
+fn ignored_corruption_is_still_recorded() {
+    let dev = mem_store(256);
+    let (turn, ino, block) = corrupt_file(&dev);
+    let config = Config {
+        corrupt_blocks: CorruptBlocks::Ignore,
+        ..Config::default()
+    };
+    let fs = TestFs::mount(turn, dev, config).unwrap();
+    let data = fs.read_file(ino, 0, 512).unwrap();
+    assert_eq!(data[17], 5 ^ 0xff);
+    assert_eq!(fs.corrupt_blocks(), BTreeSet::from([(ino, block)]));
+    // merging a write into the block would seal the corruption in, so it still fails
+    assert_eq!(fs.write_file(ino, Some(1), b"x"), Err(libc::EIO));
+}
+
+#[test]
+fn punched_holes_read_zeros_with_checksums() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 6 * 512]).unwrap();
+    let punched: Vec<usize> = (2..4)
+        .map(|block| fs.read_inode(ino, |i| i.physical(block)).unwrap().unwrap())
+        .collect();
+    let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+    fs.fallocate_file(ino, 2 * 512, 2 * 512, mode).unwrap();
+    let sums = fs.read_inode(ino, |i| i.checksums.clone()).unwrap();
+    assert_eq!(sums.len(), 4);
+    assert!(punched.iter().all(|block| !sums.contains_key(block)));
+
+    // another file reusing the freed blocks changes nothing about the hole
+    let other = fs.create("g");
+    fs.write_file(other, Some(0), &[2; 2 * 512]).unwrap();
+    let reused = fs.read_inode(other, |i| i.physical(0)).unwrap();
+    assert!(punched.contains(&reused.unwrap()));
+    let fs = fs.remount(dev, Config::default());
+    let data = fs.read_file(ino, 0, 6 * 512).unwrap();
+    assert_eq!(data[..2 * 512], [1; 2 * 512]);
+    assert_eq!(data[2 * 512..4 * 512], [0; 2 * 512]);
+    assert_eq!(data[4 * 512..], [1; 2 * 512]);
+    assert_eq!(fs.read_file(ino, 2 * 512, 100).unwrap(), [0; 100]);
+}
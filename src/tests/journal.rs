@@ -0,0 +1,76 @@
+//! The metadata journal.
+
+use super::*;
+use crate::journal::JOURNAL_PREFIX;
+
+fn journaled(keys: &[Vec<u8>]) -> usize {
+    keys.iter()
+        .filter(|key| key.starts_with(JOURNAL_PREFIX))
+        .count()
+}
+
+#[test]
+fn journal_goes_to_the_wal_device() {
+    let dev = mem_store(256);
+    let config = || Config {
+        wal: Some(wal_path()),
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config());
+    let ino = fs.create("f");
+    // a transaction interrupted before it committed stays in the journal
+    let mut tx = fs.journal.begin();
+    tx.before.insert(ino, fs.image(ino));
+    fs.journal.log(&tx);
+    let turn = fs.unmount();
+
+    assert_eq!(journaled(&store_keys(&wal_path())), 1);
+    assert_eq!(journaled(&store_keys(&meta_path())), 0);
+    // the inodes themselves stay on the metadata device
+    assert!(!get_record(ino).is_empty());
+    assert!(store_keys(&wal_path()).iter().all(|key| key.len() != 8));
+
+    let fs = TestFs::mount(turn, dev, config()).unwrap();
+    drop(fs.unmount());
+    assert_eq!(journaled(&store_keys(&wal_path())), 0);
+}
+
+#[test]
+fn replay_settles_interrupted_transactions() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let f = fs.create("f");
+    let g = fs.create("g");
+    let f_name = OsStr::new("f");
+    let g_name = OsStr::new("g");
+
+    // unlinking f got as far as writing back the directory without its entry
+    let mut tx = fs.journal.begin();
+    for ino in [FUSE_ROOT_ID, f] {
+        tx.before.insert(ino, fs.image(ino));
+    }
+    fs.journal.log(&tx);
+    fs.remove_dirent(FUSE_ROOT_ID, f_name).unwrap();
+    fs.meta.write().unwrap().write_back(FUSE_ROOT_ID);
+    let mut fs = fs.crash(dev.clone(), Config::default());
+    // rolled back, the entry is back and its inode intact
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, f_name).unwrap().ino, f);
+    assert_eq!(fs.read_inode(f, |i| i.nlink).unwrap(), 1);
+
+    // unlinking g completed, but only the journal learned of it
+    let mut tx = fs.journal.begin();
+    for ino in [FUSE_ROOT_ID, g] {
+        tx.before.insert(ino, fs.image(ino));
+    }
+    fs.journal.log(&tx);
+    fs.remove_dirent(FUSE_ROOT_ID, g_name).unwrap();
+    fs.meta.write().unwrap().modify(g, |i| i.nlink = 0).unwrap();
+    let after = [FUSE_ROOT_ID, g].map(|ino| (ino, fs.image(ino)));
+    tx.after = Some(after.into_iter().collect());
+    fs.journal.log(&tx);
+    let mut fs = fs.crash(dev, Config::default());
+    // rolled forward, the entry and its inode are gone
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, g_name), Err(libc::ENOENT));
+    assert_eq!(fs.read_inode(g, |_| ()), Err(libc::ENOENT));
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, f_name).unwrap().ino, f);
+}
@@ -0,0 +1,188 @@
+//! Tests driving [`CyanFS`] in process through its library API, mostly on data devices held in
+//! memory.
+
+mod cache;
+mod data;
+mod format;
+mod fsck;
+mod handles;
+mod integrity;
+mod journal;
+mod mount;
+mod namespace;
+mod records;
+mod stats;
+mod store;
+mod xattrs;
+
+use crate::block_dev::{BlockStore, MemBlockStore};
+use crate::{Config, CyanFS};
+use fuser::FUSE_ROOT_ID;
+use libc::c_int;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Set while a test has a metadata store open, libkv only supports one per process.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Exclusive use of the metadata store for as long as it is held.
+pub struct Turn(());
+
+impl Turn {
+    pub fn take() -> Self {
+        while BUSY
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Turn(())
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        BUSY.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Directory holding the files of the tests. It is kept between runs so the metadata device,
+/// which libkv allocates at its full size, is only created once.
+pub fn scratch_dir() -> PathBuf {
+    // O_DIRECT is refused by tmpfs, which often backs the temporary directory
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/tests");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Path of the metadata device shared by the tests.
+pub fn meta_path() -> String {
+    scratch_dir().join("meta").to_str().unwrap().to_string()
+}
+
+/// Path of the separate journal device of the tests that configure one.
+pub fn wal_path() -> String {
+    scratch_dir().join("wal").to_str().unwrap().to_string()
+}
+
+/// Keys held by the metadata store at `path`, which no filesystem may have open.
+pub fn store_keys(path: &str) -> Vec<Vec<u8>> {
+    let store = crate::open_store(path, false).unwrap();
+    let keys = store.lock().unwrap().list();
+    keys.into_iter()
+        .map(|key| key.as_bytes().to_vec())
+        .collect()
+}
+
+/// Path of the data device `name`, recreated as a sparse file of `len` zero bytes.
+pub fn data_file(name: &str, len: u64) -> String {
+    let path = scratch_dir().join(name);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    file.set_len(len).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// A zeroed in-memory data device of `blocks` blocks of 512 bytes.
+pub fn mem_store(blocks: usize) -> Arc<MemBlockStore> {
+    Arc::new(MemBlockStore::new(512, blocks))
+}
+
+/// Raw inode record of `ino` in the metadata store, which no filesystem may have open.
+pub fn get_record(ino: u64) -> Vec<u8> {
+    let store = crate::open_store(&meta_path(), false).unwrap();
+    cxx::let_cxx_string!(key = ino.to_le_bytes());
+    let value = store.lock().unwrap().get(&key);
+    value.as_bytes().to_vec()
+}
+
+/// Replaces the raw inode record of `ino` in the metadata store, which no filesystem may have
+/// open.
+pub fn put_record(ino: u64, record: &[u8]) {
+    let store = crate::open_store(&meta_path(), false).unwrap();
+    cxx::let_cxx_string!(key = ino.to_le_bytes());
+    cxx::let_cxx_string!(value = record);
+    let mut store = store.lock().unwrap();
+    store.as_mut().unwrap().put(&key, &value);
+    assert!(store.as_mut().unwrap().sync());
+}
+
+/// A started filesystem along with the turn its metadata store was opened in.
+pub struct TestFs {
+    pub fs: CyanFS,
+    turn: Turn,
+}
+
+impl TestFs {
+    /// Formats `dev` and starts the filesystem on it.
+    pub fn format(dev: Arc<dyn BlockStore>, config: Config) -> Self {
+        Self::open(Turn::take(), dev, true, config)
+    }
+    /// Starts the filesystem formatted on `dev`, failing with the errno `start` returned.
+    pub fn mount(turn: Turn, dev: Arc<dyn BlockStore>, config: Config) -> Result<Self, c_int> {
+        let mut fs = CyanFS::with_store(dev, &meta_path(), false, config).unwrap();
+        fs.start(0, 0)?;
+        Ok(Self { fs, turn })
+    }
+    fn open(turn: Turn, dev: Arc<dyn BlockStore>, new: bool, config: Config) -> Self {
+        let mut fs = CyanFS::with_store(dev, &meta_path(), new, config).unwrap();
+        fs.start(0, 0).unwrap();
+        Self { fs, turn }
+    }
+    /// Unmounts cleanly, keeping the metadata store to the caller until the turn is dropped.
+    pub fn unmount(self) -> Turn {
+        let TestFs { mut fs, turn } = self;
+        fs.shutdown();
+        drop(fs);
+        turn
+    }
+    /// Unmounts cleanly, then starts the filesystem on `dev` again.
+    pub fn remount(self, dev: Arc<dyn BlockStore>, config: Config) -> Self {
+        Self::mount(self.unmount(), dev, config).unwrap()
+    }
+    /// Abandons the filesystem without writing anything back, like a crash keeping only what
+    /// already reached the devices and the metadata store.
+    pub fn abandon(self) -> Turn {
+        let TestFs { mut fs, turn } = self;
+        let flushers = fs.inode_flusher.take().into_iter();
+        for (stop, handle) in flushers.chain(fs.block_flusher.take()) {
+            drop(stop);
+            handle.join().unwrap();
+        }
+        // dropping it would write back the cached inodes and blocks
+        std::mem::forget(fs);
+        turn
+    }
+    /// Abandons the filesystem like [`TestFs::abandon`], then starts it on `dev` again.
+    pub fn crash(self, dev: Arc<dyn BlockStore>, config: Config) -> Self {
+        Self::open(self.abandon(), dev, false, config)
+    }
+    /// Creates the regular file `name` in the root directory.
+    pub fn create(&mut self, name: &str) -> u64 {
+        self.fs
+            .create_file(0, 0, FUSE_ROOT_ID, OsStr::new(name), 0o644)
+            .unwrap()
+    }
+}
+
+impl Deref for TestFs {
+    type Target = CyanFS;
+    fn deref(&self) -> &CyanFS {
+        &self.fs
+    }
+}
+
+impl DerefMut for TestFs {
+    fn deref_mut(&mut self) -> &mut CyanFS {
+        &mut self.fs
+    }
+}
@@ -0,0 +1,102 @@
+//! Rebuilding the in-memory state at mount time.
+
+use super::*;
+use crate::allocator::Allocator;
+use crate::inode::Attrs;
+
+/// Makes the next mount rebuild the allocators from a scan of the inodes, as after a crash.
+fn drop_allocator_state() {
+    let store = crate::open_store(&meta_path(), false).unwrap();
+    cxx::let_cxx_string!(key = crate::ALLOCATOR_STATE_KEY);
+    store.lock().unwrap().as_mut().unwrap().remove(&key);
+}
+
+/// Maps logical block 0 of the stored record of `ino` to the physical `blocks`.
+fn remap(ino: u64, blocks: std::ops::Range<usize>) {
+    let (mut attrs, _) = Attrs::decode(&get_record(ino)).unwrap();
+    attrs.extents.insert(0, blocks);
+    put_record(ino, &attrs.encode());
+}
+
+#[test]
+fn mount_quarantines_corrupt_extents() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let inos: Vec<u64> = ["a", "past", "superblock", "overlap", "huge"]
+        .iter()
+        .map(|name| {
+            let ino = fs.create(name);
+            fs.write_file(ino, Some(0), &[1; 512]).unwrap();
+            ino
+        })
+        .collect();
+    let a = fs.read_inode(inos[0], |i| i.extents[&0].clone()).unwrap();
+    let turn = fs.unmount();
+    remap(inos[1], 250..260);
+    remap(inos[2], 0..1);
+    remap(inos[3], a);
+    // far past what the allocator covers, testing it would panic
+    remap(inos[4], Allocator::CAP..Allocator::CAP + 2);
+    drop_allocator_state();
+
+    let fs = TestFs::mount(turn, dev, Config::default()).unwrap();
+    let quarantined: Vec<u64> = fs.quarantined().iter().copied().collect();
+    assert_eq!(quarantined, inos[1..]);
+    assert_eq!(fs.read_file(inos[0], 0, 512).unwrap(), [1; 512]);
+    assert_eq!(fs.read_file(inos[1], 0, 512), Err(libc::EIO));
+    // the blocks of the healthy file stay allocated
+    let blocks = fs.read_inode(inos[0], |i| i.extents[&0].clone()).unwrap();
+    assert!(blocks
+        .clone()
+        .all(|b| !fs.space.lock().unwrap().block_allocator.test(b)));
+}
+
+#[test]
+fn clean_unmounts_restore_the_allocators_without_a_scan() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let inos: Vec<u64> = ["a", "b", "c"]
+        .iter()
+        .map(|name| {
+            let ino = fs.create(name);
+            fs.write_file(ino, Some(0), &[1; 4 * 512]).unwrap();
+            ino
+        })
+        .collect();
+    let blocks = fs.space.lock().unwrap().block_allocator.used_ranges();
+    let inodes = fs.inode_allocator.used_ranges();
+    let turn = fs.unmount();
+    // a scan would find the blocks of this record allocated, the saved state does not know them
+    remap(inos[2], 200..201);
+
+    let fs = TestFs::mount(turn, dev.clone(), Config::default()).unwrap();
+    assert_eq!(
+        fs.space.lock().unwrap().block_allocator.used_ranges(),
+        blocks
+    );
+    assert_eq!(fs.inode_allocator.used_ranges(), inodes);
+    assert!(fs.space.lock().unwrap().block_allocator.test(200));
+
+    // taking the state marks the mount unclean, so a crash falls back to the scan
+    let fs = fs.crash(dev, Config::default());
+    assert!(!fs.space.lock().unwrap().block_allocator.test(200));
+    assert_eq!(fs.inode_allocator.used_ranges(), inodes);
+}
+
+#[test]
+fn kernel_cache_timeouts_follow_the_config() {
+    let dev = mem_store(256);
+    let fs = TestFs::format(dev.clone(), Config::default());
+    assert_eq!(fs.config.attr_timeout, Duration::from_secs(1));
+    assert_eq!(fs.config.entry_timeout, Duration::from_secs(1));
+
+    // zero keeps the kernel from caching anything, for strict consistency
+    let config = Config {
+        attr_timeout: Duration::from_secs(5),
+        entry_timeout: Duration::ZERO,
+        ..Config::default()
+    };
+    let fs = fs.remount(dev, config);
+    assert_eq!(fs.config.attr_timeout, Duration::from_secs(5));
+    assert_eq!(fs.config.entry_timeout, Duration::ZERO);
+}
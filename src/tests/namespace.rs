@@ -0,0 +1,546 @@
+//! Directory entries, links and renames.
+
+use super::*;
+
+fn nlink(fs: &CyanFS, ino: u64) -> u32 {
+    fs.read_inode(ino, |i| i.nlink).unwrap()
+}
+
+/// Inode of the ".." entry of the directory `ino`.
+fn dotdot(fs: &mut CyanFS, ino: u64) -> u64 {
+    let entries = fs.read_dir(ino).unwrap();
+    entries.iter().find(|(name, _)| name == "..").unwrap().1.ino
+}
+
+#[test]
+fn files_live_a_whole_life_without_fuse() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let free = fs.space.lock().unwrap().block_allocator.free();
+    let name = OsStr::new("f");
+    let ino = fs.create_file(0, 0, FUSE_ROOT_ID, name, 0o644).unwrap();
+    assert_eq!(fs.write_file(ino, Some(0), &[7; 1000]), Ok(1000));
+    assert_eq!(fs.write_file(ino, None, b"tail"), Ok(4));
+
+    let attr = fs.lookup_entry(FUSE_ROOT_ID, name).unwrap();
+    assert_eq!((attr.ino, attr.size, attr.perm), (ino, 1004, 0o644));
+    let entries = fs.read_dir(FUSE_ROOT_ID).unwrap();
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, [".", "..", "f"]);
+    assert_eq!(entries[2].1.ino, ino);
+    let data = fs.read_file(ino, 0, 2000).unwrap();
+    assert_eq!(data[..1000], [7; 1000]);
+    assert_eq!(&data[1000..], b"tail");
+    assert_eq!(fs.read_file(ino, 998, 4).unwrap(), [7, 7, b't', b'a']);
+
+    // nothing but the name held the file, so unlinking deletes it
+    fs.unlink_entry(FUSE_ROOT_ID, name).unwrap();
+    assert_eq!(
+        fs.lookup_entry(FUSE_ROOT_ID, name).err(),
+        Some(libc::ENOENT)
+    );
+    assert_eq!(fs.read_dir(FUSE_ROOT_ID).unwrap().len(), 2);
+    assert!(fs.read_inode(ino, |_| ()).is_err());
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free);
+}
+
+#[test]
+fn exchange_directories_between_parents() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let a = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("a"))
+        .unwrap()
+        .ino;
+    let b = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("b"))
+        .unwrap()
+        .ino;
+    let x = fs.make_dir(0, 0, a, OsStr::new("x")).unwrap().ino;
+    let y = fs.make_dir(0, 0, b, OsStr::new("y")).unwrap().ino;
+    fs.create_file(0, 0, b, OsStr::new("f"), 0o644).unwrap();
+
+    // a directory and a directory: the counts stay, the back-pointers swap
+    fs.rename_entry(
+        a,
+        OsStr::new("x"),
+        b,
+        OsStr::new("y"),
+        libc::RENAME_EXCHANGE,
+    )
+    .unwrap();
+    assert_eq!(fs.lookup_dirent(a, OsStr::new("x")).unwrap().ino, y);
+    assert_eq!(fs.lookup_dirent(b, OsStr::new("y")).unwrap().ino, x);
+    assert_eq!(dotdot(&mut fs, x), b);
+    assert_eq!(dotdot(&mut fs, y), a);
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (3, 3));
+    assert_eq!((nlink(&fs, x), nlink(&fs, y)), (2, 2));
+
+    // a directory and a file: the ".." link moves with the directory
+    fs.rename_entry(
+        a,
+        OsStr::new("x"),
+        b,
+        OsStr::new("f"),
+        libc::RENAME_EXCHANGE,
+    )
+    .unwrap();
+    assert_eq!(dotdot(&mut fs, y), b);
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (2, 4));
+    assert_eq!(nlink(&fs, FUSE_ROOT_ID), 4);
+
+    // a missing side changes nothing
+    let err = fs.rename_entry(
+        a,
+        OsStr::new("x"),
+        b,
+        OsStr::new("z"),
+        libc::RENAME_EXCHANGE,
+    );
+    assert_eq!(err, Err(libc::ENOENT));
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (2, 4));
+
+    // the counts and pointers survive a remount
+    let mut fs = fs.remount(dev, Config::default());
+    assert_eq!(dotdot(&mut fs, x), b);
+    assert_eq!(dotdot(&mut fs, y), b);
+    assert_eq!((nlink(&fs, a), nlink(&fs, b)), (2, 4));
+}
+
+#[test]
+fn rmdir_removes_only_empty_directories() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let d = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    fs.create_file(0, 0, d, OsStr::new("f"), 0o644).unwrap();
+    assert_eq!(
+        fs.remove_dir(FUSE_ROOT_ID, OsStr::new("d")),
+        Err(libc::ENOTEMPTY)
+    );
+    assert_eq!(fs.remove_dir(d, OsStr::new("f")), Err(libc::ENOTDIR));
+    assert_eq!(nlink(&fs, FUSE_ROOT_ID), 3);
+
+    fs.unlink_entry(d, OsStr::new("f")).unwrap();
+    let free = fs.inode_allocator.free();
+    fs.remove_dir(FUSE_ROOT_ID, OsStr::new("d")).unwrap();
+    assert_eq!(fs.inode_allocator.free(), free + 1);
+    assert_eq!(fs.read_inode(d, |_| ()), Err(libc::ENOENT));
+    assert_eq!(nlink(&fs, FUSE_ROOT_ID), 2);
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new("d")).err(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[test]
+fn rename_over_a_file_reclaims_it() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    fs.write_file(a, Some(0), b"new").unwrap();
+    let free = fs.space.lock().unwrap().block_allocator.free();
+    fs.write_file(b, Some(0), &[1; 4 * 512]).unwrap();
+    let inodes = fs.inode_allocator.free();
+
+    fs.rename_entry(
+        FUSE_ROOT_ID,
+        OsStr::new("a"),
+        FUSE_ROOT_ID,
+        OsStr::new("b"),
+        0,
+    )
+    .unwrap();
+    assert_eq!(fs.space.lock().unwrap().block_allocator.free(), free);
+    assert_eq!(fs.inode_allocator.free(), inodes + 1);
+    assert_eq!(fs.read_inode(b, |_| ()), Err(libc::ENOENT));
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new("b")).unwrap().ino,
+        a
+    );
+    assert_eq!(fs.read_file(a, 0, 512).unwrap(), b"new");
+
+    // a directory only replaces an empty one
+    let d = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    let e = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("e"))
+        .unwrap()
+        .ino;
+    fs.create_file(0, 0, e, OsStr::new("f"), 0o644).unwrap();
+    let err = fs.rename_entry(
+        FUSE_ROOT_ID,
+        OsStr::new("d"),
+        FUSE_ROOT_ID,
+        OsStr::new("e"),
+        0,
+    );
+    assert_eq!(err, Err(libc::ENOTEMPTY));
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new("d")).unwrap().ino,
+        d
+    );
+}
+
+#[test]
+fn rename_flags() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    fs.write_file(a, Some(0), b"first").unwrap();
+    fs.write_file(b, Some(0), b"second").unwrap();
+    let (na, nb) = (OsStr::new("a"), OsStr::new("b"));
+
+    let noreplace = fs.rename_entry(FUSE_ROOT_ID, na, FUSE_ROOT_ID, nb, libc::RENAME_NOREPLACE);
+    assert_eq!(noreplace, Err(libc::EEXIST));
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, nb).unwrap().ino, b);
+
+    fs.rename_entry(FUSE_ROOT_ID, na, FUSE_ROOT_ID, nb, libc::RENAME_EXCHANGE)
+        .unwrap();
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, na).unwrap().ino, b);
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, nb).unwrap().ino, a);
+    assert_eq!(fs.read_file(a, 0, 512).unwrap(), b"first");
+    assert_eq!(fs.read_file(b, 0, 512).unwrap(), b"second");
+
+    // both sides of an exchange must exist
+    let c = OsStr::new("c");
+    let missing = fs.rename_entry(FUSE_ROOT_ID, na, FUSE_ROOT_ID, c, libc::RENAME_EXCHANGE);
+    assert_eq!(missing, Err(libc::ENOENT));
+    let both = libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE;
+    assert_eq!(
+        fs.rename_entry(FUSE_ROOT_ID, na, FUSE_ROOT_ID, c, both),
+        Err(libc::EINVAL)
+    );
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, na).unwrap().ino, b);
+
+    fs.rename_entry(FUSE_ROOT_ID, na, FUSE_ROOT_ID, c, libc::RENAME_NOREPLACE)
+        .unwrap();
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, c).unwrap().ino, b);
+    assert_eq!(fs.lookup_dirent(FUSE_ROOT_ID, na).err(), Some(libc::ENOENT));
+}
+
+#[test]
+fn unlink_refuses_directories() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let d = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    let f = fs.create_file(0, 0, d, OsStr::new("f"), 0o644).unwrap();
+    assert_eq!(
+        fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("d")),
+        Err(libc::EISDIR)
+    );
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new("d")).unwrap().ino,
+        d
+    );
+    assert_eq!(fs.lookup_dirent(d, OsStr::new("f")).unwrap().ino, f);
+    assert_eq!((nlink(&fs, FUSE_ROOT_ID), nlink(&fs, d)), (3, 2));
+}
+
+#[test]
+fn operations_on_the_wrong_kind_of_inode_fail() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let d = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    let f = fs.create("f");
+    let name = OsStr::new("x");
+
+    // file operations on a directory
+    assert_eq!(fs.read_file(d, 0, 512).err(), Some(libc::EISDIR));
+    assert_eq!(fs.write_file(d, Some(0), &[1; 10]), Err(libc::EISDIR));
+    assert_eq!(fs.open_file(d, libc::O_RDWR).err(), Some(libc::EISDIR));
+
+    // directory operations on a file
+    assert_eq!(
+        fs.create_file(0, 0, f, name, 0o644).err(),
+        Some(libc::ENOTDIR)
+    );
+    assert_eq!(fs.make_dir(0, 0, f, name).err(), Some(libc::ENOTDIR));
+    assert_eq!(
+        fs.make_node(0, 0, f, name, libc::S_IFIFO | 0o644, 0).err(),
+        Some(libc::ENOTDIR)
+    );
+    assert_eq!(fs.lookup_entry(f, name).err(), Some(libc::ENOTDIR));
+    assert_eq!(fs.unlink_entry(f, name), Err(libc::ENOTDIR));
+}
+
+#[test]
+fn directory_link_counts_follow_the_configured_mode() {
+    for (mode, expected) in [
+        (crate::DirNlink::Accurate, 4),
+        (crate::DirNlink::Unknown, 1),
+    ] {
+        let config = Config {
+            dir_nlink: mode,
+            ..Config::default()
+        };
+        let mut fs = TestFs::format(mem_store(256), config);
+        let d = fs
+            .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+            .unwrap()
+            .ino;
+        fs.make_dir(0, 0, d, OsStr::new("x")).unwrap();
+        fs.make_dir(0, 0, d, OsStr::new("y")).unwrap();
+        fs.create_file(0, 0, d, OsStr::new("f"), 0o644).unwrap();
+        let attrs = fs.lookup_entry(FUSE_ROOT_ID, OsStr::new("d")).unwrap();
+        assert_eq!(attrs.nlink, expected);
+        // only the report changes, the stored count stays accurate
+        assert_eq!(nlink(&fs, d), 4);
+    }
+}
+
+#[test]
+fn mknod_creates_fifos_and_device_nodes() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let fifo = fs
+        .make_node(
+            0,
+            0,
+            FUSE_ROOT_ID,
+            OsStr::new("fifo"),
+            libc::S_IFIFO | 0o600,
+            0,
+        )
+        .unwrap();
+    assert_eq!(fifo.kind, fuser::FileType::NamedPipe);
+    assert_eq!((fifo.perm, fifo.rdev), (0o600, 0));
+    let rdev = libc::makedev(1, 3) as u32;
+    let null = fs
+        .make_node(
+            0,
+            0,
+            FUSE_ROOT_ID,
+            OsStr::new("null"),
+            libc::S_IFCHR | 0o666,
+            rdev,
+        )
+        .unwrap();
+    assert_eq!(null.kind, fuser::FileType::CharDevice);
+    assert_eq!((null.perm, null.rdev), (0o666, rdev));
+    let mode = libc::S_IFDIR | 0o755;
+    assert_eq!(
+        fs.make_node(0, 0, FUSE_ROOT_ID, OsStr::new("dir"), mode, 0)
+            .err(),
+        Some(libc::EINVAL)
+    );
+
+    // the kind and device number survive a remount
+    let fs = fs.remount(dev, Config::default());
+    let stat = |ino| fs.read_inode(ino, |i| i.file_attr(512)).unwrap();
+    assert_eq!(stat(fifo.ino).kind, fuser::FileType::NamedPipe);
+    assert_eq!(
+        (stat(null.ino).kind, stat(null.ino).rdev),
+        (fuser::FileType::CharDevice, rdev)
+    );
+}
+
+#[test]
+fn directory_scans_see_the_entries_of_their_opendir() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let dir = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    for name in ["a", "c", "e"] {
+        fs.create_file(0, 0, dir, OsStr::new(name), 0o644).unwrap();
+    }
+    let names = |entries: &[(String, crate::inode::DirEntry)]| {
+        entries
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>()
+    };
+    let fh = fs.open_dir(dir).unwrap();
+    let first = names(&fs.dir_entries(dir, fh, 0).unwrap()[..3]);
+    assert_eq!(first, [".", "..", "a"]);
+
+    // entries added or removed midway neither show up nor shift the rest of the scan
+    fs.create_file(0, 0, dir, OsStr::new("b"), 0o644).unwrap();
+    fs.unlink_entry(dir, OsStr::new("c")).unwrap();
+    assert_eq!(names(fs.dir_entries(dir, fh, 3).unwrap()), ["c", "e"]);
+    assert_eq!(fs.dir_entries(dir, fh, 10).unwrap(), []);
+    assert_eq!(fs.dir_entries(FUSE_ROOT_ID, fh, 0).err(), Some(libc::EBADF));
+    fs.close_dir(fh).unwrap();
+    assert_eq!(fs.dir_entries(dir, fh, 0).err(), Some(libc::EBADF));
+    assert_eq!(fs.close_dir(fh), Err(libc::EBADF));
+
+    let fh = fs.open_dir(dir).unwrap();
+    assert_eq!(
+        names(fs.dir_entries(dir, fh, 0).unwrap()),
+        [".", "..", "a", "b", "e"]
+    );
+}
+
+#[test]
+fn directories_list_dot_and_dotdot_first() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("a"))
+        .unwrap()
+        .ino;
+    let b = fs.make_dir(0, 0, a, OsStr::new("b")).unwrap().ino;
+    let listing = |fs: &mut CyanFS, ino| -> Vec<(String, u64)> {
+        let entries = fs.read_dir(ino).unwrap();
+        entries[..2]
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.ino))
+            .collect()
+    };
+    // the root is its own parent
+    assert_eq!(
+        listing(&mut fs, FUSE_ROOT_ID),
+        [
+            (".".to_string(), FUSE_ROOT_ID),
+            ("..".to_string(), FUSE_ROOT_ID)
+        ]
+    );
+    assert_eq!(
+        listing(&mut fs, b),
+        [(".".to_string(), b), ("..".to_string(), a)]
+    );
+    fs.rename_entry(a, OsStr::new("b"), FUSE_ROOT_ID, OsStr::new("b"), 0)
+        .unwrap();
+    assert_eq!(dotdot(&mut fs, b), FUSE_ROOT_ID);
+    let ino = fs.create("f");
+    assert_eq!(fs.read_dir(ino).err(), Some(libc::ENOTDIR));
+}
+
+#[test]
+fn moved_directories_keep_their_parent_and_path() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("a"))
+        .unwrap()
+        .ino;
+    let b = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("b"))
+        .unwrap()
+        .ino;
+    let d = fs.make_dir(0, 0, a, OsStr::new("d")).unwrap().ino;
+    let f = fs.create_file(0, 0, d, OsStr::new("f"), 0o644).unwrap();
+    assert_eq!(fs.read_inode(d, |i| i.parent).unwrap(), a);
+    assert_eq!(fs.path_of(f), Ok(PathBuf::from("/a/d/f")));
+
+    fs.rename_entry(a, OsStr::new("d"), b, OsStr::new("e"), 0)
+        .unwrap();
+    assert_eq!(fs.read_inode(d, |i| i.parent).unwrap(), b);
+    assert_eq!(fs.read_inode(f, |i| i.parent).unwrap(), d);
+    assert_eq!(fs.path_of(d), Ok(PathBuf::from("/b/e")));
+    assert_eq!(fs.path_of(f), Ok(PathBuf::from("/b/e/f")));
+    assert_eq!(fs.path_of(FUSE_ROOT_ID), Ok(PathBuf::from("/")));
+}
+
+#[test]
+fn readdirplus_reports_what_getattr_does() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let dir = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    for n in 0..100u64 {
+        let name = format!("f{:03}", n);
+        let ino = fs.create_file(0, 0, dir, OsStr::new(&name), 0o644).unwrap();
+        fs.write_file(ino, Some(0), &vec![1; n as usize * 7])
+            .unwrap();
+    }
+    fs.make_dir(0, 0, dir, OsStr::new("sub")).unwrap();
+    let fh = fs.open_dir(dir).unwrap();
+    // removed after the snapshot, it has no attributes left to report
+    fs.unlink_entry(dir, OsStr::new("f050")).unwrap();
+
+    let getattr = |ino| fs.report(fs.read_inode(ino, |i| i.file_attr(512)).unwrap());
+    let listed: Vec<_> = fs.dir_entries_plus(dir, fh, 0).unwrap().collect();
+    assert_eq!(listed.len(), 2 + 100);
+    for (_, name, attrs) in &listed {
+        assert_eq!(*attrs, getattr(attrs.ino), "{}", name);
+    }
+    assert!(listed.iter().all(|(_, name, _)| *name != "f050"));
+    // a scan resumes right after the last entry it got
+    let next = listed[20].0;
+    let rest: Vec<_> = fs.dir_entries_plus(dir, fh, next).unwrap().collect();
+    assert_eq!(rest, listed[21..]);
+}
+
+#[test]
+fn malformed_names_fail_with_an_errno() {
+    use std::os::unix::ffi::OsStrExt;
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let dir = fs
+        .make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d"))
+        .unwrap()
+        .ino;
+    let ino = fs.create_file(0, 0, dir, OsStr::new("f"), 0o644).unwrap();
+    let long = "x".repeat(256);
+    let bad = [
+        (OsStr::new(&long), libc::ENAMETOOLONG),
+        (OsStr::from_bytes(b"\xff\xfe"), libc::EINVAL),
+        (OsStr::new("a/b"), libc::EINVAL),
+        (OsStr::from_bytes(b"a\0b"), libc::EINVAL),
+        (OsStr::new(".."), libc::EINVAL),
+    ];
+    for (name, errno) in bad {
+        assert_eq!(fs.create_file(0, 0, dir, name, 0o644).err(), Some(errno));
+        assert_eq!(fs.make_dir(0, 0, dir, name).err(), Some(errno));
+        assert_eq!(
+            fs.make_node(0, 0, dir, name, libc::S_IFIFO, 0).err(),
+            Some(errno)
+        );
+        assert_eq!(fs.lookup_dirent(dir, name).err(), Some(errno));
+        assert_eq!(fs.unlink_entry(dir, name).err(), Some(errno));
+        let renamed = fs.rename_entry(dir, OsStr::new("f"), dir, name, 0);
+        assert_eq!(renamed.err(), Some(errno));
+    }
+    // a name of exactly NAME_MAX bytes is fine
+    let longest = "x".repeat(255);
+    fs.rename_entry(dir, OsStr::new("f"), dir, OsStr::new(&longest), 0)
+        .unwrap();
+    assert_eq!(
+        fs.lookup_dirent(dir, OsStr::new(&longest)).unwrap().ino,
+        ino
+    );
+    assert_eq!(fs.read_dir(dir).unwrap().len(), 3);
+}
+
+#[test]
+fn subdirectories_count_as_links_and_directories_cannot_be_linked() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let d = fs.make_dir(0, 0, FUSE_ROOT_ID, OsStr::new("d")).unwrap();
+    assert_eq!(d.nlink, 2);
+    let x = fs.make_dir(0, 0, d.ino, OsStr::new("x")).unwrap().ino;
+    fs.make_dir(0, 0, d.ino, OsStr::new("y")).unwrap();
+    assert_eq!(nlink(&fs, d.ino), 4);
+    fs.remove_dir(d.ino, OsStr::new("y")).unwrap();
+    assert_eq!(nlink(&fs, d.ino), 3);
+
+    assert_eq!(
+        fs.link_entry(x, FUSE_ROOT_ID, OsStr::new("x")).err(),
+        Some(libc::EPERM)
+    );
+    assert_eq!(nlink(&fs, x), 2);
+    assert_eq!(
+        fs.lookup_dirent(FUSE_ROOT_ID, OsStr::new("x")).err(),
+        Some(libc::ENOENT)
+    );
+
+    let f = fs.create("f");
+    let attrs = fs.link_entry(f, d.ino, OsStr::new("g")).unwrap();
+    assert_eq!((attrs.ino, attrs.nlink), (f, 2));
+    assert_eq!(fs.lookup_dirent(d.ino, OsStr::new("g")).unwrap().ino, f);
+    // a taken or invalid name leaves the link count alone
+    assert_eq!(
+        fs.link_entry(f, d.ino, OsStr::new("x")).err(),
+        Some(libc::EEXIST)
+    );
+    assert_eq!(
+        fs.link_entry(f, d.ino, OsStr::new("a/b")).err(),
+        Some(libc::EINVAL)
+    );
+    assert_eq!(nlink(&fs, f), 2);
+}
@@ -0,0 +1,71 @@
+//! Inode records written by older versions.
+
+use super::*;
+use crate::inode::{Attrs, RECORD_VERSION};
+use crate::superblock::Superblock;
+
+/// `record` rewritten as a version 2 record, which ends before the parent.
+fn version_2(record: &[u8]) -> Vec<u8> {
+    let (attrs, stale) = Attrs::decode(record).unwrap();
+    assert!(!stale);
+    let mut old = u64::MAX.to_le_bytes().to_vec();
+    old.push(2);
+    let body = bincode::serialize(&attrs).unwrap();
+    old.extend_from_slice(&body[..body.len() - std::mem::size_of::<u64>()]);
+    old
+}
+
+/// Lowers the record version the superblock of `dev` allows for to 2.
+fn downgrade(dev: &dyn BlockStore) {
+    let sb = Superblock::read(dev).unwrap().unwrap();
+    Superblock {
+        inode_version: 2,
+        ..sb
+    }
+    .write(dev)
+    .unwrap();
+}
+
+#[test]
+fn mount_migrates_old_records() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), b"hello").unwrap();
+    let turn = fs.unmount();
+    put_record(ino, &version_2(&get_record(ino)));
+    downgrade(&*dev);
+
+    // mounting alone rewrites the record, the inode is never touched
+    let turn = TestFs::mount(turn, dev.clone(), Config::default())
+        .unwrap()
+        .unmount();
+    let record = get_record(ino);
+    assert_eq!(record[..8], u64::MAX.to_le_bytes());
+    assert_eq!(record[8], RECORD_VERSION);
+    let sb = Superblock::read(&*dev).unwrap().unwrap();
+    assert_eq!(sb.inode_version, RECORD_VERSION as u32);
+
+    let fs = TestFs::mount(turn, dev, Config::default()).unwrap();
+    assert_eq!(fs.read_file(ino, 0, 512).unwrap(), b"hello");
+}
+
+#[test]
+fn undecodable_records_keep_the_old_version() {
+    let dev = mem_store(256);
+    let mut fs = TestFs::format(dev.clone(), Config::default());
+    let ino = fs.create("f");
+    let turn = fs.unmount();
+    let old = version_2(&get_record(ino));
+    put_record(ino, &old);
+    put_record(ino + 1, b"garbage");
+    downgrade(&*dev);
+
+    let err = TestFs::mount(turn, dev.clone(), Config::default()).err();
+    assert_eq!(err, Some(libc::EIO));
+    let _turn = Turn::take();
+    // neither the valid record nor the superblock were touched
+    assert_eq!(get_record(ino), old);
+    let sb = Superblock::read(&*dev).unwrap().unwrap();
+    assert_eq!(sb.inode_version, 2);
+}
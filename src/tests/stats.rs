@@ -0,0 +1,120 @@
+//! Space accounting and statistics.
+
+use super::*;
+use crate::allocator::{Allocator, Fragmentation};
+use crate::metrics::Op;
+
+#[test]
+fn statfs_reports_limits_and_usage() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let before = fs.statvfs();
+    assert_eq!(before.namelen, 255);
+    assert_eq!((before.bsize, before.frsize), (512, 512));
+    assert_eq!(before.blocks, 255);
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 3 * 512]).unwrap();
+    let after = fs.statvfs();
+    assert_eq!(after.bfree, before.bfree - 3);
+    assert_eq!(after.ffree, before.ffree - 1);
+    assert_eq!(fs.statvfs(), after);
+}
+
+#[test]
+fn statfs_free_blocks_follow_writes_and_unlinks() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let before = fs.statvfs();
+    assert_eq!(before.blocks - before.bfree, 0);
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 10 * 512]).unwrap();
+    assert_eq!(fs.statvfs().bfree, before.bfree - 10);
+    assert_eq!(fs.statvfs().bavail, before.bavail - 10);
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("f")).unwrap();
+    assert_eq!(fs.statvfs(), before);
+}
+
+#[test]
+fn stats_count_cache_lookups_bytes_and_operations() {
+    let dev = mem_store(256);
+    // without read-ahead every block read is looked up on its own
+    let config = || Config {
+        read_ahead: 0,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(dev.clone(), config());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[1; 3 * 512]).unwrap();
+    assert_eq!(fs.stats().bytes_written, 3 * 512);
+    let fs = fs.remount(dev, config());
+
+    let before = fs.stats();
+    assert_eq!(fs.read_file(ino, 0, 4 * 512).unwrap().len(), 3 * 512);
+    let cold = fs.stats();
+    assert_eq!(cold.bytes_read - before.bytes_read, 3 * 512);
+    assert_eq!(cold.block_cache_misses - before.block_cache_misses, 3);
+    assert_eq!(cold.block_cache_hits, before.block_cache_hits);
+    assert_eq!(cold.inode_cache_misses - before.inode_cache_misses, 1);
+
+    fs.read_file(ino, 512, 512).unwrap();
+    let warm = fs.stats();
+    assert_eq!(warm.bytes_read - cold.bytes_read, 512);
+    assert_eq!(warm.block_cache_hits - cold.block_cache_hits, 1);
+    assert_eq!(warm.block_cache_misses, cold.block_cache_misses);
+    assert_eq!(warm.inode_cache_misses, cold.inode_cache_misses);
+    assert!(warm.inode_cache_hits > cold.inode_cache_hits);
+
+    fs.metrics.op(Op::Getattr);
+    fs.metrics.op(Op::Getattr);
+    let stats = fs.stats();
+    assert_eq!(stats.ops["getattr"] - warm.ops["getattr"], 2);
+    assert!(stats.prometheus().contains(&format!(
+        "cyanfs_operations_total{{op=\"getattr\"}} {}\n",
+        stats.ops["getattr"]
+    )));
+}
+
+#[test]
+fn fragmentation_counts_maximal_free_runs() {
+    let mut alloc = Allocator::new(0..100);
+    let frag = |free_runs, largest_free_run| Fragmentation {
+        free_runs,
+        largest_free_run,
+    };
+    assert_eq!(alloc.fragmentation(), frag(1, 100));
+    assert_eq!(alloc.alloc_contiguous(40), Some(0));
+    assert_eq!(alloc.fragmentation(), frag(1, 60));
+
+    // freed in pieces, 30..35 still makes one run
+    for range in [2..4, 10..11, 20..25, 30..32, 32..35] {
+        alloc.insert(range);
+    }
+    assert_eq!(alloc.fragmentation(), frag(5, 60));
+    alloc.remove(60..100);
+    assert_eq!(alloc.fragmentation(), frag(5, 20));
+    // freeing the gap joins 30..35 and the tail
+    alloc.insert(35..40);
+    assert_eq!(alloc.fragmentation(), frag(4, 30));
+    alloc.remove(0..100);
+    assert_eq!(alloc.fragmentation(), frag(0, 0));
+}
+
+#[test]
+fn stats_report_the_fragmentation_of_free_blocks() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let inos: Vec<u64> = (0..4).map(|n| fs.create(&n.to_string())).collect();
+    for &ino in &inos {
+        fs.write_file(ino, Some(0), &[1; 4 * 512]).unwrap();
+    }
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("1")).unwrap();
+    fs.unlink_entry(FUSE_ROOT_ID, OsStr::new("3")).unwrap();
+
+    // the blocks of "3" border the untouched free space
+    let free = fs.statvfs().bfree as usize;
+    let stats = fs.stats();
+    let frag = stats.free_space.unwrap();
+    assert_eq!(frag, fs.fragmentation());
+    assert_eq!(frag.free_runs, 2);
+    assert_eq!(frag.largest_free_run, free - 4);
+    let prometheus = stats.prometheus();
+    assert!(prometheus.contains("cyanfs_free_block_runs 2\n"));
+    assert!(prometheus.contains(&format!("cyanfs_largest_free_block_run {}\n", free - 4)));
+}
@@ -0,0 +1,110 @@
+//! The metadata store.
+
+use super::*;
+use crate::inode::Attrs;
+use crate::AtimePolicy;
+use std::io::Read;
+
+/// Files of the metadata store at `path` along with their sizes, as its entry table lists them.
+fn store_files(path: &str) -> Vec<(String, u64)> {
+    let mut table = [0u8; 512];
+    std::fs::File::open(path)
+        .unwrap()
+        .read_exact(&mut table)
+        .unwrap();
+    // two 128 byte entries follow the magic number and the disk size
+    table[16..16 + 2 * 128]
+        .chunks(128)
+        .filter(|e| i32::from_ne_bytes(e[108..112].try_into().unwrap()) != 0)
+        .map(|e| {
+            let len = e.iter().position(|&b| b == 0).unwrap();
+            let name = String::from_utf8(e[..len].to_vec()).unwrap();
+            (name, u64::from_ne_bytes(e[120..128].try_into().unwrap()))
+        })
+        .collect()
+}
+
+/// Files of the metadata store after creating and deleting `count` files and unmounting.
+fn churn(count: usize, compact_on_unmount: bool) -> Vec<(String, u64)> {
+    let config = Config {
+        compact_on_unmount,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(mem_store(256), config);
+    for n in 0..count {
+        let name = format!("f{}", n);
+        fs.create(&name);
+        fs.unlink_entry(FUSE_ROOT_ID, OsStr::new(&name)).unwrap();
+    }
+    let turn = fs.unmount();
+    let files = store_files(&meta_path());
+    drop(turn);
+    files
+}
+
+#[test]
+fn compacting_on_unmount_shrinks_the_store() {
+    let kept = churn(200, false);
+    let compacted = churn(200, true);
+    // the rewritten log replaced the old one under its name
+    assert_eq!(kept.len(), 1);
+    assert_eq!(compacted.len(), 1);
+    assert_eq!(compacted[0].0, "current");
+    assert!(compacted[0].1 < kept[0].1);
+}
+
+#[test]
+fn mounting_a_truncated_store_fails_cleanly() {
+    let dev = mem_store(256);
+    let fs = TestFs::format(dev.clone(), Config::default());
+    let _turn = fs.unmount();
+    // too short to even hold the entry table
+    let path = scratch_dir().join("truncated");
+    std::fs::write(&path, [0x5a; 100]).unwrap();
+    let res = CyanFS::with_store(dev, path.to_str().unwrap(), false, Config::default());
+    assert!(res.is_err());
+}
+
+/// Attributes of `ino` as a second instance of the metadata store sees them, which only holds
+/// what the filesystem synced to the store files.
+fn stored_attrs(ino: u64) -> Attrs {
+    let store = crate::open_store(&meta_path(), false).unwrap();
+    cxx::let_cxx_string!(key = ino.to_le_bytes());
+    let record = store.lock().unwrap().get(&key);
+    Attrs::decode(record.as_bytes()).unwrap().0
+}
+
+#[test]
+fn fsynced_metadata_reaches_the_store_files() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[7; 3 * 512]).unwrap();
+    fs.sync_file(ino, false).unwrap();
+    let attrs = stored_attrs(ino);
+    assert_eq!((attrs.size, attrs.blocks()), (3 * 512, 3));
+}
+
+#[test]
+fn fdatasync_skips_timestamp_only_changes() {
+    let config = Config {
+        atime: AtimePolicy::StrictAtime,
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(mem_store(256), config);
+    let ino = fs.create("f");
+    fs.write_file(ino, Some(0), &[7; 512]).unwrap();
+    fs.sync_file(ino, false).unwrap();
+    let atime = stored_attrs(ino).atime;
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    fs.read_file(ino, 0, 512).unwrap();
+    assert!(fs.read_inode(ino, |i| i.atime).unwrap() > atime);
+    fs.sync_file(ino, true).unwrap();
+    assert_eq!(stored_attrs(ino).atime, atime);
+    // a full fsync does write it, as does fdatasync once the data changed
+    fs.sync_file(ino, false).unwrap();
+    assert!(stored_attrs(ino).atime > atime);
+    fs.write_file(ino, Some(512), &[8; 512]).unwrap();
+    fs.sync_file(ino, true).unwrap();
+    assert_eq!(stored_attrs(ino).size, 2 * 512);
+}
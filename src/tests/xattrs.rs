@@ -0,0 +1,76 @@
+//! Extended attributes.
+
+use super::*;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+
+#[test]
+fn new_files_carry_the_default_xattrs() {
+    let label = OsStr::new("security.label");
+    let config = Config {
+        default_xattrs: BTreeMap::from([("security.label".to_string(), b"public".to_vec())]),
+        ..Config::default()
+    };
+    let mut fs = TestFs::format(mem_store(256), config);
+    let f = fs.create("f");
+    let g = fs.create("g");
+    assert_eq!(fs.get_xattr(f, label).unwrap(), b"public");
+
+    // the default is a plain xattr each file can override or drop on its own
+    fs.set_xattr(f, label, b"secret", 0).unwrap();
+    assert_eq!(fs.get_xattr(f, label).unwrap(), b"secret");
+    assert_eq!(fs.get_xattr(g, label).unwrap(), b"public");
+    fs.remove_xattr(g, label).unwrap();
+    assert_eq!(fs.get_xattr(g, label), Err(libc::ENODATA));
+    assert_eq!(
+        fs.set_xattr(g, label, b"x", libc::XATTR_REPLACE),
+        Err(libc::ENODATA)
+    );
+    assert_eq!(
+        fs.set_xattr(f, label, b"x", libc::XATTR_CREATE),
+        Err(libc::EEXIST)
+    );
+}
+
+#[test]
+fn xattr_names_must_be_utf8() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let ino = fs.create("f");
+    let name = OsString::from_vec(b"user.\xff".to_vec());
+    assert_eq!(fs.set_xattr(ino, &name, b"x", 0), Err(libc::EINVAL));
+    assert_eq!(fs.get_xattr(ino, &name), Err(libc::EINVAL));
+    assert_eq!(fs.remove_xattr(ino, &name), Err(libc::EINVAL));
+}
+
+#[test]
+fn extents_xattr_reports_the_layout() {
+    let mut fs = TestFs::format(mem_store(256), Config::default());
+    let a = fs.create("a");
+    let b = fs.create("b");
+    // interleaving the writes of two files breaks both into one extent per block
+    for n in 0..4 {
+        fs.write_file(a, Some(n * 512), &[1; 512]).unwrap();
+        fs.write_file(b, Some(n * 512), &[2; 512]).unwrap();
+    }
+    let name = OsStr::new(crate::EXTENTS_XATTR);
+    let value = String::from_utf8(fs.get_xattr(a, name).unwrap()).unwrap();
+    let reported: Vec<(usize, usize, usize)> = value
+        .lines()
+        .map(|line| {
+            let fields: Vec<usize> = line.split(' ').map(|f| f.parse().unwrap()).collect();
+            (fields[0], fields[1], fields[2])
+        })
+        .collect();
+    let extents = fs.read_inode(a, |i| i.extents.clone()).unwrap();
+    assert_eq!(reported.len(), 4);
+    assert_eq!(
+        reported,
+        extents
+            .iter()
+            .map(|(&start, e)| (start, e.start, e.len()))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(fs.set_xattr(a, name, b"0 0 1\n", 0), Err(libc::EPERM));
+    assert_eq!(fs.remove_xattr(a, name), Err(libc::EPERM));
+}